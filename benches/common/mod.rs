@@ -0,0 +1,148 @@
+//! Shared setup for the benchmarks: temporary databases, seeded random keys and YCSB's
+//! request distributions.
+
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use bytes::Bytes;
+use bssdb::{Durability, Options};
+use bssdb::blocking::DB;
+
+/// Value sizes every benchmark runs at, in bytes. The largest is past the inline threshold,
+/// so its values go through the value log.
+pub const VALUE_SIZES: [usize; 3] = [16, 256, 4096];
+
+/// Cache sizes: one much smaller than the data, so reads go to the store, and one that holds it all
+pub const CACHE_SIZES: [(&str, usize); 2] = [("cold", 1024 * 1024), ("warm", 256 * 1024 * 1024)];
+
+/// Records loaded before a benchmark runs
+pub const RECORDS: u64 = 20_000;
+
+/// A database in a temporary file, removed when dropped
+pub struct BenchDb {
+    pub db: DB,
+    path: PathBuf
+}
+
+impl BenchDb {
+    /// Open an empty database. Commits don't sync, so results measure the engine rather than the disk's flush latency.
+    pub fn new(cache_bytes: usize) -> BenchDb {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("bssdb-bench-{}-{}", std::process::id(), n));
+        let _ = std::fs::remove_file(&path);
+
+        let mut options = Options::new();
+        options.cache_size(cache_bytes).durability(Durability::NoSync);
+        let db = DB::open(&path, options).expect("opening the benchmark database failed");
+        BenchDb { db, path }
+    }
+
+    /// Open a database holding `RECORDS` records with values of `value_size` bytes
+    pub fn loaded(cache_bytes: usize, value_size: usize) -> BenchDb {
+        let bench = BenchDb::new(cache_bytes);
+        for chunk in (0..RECORDS).collect::<Vec<_>>().chunks(1000) {
+            let mut txn = bench.db.write().unwrap();
+            for &i in chunk {
+                txn.put(key(i), value(i, value_size)).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+        bench
+    }
+}
+
+impl Drop for BenchDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The key of record `i`, which sort in record order
+pub fn key(i: u64) -> Bytes {
+    Bytes::from(format!("user{:012}", i))
+}
+
+pub fn value(i: u64, size: usize) -> Bytes {
+    Bytes::from((0..size).map(|j| (i as usize + j) as u8).collect::<Vec<_>>())
+}
+
+/// A xorshift generator, so every run requests the same records
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A uniform sample from [0, n)
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// A uniform sample from [0, 1)
+    pub fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// YCSB's zipfian distribution over [0, n), with its default skew: a few records get
+/// most of the requests. Records are scrambled, so the popular ones are spread across the tree.
+#[derive(Clone)]
+pub struct Zipfian {
+    n: u64,
+    alpha: f64,
+    zeta_n: f64,
+    eta: f64
+}
+
+const THETA: f64 = 0.99;
+
+fn zeta(n: u64) -> f64 {
+    (1..=n).map(|i| 1.0 / (i as f64).powf(THETA)).sum()
+}
+
+impl Zipfian {
+    pub fn new(n: u64) -> Zipfian {
+        let zeta_n = zeta(n);
+        let zeta_2 = zeta(2);
+        Zipfian {
+            n,
+            alpha: 1.0 / (1.0 - THETA),
+            zeta_n,
+            eta: (1.0 - (2.0 / n as f64).powf(1.0 - THETA)) / (1.0 - zeta_2 / zeta_n)
+        }
+    }
+
+    /// The rank of the next record requested, most popular first
+    fn rank(&self, rng: &mut Rng) -> u64 {
+        let u = rng.unit();
+        let uz = u * self.zeta_n;
+        if uz < 1.0 { return 0 }
+        if uz < 1.0 + 0.5f64.powf(THETA) { return 1 }
+        ((self.n as f64) * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64 % self.n
+    }
+
+    pub fn next(&self, rng: &mut Rng) -> u64 {
+        // FNV-1a, as YCSB scrambles with
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for byte in self.rank(rng).to_le_bytes().iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        hash % self.n
+    }
+
+    /// A record near the most recent of `latest` records, for workload D
+    pub fn latest(&self, rng: &mut Rng, latest: u64) -> u64 {
+        latest - 1 - self.rank(rng) % latest
+    }
+}
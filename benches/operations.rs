@@ -0,0 +1,83 @@
+//! Single operations: point gets, sequential and random inserts, and scans, at each value
+//! size and cache size.
+
+use std::ops::Bound;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+mod common;
+use common::{key, value, BenchDb, Rng, CACHE_SIZES, RECORDS, VALUE_SIZES};
+
+fn point_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("point_get");
+    for &(cache, cache_bytes) in CACHE_SIZES.iter() {
+        for &size in VALUE_SIZES.iter() {
+            let bench = BenchDb::loaded(cache_bytes, size);
+            let mut rng = Rng::new(1);
+            group.throughput(Throughput::Elements(1));
+            group.bench_with_input(BenchmarkId::new(cache, size), &size, |b, _| {
+                b.iter(|| bench.db.get(&key(rng.below(RECORDS))).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Insert 100 records per commit, with keys in order or scattered
+fn insert(c: &mut Criterion, name: &str, sequential: bool) {
+    const PER_COMMIT: u64 = 100;
+
+    let mut group = c.benchmark_group(name);
+    for &(cache, cache_bytes) in CACHE_SIZES.iter() {
+        for &size in VALUE_SIZES.iter() {
+            let bench = BenchDb::loaded(cache_bytes, size);
+            let mut rng = Rng::new(2);
+            let mut next = RECORDS;
+            group.throughput(Throughput::Elements(PER_COMMIT));
+            group.bench_with_input(BenchmarkId::new(cache, size), &size, |b, &size| {
+                b.iter(|| {
+                    let mut txn = bench.db.write().unwrap();
+                    for _ in 0..PER_COMMIT {
+                        let i = if sequential { next } else { rng.next() };
+                        next += 1;
+                        txn.put(key(i), value(i, size)).unwrap();
+                    }
+                    txn.commit().unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn sequential_insert(c: &mut Criterion) {
+    insert(c, "sequential_insert", true)
+}
+
+fn random_insert(c: &mut Criterion) {
+    insert(c, "random_insert", false)
+}
+
+/// Scan 100 records from a random start
+fn scan(c: &mut Criterion) {
+    const LEN: usize = 100;
+
+    let mut group = c.benchmark_group("scan");
+    for &(cache, cache_bytes) in CACHE_SIZES.iter() {
+        for &size in VALUE_SIZES.iter() {
+            let bench = BenchDb::loaded(cache_bytes, size);
+            let mut rng = Rng::new(3);
+            group.throughput(Throughput::Elements(LEN as u64));
+            group.bench_with_input(BenchmarkId::new(cache, size), &size, |b, _| {
+                b.iter_batched(
+                    || key(rng.below(RECORDS - LEN as u64)),
+                    |start| bench.db.range((Bound::Included(start), Bound::Unbounded)).take(LEN).map(Result::unwrap).count(),
+                    BatchSize::SmallInput
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, point_get, sequential_insert, random_insert, scan);
+criterion_main!(benches);
@@ -0,0 +1,115 @@
+//! The YCSB core workloads, A to F, over a loaded database at each value size and cache size.
+//! Each iteration is one request, drawn as YCSB does: zipfian over the records, except D,
+//! which favors the latest.
+
+use std::ops::Bound;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+mod common;
+use common::{key, value, BenchDb, Rng, Zipfian, CACHE_SIZES, RECORDS, VALUE_SIZES};
+
+#[derive(Clone, Copy)]
+enum Request {
+    Read,
+    /// Read a record near the latest inserted
+    ReadLatest,
+    Update,
+    Insert,
+    /// Scan up to 100 records
+    Scan,
+    ReadModifyWrite
+}
+
+/// A workload's name and its mix of requests, by proportion
+const WORKLOADS: [(&str, &[(Request, f64)]); 6] = [
+    ("a_update_heavy", &[(Request::Read, 0.5), (Request::Update, 0.5)]),
+    ("b_read_mostly", &[(Request::Read, 0.95), (Request::Update, 0.05)]),
+    ("c_read_only", &[(Request::Read, 1.0)]),
+    ("d_read_latest", &[(Request::ReadLatest, 0.95), (Request::Insert, 0.05)]),
+    ("e_short_ranges", &[(Request::Scan, 0.95), (Request::Insert, 0.05)]),
+    ("f_read_modify_write", &[(Request::Read, 0.5), (Request::ReadModifyWrite, 0.5)])
+];
+
+fn choose(mix: &[(Request, f64)], rng: &mut Rng) -> Request {
+    let mut u = rng.unit();
+    for &(request, proportion) in mix {
+        if u < proportion { return request }
+        u -= proportion;
+    }
+    mix[mix.len() - 1].0
+}
+
+struct Client<'a> {
+    bench: &'a BenchDb,
+    rng: Rng,
+    zipfian: Zipfian,
+    /// Records inserted so far, loaded ones included
+    records: u64,
+    value_size: usize
+}
+
+impl<'a> Client<'a> {
+    fn request(&mut self, request: Request) {
+        let db = &self.bench.db;
+        match request {
+            Request::Read => {
+                db.get(&key(self.zipfian.next(&mut self.rng))).unwrap();
+            },
+            Request::ReadLatest => {
+                db.get(&key(self.zipfian.latest(&mut self.rng, self.records))).unwrap();
+            },
+            Request::Update => {
+                let i = self.zipfian.next(&mut self.rng);
+                let mut txn = db.write().unwrap();
+                txn.put(key(i), value(i + 1, self.value_size)).unwrap();
+                txn.commit().unwrap();
+            },
+            Request::Insert => {
+                let i = self.records;
+                self.records += 1;
+                let mut txn = db.write().unwrap();
+                txn.put(key(i), value(i, self.value_size)).unwrap();
+                txn.commit().unwrap();
+            },
+            Request::Scan => {
+                let start = key(self.zipfian.next(&mut self.rng));
+                let len = 1 + self.rng.below(100) as usize;
+                db.range((Bound::Included(start), Bound::Unbounded)).take(len).map(Result::unwrap).count();
+            },
+            Request::ReadModifyWrite => {
+                let i = self.zipfian.next(&mut self.rng);
+                let mut txn = db.write().unwrap();
+                let old = txn.get(&key(i)).unwrap();
+                let new = value(old.map_or(0, |old| old.len() as u64), self.value_size);
+                txn.put(key(i), new).unwrap();
+                txn.commit().unwrap();
+            }
+        }
+    }
+}
+
+fn ycsb(c: &mut Criterion) {
+    // computing zeta is slow, and every client draws over the same records
+    let zipfian = Zipfian::new(RECORDS);
+
+    for &(name, mix) in WORKLOADS.iter() {
+        let mut group = c.benchmark_group(format!("ycsb_{}", name));
+        group.throughput(Throughput::Elements(1));
+        for &(cache, cache_bytes) in CACHE_SIZES.iter() {
+            for &size in VALUE_SIZES.iter() {
+                let bench = BenchDb::loaded(cache_bytes, size);
+                let mut client = Client { bench: &bench, rng: Rng::new(4), zipfian: zipfian.clone(), records: RECORDS, value_size: size };
+                group.bench_with_input(BenchmarkId::new(cache, size), &size, |b, _| {
+                    b.iter(|| {
+                        let request = choose(mix, &mut client.rng);
+                        client.request(request)
+                    })
+                });
+            }
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, ycsb);
+criterion_main!(benches);
@@ -0,0 +1,180 @@
+//! Poke at a database file during development and incident response
+
+use std::{env, fs, process};
+use std::ascii::escape_default;
+use bytes::Bytes;
+use futures::executor::block_on;
+use futures::io::AllowStdIo;
+use futures::stream::StreamExt;
+
+use bssdb::{DB, Options, PageDetail};
+
+const USAGE: &str = "\
+usage: bssdb-cli <command> <database> [args]
+
+commands:
+  info <db>                          transaction, size and key count
+  dump <db>                          every key and value, one entry per line
+  verify <db>                        check every reachable page for damage
+  get <db> <key>                     print the value of a key
+  put <db> <key> <value>             set a key
+  page <db> <page>                   decode a page and hexdump it
+  tree <db> [--depth <n>]            the tree's pages with their separators and fill, to depth n (default 2)
+  restore <db> <backup>... [--until <tx>]
+                                     restore a chain of backup streams, oldest first, to a new database";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Bytes as printable ASCII, with everything else escaped
+fn escaped(bytes: &[u8]) -> String {
+    bytes.iter().flat_map(|&byte| escape_default(byte)).map(char::from).collect()
+}
+
+async fn open(path: &str, read_only: bool) -> Result<DB> {
+    let mut options = Options::new();
+    options.read_only(read_only).create(false);
+    Ok(DB::open(path, options).await?)
+}
+
+async fn info(path: &str) -> Result<()> {
+    let db = open(path, true).await?;
+    println!("transaction: {}", db.latest_transaction());
+    println!("size on disk: {} bytes", db.size_on_disk());
+    println!("keys: {}", db.len().await?);
+    Ok(())
+}
+
+async fn dump(path: &str) -> Result<()> {
+    let db = open(path, true).await?;
+    let mut entries = db.range(..);
+    while let Some(entry) = entries.next().await {
+        let (key, value) = entry?;
+        println!("{}\t{}", escaped(&key), escaped(&value));
+    }
+    Ok(())
+}
+
+async fn verify(path: &str) -> Result<bool> {
+    let db = open(path, true).await?;
+    let report = db.verify().await;
+    println!("transaction {}: checked {} pages holding {} entries", report.tx, report.pages_checked, report.entries);
+    for problem in &report.problems {
+        println!("{:?}", problem);
+    }
+    Ok(report.is_ok())
+}
+
+async fn get(path: &str, key: &str) -> Result<bool> {
+    let db = open(path, true).await?;
+    match db.get(key.as_bytes()).await? {
+        Some(value) => {
+            println!("{}", escaped(&value));
+            Ok(true)
+        },
+        None => Ok(false)
+    }
+}
+
+async fn put(path: &str, key: &str, value: &str) -> Result<()> {
+    let db = open(path, false).await?;
+    let mut txn = db.write().await?;
+    txn.put(Bytes::copy_from_slice(key.as_bytes()), Bytes::copy_from_slice(value.as_bytes()))?;
+    let tx = txn.commit().await?;
+    println!("committed transaction {}", tx);
+    Ok(())
+}
+
+async fn page(path: &str, idx: &str) -> Result<()> {
+    let db = open(path, true).await?;
+    let page = db.describe_page(idx.parse()?).await?;
+    println!("page {}: {:?}, written by transaction {}", page.idx, page.page_type, page.lsn);
+    if let Some(fill) = page.fill {
+        println!("fill: {:.1}%", fill * 100.0);
+    }
+
+    match &page.content {
+        PageDetail::Branch { first_child, separators, entries } => {
+            println!("children:");
+            let lows = std::iter::once(None).chain(separators.iter().map(|(key, _)| Some(key)));
+            let children = std::iter::once(*first_child).chain(separators.iter().map(|(_, child)| *child));
+            for ((low, child), entries) in lows.zip(children).zip(entries) {
+                let low = low.map_or_else(|| "-".to_string(), |key| escaped(key));
+                println!("  {}\t-> page {}\t{} entries", low, child, entries);
+            }
+        },
+        PageDetail::Leaf { entries } => {
+            println!("entries:");
+            for (key, len, logged) in entries {
+                println!("  {}\t{} bytes{}", escaped(key), len, if *logged { " in the value log" } else { "" });
+            }
+        },
+        PageDetail::Opaque => {}
+    }
+
+    println!();
+    for (i, line) in page.raw.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = line.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+        println!("{:08x}  {:<47}  {}", i * 16, hex.join(" "), text);
+    }
+    Ok(())
+}
+
+async fn tree(path: &str, args: &[String]) -> Result<()> {
+    let depth = match args {
+        [] => 2,
+        [flag, depth] if flag == "--depth" => depth.parse()?,
+        _ => return Err("expected --depth <n>".into())
+    };
+
+    let db = open(path, true).await?;
+    for node in db.tree_outline(depth).await? {
+        let low = node.separator.as_ref().map_or_else(|| "-".to_string(), |key| escaped(key));
+        let (kind, unit) = if node.is_leaf { ("leaf", "entries") } else { ("branch", "children") };
+        println!("{}{}\t{} {}\t{} {}\t{:.1}% full", "  ".repeat(node.depth), low, kind, node.idx, node.len, unit, node.fill * 100.0);
+    }
+    Ok(())
+}
+
+async fn restore(path: &str, args: &[String]) -> Result<()> {
+    let (backups, until) = match args.iter().position(|arg| arg == "--until") {
+        Some(i) => (&args[..i], Some(args.get(i + 1).ok_or("--until needs a transaction")?.parse()?)),
+        None => (args, None)
+    };
+    if backups.is_empty() { return Err("no backups given".into()) }
+
+    let mut chain = backups.iter().map(|backup| Ok(AllowStdIo::new(fs::File::open(backup)?))).collect::<Result<Vec<_>>>()?;
+    let tx = DB::restore(path, &mut chain, until).await?;
+    println!("restored to transaction {}", tx);
+    Ok(())
+}
+
+async fn run(args: &[String]) -> Result<bool> {
+    match args {
+        [command, path] if command == "info" => info(path).await.map(|()| true),
+        [command, path] if command == "dump" => dump(path).await.map(|()| true),
+        [command, path] if command == "verify" => verify(path).await,
+        [command, path, key] if command == "get" => get(path, key).await,
+        [command, path, key, value] if command == "put" => put(path, key, value).await.map(|()| true),
+        [command, path, idx] if command == "page" => page(path, idx).await.map(|()| true),
+        [command, path, rest @ ..] if command == "tree" => tree(path, rest).await.map(|()| true),
+        [command, path, rest @ ..] if command == "restore" => restore(path, rest).await.map(|()| true),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2)
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match block_on(run(&args)) {
+        Ok(true) => {},
+        // a missing key or a damaged database
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(1)
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! An async client for `bssdb-server`, mirroring the embedded `bssdb::DB` and its write
+//! transactions, so code can move between an embedded and a networked database by changing
+//! how it opens one.
+//!
+//! ```ignore
+//! let client = Client::connect("127.0.0.1:7878").await?;
+//! let mut txn = client.write().await?;
+//! txn.put(key.clone(), value)?;
+//! txn.commit().await?;
+//! assert!(client.get(&key).await?.is_some());
+//! ```
+//!
+//! Reads outside a transaction share one connection. Each write transaction has a connection
+//! of its own, which the server rolls back if it's dropped before committing.
+
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::ops::{Bound, RangeBounds};
+use bytes::{Bytes, BytesMut, BufMut};
+use futures::stream::{self, BoxStream, StreamExt};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::{TcpStream, ToSocketAddrs, lookup_host};
+use tokio::sync::Mutex;
+
+pub mod protocol;
+use protocol::{Request, Response, MAX_FRAME_LEN};
+
+pub type TransactionIdx = u64;
+
+/// Entries asked for per request while scanning a range
+const SCAN_BATCH: u32 = 1024;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// The server failed the request. `code` is the stable code of the database's error.
+    #[error("{message}")]
+    Server { code: String, message: String },
+    #[error("Server sent a response that doesn't answer the request")]
+    UnexpectedResponse
+}
+
+impl ClientError {
+    /// A stable identifier for the error, the same as the embedded database's where the server
+    /// failed the request
+    pub fn code(&self) -> &str {
+        match self {
+            ClientError::Io(_) => "io",
+            ClientError::Server { code, .. } => code,
+            ClientError::UnexpectedResponse => "unexpected_response"
+        }
+    }
+}
+
+struct Connection {
+    stream: BufStream<TcpStream>,
+    /// Requests sent whose responses haven't been read
+    in_flight: usize
+}
+
+impl Connection {
+    async fn open(addresses: &[SocketAddr]) -> io::Result<Connection> {
+        let stream = TcpStream::connect(addresses).await?;
+        stream.set_nodelay(true)?;
+        Ok(Connection { stream: BufStream::new(stream), in_flight: 0 })
+    }
+
+    /// Buffer a request, to be sent by the next `receive`
+    async fn send(&mut self, request: &Request) -> io::Result<()> {
+        let body = request.encode();
+        self.stream.write_all(&(body.len() as u32).to_le_bytes()).await?;
+        self.stream.write_all(&body).await?;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Read the response to the oldest request in flight, which must be `request`
+    async fn receive(&mut self, request: &Request) -> Result<Response, ClientError> {
+        self.stream.flush().await?;
+
+        let len = self.stream.read_u32_le().await? as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too long").into())
+        }
+        let mut body = vec![0; len];
+        self.stream.read_exact(&mut body).await?;
+        self.in_flight -= 1;
+
+        match Response::decode(request, &body)? {
+            Response::Error { code, message } => Err(ClientError::Server { code, message }),
+            response => Ok(response)
+        }
+    }
+
+    async fn call(&mut self, request: Request) -> Result<Response, ClientError> {
+        self.send(&request).await?;
+        self.receive(&request).await
+    }
+}
+
+/// A connection to a `bssdb-server`
+pub struct Client {
+    addresses: Vec<SocketAddr>,
+    connection: Mutex<Connection>
+}
+
+impl Client {
+    pub async fn connect<A: ToSocketAddrs>(address: A) -> io::Result<Client> {
+        let addresses: Vec<_> = lookup_host(address).await?.collect();
+        let connection = Connection::open(&addresses).await?;
+        Ok(Client { addresses, connection: Mutex::new(connection) })
+    }
+
+    async fn call(&self, request: Request) -> Result<Response, ClientError> {
+        let mut connection = self.connection.lock().await;
+        // a call dropped mid-flight leaves its response unread, so the connection can't be reused
+        if connection.in_flight > 0 {
+            *connection = Connection::open(&self.addresses).await?;
+        }
+        connection.call(request).await
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Bytes>, ClientError> {
+        found(self.call(Request::Get(Bytes::copy_from_slice(key))).await?)
+    }
+
+    pub async fn contains_key(&self, key: &[u8]) -> Result<bool, ClientError> {
+        Ok(self.get(key).await?.is_some())
+    }
+
+    /// Entries in `range` as of the latest commit when each batch of them is fetched, so a
+    /// long scan may see commits made while it runs
+    pub fn range<R: RangeBounds<Bytes>>(&self, range: R) -> BoxStream<'_, Result<(Bytes, Bytes), ClientError>> {
+        let start = match range.start_bound() {
+            Bound::Included(key) => Some(key.clone()),
+            Bound::Excluded(key) => Some(successor(key)),
+            Bound::Unbounded => None
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Some(successor(key)),
+            Bound::Excluded(key) => Some(key.clone()),
+            Bound::Unbounded => None
+        };
+
+        // the state is where the next batch starts, or `None` once the range is exhausted
+        stream::unfold(Some(start), move |start| {
+            let end = end.clone();
+            async move {
+                let start = start?;
+                let entries = match self.call(Request::Scan { start, end, limit: SCAN_BATCH }).await {
+                    Ok(Response::Entries(entries)) => entries,
+                    Ok(_) => return Some((vec![Err(ClientError::UnexpectedResponse)], None)),
+                    Err(err) => return Some((vec![Err(err)], None))
+                };
+                let next = match entries.last() {
+                    Some((key, _)) if entries.len() == SCAN_BATCH as usize => Some(Some(successor(key))),
+                    _ => None
+                };
+                Some((entries.into_iter().map(Ok).collect(), next))
+            }
+        }).flat_map(stream::iter).boxed()
+    }
+
+    /// Begin a write transaction. The server has one writer at a time, so this waits for any
+    /// other client's transaction to finish.
+    pub async fn write(&self) -> Result<WriteTransaction<'_>, ClientError> {
+        let mut connection = Connection::open(&self.addresses).await?;
+        expect_ok(connection.call(Request::Begin).await?)?;
+        Ok(WriteTransaction { connection, pending: vec![], client: PhantomData })
+    }
+}
+
+/// A write transaction on the server. Writes are buffered and sent together when the
+/// transaction next reads or commits, so an error from one is returned from that call.
+pub struct WriteTransaction<'client> {
+    connection: Connection,
+    pending: Vec<Request>,
+    client: PhantomData<&'client Client>
+}
+
+impl<'client> WriteTransaction<'client> {
+    pub fn put(&mut self, key: Bytes, value: Bytes) -> Result<(), ClientError> {
+        self.pending.push(Request::Put(key, value));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: Bytes) -> Result<(), ClientError> {
+        self.pending.push(Request::Delete(key));
+        Ok(())
+    }
+
+    /// Send the buffered writes, then read every response so the connection stays in step
+    async fn flush(&mut self) -> Result<(), ClientError> {
+        let pending = std::mem::take(&mut self.pending);
+        for request in &pending {
+            self.connection.send(request).await?;
+        }
+        let mut result = Ok(());
+        for request in &pending {
+            let response = self.connection.receive(request).await.and_then(expect_ok);
+            if result.is_ok() { result = response }
+        }
+        result
+    }
+
+    /// Read a key, seeing this transaction's writes
+    pub async fn get(&mut self, key: &[u8]) -> Result<Option<Bytes>, ClientError> {
+        self.flush().await?;
+        found(self.connection.call(Request::Get(Bytes::copy_from_slice(key))).await?)
+    }
+
+    pub async fn contains_key(&mut self, key: &[u8]) -> Result<bool, ClientError> {
+        Ok(self.get(key).await?.is_some())
+    }
+
+    pub async fn commit(mut self) -> Result<TransactionIdx, ClientError> {
+        self.flush().await?;
+        match self.connection.call(Request::Commit).await? {
+            Response::Committed(tx) => Ok(tx),
+            _ => Err(ClientError::UnexpectedResponse)
+        }
+    }
+}
+
+fn found(response: Response) -> Result<Option<Bytes>, ClientError> {
+    match response {
+        Response::Value(value) => Ok(Some(value)),
+        Response::NotFound => Ok(None),
+        _ => Err(ClientError::UnexpectedResponse)
+    }
+}
+
+fn expect_ok(response: Response) -> Result<(), ClientError> {
+    match response {
+        Response::Ok => Ok(()),
+        _ => Err(ClientError::UnexpectedResponse)
+    }
+}
+
+/// The least key after `key`
+fn successor(key: &Bytes) -> Bytes {
+    let mut next = BytesMut::with_capacity(key.len() + 1);
+    next.extend_from_slice(key);
+    next.put_u8(0);
+    next.freeze()
+}
@@ -0,0 +1,248 @@
+//! The wire protocol spoken between `bssdb-server` and its clients. Every message is a frame,
+//! `[len: u32][body]`, and each request gets exactly one response, in order.
+//!
+//! A request body is `[op: u8]` then its fields:
+//!
+//! - `GET key`, `PUT key value`, `DELETE key`: in the connection's transaction if it has one,
+//!   or else on their own, each write committed by itself
+//! - `SCAN start end limit`: up to `limit: u32` entries from `start` (inclusive) to `end`
+//!   (exclusive), as of the latest commit
+//! - `BEGIN`, `COMMIT`, `ABORT`: a transaction on this connection. The database has one writer
+//!   at a time, so other writers wait until it's committed or aborted.
+//!
+//! A response body is `[status: u8]` then, for `OK`, the result: nothing, a value, the
+//! transaction committed (`u64`), or for a scan `[count: u32]` then each key and value. For
+//! `ERROR` it's the error's stable code and then its message, both as bytes.
+//!
+//! Bytes are `[len: u32][bytes]`, and a bound is `[present: u8]` then the key if it's 1.
+//! Integers are little endian.
+//!
+//! What an `OK` response carries depends on the request, so responses are decoded against it.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use bytes::Bytes;
+
+pub const GET: u8 = 1;
+pub const PUT: u8 = 2;
+pub const DELETE: u8 = 3;
+pub const SCAN: u8 = 4;
+pub const BEGIN: u8 = 5;
+pub const COMMIT: u8 = 6;
+pub const ABORT: u8 = 7;
+
+pub const OK: u8 = 0;
+pub const NOT_FOUND: u8 = 1;
+pub const ERROR: u8 = 2;
+
+/// Frames larger than this are refused, so a bad length can't exhaust memory
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Get(Bytes),
+    Put(Bytes, Bytes),
+    Delete(Bytes),
+    Scan { start: Option<Bytes>, end: Option<Bytes>, limit: u32 },
+    Begin,
+    Commit,
+    Abort
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    Value(Bytes),
+    Committed(u64),
+    Entries(Vec<(Bytes, Bytes)>),
+    NotFound,
+    Error { code: String, message: String }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+/// Read one frame's body, or `None` if the connection closed between frames
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {},
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err)
+    }
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN { return Err(invalid("frame too long")) }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, body: &[u8]) -> io::Result<()> {
+    writer.write_all(&(body.len() as u32).to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn put_bound(out: &mut Vec<u8>, bound: &Option<Bytes>) {
+    match bound {
+        Some(key) => {
+            out.push(1);
+            put_bytes(out, key);
+        },
+        None => out.push(0)
+    }
+}
+
+/// Reads fields from a frame body, failing on truncation
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len { return Err(invalid("truncated frame")) }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("string isn't UTF-8"))
+    }
+
+    fn bytes(&mut self) -> io::Result<Bytes> {
+        let len = self.u32()? as usize;
+        Ok(Bytes::copy_from_slice(self.take(len)?))
+    }
+
+    fn bound(&mut self) -> io::Result<Option<Bytes>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.bytes()?)),
+            _ => Err(invalid("bad bound"))
+        }
+    }
+
+    fn end(self) -> io::Result<()> {
+        if self.0.is_empty() { Ok(()) } else { Err(invalid("trailing bytes in frame")) }
+    }
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Request::Get(key) => {
+                out.push(GET);
+                put_bytes(&mut out, key);
+            },
+            Request::Put(key, value) => {
+                out.push(PUT);
+                put_bytes(&mut out, key);
+                put_bytes(&mut out, value);
+            },
+            Request::Delete(key) => {
+                out.push(DELETE);
+                put_bytes(&mut out, key);
+            },
+            Request::Scan { start, end, limit } => {
+                out.push(SCAN);
+                put_bound(&mut out, start);
+                put_bound(&mut out, end);
+                out.extend_from_slice(&limit.to_le_bytes());
+            },
+            Request::Begin => out.push(BEGIN),
+            Request::Commit => out.push(COMMIT),
+            Request::Abort => out.push(ABORT)
+        }
+        out
+    }
+
+    pub fn decode(body: &[u8]) -> io::Result<Request> {
+        let mut fields = Fields(body);
+        let request = match fields.u8()? {
+            GET => Request::Get(fields.bytes()?),
+            PUT => Request::Put(fields.bytes()?, fields.bytes()?),
+            DELETE => Request::Delete(fields.bytes()?),
+            SCAN => Request::Scan { start: fields.bound()?, end: fields.bound()?, limit: fields.u32()? },
+            BEGIN => Request::Begin,
+            COMMIT => Request::Commit,
+            ABORT => Request::Abort,
+            _ => return Err(invalid("unknown operation"))
+        };
+        fields.end()?;
+        Ok(request)
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Response::Ok => out.push(OK),
+            Response::Value(value) => {
+                out.push(OK);
+                put_bytes(&mut out, value);
+            },
+            Response::Committed(tx) => {
+                out.push(OK);
+                out.extend_from_slice(&tx.to_le_bytes());
+            },
+            Response::Entries(entries) => {
+                out.push(OK);
+                out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+                for (key, value) in entries {
+                    put_bytes(&mut out, key);
+                    put_bytes(&mut out, value);
+                }
+            },
+            Response::NotFound => out.push(NOT_FOUND),
+            Response::Error { code, message } => {
+                out.push(ERROR);
+                put_bytes(&mut out, code.as_bytes());
+                put_bytes(&mut out, message.as_bytes());
+            }
+        }
+        out
+    }
+
+    /// Decode the response to `request`
+    pub fn decode(request: &Request, body: &[u8]) -> io::Result<Response> {
+        let mut fields = Fields(body);
+        let response = match (fields.u8()?, request) {
+            (OK, Request::Get(_)) => Response::Value(fields.bytes()?),
+            (OK, Request::Commit) => Response::Committed(fields.u64()?),
+            (OK, Request::Scan { .. }) => {
+                let count = fields.u32()?;
+                let mut entries = Vec::with_capacity(count.min(1024) as usize);
+                for _ in 0..count {
+                    entries.push((fields.bytes()?, fields.bytes()?));
+                }
+                Response::Entries(entries)
+            },
+            (OK, _) => Response::Ok,
+            (NOT_FOUND, _) => Response::NotFound,
+            (ERROR, _) => Response::Error { code: fields.string()?, message: fields.string()? },
+            _ => return Err(invalid("unknown status"))
+        };
+        fields.end()?;
+        Ok(response)
+    }
+}
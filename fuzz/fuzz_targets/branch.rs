@@ -0,0 +1,7 @@
+#![no_main]
+// The data of a branch page
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    bssdb::fuzz::branch(data);
+});
@@ -0,0 +1,7 @@
+#![no_main]
+// The data of an overflow chain: dictionaries, write buffer records and archived commits
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    bssdb::fuzz::chain(data);
+});
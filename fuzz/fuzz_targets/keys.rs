@@ -0,0 +1,17 @@
+#![no_main]
+// Order-preserving keys: whatever decodes must encode back to the same bytes
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use bssdb::keys;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(key) = keys::decode::<(String, i64, Option<Bytes>, bool)>(data) {
+        assert_eq!(&keys::encode(&key)[..], data);
+    }
+    if let Some(key) = keys::decode::<(f64, u32, Bytes)>(data) {
+        assert_eq!(&keys::encode(&key)[..], data);
+    }
+    if let Some(end) = keys::prefix_end(data) {
+        assert!(&end[..] > data);
+    }
+});
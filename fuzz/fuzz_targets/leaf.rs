@@ -0,0 +1,7 @@
+#![no_main]
+// The data of a leaf page
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    bssdb::fuzz::leaf(data);
+});
@@ -0,0 +1,7 @@
+#![no_main]
+// A raw page, as read from a corrupt file
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    bssdb::fuzz::page(data);
+});
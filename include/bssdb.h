@@ -0,0 +1,67 @@
+/* The C interface to bssdb, built with the `ffi` feature. Keep in sync with src/ffi.rs. */
+
+#ifndef BSSDB_H
+#define BSSDB_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define BSSDB_OK 0
+#define BSSDB_NOT_FOUND 1
+#define BSSDB_DONE 2
+#define BSSDB_ERROR -1
+
+typedef struct bssdb_db bssdb_db;
+typedef struct bssdb_txn bssdb_txn;
+typedef struct bssdb_iter bssdb_iter;
+
+/* Bytes owned by the caller, freed with bssdb_bytes_free */
+typedef struct bssdb_bytes {
+    uint8_t *data;
+    size_t len;
+} bssdb_bytes;
+
+/* Called with each entry of a scan. Returning non-zero stops the scan. */
+typedef int (*bssdb_scan_fn)(void *ctx, const uint8_t *key, size_t key_len, const uint8_t *value, size_t value_len);
+
+/* The calling thread's last error, valid until its next error, or NULL if none */
+const char *bssdb_last_error_code(void);
+const char *bssdb_last_error_message(void);
+
+int bssdb_open(const char *path, bssdb_db **out);
+/* Transactions and iterators must be finished first */
+void bssdb_close(bssdb_db *db);
+
+/* BSSDB_NOT_FOUND if the key has no value */
+int bssdb_get(const bssdb_db *db, const uint8_t *key, size_t key_len, bssdb_bytes *value);
+int bssdb_put(const bssdb_db *db, const uint8_t *key, size_t key_len, const uint8_t *value, size_t value_len);
+int bssdb_delete(const bssdb_db *db, const uint8_t *key, size_t key_len);
+
+int bssdb_txn_begin(const bssdb_db *db, bssdb_txn **out);
+int bssdb_txn_put(bssdb_txn *txn, const uint8_t *key, size_t key_len, const uint8_t *value, size_t value_len);
+int bssdb_txn_delete(bssdb_txn *txn, const uint8_t *key, size_t key_len);
+int bssdb_txn_get(const bssdb_txn *txn, const uint8_t *key, size_t key_len, bssdb_bytes *value);
+/* Frees the transaction, whether or not the commit succeeds */
+int bssdb_txn_commit(bssdb_txn *txn);
+void bssdb_txn_abort(bssdb_txn *txn);
+
+/* start is inclusive and end exclusive; either may be NULL for no bound */
+int bssdb_iter_open(const bssdb_db *db, const uint8_t *start, size_t start_len, const uint8_t *end, size_t end_len, bssdb_iter **out);
+/* BSSDB_DONE after the last entry */
+int bssdb_iter_next(bssdb_iter *iter, bssdb_bytes *key, bssdb_bytes *value);
+void bssdb_iter_free(bssdb_iter *iter);
+
+/* The pointers passed to callback are valid only during the call */
+int bssdb_scan(const bssdb_db *db, const uint8_t *start, size_t start_len, const uint8_t *end, size_t end_len, bssdb_scan_fn callback, void *ctx);
+
+void bssdb_bytes_free(bssdb_bytes bytes);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
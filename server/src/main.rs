@@ -0,0 +1,152 @@
+//! Serve a database over TCP, for services that can't link the crate or its C interface.
+//! See `bssdb_client::protocol` for the wire format. Each connection is served on its own thread.
+
+use std::{env, process, thread};
+use std::io::{self, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::sync::Arc;
+use bytes::Bytes;
+
+use bssdb::Options;
+use bssdb::blocking::{DB, WriteTransaction};
+use bssdb_client::protocol::{self, Request, Response};
+
+const USAGE: &str = "\
+usage: bssdb-server <database> [--listen <address>]
+
+Serves the database, creating it if it's missing, on <address> (default 127.0.0.1:7878).";
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:7878";
+
+fn failed<E: std::fmt::Display>(code: &str, err: E) -> Response {
+    Response::Error { code: code.to_string(), message: err.to_string() }
+}
+
+fn found(value: Option<Bytes>) -> Response {
+    match value {
+        Some(value) => Response::Value(value),
+        None => Response::NotFound
+    }
+}
+
+fn bound(key: Option<Bytes>, inclusive: bool) -> Bound<Bytes> {
+    match (key, inclusive) {
+        (None, _) => Bound::Unbounded,
+        (Some(key), true) => Bound::Included(key),
+        (Some(key), false) => Bound::Excluded(key)
+    }
+}
+
+/// Commit one write by itself
+fn write_one(db: &DB, key: Bytes, value: Option<Bytes>) -> Response {
+    let mut txn = match db.write() {
+        Ok(txn) => txn,
+        Err(err) => return failed("io", err)
+    };
+    let written = match value {
+        Some(value) => txn.put(key, value),
+        None => txn.delete(key)
+    };
+    match written.and_then(|()| txn.commit()) {
+        Ok(_) => Response::Ok,
+        Err(err) => failed(err.code(), err)
+    }
+}
+
+/// Answer one request, in `txn` if the connection has begun one
+fn handle<'db>(db: &'db DB, txn: &mut Option<WriteTransaction<'db>>, request: Request) -> Response {
+    match (request, txn.as_mut()) {
+        (Request::Get(key), Some(txn)) => txn.get(&key).map_or_else(|err| failed(err.code(), err), found),
+        (Request::Get(key), None) => db.get(&key).map_or_else(|err| failed(err.code(), err), found),
+        (Request::Put(key, value), Some(txn)) => txn.put(key, value).map_or_else(|err| failed(err.code(), err), |()| Response::Ok),
+        (Request::Put(key, value), None) => write_one(db, key, Some(value)),
+        (Request::Delete(key), Some(txn)) => txn.delete(key).map_or_else(|err| failed(err.code(), err), |()| Response::Ok),
+        (Request::Delete(key), None) => write_one(db, key, None),
+        (Request::Scan { start, end, limit }, _) => {
+            let entries: Result<Vec<_>, _> = db.range((bound(start, true), bound(end, false))).take(limit as usize).collect();
+            entries.map_or_else(|err| failed(err.code(), err), Response::Entries)
+        },
+        (Request::Begin, Some(_)) => failed("in_transaction", "a transaction is already open on this connection"),
+        (Request::Begin, None) => match db.write() {
+            Ok(begun) => {
+                *txn = Some(begun);
+                Response::Ok
+            },
+            Err(err) => failed("io", err)
+        },
+        (Request::Commit, Some(_)) => match txn.take().unwrap().commit() {
+            Ok(tx) => Response::Committed(tx),
+            Err(err) => failed(err.code(), err)
+        },
+        (Request::Abort, Some(_)) => {
+            *txn = None;
+            Response::Ok
+        },
+        (Request::Commit, None) | (Request::Abort, None) => failed("no_transaction", "no transaction is open on this connection")
+    }
+}
+
+/// Serve requests until the client disconnects. A transaction left open is rolled back.
+fn serve(db: &DB, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut txn = None;
+
+    while let Some(body) = protocol::read_frame(&mut reader)? {
+        let response = match Request::decode(&body) {
+            Ok(request) => handle(db, &mut txn, request),
+            Err(err) => {
+                // the stream can't be trusted past a frame that doesn't parse
+                protocol::write_frame(&mut writer, &failed("bad_request", err).encode())?;
+                return Ok(())
+            }
+        };
+        protocol::write_frame(&mut writer, &response.encode())?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (path, address) = match args.as_slice() {
+        [path] => (path, DEFAULT_ADDRESS),
+        [path, flag, address] if flag == "--listen" => (path, address.as_str()),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2)
+        }
+    };
+
+    let db = match DB::open(path, Options::new()) {
+        Ok(db) => Arc::new(db),
+        Err(err) => {
+            eprintln!("error: opening {}: {}", path, err);
+            process::exit(1)
+        }
+    };
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("error: listening on {}: {}", address, err);
+            process::exit(1)
+        }
+    };
+    eprintln!("serving {} on {}", path, address);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let peer = stream.peer_addr().map_or_else(|_| "unknown peer".to_string(), |peer| peer.to_string());
+                    if let Err(err) = serve(&db, stream) {
+                        eprintln!("{}: {}", peer, err);
+                    }
+                });
+            },
+            Err(err) => eprintln!("error: accepting a connection: {}", err)
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! A blocking facade over the async API, for callers that aren't async. Each call drives the
+//! async version to completion on the calling thread; page I/O completes through io_uring, so
+//! no separate runtime is needed.
+
+use std::{io, path::Path, sync::Arc};
+use std::ops::{Deref, RangeBounds};
+use bytes::Bytes;
+use futures::executor::{block_on, block_on_stream, BlockingStream};
+
+use crate::db::{self, Batch, BatchOutcome, ExportError, OpenError, Options, PageStore, RangeSize, ReplicationError, RetrieveError, Setting, Statistics, TransactionIdx, WriteError};
+
+/// A database with blocking methods. Derefs to the async `DB` for anything not wrapped here.
+pub struct DB {
+    db: db::DB
+}
+
+impl DB {
+    pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<DB, OpenError> {
+        Ok(DB { db: block_on(db::DB::open(path, options))? })
+    }
+
+    pub fn open_store(store: Arc<dyn PageStore>, options: Options) -> Result<DB, OpenError> {
+        Ok(DB { db: block_on(db::DB::open_store(store, options))? })
+    }
+
+    pub fn open_follower<P: AsRef<Path>>(path: P, options: Options) -> Result<DB, OpenError> {
+        Ok(DB { db: block_on(db::DB::open_follower(path, options))? })
+    }
+
+    /// Wrap an already open database
+    pub fn from_async(db: db::DB) -> DB {
+        DB { db }
+    }
+
+    pub fn into_async(self) -> db::DB {
+        self.db
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>, RetrieveError> {
+        block_on(self.db.get(key))
+    }
+
+    pub fn get_many(&self, keys: &[Bytes]) -> Result<Vec<Option<Bytes>>, RetrieveError> {
+        block_on(self.db.get_many(keys))
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool, RetrieveError> {
+        block_on(self.db.contains_key(key))
+    }
+
+    /// Iterate over the entries with keys in `range`, in key order
+    pub fn range<R: RangeBounds<Bytes>>(&self, range: R) -> Iter<'_> {
+        Iter { entries: block_on_stream(self.db.range(range)) }
+    }
+
+    pub fn write(&self) -> io::Result<WriteTransaction<'_>> {
+        Ok(WriteTransaction { txn: block_on(self.db.write())? })
+    }
+
+    /// Pin the latest committed version
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        Snapshot { snapshot: self.db.snapshot() }
+    }
+
+    pub fn apply_batch(&self, batch: Batch) -> Result<TransactionIdx, WriteError> {
+        block_on(self.db.apply_batch(batch))
+    }
+
+    pub fn apply_batch_with_token(&self, batch: Batch, token: Bytes) -> Result<BatchOutcome, WriteError> {
+        block_on(self.db.apply_batch_with_token(batch, token))
+    }
+
+    pub fn flush_write_buffer(&self) -> Result<(), WriteError> {
+        block_on(self.db.flush_write_buffer())
+    }
+
+    pub fn set_option(&self, setting: Setting) -> io::Result<()> {
+        block_on(self.db.set_option(setting))
+    }
+
+    pub fn backup_to<P: AsRef<Path>>(&self, path: P) -> Result<(), OpenError> {
+        block_on(self.db.backup_to(path))
+    }
+
+    pub fn len(&self) -> Result<u64, RetrieveError> {
+        block_on(self.db.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool, RetrieveError> {
+        block_on(self.db.is_empty())
+    }
+
+    pub fn approximate_range_size<R: RangeBounds<Bytes>>(&self, range: R) -> Result<RangeSize, RetrieveError> {
+        block_on(self.db.approximate_range_size(range))
+    }
+
+    pub fn prefix_histogram(&self, depth: usize) -> Result<Vec<(Bytes, RangeSize)>, RetrieveError> {
+        block_on(self.db.prefix_histogram(depth))
+    }
+
+    pub fn first(&self) -> Result<Option<(Bytes, Bytes)>, RetrieveError> {
+        block_on(self.db.first())
+    }
+
+    pub fn last(&self) -> Result<Option<(Bytes, Bytes)>, RetrieveError> {
+        block_on(self.db.last())
+    }
+
+    pub fn nth(&self, n: u64) -> Result<Option<(Bytes, Bytes)>, RetrieveError> {
+        block_on(self.db.nth(n))
+    }
+
+    pub fn rank(&self, key: &[u8]) -> Result<u64, RetrieveError> {
+        block_on(self.db.rank(key))
+    }
+
+    pub fn stats(&self) -> Result<Statistics, RetrieveError> {
+        block_on(self.db.stats())
+    }
+
+    /// Apply replication frames until they run out or one fails to apply
+    pub fn follow<I: IntoIterator<Item = Bytes>>(&self, frames: I) -> Result<(), ReplicationError> {
+        for frame in frames {
+            block_on(self.db.apply_replication_record(&frame))?;
+        }
+        Ok(())
+    }
+
+    pub fn replicated_tx(&self) -> Result<Option<TransactionIdx>, RetrieveError> {
+        block_on(self.db.replicated_tx())
+    }
+}
+
+impl Deref for DB {
+    type Target = db::DB;
+
+    fn deref(&self) -> &db::DB {
+        &self.db
+    }
+}
+
+/// A write transaction with blocking reads and commit
+pub struct WriteTransaction<'db> {
+    txn: db::WriteTransaction<'db>
+}
+
+impl<'db> WriteTransaction<'db> {
+    pub fn put(&mut self, key: Bytes, value: Bytes) -> Result<(), WriteError> {
+        self.txn.put(key, value)
+    }
+
+    pub fn merge(&mut self, key: Bytes, operand: Bytes) -> Result<(), WriteError> {
+        self.txn.merge(key, operand)
+    }
+
+    pub fn delete(&mut self, key: Bytes) -> Result<(), WriteError> {
+        self.txn.delete(key)
+    }
+
+    pub fn delete_range<R: RangeBounds<Bytes>>(&mut self, range: R) -> Result<(), WriteError> {
+        block_on(self.txn.delete_range(range))
+    }
+
+    pub fn compare_and_swap(&mut self, key: Bytes, expected: Option<Bytes>, new: Option<Bytes>) -> Result<(), db::CasError> {
+        block_on(self.txn.compare_and_swap(key, expected, new))
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>, RetrieveError> {
+        block_on(self.txn.get(key))
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool, RetrieveError> {
+        block_on(self.txn.contains_key(key))
+    }
+
+    /// Iterate over the entries with keys in `range` as this transaction sees them
+    pub fn range<R: RangeBounds<Bytes>>(&self, range: R) -> Iter<'_> {
+        Iter { entries: block_on_stream(self.txn.range(range)) }
+    }
+
+    /// Estimate what committing would write, without committing
+    pub fn plan(&self) -> Result<db::CommitPlan, WriteError> {
+        block_on(self.txn.plan())
+    }
+
+    pub fn commit(self) -> Result<TransactionIdx, WriteError> {
+        block_on(self.txn.commit())
+    }
+
+    /// The async transaction, for methods not wrapped here
+    pub fn as_async(&mut self) -> &mut db::WriteTransaction<'db> {
+        &mut self.txn
+    }
+}
+
+/// A snapshot with blocking reads
+#[derive(Clone)]
+pub struct Snapshot<'db> {
+    snapshot: db::Snapshot<'db>
+}
+
+impl<'db> Snapshot<'db> {
+    pub fn txn_idx(&self) -> TransactionIdx {
+        self.snapshot.txn_idx()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.snapshot.generation()
+    }
+
+    pub fn is_current(&self) -> bool {
+        self.snapshot.is_current()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>, RetrieveError> {
+        block_on(self.snapshot.get(key))
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool, RetrieveError> {
+        block_on(self.snapshot.contains_key(key))
+    }
+
+    /// Iterate over the entries with keys in `range` as of the snapshot's commit
+    pub fn range<R: RangeBounds<Bytes>>(&self, range: R) -> Iter<'_> {
+        Iter { entries: block_on_stream(self.snapshot.range(range)) }
+    }
+
+    /// Write the snapshot to a new, compacted database at `path`
+    pub fn export_to<P: AsRef<Path>>(&self, path: P) -> Result<TransactionIdx, ExportError> {
+        block_on(self.snapshot.export_to(path))
+    }
+
+    /// The async snapshot, for methods not wrapped here
+    pub fn as_async(&self) -> &db::Snapshot<'db> {
+        &self.snapshot
+    }
+}
+
+/// Entries of a range scan, read as they're iterated
+pub struct Iter<'a> {
+    entries: BlockingStream<db::Scan<'a>>
+}
+
+impl<'a> Iter<'a> {
+    /// The database's generation, taken before the iterator started reading. See `DB::generation`.
+    pub fn generation(&self) -> u64 {
+        self.entries.get_ref().generation()
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<(Bytes, Bytes), RetrieveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+}
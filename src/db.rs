@@ -1,14 +1,475 @@
 
-use bytes::{Bytes};
+use std::{fs, io, path::Path, sync::Arc};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::BTreeMap;
+use futures::future::{join_all, try_join_all};
+use futures::lock::Mutex as AsyncMutex;
+use parking_lot::Mutex;
+use thiserror::Error;
 
+// first, so its macros are in scope in the modules below
+#[macro_use]
+mod trace;
+#[cfg(feature = "test-util")]
+mod delay_store;
+mod archive;
+mod backpressure;
+mod backup;
+mod batch;
+mod branch;
+mod buffer_pool;
+mod bulk_load;
+mod cas;
+mod clock;
+mod commit_hook;
+mod compression;
+mod descent;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod error;
+mod error_kind;
+#[cfg(feature = "zstd")]
+mod dictionary;
+mod eviction;
+mod export;
+#[cfg(feature = "fault-injection")]
+mod faults;
 mod file_store;
+mod filter;
+mod follower;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+mod fs_util;
+mod header;
+mod index;
+mod inspect;
+#[cfg(feature = "serde")]
+mod key_codec;
+mod leaf;
+mod maintenance;
+mod memtable;
+mod merge;
+mod metrics;
+mod migrations;
+mod observer;
+mod options;
+mod overflow;
+mod packed;
 mod page;
+mod page_cache;
+mod quarantine;
+mod range;
+mod rank;
+mod read_ops;
+mod replication;
+mod settings;
+#[cfg(feature = "sim")]
+mod sim;
+mod size;
+mod slow_log;
+mod snapshot;
+mod spill;
+mod spawn;
+mod store;
 mod transaction;
+mod tree;
+mod ttl;
+#[cfg(feature = "serde")]
+mod typed;
+mod value_log;
+mod verify;
+mod version;
+mod watch;
+mod write_back;
+mod write_transaction;
 
-pub use file_store::{FileStore, RetrieveError};
-pub use page::{Page, PageContent, PageIndex};
+#[cfg(feature = "test-util")]
+pub use delay_store::{DelayStore, DelayConfig, Latency};
+pub use backpressure::Backpressure;
+pub use backup::{BackupError, RestoreError};
+pub use export::{ExportError, ImportError};
+#[cfg(feature = "fault-injection")]
+pub use faults::{Faults, Fault, FaultPoint};
+pub use cas::CasError;
+pub use clock::{Clock, SystemClock, ManualClock};
+pub use commit_hook::CommitSummary;
+pub use archive::KeyChange;
+pub use batch::{Batch, BatchOutcome};
+pub use compression::Compression;
+pub use descent::{DescentError, CrossLink};
+pub use error::Error;
+pub use error_kind::ErrorKind;
+pub use eviction::EvictionPolicy;
+pub use file_store::{FileStore, RetrieveError, Durability, StoreMetrics};
+pub use header::FormatError;
+pub use index::Index;
+pub use inspect::{PageDescription, PageDetail, OutlineNode};
+pub use maintenance::MaintenancePause;
+pub use memtable::FlushPolicy;
+#[cfg(feature = "encryption")]
+pub use encryption::{EncryptionConfig, Cipher};
+pub use observer::{Observer, NoopObserver, OperationKind, SlowOperation};
+pub use options::Options;
+pub use packed::{PackedDb, PackedError};
+pub use read_ops::ReadOps;
+pub use page::{Page, PageContent, PageIndex, PageType};
+pub use page_cache::{PageCache, CacheConfig, CacheStats, ChecksumSampling};
+pub use buffer_pool::PoolBacking;
+pub use metrics::{Statistics, LatencyHistogram};
+pub use range::Scan;
+pub use quarantine::{RecoveryMode, DamagedRange, CorruptionReport};
+pub use settings::Setting;
+pub use size::RangeSize;
+pub use snapshot::Snapshot;
+#[cfg(feature = "sim")]
+pub use sim::{SimStore, SimConfig, SimExecutor};
+pub use spawn::{Spawn, ThreadSpawner};
+#[cfg(feature = "tokio")]
+pub use spawn::TokioSpawner;
+#[cfg(feature = "async-std")]
+pub use spawn::AsyncStdSpawner;
+pub use store::PageStore;
+pub use transaction::TransactionIdx;
+pub use value_log::{Compaction, ValueLogStats};
+pub use verify::{VerifyReport, VerifyProblem};
+pub use watch::Event;
+pub use replication::{ReplicationRecord, ReplicationError};
+pub use follower::ReplicationLag;
+#[cfg(feature = "serde")]
+pub use key_codec::{encode_key, decode_key, KeyError};
+#[cfg(feature = "serde")]
+pub use typed::{TypedTree, TypedError};
+pub use write_transaction::{WriteTransaction, WriteError, CommitPlan};
+pub(crate) use value_log::ValuePointer;
+pub(crate) use leaf::{LeafEntry, LeafValue};
+
+use compression::Dictionaries;
+use header::FileHeader;
+use maintenance::MaintenanceGate;
+use snapshot::SnapshotPins;
+use memtable::MemTable;
+use value_log::{SegmentTable, ValueLogWriter};
+use version::{VersionHeader, FIRST_DATA_PAGE};
+use write_back::WriteBack;
+
+/// Pages copied concurrently by a backup
+const BACKUP_BATCH_PAGES: u64 = 64;
+
+#[derive(Error, Debug, Clone)]
+pub enum OpenError {
+    #[error("{0}")]
+    Io(#[source] #[from] Arc<io::Error>),
+    #[error("{0}")]
+    Retrieve(#[source] #[from] RetrieveError),
+    #[error("{0}")]
+    Format(#[source] #[from] FormatError),
+    #[error("Database is locked{}", held_by(.holder_pid))]
+    Locked { holder_pid: Option<u32> }
+}
+
+pub(crate) fn held_by(holder_pid: &Option<u32>) -> String {
+    match holder_pid {
+        Some(pid) => format!(" by process {}", pid),
+        None => String::new()
+    }
+}
 
 pub struct DB {
-    version: Bytes
+    store: Arc<dyn PageStore>,
+    cache: PageCache,
+    /// The options opened with, overridden by persisted settings
+    options: Mutex<Options>,
+    version: Mutex<VersionHeader>,
+    /// The transaction of the latest commit to change data, set while holding `write_buffer`
+    generation: AtomicU64,
+    /// Held while writing a new version
+    writer: AsyncMutex<()>,
+    write_back: Arc<WriteBack>,
+    /// Appended to only while holding `writer`
+    value_log: Mutex<ValueLogWriter>,
+    /// Trained compression dictionaries, as of the latest version
+    dictionaries: Mutex<Arc<Dictionaries>>,
+    /// Committed writes not yet applied to the tree, which reads check first
+    write_buffer: Mutex<MemTable>,
+    /// Called after each commit
+    commit_hooks: Mutex<Vec<commit_hook::CommitHook>>,
+    /// Subscribers to committed changes
+    watchers: Mutex<Vec<watch::Watcher>>,
+    /// Replication streams following commits
+    replicas: Mutex<Vec<replication::Replica>>,
+    /// The replicated transaction being received, as a follower
+    replicating: AsyncMutex<Option<(TransactionIdx, Batch)>>,
+    /// Set when opened as a follower
+    follower: Mutex<Option<follower::FollowerState>>,
+    /// The extractors of the secondary indexes created since opening
+    indexes: Mutex<BTreeMap<String, index::Extractor>>,
+    /// Folds the operands of merges
+    merge_operator: Mutex<Option<merge::MergeOperator>>,
+    /// The store's encryption, to rotate keys
+    #[cfg(feature = "encryption")]
+    encrypted: Option<Arc<encryption::EncryptedStore>>,
+    /// Corrupt pages found so far, in best-effort recovery
+    quarantine: Option<Arc<quarantine::Quarantine>>,
+    maintenance: Arc<MaintenanceGate>,
+    /// The versions live snapshots are pinned to
+    snapshots: Arc<SnapshotPins>,
+    metrics: metrics::Metrics
 }
 
+impl DB {
+    /// Options for opening a database
+    pub fn options() -> Options {
+        Options::new()
+    }
+
+    /// Open a database, creating it if the file is empty
+    pub async fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<DB, OpenError> {
+        let path = path.as_ref();
+        if options.create && !options.read_only && !path.exists() {
+            DB::create_file(path, &options).await?;
+        }
+
+        let store = FileStore::open(path, &options).await?;
+        DB::open_store(store, options).await
+    }
+
+    /// Initialize a new database in a temporary file and link it into place, so a crash
+    /// during creation never leaves a half-written database at `path`.
+    ///
+    /// Linking rather than renaming never replaces a database another process created first.
+    async fn create_file(path: &Path, options: &Options) -> Result<(), OpenError> {
+        let mut tmp_name = path.file_name()
+            .ok_or_else(|| Arc::new(io::Error::new(io::ErrorKind::InvalidInput, "database path has no file name")))?
+            .to_owned();
+        tmp_name.push(format!(".creating-{}", std::process::id()));
+        let tmp = path.with_file_name(tmp_name);
+
+        let created: Result<(), OpenError> = async {
+            let store: Arc<dyn PageStore> = FileStore::open(&tmp, options).await?;
+            #[cfg(feature = "encryption")]
+            let store = encryption::wrap(store, options);
+
+            FileHeader::for_options(options).write(&*store, Durability::SyncData).await.map_err(Arc::new)?;
+            VersionHeader::initial().write(&*store, Durability::SyncData).await.map_err(Arc::new)?;
+            std::mem::drop(store);
+
+            match fs::hard_link(&tmp, path) {
+                Err(err) if err.kind() != io::ErrorKind::AlreadyExists => Err(Arc::new(err).into()),
+                _ => Ok(())
+            }
+        }.await;
+
+        let removed = fs::remove_file(&tmp);
+        created?;
+        removed.map_err(Arc::new)?;
+
+        fs_util::sync_parent_dir(path).map_err(Arc::new)?;
+        Ok(())
+    }
+
+    /// Open a database kept in any page store, creating it if the store is empty
+    pub async fn open_store(store: Arc<dyn PageStore>, mut options: Options) -> Result<DB, OpenError> {
+        #[cfg(feature = "encryption")]
+        let encrypted = encryption::encrypted_store(store.clone(), &options);
+        #[cfg(feature = "encryption")]
+        let store: Arc<dyn PageStore> = match &encrypted {
+            Some(encrypted) => encrypted.clone(),
+            None => store
+        };
+
+        let header = FileHeader::load(&store).await?.transpose()?;
+        let version = match header {
+            Some(header) => {
+                #[cfg(feature = "encryption")]
+                encryption::check(&header, &options)?;
+                VersionHeader::load_latest(&store).await?.ok_or_else(|| header.missing_version())?
+            },
+            None if !options.create || options.read_only => return Err(FormatError::Empty.into()),
+            None => {
+                FileHeader::for_options(&options).write(&store, options.durability).await.map_err(Arc::new)?;
+
+                let version = VersionHeader::initial();
+                version.write(&store, options.durability).await.map_err(Arc::new)?;
+                version
+            }
+        };
+
+        #[cfg(feature = "encryption")]
+        if let Some(encrypted) = &encrypted {
+            encrypted.recover(version.rotation, &options).await?;
+        }
+
+        version.settings.apply(&mut options);
+        let version = match &header {
+            Some(header) => migrations::upgrade(&store, *header, version, &options).await?,
+            None => version
+        };
+        let cache = PageCache::new(store.clone(), &options);
+
+        let dictionaries = Arc::new(match version.dictionaries {
+            Some(idx) => {
+                let encoded = overflow::read_chain(&cache, idx, 0).await?;
+                Dictionaries::decode(&encoded).ok_or(RetrieveError::Malformed(idx))?
+            },
+            None => Dictionaries::default()
+        });
+        let write_buffer = memtable::replay(&cache, version.journal, version.page_count, options.clock.now()).await?;
+        let quarantine = match options.recovery {
+            RecoveryMode::Strict => None,
+            RecoveryMode::BestEffort => Some(Arc::new(quarantine::Quarantine::new()))
+        };
+
+        let segments = match SegmentTable::load(&cache, version.segments).await {
+            Ok(Some(segments)) => Ok(segments),
+            // files written before the table was kept
+            Ok(None) => value_log::rebuild(&*store, &cache, version.tree_root, &write_buffer.merged(vec![]), version.page_count, options.max_tree_depth).await,
+            Err(err) => Err(err)
+        };
+        let segments = match segments {
+            Ok(segments) => segments,
+            // the stats are only missing what a damaged tree would have shown
+            Err(_) if quarantine.is_some() => SegmentTable::new(),
+            Err(err) => return Err(err.into())
+        };
+        let mut value_log = ValueLogWriter::new(segments);
+        value_log.set_dictionaries(dictionaries.clone());
+
+        Ok(DB {
+            cache,
+            write_back: Arc::new(WriteBack::new(store.clone()).map_err(Arc::new)?),
+            store,
+            options: Mutex::new(options),
+            version: Mutex::new(version),
+            generation: AtomicU64::new(version.tx),
+            writer: AsyncMutex::new(()),
+            value_log: Mutex::new(value_log),
+            dictionaries: Mutex::new(dictionaries),
+            write_buffer: Mutex::new(write_buffer),
+            commit_hooks: Mutex::new(vec![]),
+            watchers: Mutex::new(vec![]),
+            replicas: Mutex::new(vec![]),
+            replicating: AsyncMutex::new(None),
+            follower: Mutex::new(None),
+            indexes: Mutex::new(BTreeMap::new()),
+            merge_operator: Mutex::new(None),
+            #[cfg(feature = "encryption")]
+            encrypted,
+            quarantine,
+            maintenance: Arc::new(MaintenanceGate::new()),
+            snapshots: Arc::new(SnapshotPins::new()),
+            metrics: metrics::Metrics::default()
+        })
+    }
+
+    fn dictionaries(&self) -> Arc<Dictionaries> {
+        self.dictionaries.lock().clone()
+    }
+
+    /// I/O queue metrics from the page store, if it keeps them
+    pub fn store_metrics(&self) -> Option<StoreMetrics> {
+        self.store.metrics()
+    }
+
+    /// Reserve disk space for the database file to grow to `bytes`, so writes up to there
+    /// neither allocate blocks nor fail for lack of space. The file's length is unchanged.
+    /// Blocks the thread while the file system reserves the space.
+    pub fn preallocate(&self, bytes: u64) -> io::Result<()> {
+        self.check_writable()?;
+        self.store.preallocate(bytes)
+    }
+
+    /// A number that increases with every commit that changes data, and only then. Unlike
+    /// `latest_transaction`, maintenance such as applying the write buffer leaves it as it is.
+    ///
+    /// To check cheaply whether anything changed since a read, take the generation before the
+    /// read and compare it with a later one; iterators and snapshots are stamped the same way.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// The transaction that wrote the latest version
+    pub fn latest_transaction(&self) -> TransactionIdx {
+        self.version.lock().tx
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.options.lock().read_only
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.is_read_only() || self.is_follower() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, error::ReadOnly))
+        }
+        Ok(())
+    }
+
+    /// Change a setting on the running database. Settings are persisted, so they also apply after reopening.
+    pub async fn set_option(&self, setting: Setting) -> io::Result<()> {
+        self.check_writable()?;
+        let _writer = self.writer.lock().await;
+
+        let mut version = *self.version.lock();
+        version.tx += 1;
+        version.settings.set(setting);
+
+        let mut options = self.options.lock().clone();
+        version.settings.apply(&mut options);
+
+        version.write(&self.store, options.durability).await?;
+
+        *self.version.lock() = version;
+        self.cache.set_max_bytes(options.cache.max_bytes);
+        *self.options.lock() = options;
+
+        Ok(())
+    }
+
+    /// Read a page below the version's page count, or `None` if it was allocated but never
+    /// written (such as the unused end of a value log segment)
+    async fn read_allocated_page(&self, idx: PageIndex) -> Result<Option<PageContent>, RetrieveError> {
+        match self.store.read_page(idx).await {
+            Ok(page) => Ok(Some(page)),
+            Err(RetrieveError::OutOfPages) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Copy the database to a new file, while it stays open for reads and writes.
+    ///
+    /// The copy holds the latest version as of the start of the backup. Committed pages are
+    /// never modified in place, so they can be copied while later transactions commit.
+    pub async fn backup_to<P: AsRef<Path>>(&self, path: P) -> Result<(), OpenError> {
+        let path = path.as_ref();
+        let _pinned = self.snapshots.pin(self.version.lock().tx);
+        let version = *self.version.lock();
+
+        let options = self.options.lock().clone();
+        let target: Arc<dyn PageStore> = FileStore::open(path, &Options::new()).await?;
+        #[cfg(feature = "encryption")]
+        let target = encryption::wrap(target, &options);
+
+        if FileHeader::load(&*target).await?.is_some() {
+            return Err(Arc::new(io::Error::new(io::ErrorKind::AlreadyExists, "backup target is not empty")).into())
+        }
+
+        let mut batch_start = FIRST_DATA_PAGE;
+        while batch_start < version.page_count {
+            let batch = batch_start..version.page_count.min(batch_start + BACKUP_BATCH_PAGES);
+
+            let pages = try_join_all(batch.clone().map(|idx| self.read_allocated_page(idx))).await?;
+            let writes = batch.zip(pages.iter())
+                .filter_map(|(idx, page)| Some(target.write_page(idx, page.as_ref()?)));
+            join_all(writes).await.into_iter().collect::<io::Result<()>>().map_err(Arc::new)?;
+
+            batch_start += BACKUP_BATCH_PAGES;
+        }
+
+        // the pages must be durable before the header and root make the copy valid
+        target.sync(Durability::SyncData).await.map_err(Arc::new)?;
+        FileHeader::for_options(&options).write(&*target, Durability::SyncData).await.map_err(Arc::new)?;
+        version.write(&*target, Durability::SyncData).await.map_err(Arc::new)?;
+        fs_util::sync_parent_dir(path).map_err(Arc::new)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! The commit archive: the versions that the last few commits replaced, for debugging. Pages
+//! are copied on write, so each archived root still leads to the tree as it was before the
+//! commit: the pre-images of every page the commit changed.
+//!
+//! The archive is an overflow chain, rewritten by each commit, holding `[commits: u32]` and
+//! then, newest first, `[tx: u64][tree_root: u64][journal: u64]` for each archived commit.
+
+use bytes::Bytes;
+
+use super::{DB, PageCache, PageIndex, RetrieveError, TransactionIdx};
+use super::leaf::LeafValue;
+use super::memtable;
+use super::overflow;
+use super::page::Cursor;
+use super::transaction::Transaction;
+use super::tree;
+use super::version::VersionHeader;
+
+const NO_PAGE: PageIndex = u64::MAX;
+
+/// The state a commit replaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ArchivedCommit {
+    /// The commit
+    pub tx: TransactionIdx,
+    /// The tree and journal before it
+    pub tree_root: Option<PageIndex>,
+    pub journal: Option<PageIndex>
+}
+
+/// One commit's change to a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChange {
+    pub tx: TransactionIdx,
+    pub before: Option<Bytes>,
+    pub after: Option<Bytes>
+}
+
+fn encode(commits: &[ArchivedCommit]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + commits.len() * 24);
+    buf.extend_from_slice(&(commits.len() as u32).to_le_bytes());
+    for commit in commits {
+        buf.extend_from_slice(&commit.tx.to_le_bytes());
+        buf.extend_from_slice(&commit.tree_root.unwrap_or(NO_PAGE).to_le_bytes());
+        buf.extend_from_slice(&commit.journal.unwrap_or(NO_PAGE).to_le_bytes());
+    }
+    buf
+}
+
+pub(super) fn decode(data: &[u8]) -> Option<Vec<ArchivedCommit>> {
+    let mut buf = Cursor::new(data);
+    let page = |idx| match idx {
+        NO_PAGE => None,
+        idx => Some(idx)
+    };
+
+    let count = buf.u32()?;
+    let mut commits = Vec::with_capacity(count.min(data.len() / 24));
+    for _ in 0..count {
+        commits.push(ArchivedCommit {
+            tx: buf.u64()?,
+            tree_root: page(buf.u64()?),
+            journal: page(buf.u64()?)
+        });
+    }
+
+    Some(commits)
+}
+
+pub(crate) async fn load(cache: &PageCache, archive: Option<PageIndex>) -> Result<Vec<ArchivedCommit>, RetrieveError> {
+    match archive {
+        Some(idx) => decode(&overflow::read_chain(cache, idx, 0).await?).ok_or(RetrieveError::Malformed(idx)),
+        None => Ok(vec![])
+    }
+}
+
+/// Archive the state `version` had before commit `tx`, keeping the latest `keep` commits.
+/// Returns the new archive, or `None` when archiving is off.
+pub(crate) async fn record(cache: &PageCache, txn: &Transaction, version: &VersionHeader, keep: usize) -> Result<Option<PageIndex>, RetrieveError> {
+    if keep == 0 { return Ok(None) }
+
+    let mut commits = load(cache, version.archive).await?;
+    commits.insert(0, ArchivedCommit { tx: txn.idx(), tree_root: version.tree_root, journal: version.journal });
+    commits.truncate(keep);
+
+    Ok(Some(overflow::write_chain(txn, &encode(&commits))))
+}
+
+impl DB {
+    /// The value of `key` in a tree and journal
+    async fn value_at(&self, tree_root: Option<PageIndex>, journal: Option<PageIndex>, page_count: u64, key: &[u8]) -> Result<Option<Bytes>, RetrieveError> {
+        let value = match memtable::journal_get(&self.cache, journal, page_count, key).await? {
+            Some(value) => value,
+            None => match tree_root {
+                Some(root) => {
+                    let max_depth = self.options.lock().max_tree_depth;
+                    tree::lookup(&self.cache, root, key, max_depth).await?
+                },
+                None => None
+            }
+        };
+
+        Ok(match value {
+            Some(LeafValue::Inline(value)) => Some(value),
+            Some(value) => Some(value.read(&self.cache, &self.dictionaries()).await?),
+            None => None
+        })
+    }
+
+    /// How the archived commits changed `key`, newest first. Only covers the commits kept by
+    /// `Options::archive_commits`. Useful for finding which commit wrote an unexpected value.
+    pub async fn explain_change(&self, key: &[u8]) -> Result<Vec<KeyChange>, RetrieveError> {
+        let version = *self.version.lock();
+        let commits = load(&self.cache, version.archive).await?;
+
+        let mut changes = vec![];
+        let mut after = self.value_at(version.tree_root, version.journal, version.page_count, key).await?;
+        for commit in commits {
+            let before = self.value_at(commit.tree_root, commit.journal, version.page_count, key).await?;
+            if before != after {
+                changes.push(KeyChange { tx: commit.tx, before: before.clone(), after });
+            }
+            after = before;
+        }
+
+        Ok(changes)
+    }
+}
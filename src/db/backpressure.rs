@@ -0,0 +1,102 @@
+//! Slowing writers down while dirty state piles up faster than it's written out
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use super::{DB, WriteError};
+
+/// When to push back on writers. Two queues are watched: dirty pages waiting for the
+/// write-back thread, and committed writes waiting in the write buffer to be applied to the
+/// tree. Past a queue's `slowdown` threshold each commit is delayed, longer the nearer the
+/// queue is to its `stop` threshold. At `stop`, writes fail with `WriteError::WriteStall`
+/// until it drains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backpressure {
+    pub slowdown_dirty_pages: usize,
+    pub stop_dirty_pages: usize,
+    pub slowdown_buffer_bytes: usize,
+    pub stop_buffer_bytes: usize,
+    /// The delay at the stop threshold, which is also how long a commit waits for a stopped
+    /// queue to drain before failing
+    pub max_delay: Duration
+}
+
+impl Default for Backpressure {
+    fn default() -> Self {
+        Backpressure {
+            slowdown_dirty_pages: 16 * 1024,
+            stop_dirty_pages: 64 * 1024,
+            slowdown_buffer_bytes: 64 * 1024 * 1024,
+            stop_buffer_bytes: 256 * 1024 * 1024,
+            max_delay: Duration::from_millis(100)
+        }
+    }
+}
+
+enum Pressure {
+    None,
+    Slowdown(Duration),
+    Stop
+}
+
+impl Backpressure {
+    /// How far `level` is from `slowdown` towards `stop`, from 0 to 1, or above 1 once stopped
+    fn share(level: usize, slowdown: usize, stop: usize) -> f64 {
+        if level < slowdown { return 0.0 }
+        if level >= stop { return f64::INFINITY }
+        (level - slowdown + 1) as f64 / (stop - slowdown) as f64
+    }
+
+    fn pressure(&self, dirty_pages: usize, buffer_bytes: usize) -> Pressure {
+        let share = Backpressure::share(dirty_pages, self.slowdown_dirty_pages, self.stop_dirty_pages)
+            .max(Backpressure::share(buffer_bytes, self.slowdown_buffer_bytes, self.stop_buffer_bytes));
+
+        if share == 0.0 {
+            Pressure::None
+        } else if share > 1.0 {
+            Pressure::Stop
+        } else {
+            Pressure::Slowdown(self.max_delay.mul_f64(share))
+        }
+    }
+}
+
+impl DB {
+    fn pressure(&self) -> (Pressure, Duration) {
+        let backpressure = match self.options.lock().backpressure {
+            Some(backpressure) => backpressure,
+            None => return (Pressure::None, Duration::from_secs(0))
+        };
+        let buffer_bytes = self.write_buffer.lock().bytes();
+        (backpressure.pressure(self.write_back.dirty_pages(), buffer_bytes), backpressure.max_delay)
+    }
+
+    /// Fail with `WriteStall` if writes are stopped
+    pub(super) fn check_stall(&self) -> Result<(), WriteError> {
+        match self.pressure() {
+            (Pressure::Stop, _) => {
+                self.metrics.stalled_writes.fetch_add(1, Ordering::Relaxed);
+                Err(WriteError::WriteStall)
+            },
+            _ => Ok(())
+        }
+    }
+
+    /// Delay a commit as the queues demand, or fail with `WriteStall` if writes stay stopped
+    pub(super) async fn throttle(&self) -> Result<(), WriteError> {
+        let spawner = self.options.lock().spawner.clone();
+        match self.pressure() {
+            (Pressure::None, _) => return Ok(()),
+            (Pressure::Slowdown(delay), _) => {
+                self.metrics.delayed_writes.fetch_add(1, Ordering::Relaxed);
+                spawner.sleep(delay).await;
+                return Ok(())
+            },
+            (Pressure::Stop, max_delay) => {
+                self.metrics.delayed_writes.fetch_add(1, Ordering::Relaxed);
+                spawner.sleep(max_delay).await;
+            }
+        }
+        self.check_stall()
+    }
+}
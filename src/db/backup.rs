@@ -0,0 +1,206 @@
+use std::{io, path::Path, sync::Arc};
+use futures::future::try_join_all;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use thiserror::Error;
+
+use super::{DB, Durability, FileStore, OpenError, Options, PageContent, PageIndex, PageStore, RetrieveError, TransactionIdx, BACKUP_BATCH_PAGES};
+use super::fs_util;
+use super::header::FileHeader;
+use super::page::{self, PAGE_SIZE};
+use super::version::{VersionHeader, FIRST_DATA_PAGE};
+
+// A backup stream is:
+//
+// - a header: `MAGIC`, `STREAM_VERSION` (u32), then the `since` and `txn` transaction indexes and the
+//   page count of the backed up version (u64 each)
+// - one record per page written after `since`: its index (u64) and its `PAGE_SIZE` bytes
+// - `END_OF_PAGES` (u64), then the root page of the backed up version
+//
+// Integers are little endian. Pages carry their own checksums.
+
+pub(crate) const MAGIC: [u8; 8] = *b"BSSDBBAK";
+pub(crate) const STREAM_VERSION: u32 = 1;
+pub(crate) const END_OF_PAGES: u64 = u64::MAX;
+pub(crate) const STREAM_HEADER_LEN: usize = 36;
+
+#[derive(Error, Debug, Clone)]
+pub enum BackupError {
+    #[error("{0}")]
+    Io(#[source] #[from] Arc<io::Error>),
+    #[error("{0}")]
+    Retrieve(#[source] #[from] RetrieveError)
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum RestoreError {
+    #[error("{0}")]
+    Io(#[source] #[from] Arc<io::Error>),
+    #[error("{0}")]
+    Open(#[source] #[from] OpenError),
+    #[error("Not a backup stream")]
+    BadMagic,
+    #[error("Backup stream version {0} is not supported")]
+    UnsupportedVersion(u32),
+    #[error("Backup of changes after transaction {since} doesn't continue from transaction {expected}")]
+    BrokenChain { expected: TransactionIdx, since: TransactionIdx },
+    #[error("Page {0} in the backup is corrupt")]
+    CorruptPage(PageIndex),
+    #[error("Root page in the backup is corrupt")]
+    CorruptRoot,
+    #[error("No backup in the chain is old enough to restore")]
+    NothingToRestore
+}
+
+impl From<io::Error> for RestoreError {
+    fn from(err: io::Error) -> Self {
+        RestoreError::Io(Arc::new(err))
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(err: io::Error) -> Self {
+        BackupError::Io(Arc::new(err))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct StreamHeader {
+    /// Only pages written after this transaction are included; zero for a full backup
+    pub since: TransactionIdx,
+    /// The version backed up
+    pub txn: TransactionIdx,
+    pub page_count: u64
+}
+
+impl StreamHeader {
+    pub fn encode(&self) -> [u8; STREAM_HEADER_LEN] {
+        let mut buf = [0; STREAM_HEADER_LEN];
+        buf[0..8].copy_from_slice(&MAGIC);
+        buf[8..12].copy_from_slice(&STREAM_VERSION.to_le_bytes());
+        buf[12..20].copy_from_slice(&self.since.to_le_bytes());
+        buf[20..28].copy_from_slice(&self.txn.to_le_bytes());
+        buf[28..36].copy_from_slice(&self.page_count.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8; STREAM_HEADER_LEN]) -> Result<StreamHeader, RestoreError> {
+        if buf[0..8] != MAGIC { return Err(RestoreError::BadMagic) }
+
+        let mut version = [0; 4];
+        version.copy_from_slice(&buf[8..12]);
+        let version = u32::from_le_bytes(version);
+        if version != STREAM_VERSION { return Err(RestoreError::UnsupportedVersion(version)) }
+
+        let field = |at: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&buf[at..at + 8]);
+            u64::from_le_bytes(bytes)
+        };
+
+        Ok(StreamHeader { since: field(12), txn: field(20), page_count: field(28) })
+    }
+}
+
+async fn read_page<R: AsyncRead + Unpin>(reader: &mut R, idx: PageIndex, buf: &mut [u8]) -> Result<PageContent, RestoreError> {
+    reader.read_exact(buf).await?;
+
+    if !page::checksum_ok(buf) { return Err(RestoreError::CorruptPage(idx)) }
+    PageContent::from_bytes(buf).ok_or(RestoreError::CorruptPage(idx))
+}
+
+impl DB {
+    /// Write every page modified after transaction `since` to `writer`, followed by the latest root.
+    ///
+    /// Returns the transaction the backup is consistent with, which is the `since` for the next
+    /// incremental backup. Pass zero for a full backup.
+    pub async fn backup_since<W: AsyncWrite + Unpin>(&self, since: TransactionIdx, writer: &mut W) -> Result<TransactionIdx, BackupError> {
+        let version = *self.version.lock();
+
+        let header = StreamHeader { since, txn: version.tx, page_count: version.page_count };
+        writer.write_all(&header.encode()).await?;
+
+        // every page is read, but only the modified ones are written
+        let mut batch_start = FIRST_DATA_PAGE;
+        while batch_start < version.page_count {
+            let batch = batch_start..version.page_count.min(batch_start + BACKUP_BATCH_PAGES);
+            let pages = try_join_all(batch.clone().map(|idx| self.read_allocated_page(idx))).await?;
+
+            for (idx, page) in batch.zip(pages.iter()) {
+                // never written pages are zeroed, so they're skipped too
+                let page = match page {
+                    Some(page) if page.lsn() > since => page,
+                    _ => continue
+                };
+
+                writer.write_all(&idx.to_le_bytes()).await?;
+                writer.write_all(page.as_slice()).await?;
+            }
+
+            batch_start += BACKUP_BATCH_PAGES;
+        }
+
+        writer.write_all(&END_OF_PAGES.to_le_bytes()).await?;
+        writer.write_all(version.encode().as_slice()).await?;
+        writer.flush().await?;
+
+        Ok(version.tx)
+    }
+
+    /// Create a database at `path` from a full backup followed by any number of incremental backups.
+    ///
+    /// Each backup must continue from the transaction the previous one ended at. Backups of versions
+    /// newer than `until` are ignored, restoring the database as of the last backup at or before it.
+    /// Returns the restored transaction.
+    pub async fn restore<P: AsRef<Path>, R: AsyncRead + Unpin>(path: P, chain: &mut [R], until: Option<TransactionIdx>) -> Result<TransactionIdx, RestoreError> {
+        let mut options = Options::new();
+        options.durability(Durability::SyncData);
+
+        let path = path.as_ref();
+        let target: Arc<dyn PageStore> = FileStore::open(path, &options).await?;
+        if FileHeader::load(&*target).await.map_err(OpenError::from)?.is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "restore target is not empty").into())
+        }
+
+        let mut restored: Option<VersionHeader> = None;
+        let mut buf = vec![0; PAGE_SIZE];
+
+        for reader in chain.iter_mut() {
+            let mut header = [0; STREAM_HEADER_LEN];
+            reader.read_exact(&mut header).await?;
+            let header = StreamHeader::decode(&header)?;
+
+            if until.map_or(false, |until| header.txn > until) { break }
+
+            let expected = restored.map_or(0, |version| version.tx);
+            if header.since != expected {
+                return Err(RestoreError::BrokenChain { expected, since: header.since })
+            }
+
+            loop {
+                let mut idx = [0; 8];
+                reader.read_exact(&mut idx).await?;
+                let idx = u64::from_le_bytes(idx);
+
+                if idx == END_OF_PAGES { break }
+                if idx < FIRST_DATA_PAGE || idx >= header.page_count { return Err(RestoreError::CorruptPage(idx)) }
+
+                let page = read_page(reader, idx, &mut buf).await?;
+                target.write_page(idx, &page).await?;
+            }
+
+            reader.read_exact(&mut buf).await?;
+            let root = PageContent::from_bytes(&buf).and_then(|root| VersionHeader::decode(&root));
+            restored = Some(root.ok_or(RestoreError::CorruptRoot)?);
+        }
+
+        let version = restored.ok_or(RestoreError::NothingToRestore)?;
+
+        // the pages must be durable before the header and root make the database valid
+        target.sync(Durability::SyncData).await?;
+        FileHeader::current().write(&*target, Durability::SyncData).await?;
+        version.write(&*target, Durability::SyncData).await?;
+        fs_util::sync_parent_dir(path)?;
+
+        Ok(version.tx)
+    }
+}
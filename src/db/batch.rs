@@ -0,0 +1,113 @@
+//! Batches of writes applied in one transaction, optionally exactly once per token
+
+use bytes::Bytes;
+
+use super::{DB, TransactionIdx, WriteError, WriteTransaction};
+
+/// Changes committed per transaction by `DB::ingest_chunked`
+const INGEST_CHUNK: usize = 64 * 1024;
+
+/// Changes to apply together. A later change to a key replaces an earlier one.
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    writes: Vec<(Bytes, Option<Bytes>)>
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    pub fn put(&mut self, key: Bytes, value: Bytes) -> &mut Self {
+        self.writes.push((key, Some(value)));
+        self
+    }
+
+    pub fn delete(&mut self, key: Bytes) -> &mut Self {
+        self.writes.push((key, None));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+/// What became of a batch applied with a token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchOutcome {
+    /// Applied by this call, in the given transaction
+    Applied(TransactionIdx),
+    /// Skipped, since the token was already applied by the given transaction
+    AlreadyApplied(TransactionIdx)
+}
+
+impl DB {
+    /// Apply every change in `batch` in one transaction
+    pub async fn apply_batch(&self, batch: Batch) -> Result<TransactionIdx, WriteError> {
+        let mut tx = self.write().await?;
+        for (key, value) in batch.writes {
+            match value {
+                Some(value) => tx.put(key, value)?,
+                None => tx.delete(key)?
+            }
+        }
+        tx.commit().await
+    }
+
+    /// Load many changes at once, such as when restoring a dump or importing initial data. The
+    /// changes are sorted and committed in transactions of up to 64Ki changes, each skipping the
+    /// write buffer and applied to the tree like any other commit, so in-key-order writes share
+    /// the copied paths. Empty ranges are not bulk-built into fresh subtrees.
+    ///
+    /// Not atomic: if a transaction fails, say with `WriteError::WriteStall`, those before it
+    /// stay committed. Puts and deletes are idempotent, so calling again with the same changes
+    /// finishes the job. Returns the last transaction, or `None` if there were no changes.
+    pub async fn ingest_chunked(&self, mut changes: Vec<(Bytes, Option<Bytes>)>) -> Result<Option<TransactionIdx>, WriteError> {
+        // the sort is stable, so the last change to a key still comes last and wins
+        changes.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut committed = None;
+        for chunk in changes.chunks(INGEST_CHUNK) {
+            let mut tx = self.write().await?;
+            tx.bypass_buffer = true;
+            for (key, value) in chunk {
+                match value {
+                    Some(value) => tx.put(key.clone(), value.clone())?,
+                    None => tx.delete(key.clone())?
+                }
+            }
+            committed = Some(tx.commit().await?);
+        }
+
+        Ok(committed)
+    }
+
+    /// Apply `batch` unless a batch with the same `token` was applied before, so a delivery
+    /// retried by a queue or replication stream isn't applied twice. The token is recorded
+    /// in the same transaction as the batch. Tokens are kept for the life of the database.
+    pub async fn apply_batch_with_token(&self, batch: Batch, token: Bytes) -> Result<BatchOutcome, WriteError> {
+        self.apply_with_token(self.write().await?, batch, token).await
+    }
+
+    pub(super) async fn apply_with_token(&self, mut tx: WriteTransaction<'_>, batch: Batch, token: Bytes) -> Result<BatchOutcome, WriteError> {
+        tx.check_key(&token)?;
+        if let Some(applied) = tx.token_applied(&token).await? {
+            return Ok(BatchOutcome::AlreadyApplied(applied))
+        }
+
+        for (key, value) in batch.writes {
+            match value {
+                Some(value) => tx.put(key, value)?,
+                None => tx.delete(key)?
+            }
+        }
+        tx.record_token(token);
+
+        Ok(BatchOutcome::Applied(tx.commit().await?))
+    }
+}
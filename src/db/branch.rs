@@ -0,0 +1,177 @@
+//! Branch page encoding. A branch with `n` separators has `n + 1` children: child 0 holds
+//! keys below the first separator, and child `i + 1` holds keys at or above separator `i`.
+//!
+//! Layout of the page data: `[separators: u16][filter_len: u8][child 0: u64]`, then for each
+//! separator `[key_len: u16][key][child: u64]`, then a bloom filter of `filter_len` bytes for
+//! each child, then `[entries: u64][bytes: u64]` counting each child's subtree. Only branches
+//! over leaves have filters; others have a `filter_len` of zero.
+
+use bytes::Bytes;
+
+use super::PageIndex;
+use super::filter;
+use super::page::{Cursor, PageContent, PageType, PAGE_DATA_LEN};
+
+/// Filters are at most this long, so the length fits the header
+pub const MAX_FILTER_LEN: usize = u8::MAX as usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Branch {
+    pub first_child: PageIndex,
+    /// Separator keys in order, each with the child holding keys from it up to the next
+    pub separators: Vec<(Bytes, PageIndex)>,
+    /// A filter over each child's keys, when every child is a leaf with one of the same length
+    pub filters: Option<Vec<Bytes>>,
+    /// The size of each child's subtree
+    pub stats: Vec<Stats>
+}
+
+/// The size of a subtree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Stats {
+    pub entries: u64,
+    /// Bytes of keys and values, as stored
+    pub bytes: u64
+}
+
+impl Stats {
+    pub fn add(self, other: Stats) -> Stats {
+        Stats { entries: self.entries + other.entries, bytes: self.bytes + other.bytes }
+    }
+}
+
+impl Branch {
+    /// Which child's range holds `key`, counting the first child as 0
+    pub fn child_index(&self, key: &[u8]) -> usize {
+        self.separators.partition_point(|(separator, _)| &separator[..] <= key)
+    }
+
+    pub fn child(&self, i: usize) -> PageIndex {
+        match i {
+            0 => self.first_child,
+            i => self.separators[i - 1].1
+        }
+    }
+
+    /// The child whose range holds `key`
+    pub fn child_for(&self, key: &[u8]) -> PageIndex {
+        self.child(self.child_index(key))
+    }
+
+    /// The filter of the `i`th child, if the branch has filters
+    pub fn filter(&self, i: usize) -> Option<Bytes> {
+        self.filters.as_ref().map(|filters| filters[i].clone())
+    }
+
+    /// False only if the child whose range holds `key` certainly doesn't have it
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        match &self.filters {
+            Some(filters) => filter::may_contain(&filters[self.child_index(key)], key),
+            None => true
+        }
+    }
+
+    /// The size of the whole branch's subtree
+    pub fn total(&self) -> Stats {
+        self.stats.iter().fold(Stats::default(), |total, stats| total.add(*stats))
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = PageIndex> + '_ {
+        std::iter::once(self.first_child).chain(self.separators.iter().map(|(_, child)| *child))
+    }
+
+    /// Bytes per child filter, or zero when the filters can't be encoded
+    fn filter_len(&self) -> usize {
+        let filters = match &self.filters {
+            Some(filters) if filters.len() == self.separators.len() + 1 => filters,
+            _ => return 0
+        };
+
+        let len = filters[0].len();
+        if len > MAX_FILTER_LEN || filters.iter().any(|filter| filter.len() != len) { return 0 }
+        len
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        2 + 1 + 8 + self.separators.iter().map(|(key, _)| 2 + key.len() + 8).sum::<usize>()
+            + (self.separators.len() + 1) * (self.filter_len() + 16)
+    }
+
+    pub fn fits(&self) -> bool {
+        self.encoded_len() <= PAGE_DATA_LEN
+    }
+
+    /// Encode into a branch page, or `None` if it doesn't fit
+    pub fn encode(&self) -> Option<PageContent> {
+        if !self.fits() { return None }
+
+        let filter_len = self.filter_len();
+        let mut page = PageContent::new(PageType::Branch);
+        let buf = &mut page.data;
+        let mut pos = 0;
+        let mut put = |bytes: &[u8]| {
+            buf[pos..pos + bytes.len()].copy_from_slice(bytes);
+            pos += bytes.len();
+        };
+
+        put(&(self.separators.len() as u16).to_le_bytes());
+        put(&[filter_len as u8]);
+        put(&self.first_child.to_le_bytes());
+        for (key, child) in self.separators.iter() {
+            put(&(key.len() as u16).to_le_bytes());
+            put(key);
+            put(&child.to_le_bytes());
+        }
+        if filter_len > 0 {
+            for filter in self.filters.iter().flatten() {
+                put(filter);
+            }
+        }
+        for stats in &self.stats {
+            put(&stats.entries.to_le_bytes());
+            put(&stats.bytes.to_le_bytes());
+        }
+
+        Some(page)
+    }
+
+    /// Decode a branch page, or `None` if it isn't a well-formed branch
+    pub fn decode(page: &PageContent) -> Option<Branch> {
+        Branch::decode_layout(page, true)
+    }
+
+    /// Decode a branch page written before branches counted their subtrees (format version
+    /// 4), with every child's stats zero
+    pub fn decode_without_stats(page: &PageContent) -> Option<Branch> {
+        Branch::decode_layout(page, false)
+    }
+
+    fn decode_layout(page: &PageContent, with_stats: bool) -> Option<Branch> {
+        if !matches!(page.page_type, PageType::Branch) { return None }
+
+        let mut buf = Cursor::new(&page.data);
+
+        let count = buf.u16()?;
+        let filter_len = buf.take(1)?[0] as usize;
+        let first_child = buf.u64()?;
+
+        let mut separators = Vec::with_capacity(count);
+        for _ in 0..count {
+            let key_len = buf.u16()?;
+            let key = Bytes::copy_from_slice(buf.take(key_len)?);
+            separators.push((key, buf.u64()?));
+        }
+
+        let filters = match filter_len {
+            0 => None,
+            len => Some((0..=count).map(|_| buf.take(len).map(Bytes::copy_from_slice)).collect::<Option<Vec<_>>>()?)
+        };
+
+        let stats = match with_stats {
+            true => (0..=count).map(|_| Some(Stats { entries: buf.u64()?, bytes: buf.u64()? })).collect::<Option<Vec<_>>>()?,
+            false => vec![Stats::default(); count + 1]
+        };
+
+        Some(Branch { first_child, separators, filters, stats })
+    }
+}
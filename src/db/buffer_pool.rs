@@ -0,0 +1,153 @@
+//! Memory for the page cache mapped from 2MiB huge pages, so a large cache needs far fewer TLB
+//! entries. The pool is one anonymous mapping, divided into page-sized slots: a cached page is
+//! copied into a slot when it's loaded, and hits share the slot without copying. A slot goes back
+//! to the pool once it's evicted and no reader still holds it. Overflow chains, and pages
+//! arriving once every slot is taken, are cached on the heap as before.
+//!
+//! The mapping tries `MAP_HUGETLB` first, which needs huge pages reserved by the system
+//! (`vm.nr_hugepages`), then falls back to ordinary pages advised with `MADV_HUGEPAGE`, which
+//! transparent huge pages may back.
+
+use std::ops::{Deref, Range};
+use std::ptr::NonNull;
+use std::sync::Arc;
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use super::page::PAGE_SIZE;
+
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// How the page cache's buffer pool is backed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolBacking {
+    /// Huge pages reserved with `MAP_HUGETLB`
+    HugeTlb,
+    /// Ordinary pages advised with `MADV_HUGEPAGE`, which the kernel may back with
+    /// transparent huge pages
+    Transparent
+}
+
+pub(super) struct BufferPool {
+    base: NonNull<u8>,
+    len: usize,
+    backing: PoolBacking,
+    free: Mutex<Vec<usize>>
+}
+
+// the mapping is only reached through slots, each owned by one cache entry
+unsafe impl Send for BufferPool {}
+unsafe impl Sync for BufferPool {}
+
+impl BufferPool {
+    /// Map a pool of at least `bytes`, or `None` if no mapping could be made
+    pub fn new(bytes: usize) -> Option<Arc<BufferPool>> {
+        let len = (bytes + HUGE_PAGE_SIZE - 1) / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+        if len == 0 { return None }
+
+        let (base, backing) = match map(len, libc::MAP_HUGETLB) {
+            Some(base) => (base, PoolBacking::HugeTlb),
+            None => {
+                let base = map(len, 0)?;
+                // advice only: without transparent huge pages the pool still works, on small pages
+                unsafe { libc::madvise(base.as_ptr() as *mut libc::c_void, len, libc::MADV_HUGEPAGE); }
+                (base, PoolBacking::Transparent)
+            }
+        };
+
+        Some(Arc::new(BufferPool {
+            base,
+            len,
+            backing,
+            free: Mutex::new((0..len / PAGE_SIZE).rev().collect())
+        }))
+    }
+
+    pub fn backing(&self) -> PoolBacking {
+        self.backing
+    }
+
+    /// Copy a page into a free slot, or give it back if the pool is full
+    pub fn store(self: &Arc<Self>, page: Bytes) -> Result<Slot, Bytes> {
+        if page.len() != PAGE_SIZE { return Err(page) }
+        let index = match self.free.lock().pop() {
+            Some(index) => index,
+            None => return Err(page)
+        };
+
+        let slot = Slot { pool: self.clone(), index };
+        unsafe { std::ptr::copy_nonoverlapping(page.as_ptr(), slot.ptr(), PAGE_SIZE); }
+        Ok(slot)
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base.as_ptr() as *mut libc::c_void, self.len); }
+    }
+}
+
+fn map(len: usize, flags: libc::c_int) -> Option<NonNull<u8>> {
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
+            -1,
+            0
+        )
+    };
+    if addr == libc::MAP_FAILED { return None }
+    NonNull::new(addr as *mut u8)
+}
+
+/// A page held in the pool, returned to it when dropped. Its bytes never change once stored.
+pub struct Slot {
+    pool: Arc<BufferPool>,
+    index: usize
+}
+
+impl Slot {
+    fn ptr(&self) -> *mut u8 {
+        unsafe { self.pool.base.as_ptr().add(self.index * PAGE_SIZE) }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr(), PAGE_SIZE) }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.pool.free.lock().push(self.index);
+    }
+}
+
+/// A chunk held by the page cache, and handed out on a hit. Cloning shares it.
+#[derive(Clone)]
+pub enum Chunk {
+    Heap(Bytes),
+    Pooled(Arc<Slot>)
+}
+
+impl Chunk {
+    /// Part of the chunk as `Bytes`, which is copied out of the pool if it's held there
+    pub fn slice(&self, range: Range<usize>) -> Bytes {
+        match self {
+            Chunk::Heap(data) => data.slice(range),
+            Chunk::Pooled(slot) => Bytes::copy_from_slice(&slot.as_slice()[range])
+        }
+    }
+}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Chunk::Heap(data) => data,
+            Chunk::Pooled(slot) => slot.as_slice()
+        }
+    }
+}
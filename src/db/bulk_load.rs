@@ -0,0 +1,97 @@
+//! Loading an empty database from a sorted stream, building the tree bottom-up
+
+use std::sync::atomic::Ordering;
+use bytes::Bytes;
+use futures::stream::{Stream, StreamExt};
+
+use super::{DB, TransactionIdx, WriteError};
+use super::leaf::{LeafEntry, LeafValue};
+use super::transaction::Transaction;
+use super::tree::{Builder, NodeFormat};
+use super::version::VersionHeader;
+
+/// Leaves are filled to this share of a page, so inserts after the load don't split every leaf
+const LEAF_FILL: f64 = 0.9;
+
+/// Entries loaded between waits for the pages written so far, which bounds the pages in memory
+const FLUSH_EVERY: u64 = 64 * 1024;
+
+impl DB {
+    /// Load pairs into an empty database in one transaction, for initial imports too large to
+    /// sort in memory. Keys must be strictly increasing. Leaves are packed as the pairs
+    /// arrive and written in order, and the branches above them are filled as they go, so
+    /// every page is written once and the file grows sequentially.
+    ///
+    /// Fails with `NotEmpty` unless the database is empty, and `Unsorted` if a key isn't
+    /// greater than the one before it, in which case nothing is loaded.
+    pub async fn bulk_load<S: Stream<Item = (Bytes, Bytes)> + Unpin>(&self, mut pairs: S) -> Result<TransactionIdx, WriteError> {
+        self.check_writable()?;
+        let _writer = self.writer.lock().await;
+
+        let version = *self.version.lock();
+        if version.tree_root.is_some() || version.journal.is_some() { return Err(WriteError::NotEmpty) }
+
+        let (format, durability, threshold, compression, max_key_len, max_value_len, observer) = {
+            let options = self.options.lock();
+            (NodeFormat::new(&options), options.durability, options.value_inline_threshold, options.compression,
+                options.max_key_len, options.max_value_len, options.observer.clone())
+        };
+        let txn = Transaction::new(version.tx + 1, self.store.clone(), self.write_back.clone(), durability, version.page_count);
+
+        let built: Result<_, WriteError> = async {
+            let mut builder = Builder::new(format, LEAF_FILL);
+            let mut last: Option<Bytes> = None;
+            let mut loaded = 0u64;
+
+            while let Some((key, value)) = pairs.next().await {
+                if key.len() > max_key_len { return Err(WriteError::KeyTooLarge { len: key.len(), max: max_key_len }) }
+                if value.len() as u64 > max_value_len { return Err(WriteError::ValueTooLarge { len: value.len() as u64, max: max_value_len }) }
+                if last.as_ref().map_or(false, |last| *last >= key) { return Err(WriteError::Unsorted) }
+
+                let value = LeafValue::store(&txn, &mut self.value_log.lock(), &key, value, threshold, compression);
+                builder.push(&txn, LeafEntry { key: key.clone(), value })?;
+                last = Some(key);
+
+                loaded += 1;
+                if loaded % FLUSH_EVERY == 0 { txn.flush().await? }
+            }
+
+            builder.finish(&txn)
+        }.await;
+
+        let tree_root = match built {
+            Ok(tree_root) => tree_root,
+            Err(err) => {
+                self.value_log.lock().rolled_back();
+                return Err(err)
+            }
+        };
+        let segments = {
+            let mut value_log = self.value_log.lock();
+            value_log.seal(&txn);
+            value_log.write_table(&txn, version.segments)
+        };
+
+        let version = VersionHeader {
+            tx: txn.idx(),
+            tree_root,
+            page_count: txn.page_count(),
+            segments,
+            ..version
+        };
+        if let Err(err) = txn.commit(version).await {
+            self.value_log.lock().rolled_back();
+            return Err(err.into())
+        }
+
+        self.value_log.lock().committed(&txn);
+        {
+            let _buffer = self.write_buffer.lock();
+            *self.version.lock() = version;
+            self.generation.store(version.tx, Ordering::Release);
+        }
+        observer.on_commit(version.tx);
+
+        Ok(version.tx)
+    }
+}
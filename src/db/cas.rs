@@ -0,0 +1,39 @@
+use bytes::Bytes;
+use thiserror::Error;
+
+use super::{RetrieveError, WriteError, WriteTransaction};
+
+#[derive(Error, Debug, Clone)]
+pub enum CasError {
+    #[error("Key doesn't have the expected value")]
+    Mismatch {
+        /// The value the key has, including this transaction's changes
+        current: Option<Bytes>
+    },
+    #[error("{0}")]
+    Write(#[source] #[from] WriteError)
+}
+
+impl From<RetrieveError> for CasError {
+    fn from(err: RetrieveError) -> Self {
+        CasError::Write(err.into())
+    }
+}
+
+impl<'db> WriteTransaction<'db> {
+    /// Set `key` to `new`, or delete it for `None`, if its value is `expected`, where `None`
+    /// expects no value. Otherwise fail with `Mismatch` and change nothing.
+    ///
+    /// The transaction holds the writer lock from start to commit, so no other commit can
+    /// change the key between this check and this transaction's commit.
+    pub async fn compare_and_swap(&mut self, key: Bytes, expected: Option<Bytes>, new: Option<Bytes>) -> Result<(), CasError> {
+        let current = self.get(&key).await?;
+        if current != expected { return Err(CasError::Mismatch { current }) }
+
+        match new {
+            Some(value) => self.put(key, value)?,
+            None => self.delete(key)?
+        }
+        Ok(())
+    }
+}
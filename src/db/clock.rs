@@ -0,0 +1,56 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use parking_lot::Mutex;
+
+/// The source of wall-clock time for TTLs, leases and timestamps.
+///
+/// Register one with `Options::clock` to make time deterministic in tests and simulations.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// Milliseconds since the unix epoch, as stored in the database
+    fn unix_millis(&self) -> u64 {
+        self.now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+    }
+}
+
+/// The operating system's clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to
+pub struct ManualClock {
+    now: Mutex<SystemTime>
+}
+
+impl ManualClock {
+    pub fn new(start: SystemTime) -> ManualClock {
+        ManualClock { now: Mutex::new(start) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock() += by;
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock() = now;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock()
+    }
+}
+
+/// Lets a test keep a handle to the clock it registered
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> SystemTime {
+        (**self).now()
+    }
+}
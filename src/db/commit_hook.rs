@@ -0,0 +1,43 @@
+//! Callbacks run after each commit is durable
+
+use std::sync::Arc;
+use bytes::Bytes;
+
+use super::{DB, TransactionIdx};
+use super::tree::Write;
+
+/// The keys a transaction changed, in key order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitSummary {
+    pub tx: TransactionIdx,
+    /// Keys given a value
+    pub put: Vec<Bytes>,
+    /// Keys deleted, whether or not they had a value
+    pub deleted: Vec<Bytes>
+}
+
+pub(crate) type CommitHook = Arc<dyn Fn(&CommitSummary) + Send + Sync>;
+
+impl DB {
+    /// Call `callback` after each transaction commits and is durable, with the keys it
+    /// changed. Callbacks run inline, before the next transaction can start, so they should
+    /// return quickly. Bulk loads aren't reported.
+    pub fn on_commit<F: Fn(&CommitSummary) + Send + Sync + 'static>(&self, callback: F) {
+        self.commit_hooks.lock().push(Arc::new(callback));
+    }
+
+    pub(super) fn run_commit_hooks(&self, tx: TransactionIdx, writes: &[Write]) {
+        let hooks = self.commit_hooks.lock().clone();
+        if hooks.is_empty() { return }
+
+        let (put, deleted) = writes.iter().partition::<Vec<_>, _>(|(_, value)| value.is_some());
+        let summary = CommitSummary {
+            tx,
+            put: put.into_iter().map(|(key, _)| key.clone()).collect(),
+            deleted: deleted.into_iter().map(|(key, _)| key.clone()).collect()
+        };
+        for hook in hooks {
+            hook(&summary);
+        }
+    }
+}
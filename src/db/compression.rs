@@ -0,0 +1,139 @@
+use std::convert::TryInto;
+use bytes::Bytes;
+
+use super::page::Cursor;
+
+/// How leaf pages and value log records are compressed. Codecs are behind the `lz4` and
+/// `zstd` features; data is written uncompressed when its codec isn't compiled in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+    /// `level` is zstd's, from 1 (fastest) to 22 (smallest)
+    Zstd { level: i32 }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+/// Codec ids, as stored on disk
+pub(crate) const NONE: u8 = 0;
+#[cfg_attr(not(feature = "lz4"), allow(dead_code))]
+const LZ4: u8 = 1;
+#[cfg_attr(not(feature = "zstd"), allow(dead_code))]
+const ZSTD: u8 = 2;
+/// Codecs from here on are zstd with the dictionary of id `codec - FIRST_DICTIONARY`
+const FIRST_DICTIONARY: u8 = 16;
+
+/// zstd dictionaries trained by `DB::train_dictionary`. Every dictionary ever trained is kept,
+/// since values compressed with it may still be live.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Dictionaries {
+    dicts: Vec<Bytes>
+}
+
+impl Dictionaries {
+    /// The newest dictionary, with the codec that marks data compressed with it
+    pub fn latest(&self) -> Option<(u8, &[u8])> {
+        let id = self.dicts.len().checked_sub(1)?;
+        Some((FIRST_DICTIONARY + id as u8, &self.dicts[id]))
+    }
+
+    #[cfg_attr(not(feature = "zstd"), allow(dead_code))]
+    fn for_codec(&self, codec: u8) -> Option<&[u8]> {
+        let id = codec.checked_sub(FIRST_DICTIONARY)?;
+        self.dicts.get(id as usize).map(|dict| &dict[..])
+    }
+
+    /// Add a dictionary, or `None` if there's no codec left for it
+    pub fn with(&self, dict: Bytes) -> Option<Dictionaries> {
+        if FIRST_DICTIONARY as usize + self.dicts.len() > u8::MAX as usize { return None }
+
+        let mut dicts = self.dicts.clone();
+        dicts.push(dict);
+        Some(Dictionaries { dicts })
+    }
+
+    /// `[count: u8]`, then each dictionary as `[len: u32][bytes]`
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![self.dicts.len() as u8];
+        for dict in self.dicts.iter() {
+            buf.extend_from_slice(&(dict.len() as u32).to_le_bytes());
+            buf.extend_from_slice(dict);
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Dictionaries> {
+        let mut buf = Cursor::new(buf);
+        let count = buf.take(1)?[0];
+
+        let mut dicts = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let len = u32::from_le_bytes(buf.take(4)?.try_into().unwrap()) as usize;
+            dicts.push(Bytes::copy_from_slice(buf.take(len)?));
+        }
+
+        Some(Dictionaries { dicts })
+    }
+}
+
+impl Compression {
+    /// Compress `data`, returning the codec used and the compressed bytes. Returns `None`
+    /// if compression is off, not compiled in, or doesn't make `data` smaller. zstd uses
+    /// the latest dictionary, if there is one.
+    pub(crate) fn compress(&self, data: &[u8], _dictionaries: &Dictionaries) -> Option<(u8, Vec<u8>)> {
+        if data.len() > u32::MAX as usize { return None }
+
+        let (codec, compressed) = match self {
+            Compression::None => return None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => (LZ4, lz4_flex::block::compress(data)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level } => match _dictionaries.latest() {
+                Some((codec, dict)) => {
+                    let mut compressor = zstd_codec::bulk::Compressor::with_dictionary(*level, dict).ok()?;
+                    (codec, compressor.compress(data).ok()?)
+                },
+                None => (ZSTD, zstd_codec::bulk::compress(data, *level).ok()?)
+            },
+            #[allow(unreachable_patterns)]
+            _ => return None
+        };
+
+        // prefixed with the original length, so decompression allocates exactly once
+        let mut framed = Vec::with_capacity(4 + compressed.len());
+        framed.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        framed.extend_from_slice(&compressed);
+
+        if framed.len() >= data.len() { return None }
+        Some((codec, framed))
+    }
+}
+
+/// Decompress data written by `Compression::compress`, or `None` if it's corrupt, longer
+/// than `max_len`, or uses a codec (or dictionary) that isn't available
+pub(crate) fn decompress(codec: u8, framed: &[u8], max_len: usize, _dictionaries: &Dictionaries) -> Option<Vec<u8>> {
+    let len = u32::from_le_bytes(framed.get(0..4)?.try_into().unwrap()) as usize;
+    if len > max_len { return None }
+    let _compressed = &framed[4..];
+
+    let data = match codec {
+        #[cfg(feature = "lz4")]
+        LZ4 => lz4_flex::block::decompress(_compressed, len).ok()?,
+        #[cfg(feature = "zstd")]
+        ZSTD => zstd_codec::bulk::decompress(_compressed, len).ok()?,
+        #[cfg(feature = "zstd")]
+        codec if codec >= FIRST_DICTIONARY => {
+            let mut decompressor = zstd_codec::bulk::Decompressor::with_dictionary(_dictionaries.for_codec(codec)?).ok()?;
+            decompressor.decompress(_compressed, len).ok()?
+        },
+        _ => return None
+    };
+
+    if data.len() != len { return None }
+    Some(data)
+}
@@ -0,0 +1,209 @@
+use std::{io, thread, sync::Arc, time::{Duration, SystemTime}};
+use std::collections::BTreeMap;
+use std::future::Future;
+use futures::channel::oneshot;
+use futures::future::{BoxFuture, FutureExt};
+use parking_lot::{Condvar, Mutex};
+
+use super::{Clock, SystemClock, Durability, PageContent, PageIndex, PageStore, RetrieveError, StoreMetrics};
+
+/// A latency distribution for one kind of operation
+#[derive(Debug, Clone, Copy)]
+pub enum Latency {
+    None,
+    Fixed(Duration),
+    /// Uniformly distributed between `min` and `max`
+    Uniform { min: Duration, max: Duration },
+    /// Usually `base`, but `spike` with the given probability
+    Spiky { base: Duration, spike: Duration, probability: f64 }
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency::None
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DelayConfig {
+    pub read: Latency,
+    pub write: Latency,
+    pub sync: Latency,
+    /// Seeds the generator for random latencies, so a test sees the same sequence of delays every run
+    pub seed: u64
+}
+
+/// A `PageStore` wrapper delaying every operation, for testing behavior under slow IO.
+///
+/// Delays are added before reads and syncs, and after writes complete, so writes are still
+/// submitted immediately. Delays are measured on the system clock, or on the clock given to
+/// `with_clock`, so a test using a `ManualClock` decides when each one ends.
+pub struct DelayStore<S: PageStore> {
+    inner: S,
+    config: DelayConfig,
+    rng: Mutex<u64>,
+    timer: Timer
+}
+
+impl<S: PageStore> DelayStore<S> {
+    pub fn new(inner: S, config: DelayConfig) -> Arc<DelayStore<S>> {
+        DelayStore::build(inner, config, Arc::new(SystemClock), true)
+    }
+
+    /// Measure delays on `clock`. Each ends once the clock reaches the time it was started at
+    /// plus its latency, and delays ending at the same time end in the order they started.
+    pub fn with_clock(inner: S, config: DelayConfig, clock: Arc<dyn Clock>) -> Arc<DelayStore<S>> {
+        DelayStore::build(inner, config, clock, false)
+    }
+
+    fn build(inner: S, config: DelayConfig, clock: Arc<dyn Clock>, system: bool) -> Arc<DelayStore<S>> {
+        Arc::new(DelayStore {
+            inner,
+            config,
+            // xorshift gets stuck at zero
+            rng: Mutex::new(config.seed | 1),
+            timer: Timer::new(clock, system)
+        })
+    }
+
+    /// A uniform sample from [0, 1)
+    fn next_unit(&self) -> f64 {
+        let mut state = self.rng.lock();
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        (*state >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn sample(&self, latency: Latency) -> Duration {
+        match latency {
+            Latency::None => Duration::from_secs(0),
+            Latency::Fixed(delay) => delay,
+            Latency::Uniform { min, max } => min + (max - min).mul_f64(self.next_unit()),
+            Latency::Spiky { base, spike, probability } => if self.next_unit() < probability { spike } else { base }
+        }
+    }
+}
+
+/// How often the timer looks at a clock that isn't the system's, which can jump at any time
+const CLOCK_POLL: Duration = Duration::from_millis(1);
+
+#[derive(Default)]
+struct Waiting {
+    /// Ordered by deadline, then by arrival
+    waiters: BTreeMap<(SystemTime, u64), oneshot::Sender<()>>,
+    arrived: u64,
+    stopped: bool
+}
+
+struct TimerState {
+    clock: Arc<dyn Clock>,
+    /// Whether `clock` is the system clock, so sleeping until a deadline is exact
+    system: bool,
+    waiting: Mutex<Waiting>,
+    changed: Condvar
+}
+
+/// Ends delays from one thread per store, so they don't depend on an executor's timer.
+/// Deadlines are read from the store's clock, so a `ManualClock` ends them only as it's advanced.
+struct Timer {
+    state: Arc<TimerState>
+}
+
+impl Timer {
+    fn new(clock: Arc<dyn Clock>, system: bool) -> Timer {
+        let state = Arc::new(TimerState { clock, system, waiting: Mutex::default(), changed: Condvar::new() });
+        let running = state.clone();
+        thread::Builder::new()
+            .name("bssdb-delay-timer".into())
+            .spawn(move || run_timer(&running))
+            .expect("failed to spawn the delay timer thread");
+        Timer { state }
+    }
+
+    /// Wait for `duration` on the clock, from now rather than from the first poll
+    fn delay(&self, duration: Duration) -> impl Future<Output = ()> {
+        let wait = if duration == Duration::from_secs(0) { None } else {
+            let (done, wait) = oneshot::channel();
+            let deadline = self.state.clock.now() + duration;
+            let mut waiting = self.state.waiting.lock();
+            let arrived = waiting.arrived;
+            waiting.arrived += 1;
+            waiting.waiters.insert((deadline, arrived), done);
+            self.state.changed.notify_one();
+            Some(wait)
+        };
+
+        async move {
+            if let Some(wait) = wait { let _ = wait.await; }
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.state.waiting.lock().stopped = true;
+        self.state.changed.notify_one();
+    }
+}
+
+/// Wake each waiter once the clock passes its deadline, until the timer is dropped
+fn run_timer(state: &TimerState) {
+    let mut waiting = state.waiting.lock();
+    while !waiting.stopped {
+        let now = state.clock.now();
+        while let Some(&(deadline, n)) = waiting.waiters.keys().next() {
+            if deadline > now { break }
+            if let Some(done) = waiting.waiters.remove(&(deadline, n)) { let _ = done.send(()); }
+        }
+
+        match waiting.waiters.keys().next() {
+            Some(&(deadline, _)) => {
+                let until = deadline.duration_since(now).unwrap_or_default();
+                let timeout = if state.system { until } else { until.min(CLOCK_POLL) };
+                state.changed.wait_for(&mut waiting, timeout);
+            },
+            None => state.changed.wait(&mut waiting)
+        }
+    }
+}
+
+impl<S: PageStore> PageStore for DelayStore<S> {
+    fn read_page(&self, idx: PageIndex) -> BoxFuture<'_, Result<PageContent, RetrieveError>> {
+        let delay = self.timer.delay(self.sample(self.config.read));
+        async move {
+            delay.await;
+            self.inner.read_page(idx).await
+        }.boxed()
+    }
+
+    fn write_page<'a>(&'a self, idx: PageIndex, page: &'a PageContent) -> BoxFuture<'a, io::Result<()>> {
+        let latency = self.sample(self.config.write);
+        let write = self.inner.write_page(idx, page);
+        async move {
+            let res = write.await;
+            self.timer.delay(latency).await;
+            res
+        }.boxed()
+    }
+
+    fn sync(&self, durability: Durability) -> BoxFuture<'_, io::Result<()>> {
+        let delay = self.timer.delay(self.sample(self.config.sync));
+        async move {
+            delay.await;
+            self.inner.sync(durability).await
+        }.boxed()
+    }
+
+    fn metrics(&self) -> Option<StoreMetrics> {
+        self.inner.metrics()
+    }
+
+    fn preallocate(&self, bytes: u64) -> io::Result<()> {
+        self.inner.preallocate(bytes)
+    }
+
+    fn discard(&self, start: PageIndex, pages: u64) -> io::Result<()> {
+        self.inner.discard(start, pages)
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use thiserror::Error;
+
+use super::PageIndex;
+
+/// Far deeper than any real tree: even with only two children per branch, this covers more pages than a file can hold
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 64;
+
+#[derive(Error, Debug, Clone)]
+pub enum DescentError {
+    #[error("Tree descent exceeded the maximum depth of {max_depth} (visited pages {trail:?})")]
+    TooDeep {
+        max_depth: usize,
+        /// Every page visited, from the root down
+        trail: Vec<PageIndex>
+    },
+    #[error("Tree descent reached page {page} twice, so child pointers form a cycle (visited pages {trail:?})")]
+    Cycle {
+        page: PageIndex,
+        trail: Vec<PageIndex>
+    }
+}
+
+/// Tracks the pages visited while descending a tree, so a corrupt child pointer
+/// (such as one forming a cycle) fails the descent instead of being followed forever.
+#[derive(Clone)]
+pub(crate) struct Descent {
+    max_depth: usize,
+    trail: Vec<PageIndex>
+}
+
+impl Descent {
+    pub fn new(max_depth: usize) -> Descent {
+        Descent { max_depth, trail: Vec::new() }
+    }
+
+    /// Record stepping into `page`
+    pub fn enter(&mut self, page: PageIndex) -> Result<(), DescentError> {
+        // the trail is only as long as the tree is deep, so searching it is cheap
+        let revisited = self.trail.contains(&page);
+        self.trail.push(page);
+
+        if revisited {
+            return Err(DescentError::Cycle { page, trail: std::mem::take(&mut self.trail) })
+        }
+        if self.trail.len() > self.max_depth {
+            return Err(DescentError::TooDeep {
+                max_depth: self.max_depth,
+                trail: std::mem::take(&mut self.trail)
+            })
+        }
+
+        Ok(())
+    }
+
+    /// Record stepping back out of the last page entered
+    pub fn leave(&mut self) {
+        self.trail.pop();
+    }
+
+    pub fn depth(&self) -> usize {
+        self.trail.len()
+    }
+
+    pub fn trail(&self) -> &[PageIndex] {
+        &self.trail
+    }
+}
+
+#[derive(Error, Debug, Clone)]
+#[error("Page {page} is reachable from both {first_parent:?} and {second_parent:?}")]
+pub struct CrossLink {
+    pub page: PageIndex,
+    /// The parent the page was first reached from, or `None` for a root
+    pub first_parent: Option<PageIndex>,
+    pub second_parent: Option<PageIndex>
+}
+
+/// Records every page reached while walking entire trees (e.g. to verify them).
+///
+/// Every page has exactly one parent, so reaching a page a second time means a pointer cycle,
+/// or a page shared between subtrees or trees. Both are how copy-on-write bugs usually show up.
+pub(crate) struct PageWalk {
+    parents: HashMap<PageIndex, Option<PageIndex>>
+}
+
+impl PageWalk {
+    pub fn new() -> PageWalk {
+        PageWalk { parents: HashMap::new() }
+    }
+
+    /// Record reaching `page` from `parent`. A walk must not descend into a page that fails this check.
+    pub fn visit(&mut self, page: PageIndex, parent: Option<PageIndex>) -> Result<(), CrossLink> {
+        match self.parents.insert(page, parent) {
+            None => Ok(()),
+            Some(first_parent) => {
+                self.parents.insert(page, first_parent);
+                Err(CrossLink { page, first_parent, second_parent: parent })
+            }
+        }
+    }
+
+    pub fn contains(&self, page: PageIndex) -> bool {
+        self.parents.contains_key(&page)
+    }
+
+    pub fn len(&self) -> usize {
+        self.parents.len()
+    }
+}
@@ -0,0 +1,44 @@
+use std::{io, sync::Arc};
+
+use super::{DB, WriteError};
+use super::overflow;
+use super::tree;
+
+impl DB {
+    /// Train a zstd dictionary on up to `max_samples` values, and use it to compress values
+    /// written from now on. Helps when there are many small, similar values, which compress
+    /// poorly on their own. Only applies with `Compression::Zstd`.
+    ///
+    /// Earlier dictionaries are kept, since values compressed with them may still be live.
+    pub async fn train_dictionary(&self, max_samples: usize, dict_size: usize) -> Result<(), WriteError> {
+        let mut tx = self.write().await?;
+        let dictionaries = self.dictionaries();
+
+        let mut sampled = vec![];
+        if let Some(root) = tx.version.tree_root {
+            let max_depth = self.options.lock().max_tree_depth;
+            tree::scan_leaves(&self.cache, root, max_depth, |entries| {
+                sampled.extend(entries.into_iter().map(|entry| entry.value).take(max_samples - sampled.len()));
+                sampled.len() < max_samples
+            }).await?;
+        }
+
+        let mut samples = Vec::with_capacity(sampled.len());
+        for value in sampled {
+            samples.push(value.read(&self.cache, &dictionaries).await?);
+        }
+
+        let dict = zstd_codec::dict::from_samples(&samples, dict_size)?;
+        let dictionaries = dictionaries.with(dict.into())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no codec left for another dictionary"))?;
+
+        tx.version.dictionaries = Some(overflow::write_chain(&tx.txn, &dictionaries.encode()));
+        tx.commit().await?;
+
+        let dictionaries = Arc::new(dictionaries);
+        *self.dictionaries.lock() = dictionaries.clone();
+        self.value_log.lock().set_dictionaries(dictionaries);
+
+        Ok(())
+    }
+}
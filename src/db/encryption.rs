@@ -0,0 +1,547 @@
+//! Encryption at rest. `EncryptedStore` wraps the page store, sealing each page with an AEAD
+//! as it's written and opening it as it's read, so the rest of the engine only sees plaintext.
+//!
+//! A sealed page keeps its lsn and type in the clear, authenticated along with its index, and
+//! its data is encrypted in place. The tag fills the checksum field and the start of the seal
+//! field, and the seal ends with a random salt and the id of the key. The nonce is derived from
+//! the page index, lsn and salt: the salt keeps it unique when pages of a rolled-back transaction
+//! are written again under the same lsn. The header page stays in the clear, so the file can
+//! still be identified.
+//!
+//! Rotating the key seals pages written from then on with the new key, while the sweeper
+//! re-encrypts older pages in place. Each batch is first copied to a buffer and recorded in the
+//! version, so a torn write in place can be repaired from the copy.
+
+use std::{fmt, io, sync::Arc};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::{AeadInPlace, NewAead, generic_array::GenericArray};
+use futures::future::{try_join_all, BoxFuture, FutureExt};
+use parking_lot::RwLock;
+
+use super::{DB, Durability, FormatError, Options, PageContent, PageIndex, PageStore, RetrieveError, StoreMetrics, WriteError};
+use super::header::{FileHeader, FEATURE_ENCRYPTED, HEADER_PAGE};
+use super::page::{CHECKSUM_LEN, SEAL_LEN};
+use super::transaction::Transaction;
+use super::version::{KeyRotation, VersionHeader, FIRST_DATA_PAGE};
+
+const TAG_LEN: usize = 16;
+const SALT_LEN: usize = SEAL_LEN - (TAG_LEN - CHECKSUM_LEN);
+
+/// Pages re-encrypted between syncs by the key rotation sweeper
+const SWEEP_BATCH: u64 = 256;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    XChaCha20Poly1305 = 1,
+    /// Faster on CPUs with AES instructions. Its 96-bit nonce holds the salt and the low bits
+    /// of the index and lsn.
+    Aes256Gcm = 2
+}
+
+/// Encrypts every page but the header. Keys are never stored: keep them somewhere safe, since
+/// the database can't be read without them.
+#[derive(Clone)]
+pub struct EncryptionConfig {
+    pub key: [u8; 32],
+    pub cipher: Cipher,
+    /// The key being rotated away from, needed to reopen a database before
+    /// `DB::sweep_key_rotation` finishes
+    pub previous_key: Option<[u8; 32]>
+}
+
+impl fmt::Debug for EncryptionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionConfig")
+            .field("key", &"<redacted>")
+            .field("cipher", &self.cipher)
+            .field("previous_key", &self.previous_key.map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+enum Aead {
+    XChaCha20Poly1305(XChaCha20Poly1305),
+    Aes256Gcm(Aes256Gcm)
+}
+
+impl Aead {
+    fn new(cipher: Cipher, key: &[u8; 32]) -> Aead {
+        let key = GenericArray::from_slice(key);
+        match cipher {
+            Cipher::XChaCha20Poly1305 => Aead::XChaCha20Poly1305(XChaCha20Poly1305::new(key)),
+            Cipher::Aes256Gcm => Aead::Aes256Gcm(Aes256Gcm::new(key))
+        }
+    }
+
+    fn nonce(idx: PageIndex, lsn: u64, salt: &[u8; SALT_LEN]) -> [u8; 24] {
+        let mut nonce = [0; 24];
+        nonce[0..8].copy_from_slice(&idx.to_le_bytes());
+        nonce[8..16].copy_from_slice(&lsn.to_le_bytes());
+        nonce[16..24].copy_from_slice(salt);
+        nonce
+    }
+
+    fn short_nonce(idx: PageIndex, lsn: u64, salt: &[u8; SALT_LEN]) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[0..8].copy_from_slice(salt);
+        nonce[8..12].copy_from_slice(&((idx ^ lsn) as u32).to_le_bytes());
+        nonce
+    }
+
+    fn seal(&self, idx: PageIndex, lsn: u64, salt: &[u8; SALT_LEN], aad: &[u8], buf: &mut [u8]) -> [u8; TAG_LEN] {
+        let tag = match self {
+            Aead::XChaCha20Poly1305(aead) => aead.encrypt_in_place_detached(GenericArray::from_slice(&Aead::nonce(idx, lsn, salt)), aad, buf),
+            Aead::Aes256Gcm(aead) => aead.encrypt_in_place_detached(GenericArray::from_slice(&Aead::short_nonce(idx, lsn, salt)), aad, buf)
+        };
+
+        let mut sealed = [0; TAG_LEN];
+        // only fails for buffers beyond the cipher's limit, far larger than a page
+        sealed.copy_from_slice(&tag.expect("page is too large to encrypt"));
+        sealed
+    }
+
+    fn open(&self, idx: PageIndex, lsn: u64, salt: &[u8; SALT_LEN], aad: &[u8], buf: &mut [u8], tag: &[u8; TAG_LEN]) -> bool {
+        let tag = GenericArray::from_slice(tag);
+        match self {
+            Aead::XChaCha20Poly1305(aead) => aead.decrypt_in_place_detached(GenericArray::from_slice(&Aead::nonce(idx, lsn, salt)), aad, buf, tag),
+            Aead::Aes256Gcm(aead) => aead.decrypt_in_place_detached(GenericArray::from_slice(&Aead::short_nonce(idx, lsn, salt)), aad, buf, tag)
+        }.is_ok()
+    }
+}
+
+const KEY_ID_AAD: &[u8] = b"bssdb key id";
+
+struct Key {
+    /// Recorded on each page sealed with the key, to find the key to open it with. Ids of two
+    /// keys may collide, so it's only a hint.
+    id: u8,
+    aead: Aead
+}
+
+impl Key {
+    fn new(cipher: Cipher, key: &[u8; 32]) -> Arc<Key> {
+        let aead = Aead::new(cipher, key);
+        // the tag of an empty message under a nonce no page uses
+        let id = aead.seal(HEADER_PAGE, 0, &[0; SALT_LEN], KEY_ID_AAD, &mut [])[0];
+        Arc::new(Key { id, aead })
+    }
+}
+
+/// Check that the database's encryption matches the options it's opened with. Whether the key
+/// is right shows once the root pages are opened.
+pub(crate) fn check(header: &FileHeader, options: &Options) -> Result<(), FormatError> {
+    let encrypted = header.features & FEATURE_ENCRYPTED != 0;
+    match &options.encryption {
+        None if encrypted => Err(FormatError::Encrypted),
+        None => Ok(()),
+        Some(_) if !encrypted => Err(FormatError::NotEncrypted),
+        Some(config) if header.cipher != config.cipher as u8 => Err(FormatError::WrongKey),
+        Some(_) => Ok(())
+    }
+}
+
+/// An encrypting wrapper for `store`, if `options` ask for encryption
+pub(crate) fn encrypted_store(store: Arc<dyn PageStore>, options: &Options) -> Option<Arc<EncryptedStore>> {
+    options.encryption.as_ref().map(|config| Arc::new(EncryptedStore {
+        inner: store,
+        cipher: config.cipher,
+        keys: RwLock::new(Keys {
+            current: Key::new(config.cipher, &config.key),
+            previous: config.previous_key.as_ref().map(|key| Key::new(config.cipher, key))
+        })
+    }))
+}
+
+/// Wrap `store` to encrypt its pages, if `options` ask for encryption
+pub(crate) fn wrap(store: Arc<dyn PageStore>, options: &Options) -> Arc<dyn PageStore> {
+    match encrypted_store(store.clone(), options) {
+        Some(encrypted) => encrypted,
+        None => store
+    }
+}
+
+struct Keys {
+    /// Seals every page written
+    current: Arc<Key>,
+    /// Still opens pages sealed before a rotation
+    previous: Option<Arc<Key>>
+}
+
+pub(crate) struct EncryptedStore {
+    inner: Arc<dyn PageStore>,
+    cipher: Cipher,
+    keys: RwLock<Keys>
+}
+
+/// The index, lsn and type of a page, which are authenticated but not encrypted
+fn associated_data(idx: PageIndex, page: &PageContent) -> [u8; 17] {
+    let mut aad = [0; 17];
+    aad[0..8].copy_from_slice(&idx.to_le_bytes());
+    aad[8..16].copy_from_slice(&page.lsn);
+    aad[16] = page.page_type.clone() as u8;
+    aad
+}
+
+/// Pages allocated but never written read back as zeros, and are passed through as they are
+fn is_blank(page: &PageContent) -> bool {
+    page.checksum == [0; CHECKSUM_LEN] && page.seal == [0; SEAL_LEN]
+}
+
+impl EncryptedStore {
+    fn seal(&self, idx: PageIndex, page: &mut PageContent) -> io::Result<()> {
+        let key = self.keys.read().current.clone();
+
+        let mut salt = [0; SALT_LEN];
+        getrandom::getrandom(&mut salt[..SALT_LEN - 1]).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        salt[SALT_LEN - 1] = key.id;
+
+        let aad = associated_data(idx, page);
+        let tag = key.aead.seal(idx, page.lsn(), &salt, &aad, &mut page.data);
+
+        page.checksum.copy_from_slice(&tag[..CHECKSUM_LEN]);
+        page.seal[..TAG_LEN - CHECKSUM_LEN].copy_from_slice(&tag[CHECKSUM_LEN..]);
+        page.seal[TAG_LEN - CHECKSUM_LEN..].copy_from_slice(&salt);
+        Ok(())
+    }
+
+    /// Open a sealed page, returning whether it was sealed with the current key
+    fn open(&self, idx: PageIndex, page: &mut PageContent) -> Result<bool, RetrieveError> {
+        let mut tag = [0; TAG_LEN];
+        tag[..CHECKSUM_LEN].copy_from_slice(&page.checksum);
+        tag[CHECKSUM_LEN..].copy_from_slice(&page.seal[..TAG_LEN - CHECKSUM_LEN]);
+        let mut salt = [0; SALT_LEN];
+        salt.copy_from_slice(&page.seal[TAG_LEN - CHECKSUM_LEN..]);
+
+        let (current, previous) = {
+            let keys = self.keys.read();
+            (keys.current.clone(), keys.previous.clone())
+        };
+
+        // try the key the page names first, each on a copy, since a failed attempt may leave
+        // the data scrambled
+        let mut candidates = vec![(current, true)];
+        candidates.extend(previous.map(|key| (key, false)));
+        candidates.sort_by_key(|(key, _)| key.id != salt[SALT_LEN - 1]);
+
+        let aad = associated_data(idx, page);
+        let opened = candidates.into_iter().find_map(|(key, current)| {
+            let mut data = page.data;
+            key.aead.open(idx, page.lsn(), &salt, &aad, &mut data, &tag).then(|| (data, current))
+        });
+        let opened = match opened {
+            Some((data, current)) => {
+                page.data = data;
+                current
+            },
+            None => return Err(RetrieveError::BadChecksum)
+        };
+
+        // hand the engine the page as it was before sealing
+        page.seal = [0; SEAL_LEN];
+        page.update_checksum();
+        Ok(opened)
+    }
+
+    fn rotating(&self) -> bool {
+        self.keys.read().previous.is_some()
+    }
+
+    /// Seal pages with `key` from now on, while still opening pages sealed with the current key
+    fn rotate(&self, key: &[u8; 32]) {
+        let mut keys = self.keys.write();
+        let previous = std::mem::replace(&mut keys.current, Key::new(self.cipher, key));
+        keys.previous = Some(previous);
+    }
+
+    /// Undo `rotate`, when the rotation couldn't be committed
+    fn abandon_rotation(&self) {
+        let mut keys = self.keys.write();
+        if let Some(previous) = keys.previous.take() {
+            keys.current = previous;
+        }
+    }
+
+    fn finish_rotation(&self) {
+        self.keys.write().previous = None;
+    }
+
+    /// A page as stored, resealed with the current key, and whether that changed it
+    async fn reseal(&self, idx: PageIndex) -> Result<(Box<PageContent>, bool), RetrieveError> {
+        let raw = Box::new(self.inner.read_page(idx).await?);
+        if is_blank(&raw) { return Ok((raw, false)) }
+
+        let mut page = raw.clone();
+        if self.open(idx, &mut page)? { return Ok((raw, false)) }
+
+        self.seal(idx, &mut page).map_err(Arc::new)?;
+        Ok((page, true))
+    }
+
+    /// Pick up a rotation as of the latest version: drop the previous key if it's finished, or
+    /// repair a batch an interrupted sweep may have left half-written
+    pub(crate) async fn recover(&self, rotation: Option<KeyRotation>, options: &Options) -> Result<(), RetrieveError> {
+        let rotation = match rotation {
+            Some(rotation) => rotation,
+            None => {
+                self.finish_rotation();
+                return Ok(())
+            }
+        };
+        if options.read_only { return Ok(()) }
+
+        for i in 0..rotation.pending {
+            let page = self.inner.read_page(rotation.buffer + i).await?;
+            self.inner.write_page(rotation.cursor + i, &page).await.map_err(Arc::new)?;
+        }
+        self.inner.sync(options.durability).await.map_err(Arc::new)?;
+        Ok(())
+    }
+}
+
+impl PageStore for EncryptedStore {
+    fn read_page(&self, idx: PageIndex) -> BoxFuture<'_, Result<PageContent, RetrieveError>> {
+        async move {
+            let mut page = self.inner.read_page(idx).await?;
+            if idx == HEADER_PAGE || is_blank(&page) { return Ok(page) }
+
+            match self.open(idx, &mut page) {
+                // the sweeper may have been rewriting the page as it was read
+                Err(RetrieveError::BadChecksum) if self.rotating() => {
+                    let mut page = self.inner.read_page(idx).await?;
+                    self.open(idx, &mut page)?;
+                    Ok(page)
+                },
+                opened => opened.map(|_| page)
+            }
+        }.boxed()
+    }
+
+    fn write_page<'a>(&'a self, idx: PageIndex, page: &'a PageContent) -> BoxFuture<'a, io::Result<()>> {
+        if idx == HEADER_PAGE { return self.inner.write_page(idx, page) }
+
+        async move {
+            let mut sealed = Box::new(page.clone());
+            self.seal(idx, &mut sealed)?;
+            self.inner.write_page(idx, &sealed).await
+        }.boxed()
+    }
+
+    fn sync(&self, durability: Durability) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.sync(durability)
+    }
+
+    fn metrics(&self) -> Option<StoreMetrics> {
+        self.inner.metrics()
+    }
+
+    fn preallocate(&self, bytes: u64) -> io::Result<()> {
+        self.inner.preallocate(bytes)
+    }
+
+    fn discard(&self, start: PageIndex, pages: u64) -> io::Result<()> {
+        self.inner.discard(start, pages)
+    }
+}
+
+fn not_encrypted() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "database is not encrypted")
+}
+
+impl DB {
+    /// Start sealing pages with `key`. Pages already written are re-encrypted by
+    /// `sweep_key_rotation`; until it finishes, reopening the database needs both keys, with
+    /// the old one as `EncryptionConfig::previous_key`.
+    pub async fn rotate_key(&self, key: [u8; 32]) -> Result<(), WriteError> {
+        let encrypted = self.encrypted.as_ref().ok_or_else(not_encrypted)?;
+        self.check_writable()?;
+        let _writer = self.writer.lock().await;
+
+        let version = *self.version.lock();
+        if version.rotation.is_some() || encrypted.rotating() {
+            return Err(io::Error::new(io::ErrorKind::Other, "a key rotation is already in progress").into())
+        }
+
+        let durability = self.options.lock().durability;
+        let txn = Transaction::new(version.tx + 1, self.store.clone(), self.write_back.clone(), durability, version.page_count);
+        let buffer = txn.alloc_run(SWEEP_BATCH, 1);
+
+        let version = VersionHeader {
+            tx: txn.idx(),
+            page_count: txn.page_count(),
+            rotation: Some(KeyRotation { cursor: FIRST_DATA_PAGE, end: version.page_count, buffer, pending: 0 }),
+            ..version
+        };
+
+        encrypted.rotate(&key);
+        if let Err(err) = txn.commit(version).await {
+            encrypted.abandon_rotation();
+            return Err(err.into())
+        }
+
+        *self.version.lock() = version;
+        Ok(())
+    }
+
+    /// Re-encrypt up to `max_pages` pages sealed with the previous key. Run it in the background
+    /// until it returns true, once the rotation is finished and the previous key is no longer
+    /// needed. Like other maintenance, it does nothing while paused.
+    pub async fn sweep_key_rotation(&self, max_pages: u64) -> Result<bool, WriteError> {
+        let encrypted = self.encrypted.as_ref().ok_or_else(not_encrypted)?;
+        if self.is_maintenance_paused() { return Ok(false) }
+        self.check_writable()?;
+        let _writer = self.writer.lock().await;
+
+        let mut version = *self.version.lock();
+        let mut rotation = match version.rotation {
+            Some(rotation) => rotation,
+            None => return Ok(true)
+        };
+        let durability = self.options.lock().durability;
+
+        let mut swept = 0;
+        while rotation.cursor < rotation.end && swept < max_pages {
+            let batch = (rotation.end - rotation.cursor).min(SWEEP_BATCH).min(max_pages - swept);
+            let pages = try_join_all((rotation.cursor..rotation.cursor + batch).map(|idx| encrypted.reseal(idx))).await?;
+
+            // copy the batch aside, and record it, before rewriting anything in place
+            for (i, (page, _)) in pages.iter().enumerate() {
+                encrypted.inner.write_page(rotation.buffer + i as u64, page).await?;
+            }
+            encrypted.inner.sync(durability).await?;
+
+            rotation.pending = batch;
+            version.tx += 1;
+            version.rotation = Some(rotation);
+            version.write(&*self.store, durability).await?;
+            *self.version.lock() = version;
+
+            for (i, (page, changed)) in pages.iter().enumerate() {
+                if *changed {
+                    encrypted.inner.write_page(rotation.cursor + i as u64, page).await?;
+                }
+            }
+            encrypted.inner.sync(durability).await?;
+
+            rotation.cursor += batch;
+            rotation.pending = 0;
+            swept += batch;
+
+            version.tx += 1;
+            version.rotation = Some(rotation).filter(|rotation| rotation.cursor < rotation.end);
+            version.write(&*self.store, durability).await?;
+            *self.version.lock() = version;
+        }
+
+        if version.rotation.is_some() { return Ok(false) }
+
+        encrypted.finish_rotation();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use futures::executor::block_on;
+    use parking_lot::Mutex;
+    use super::*;
+    use super::super::PageType;
+    use super::super::page::PAGE_DATA_LEN;
+
+    /// Pages in memory, as the encrypting store writes them
+    #[derive(Default)]
+    struct Memory {
+        pages: Mutex<HashMap<PageIndex, PageContent>>
+    }
+
+    impl PageStore for Memory {
+        fn read_page(&self, idx: PageIndex) -> BoxFuture<'_, Result<PageContent, RetrieveError>> {
+            let page = self.pages.lock().get(&idx).cloned().ok_or(RetrieveError::OutOfPages);
+            async move { page }.boxed()
+        }
+
+        fn write_page<'a>(&'a self, idx: PageIndex, page: &'a PageContent) -> BoxFuture<'a, io::Result<()>> {
+            self.pages.lock().insert(idx, page.clone());
+            async { Ok(()) }.boxed()
+        }
+
+        fn sync(&self, _durability: Durability) -> BoxFuture<'_, io::Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+    }
+
+    fn encrypted(memory: &Arc<Memory>, cipher: Cipher, key: u8) -> Arc<EncryptedStore> {
+        let mut options = Options::new();
+        options.encryption(Some(EncryptionConfig { key: [key; 32], cipher, previous_key: None }));
+        encrypted_store(memory.clone(), &options).unwrap()
+    }
+
+    fn page(fill: u8) -> PageContent {
+        let mut page = PageContent::new(PageType::Leaf);
+        page.data = [fill; PAGE_DATA_LEN];
+        page.set_lsn(7);
+        page.update_checksum();
+        page
+    }
+
+    #[test]
+    fn round_trip() {
+        for &cipher in &[Cipher::XChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let memory = Arc::new(Memory::default());
+            let store = encrypted(&memory, cipher, 1);
+            let plain = page(0xAB);
+            block_on(store.write_page(5, &plain)).unwrap();
+
+            // only the lsn and type stay in the clear
+            let sealed = memory.pages.lock()[&5].clone();
+            assert_ne!(&sealed.data[..], &plain.data[..]);
+            assert_eq!(sealed.lsn(), 7);
+
+            let read = block_on(store.read_page(5)).unwrap();
+            assert_eq!(read.as_slice(), plain.as_slice());
+        }
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let memory = Arc::new(Memory::default());
+        block_on(encrypted(&memory, Cipher::XChaCha20Poly1305, 1).write_page(5, &page(0xAB))).unwrap();
+
+        let wrong = encrypted(&memory, Cipher::XChaCha20Poly1305, 2);
+        assert!(matches!(block_on(wrong.read_page(5)), Err(RetrieveError::BadChecksum)));
+
+        // a page moved to another index, or given another lsn, doesn't open either
+        let right = encrypted(&memory, Cipher::XChaCha20Poly1305, 1);
+        let sealed = memory.pages.lock()[&5].clone();
+        memory.pages.lock().insert(6, sealed.clone());
+        assert!(matches!(block_on(right.read_page(6)), Err(RetrieveError::BadChecksum)));
+        let mut relabeled = sealed;
+        relabeled.set_lsn(8);
+        memory.pages.lock().insert(5, relabeled);
+        assert!(matches!(block_on(right.read_page(5)), Err(RetrieveError::BadChecksum)));
+    }
+
+    #[test]
+    fn rotation_opens_pages_sealed_with_the_previous_key() {
+        let memory = Arc::new(Memory::default());
+        let store = encrypted(&memory, Cipher::Aes256Gcm, 1);
+        let old = page(1);
+        block_on(store.write_page(5, &old)).unwrap();
+
+        store.rotate(&[2; 32]);
+        let new = page(2);
+        block_on(store.write_page(6, &new)).unwrap();
+        assert_eq!(block_on(store.read_page(5)).unwrap().as_slice(), old.as_slice());
+        assert_eq!(block_on(store.read_page(6)).unwrap().as_slice(), new.as_slice());
+
+        // resealing moves the old page to the new key, after which the old key isn't needed
+        let (resealed, changed) = block_on(store.reseal(5)).unwrap();
+        assert!(changed);
+        block_on(memory.write_page(5, &resealed)).unwrap();
+        store.finish_rotation();
+        assert_eq!(block_on(store.read_page(5)).unwrap().as_slice(), old.as_slice());
+        assert!(matches!(block_on(encrypted(&memory, Cipher::Aes256Gcm, 1).read_page(6)), Err(RetrieveError::BadChecksum)));
+    }
+}
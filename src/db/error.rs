@@ -0,0 +1,209 @@
+//! One error type for every operation, by class of failure, for callers that would rather
+//! branch on what went wrong than on which operation failed. Each operation's own error
+//! converts into it with `?`.
+
+use std::{fmt, io};
+use std::sync::Arc;
+use thiserror::Error;
+
+use super::{BackupError, CasError, DescentError, ExportError, FormatError, ImportError, OpenError, PackedError, PageIndex, ReplicationError, RestoreError, RetrieveError, WriteError};
+#[cfg(feature = "serde")]
+use super::{KeyError, TypedError};
+
+#[derive(Error, Debug, Clone)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[source] Arc<io::Error>),
+    #[error("Database is corrupt{}: {detail}", at_page(.page))]
+    Corruption { page: Option<PageIndex>, detail: String },
+    #[error("{0}")]
+    Conflict(String),
+    #[error("Database is locked{}", super::held_by(.holder_pid))]
+    Locked { holder_pid: Option<u32> },
+    #[error("No space left on the device")]
+    Full,
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[error("Database is opened read-only")]
+    ReadOnly,
+    #[error("{0}")]
+    Incompatible(String)
+}
+
+fn at_page(page: &Option<PageIndex>) -> String {
+    match page {
+        Some(page) => format!(" at page {}", page),
+        None => String::new()
+    }
+}
+
+/// Carried by the `io::Error` a write to a read-only database fails with, so it converts to
+/// `Error::ReadOnly`
+#[derive(Debug)]
+pub(crate) struct ReadOnly;
+
+impl fmt::Display for ReadOnly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("database is opened read-only")
+    }
+}
+
+impl std::error::Error for ReadOnly {}
+
+impl From<Arc<io::Error>> for Error {
+    fn from(err: Arc<io::Error>) -> Self {
+        if err.get_ref().map_or(false, |inner| inner.is::<ReadOnly>()) { return Error::ReadOnly }
+        match err.raw_os_error() {
+            Some(libc::ENOSPC) | Some(libc::EDQUOT) => Error::Full,
+            _ => Error::Io(err)
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Arc::new(err).into()
+    }
+}
+
+impl From<DescentError> for Error {
+    fn from(err: DescentError) -> Self {
+        let page = match &err {
+            DescentError::TooDeep { trail, .. } => trail.last().copied(),
+            DescentError::Cycle { page, .. } => Some(*page)
+        };
+        Error::Corruption { page, detail: err.to_string() }
+    }
+}
+
+impl From<RetrieveError> for Error {
+    fn from(err: RetrieveError) -> Self {
+        match err {
+            RetrieveError::Io(err) => err.into(),
+            RetrieveError::Descent(err) => err.into(),
+            RetrieveError::Malformed(page) | RetrieveError::Quarantined(page) => Error::Corruption { page: Some(page), detail: err.to_string() },
+            RetrieveError::BadChecksum | RetrieveError::OutOfPages => Error::Corruption { page: None, detail: err.to_string() }
+        }
+    }
+}
+
+impl From<WriteError> for Error {
+    fn from(err: WriteError) -> Self {
+        match err {
+            WriteError::Io(err) | WriteError::Full(err) => err.into(),
+            WriteError::Retrieve(err) => err.into(),
+            WriteError::EntryTooLarge | WriteError::KeyTooLarge { .. } | WriteError::ValueTooLarge { .. }
+                | WriteError::NoMergeOperator | WriteError::NotEmpty | WriteError::Unsorted => Error::InvalidArgument(err.to_string()),
+            WriteError::WriteStall => Arc::new(io::Error::new(io::ErrorKind::WouldBlock, err.to_string())).into()
+        }
+    }
+}
+
+impl From<FormatError> for Error {
+    fn from(err: FormatError) -> Self {
+        match err {
+            FormatError::BadChecksum | FormatError::NoVersion => Error::Corruption { page: None, detail: err.to_string() },
+            _ => Error::Incompatible(err.to_string())
+        }
+    }
+}
+
+impl From<OpenError> for Error {
+    fn from(err: OpenError) -> Self {
+        match err {
+            OpenError::Io(err) => err.into(),
+            OpenError::Retrieve(err) => err.into(),
+            OpenError::Format(err) => err.into(),
+            OpenError::Locked { holder_pid } => Error::Locked { holder_pid }
+        }
+    }
+}
+
+impl From<BackupError> for Error {
+    fn from(err: BackupError) -> Self {
+        match err {
+            BackupError::Io(err) => err.into(),
+            BackupError::Retrieve(err) => err.into()
+        }
+    }
+}
+
+impl From<ExportError> for Error {
+    fn from(err: ExportError) -> Self {
+        match err {
+            ExportError::Io(err) => err.into(),
+            ExportError::Retrieve(err) => err.into(),
+            ExportError::Open(err) => err.into(),
+            ExportError::Write(err) => err.into()
+        }
+    }
+}
+
+impl From<ImportError> for Error {
+    fn from(err: ImportError) -> Self {
+        match err {
+            ImportError::Io(err) => err.into(),
+            ImportError::Write(err) => err.into(),
+            ImportError::BadMagic | ImportError::UnsupportedVersion(_) => Error::Incompatible(err.to_string()),
+            ImportError::Corrupt | ImportError::WrongCount { .. } => Error::Corruption { page: None, detail: err.to_string() }
+        }
+    }
+}
+
+impl From<ReplicationError> for Error {
+    fn from(err: ReplicationError) -> Self {
+        match err {
+            ReplicationError::Retrieve(err) => err.into(),
+            ReplicationError::Write(err) => err.into(),
+            ReplicationError::Corrupt => Error::Corruption { page: None, detail: err.to_string() },
+            ReplicationError::Unavailable { .. } | ReplicationError::OutOfOrder(_) => Error::InvalidArgument(err.to_string())
+        }
+    }
+}
+
+impl From<RestoreError> for Error {
+    fn from(err: RestoreError) -> Self {
+        match err {
+            RestoreError::Io(err) => err.into(),
+            RestoreError::Open(err) => err.into(),
+            RestoreError::BadMagic | RestoreError::UnsupportedVersion(_) => Error::Incompatible(err.to_string()),
+            RestoreError::CorruptPage(page) => Error::Corruption { page: Some(page), detail: err.to_string() },
+            RestoreError::CorruptRoot => Error::Corruption { page: None, detail: err.to_string() },
+            RestoreError::BrokenChain { .. } | RestoreError::NothingToRestore => Error::InvalidArgument(err.to_string())
+        }
+    }
+}
+
+impl From<CasError> for Error {
+    fn from(err: CasError) -> Self {
+        match err {
+            CasError::Mismatch { .. } => Error::Conflict(err.to_string()),
+            CasError::Write(err) => err.into()
+        }
+    }
+}
+
+impl From<PackedError> for Error {
+    fn from(err: PackedError) -> Self {
+        Error::InvalidArgument(err.to_string())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<KeyError> for Error {
+    fn from(err: KeyError) -> Self {
+        Error::InvalidArgument(err.to_string())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<TypedError> for Error {
+    fn from(err: TypedError) -> Self {
+        match err {
+            TypedError::Key(err) => err.into(),
+            TypedError::Value(_) => Error::InvalidArgument(err.to_string()),
+            TypedError::Retrieve(err) => err.into(),
+            TypedError::Write(err) => err.into()
+        }
+    }
+}
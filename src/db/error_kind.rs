@@ -0,0 +1,400 @@
+//! Classifying errors, so embedders can decide whether to retry or raise an alarm without
+//! matching on every variant or on messages. The codes are stable across releases.
+
+use std::io;
+
+#[cfg(feature = "serde")]
+use super::{KeyError, TypedError};
+use super::{BackupError, CasError, Error, DescentError, ExportError, FormatError, ImportError, OpenError, PackedError, ReplicationError, RestoreError, RetrieveError, WriteError};
+
+/// What went wrong, broadly
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// May succeed if tried again, such as a timed out read or a lock held by another process
+    Transient,
+    /// Any other I/O failure
+    Io,
+    /// The database's pages aren't what was written
+    Corruption,
+    /// The database or backup was written by an incompatible build, or needs other options
+    Incompatible,
+    /// The request itself can't succeed
+    InvalidInput,
+    /// The data isn't in the state the request expected, so it should be reread before retrying
+    Conflict
+}
+
+impl ErrorKind {
+    pub fn is_retryable(&self) -> bool {
+        *self == ErrorKind::Transient
+    }
+
+    pub fn is_corruption(&self) -> bool {
+        *self == ErrorKind::Corruption
+    }
+}
+
+fn io_kind(err: &io::Error) -> ErrorKind {
+    match err.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ErrorKind::Transient,
+        io::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
+        _ => ErrorKind::Io
+    }
+}
+
+/// Adds the helpers every error shares, given its `kind` and `code`
+macro_rules! classify {
+    ($error:ty) => {
+        impl $error {
+            /// Whether the operation may succeed if tried again
+            pub fn is_retryable(&self) -> bool {
+                self.kind().is_retryable()
+            }
+
+            /// Whether the error means the database is corrupt
+            pub fn is_corruption(&self) -> bool {
+                self.kind().is_corruption()
+            }
+        }
+    };
+}
+
+impl RetrieveError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RetrieveError::Io(err) => io_kind(err),
+            RetrieveError::BadChecksum | RetrieveError::OutOfPages | RetrieveError::Malformed(_)
+                | RetrieveError::Quarantined(_) => ErrorKind::Corruption,
+            RetrieveError::Descent(err) => err.kind()
+        }
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            RetrieveError::Io(_) => "io",
+            RetrieveError::BadChecksum => "bad_checksum",
+            RetrieveError::OutOfPages => "out_of_pages",
+            RetrieveError::Malformed(_) => "malformed_page",
+            RetrieveError::Quarantined(_) => "quarantined_page",
+            RetrieveError::Descent(err) => err.code()
+        }
+    }
+}
+classify!(RetrieveError);
+
+impl DescentError {
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::Corruption
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            DescentError::TooDeep { .. } => "descent_too_deep",
+            DescentError::Cycle { .. } => "descent_cycle"
+        }
+    }
+}
+classify!(DescentError);
+
+impl WriteError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            WriteError::Io(err) | WriteError::Full(err) => io_kind(err),
+            WriteError::Retrieve(err) => err.kind(),
+            WriteError::EntryTooLarge | WriteError::KeyTooLarge { .. } | WriteError::ValueTooLarge { .. }
+                | WriteError::NoMergeOperator | WriteError::NotEmpty | WriteError::Unsorted => ErrorKind::InvalidInput,
+            WriteError::WriteStall => ErrorKind::Transient
+        }
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            WriteError::Io(_) => "io",
+            WriteError::Retrieve(err) => err.code(),
+            WriteError::EntryTooLarge => "entry_too_large",
+            WriteError::KeyTooLarge { .. } => "key_too_large",
+            WriteError::ValueTooLarge { .. } => "value_too_large",
+            WriteError::NoMergeOperator => "no_merge_operator",
+            WriteError::NotEmpty => "not_empty",
+            WriteError::Unsorted => "unsorted_keys",
+            WriteError::WriteStall => "write_stall",
+            WriteError::Full(_) => "full"
+        }
+    }
+}
+classify!(WriteError);
+
+impl FormatError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            FormatError::BadChecksum | FormatError::NoVersion => ErrorKind::Corruption,
+            FormatError::BadMagic | FormatError::UnsupportedVersion { .. } | FormatError::NeedsUpgrade { .. }
+                | FormatError::NoMigration { .. } | FormatError::PageSize { .. }
+                | FormatError::Endianness | FormatError::UnsupportedFeatures(_) | FormatError::Encrypted
+                | FormatError::NotEncrypted | FormatError::WrongKey | FormatError::Empty => ErrorKind::Incompatible
+        }
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            FormatError::BadMagic => "bad_magic",
+            FormatError::BadChecksum => "bad_header_checksum",
+            FormatError::UnsupportedVersion { .. } => "unsupported_version",
+            FormatError::NeedsUpgrade { .. } => "needs_upgrade",
+            FormatError::NoMigration { .. } => "no_migration",
+            FormatError::PageSize { .. } => "page_size_mismatch",
+            FormatError::Endianness => "endianness_mismatch",
+            FormatError::UnsupportedFeatures(_) => "unsupported_features",
+            FormatError::Encrypted => "encrypted",
+            FormatError::NotEncrypted => "not_encrypted",
+            FormatError::WrongKey => "wrong_key",
+            FormatError::NoVersion => "no_version",
+            FormatError::Empty => "empty"
+        }
+    }
+}
+classify!(FormatError);
+
+impl OpenError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            OpenError::Io(err) => io_kind(err),
+            OpenError::Retrieve(err) => err.kind(),
+            OpenError::Format(err) => err.kind(),
+            OpenError::Locked { .. } => ErrorKind::Transient
+        }
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            OpenError::Io(_) => "io",
+            OpenError::Retrieve(err) => err.code(),
+            OpenError::Format(err) => err.code(),
+            OpenError::Locked { .. } => "locked"
+        }
+    }
+}
+classify!(OpenError);
+
+impl BackupError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            BackupError::Io(err) => io_kind(err),
+            BackupError::Retrieve(err) => err.kind()
+        }
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            BackupError::Io(_) => "io",
+            BackupError::Retrieve(err) => err.code()
+        }
+    }
+}
+classify!(BackupError);
+
+impl ExportError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ExportError::Io(err) => io_kind(err),
+            ExportError::Retrieve(err) => err.kind(),
+            ExportError::Open(err) => err.kind(),
+            ExportError::Write(err) => err.kind()
+        }
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ExportError::Io(_) => "io",
+            ExportError::Retrieve(err) => err.code(),
+            ExportError::Open(err) => err.code(),
+            ExportError::Write(err) => err.code()
+        }
+    }
+}
+classify!(ExportError);
+
+impl ImportError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ImportError::Io(err) => io_kind(err),
+            ImportError::Write(err) => err.kind(),
+            ImportError::BadMagic | ImportError::UnsupportedVersion(_) => ErrorKind::Incompatible,
+            ImportError::Corrupt | ImportError::WrongCount { .. } => ErrorKind::Corruption
+        }
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ImportError::Io(_) => "io",
+            ImportError::Write(err) => err.code(),
+            ImportError::BadMagic => "bad_dump_magic",
+            ImportError::UnsupportedVersion(_) => "unsupported_dump_version",
+            ImportError::Corrupt => "corrupt_dump",
+            ImportError::WrongCount { .. } => "truncated_dump"
+        }
+    }
+}
+classify!(ImportError);
+
+impl ReplicationError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ReplicationError::Retrieve(err) => err.kind(),
+            ReplicationError::Write(err) => err.kind(),
+            ReplicationError::Corrupt => ErrorKind::Corruption,
+            ReplicationError::Unavailable { .. } | ReplicationError::OutOfOrder(_) => ErrorKind::InvalidInput
+        }
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ReplicationError::Retrieve(err) => err.code(),
+            ReplicationError::Write(err) => err.code(),
+            ReplicationError::Unavailable { .. } => "replication_unavailable",
+            ReplicationError::Corrupt => "corrupt_replication_record",
+            ReplicationError::OutOfOrder(_) => "replication_out_of_order"
+        }
+    }
+}
+classify!(ReplicationError);
+
+impl RestoreError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RestoreError::Io(err) => io_kind(err),
+            RestoreError::Open(err) => err.kind(),
+            RestoreError::BadMagic | RestoreError::UnsupportedVersion(_) => ErrorKind::Incompatible,
+            RestoreError::CorruptPage(_) | RestoreError::CorruptRoot => ErrorKind::Corruption,
+            RestoreError::BrokenChain { .. } | RestoreError::NothingToRestore => ErrorKind::InvalidInput
+        }
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            RestoreError::Io(_) => "io",
+            RestoreError::Open(err) => err.code(),
+            RestoreError::BadMagic => "bad_backup_magic",
+            RestoreError::UnsupportedVersion(_) => "unsupported_backup_version",
+            RestoreError::BrokenChain { .. } => "broken_backup_chain",
+            RestoreError::CorruptPage(_) => "corrupt_backup_page",
+            RestoreError::CorruptRoot => "corrupt_backup_root",
+            RestoreError::NothingToRestore => "nothing_to_restore"
+        }
+    }
+}
+classify!(RestoreError);
+
+impl CasError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            CasError::Mismatch { .. } => ErrorKind::Conflict,
+            CasError::Write(err) => err.kind()
+        }
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            CasError::Mismatch { .. } => "cas_mismatch",
+            CasError::Write(err) => err.code()
+        }
+    }
+}
+classify!(CasError);
+
+impl PackedError {
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::InvalidInput
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            PackedError::Empty => "empty_image",
+            PackedError::PartialPage(_) => "partial_page"
+        }
+    }
+}
+classify!(PackedError);
+
+#[cfg(feature = "serde")]
+impl KeyError {
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::InvalidInput
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            KeyError::Custom(_) => "key_custom",
+            KeyError::Unsupported(_) => "key_unsupported",
+            KeyError::Malformed => "key_malformed"
+        }
+    }
+}
+#[cfg(feature = "serde")]
+classify!(KeyError);
+
+#[cfg(feature = "serde")]
+impl TypedError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            TypedError::Key(err) => err.kind(),
+            TypedError::Value(_) => ErrorKind::InvalidInput,
+            TypedError::Retrieve(err) => err.kind(),
+            TypedError::Write(err) => err.kind()
+        }
+    }
+
+    /// A stable identifier for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            TypedError::Key(err) => err.code(),
+            TypedError::Value(_) => "value_encoding",
+            TypedError::Retrieve(err) => err.code(),
+            TypedError::Write(err) => err.code()
+        }
+    }
+}
+#[cfg(feature = "serde")]
+classify!(TypedError);
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io(err) => io_kind(err),
+            Error::Corruption { .. } => ErrorKind::Corruption,
+            Error::Conflict(_) => ErrorKind::Conflict,
+            Error::Locked { .. } => ErrorKind::Transient,
+            Error::Full => ErrorKind::Io,
+            Error::InvalidArgument(_) | Error::ReadOnly => ErrorKind::InvalidInput,
+            Error::Incompatible(_) => ErrorKind::Incompatible
+        }
+    }
+
+    /// A stable identifier for the class of error
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Io(_) => "io",
+            Error::Corruption { .. } => "corruption",
+            Error::Conflict(_) => "conflict",
+            Error::Locked { .. } => "locked",
+            Error::Full => "full",
+            Error::InvalidArgument(_) => "invalid_argument",
+            Error::ReadOnly => "read_only",
+            Error::Incompatible(_) => "incompatible"
+        }
+    }
+}
+classify!(Error);
@@ -0,0 +1,213 @@
+use std::sync::Arc;
+use lru::LruCache;
+
+use super::{PageIndex, Observer};
+use super::buffer_pool::Chunk;
+
+/// How the page cache picks what to evict
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Plain least-recently-used. A large scan will flush the entire cache.
+    Lru,
+    /// Segmented LRU: chunks enter a probationary segment and are only promoted to the
+    /// protected segment when hit again, so a one-off scan can only churn the probationary segment.
+    Slru,
+    /// Segmented LRU behind a TinyLFU admission filter: when the cache is full, a new chunk is
+    /// only admitted if it has been requested more often than the chunk it would evict.
+    TinyLfu
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy::Slru
+    }
+}
+
+/// Share of the cache reserved for the protected segment, in percent
+const PROTECTED_PERCENT: usize = 80;
+
+/// Counters saturate at this value, which is plenty to compare hot and cold chunks
+const MAX_FREQUENCY: u8 = 15;
+
+const SKETCH_SEEDS: [u64; 4] = [0xc3a5c85c97cb3127, 0xb492b66fbe98f273, 0x9ae16a3b2f90404f, 0xcbf29ce484222325];
+
+/// A count-min sketch approximating how often each chunk was requested recently.
+///
+/// Counters are halved periodically so that old popularity fades.
+struct FrequencySketch {
+    counters: Vec<u8>,
+    mask: usize,
+    additions: usize,
+    sample_size: usize
+}
+
+impl FrequencySketch {
+    fn new(expected_entries: usize) -> FrequencySketch {
+        let width = expected_entries.max(16).next_power_of_two();
+
+        FrequencySketch {
+            counters: vec![0; width * SKETCH_SEEDS.len()],
+            mask: width - 1,
+            additions: 0,
+            sample_size: width * 10
+        }
+    }
+
+    fn slot(&self, idx: PageIndex, row: usize) -> usize {
+        let hash = (idx ^ SKETCH_SEEDS[row]).wrapping_mul(0x9e3779b97f4a7c15);
+        row * (self.mask + 1) + ((hash >> 32) as usize & self.mask)
+    }
+
+    fn increment(&mut self, idx: PageIndex) {
+        for row in 0..SKETCH_SEEDS.len() {
+            let slot = self.slot(idx, row);
+            let counter = &mut self.counters[slot];
+            if *counter < MAX_FREQUENCY { *counter += 1; }
+        }
+
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            for counter in self.counters.iter_mut() { *counter /= 2; }
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency(&self, idx: PageIndex) -> u8 {
+        (0..SKETCH_SEEDS.len()).map(|row| self.counters[self.slot(idx, row)]).min().unwrap_or(0)
+    }
+}
+
+/// A cache of chunks weighted by their length, evicting according to an `EvictionPolicy`.
+///
+/// Under `EvictionPolicy::Lru` only the probationary segment is used.
+pub(super) struct WeightedCache {
+    policy: EvictionPolicy,
+    probation: LruCache<PageIndex, Chunk>,
+    protected: LruCache<PageIndex, Chunk>,
+    probation_bytes: usize,
+    protected_bytes: usize,
+    max_bytes: usize,
+    sketch: Option<FrequencySketch>,
+    observer: Arc<dyn Observer>
+}
+
+impl WeightedCache {
+    pub(super) fn new(max_bytes: usize, policy: EvictionPolicy, expected_entries: usize, observer: Arc<dyn Observer>) -> WeightedCache {
+        WeightedCache {
+            policy,
+            probation: LruCache::unbounded(),
+            protected: LruCache::unbounded(),
+            probation_bytes: 0,
+            protected_bytes: 0,
+            max_bytes,
+            sketch: match policy {
+                EvictionPolicy::TinyLfu => Some(FrequencySketch::new(expected_entries)),
+                _ => None
+            },
+            observer
+        }
+    }
+
+    pub(super) fn used_bytes(&self) -> usize {
+        self.probation_bytes + self.protected_bytes
+    }
+
+    pub(super) fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.probation.len() + self.protected.len()
+    }
+
+    pub(super) fn get(&mut self, idx: PageIndex) -> Option<Chunk> {
+        if let Some(sketch) = &mut self.sketch { sketch.increment(idx); }
+
+        if self.policy == EvictionPolicy::Lru {
+            return self.probation.get(&idx).cloned();
+        }
+
+        if let Some(data) = self.protected.get(&idx) { return Some(data.clone()) }
+
+        // a second hit promotes a probationary chunk
+        let data = self.probation.pop(&idx)?;
+        self.probation_bytes -= data.len();
+        self.protected_bytes += data.len();
+        self.protected.put(idx, data.clone());
+
+        self.demote_overflow();
+
+        Some(data)
+    }
+
+    pub(super) fn put(&mut self, idx: PageIndex, data: Chunk) {
+        // caching a chunk larger than the whole cache would just flush everything else
+        if data.len() > self.max_bytes { return }
+
+        self.remove(idx);
+
+        if let Some(sketch) = &self.sketch {
+            if self.used_bytes() + data.len() > self.max_bytes {
+                if let Some((victim, _)) = self.probation.peek_lru() {
+                    if sketch.frequency(idx) <= sketch.frequency(*victim) { return }
+                }
+            }
+        }
+
+        self.probation_bytes += data.len();
+        self.probation.put(idx, data);
+
+        self.evict_overflow();
+    }
+
+    pub(super) fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+        self.evict_overflow();
+        self.demote_overflow();
+    }
+
+    fn evict_overflow(&mut self) {
+        while self.used_bytes() > self.max_bytes {
+            let evicted = match self.probation.pop_lru() {
+                Some(evicted) => {
+                    self.probation_bytes -= evicted.1.len();
+                    evicted
+                },
+                None => match self.protected.pop_lru() {
+                    Some(evicted) => {
+                        self.protected_bytes -= evicted.1.len();
+                        evicted
+                    },
+                    None => break
+                }
+            };
+
+            self.observer.on_eviction(evicted.0, evicted.1.len());
+        }
+    }
+
+    pub(super) fn remove(&mut self, idx: PageIndex) {
+        if let Some(removed) = self.protected.pop(&idx) {
+            self.protected_bytes -= removed.len();
+        }
+        if let Some(removed) = self.probation.pop(&idx) {
+            self.probation_bytes -= removed.len();
+        }
+    }
+
+    /// Move chunks out of an over-full protected segment back into probation
+    fn demote_overflow(&mut self) {
+        let max_protected = self.max_bytes / 100 * PROTECTED_PERCENT;
+
+        while self.protected_bytes > max_protected {
+            match self.protected.pop_lru() {
+                Some((idx, data)) => {
+                    self.protected_bytes -= data.len();
+                    self.probation_bytes += data.len();
+                    self.probation.put(idx, data);
+                },
+                None => break
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! A logical dump of a database's entries, independent of the page format, so data survives
+//! moving between crate versions whose on-disk formats differ.
+
+use std::{io, sync::Arc};
+use std::convert::TryInto;
+use std::ops::Bound;
+use bytes::Bytes;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::stream::TryStreamExt;
+use thiserror::Error;
+
+use super::{DB, OpenError, RetrieveError, TransactionIdx, WriteError};
+use super::compression::{self, Compression, Dictionaries, NONE};
+use super::ttl;
+
+// A dump is:
+//
+// - a header: `MAGIC`, `DUMP_VERSION` (u32), then the transaction dumped (u64)
+// - blocks of records, each `[codec: u8][len: u32][data]`, where data is the records as written
+//   by `Compression::compress` with the given codec, or as they are if the codec is `NONE`
+// - an empty block, then the number of records in the dump (u64)
+//
+// A record is `[key len: u32][key][value len: u32][value][expiry: u64]`, with the expiry in
+// Unix milliseconds, or `NO_EXPIRY`. Records are in key order. Integers are little endian.
+//
+// Blocks are compressed without dictionaries, so a dump can be read without the database
+// it came from. Later versions of the format must keep reading older dumps.
+
+pub(crate) const MAGIC: [u8; 8] = *b"BSSDBDMP";
+pub(crate) const DUMP_VERSION: u32 = 1;
+const NO_EXPIRY: u64 = 0;
+
+/// Records are gathered into blocks of about this many bytes before compression
+const BLOCK_LEN: usize = 64 * 1024;
+
+/// Records imported per transaction
+const IMPORT_BATCH: usize = 4096;
+
+#[derive(Error, Debug, Clone)]
+pub enum ExportError {
+    #[error("{0}")]
+    Io(#[source] #[from] Arc<io::Error>),
+    #[error("{0}")]
+    Retrieve(#[source] #[from] RetrieveError),
+    /// Creating the database a snapshot is exported to failed
+    #[error("{0}")]
+    Open(#[source] #[from] OpenError),
+    /// Writing the database a snapshot is exported to failed
+    #[error("{0}")]
+    Write(#[source] #[from] WriteError)
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum ImportError {
+    #[error("{0}")]
+    Io(#[source] #[from] Arc<io::Error>),
+    #[error("{0}")]
+    Write(#[source] #[from] WriteError),
+    #[error("Not a dump")]
+    BadMagic,
+    #[error("Dump version {0} is not supported")]
+    UnsupportedVersion(u32),
+    #[error("Dump is corrupt, or compressed with a codec that isn't compiled in")]
+    Corrupt,
+    #[error("Dump ends with {found} records, but records {expected}")]
+    WrongCount { expected: u64, found: u64 }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(err: io::Error) -> Self {
+        ExportError::Io(Arc::new(err))
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(err: io::Error) -> Self {
+        ImportError::Io(Arc::new(err))
+    }
+}
+
+impl From<RetrieveError> for ImportError {
+    fn from(err: RetrieveError) -> Self {
+        ImportError::Write(err.into())
+    }
+}
+
+async fn write_block<W: AsyncWrite + Unpin>(writer: &mut W, block: &[u8], compression: Compression) -> Result<(), ExportError> {
+    let (codec, data) = match compression.compress(block, &Dictionaries::default()) {
+        Some((codec, compressed)) => (codec, compressed),
+        None => (NONE, block.to_vec())
+    };
+    writer.write_all(&[codec]).await?;
+    writer.write_all(&(data.len() as u32).to_le_bytes()).await?;
+    writer.write_all(&data).await?;
+    Ok(())
+}
+
+/// Read the next block, or `None` at the empty block that ends the records
+async fn read_block<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>, ImportError> {
+    let mut head = [0; 5];
+    reader.read_exact(&mut head).await?;
+    let len = u32::from_le_bytes(head[1..5].try_into().unwrap()) as usize;
+    if len == 0 { return Ok(None) }
+
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data).await?;
+    match head[0] {
+        NONE => Ok(Some(data)),
+        codec => compression::decompress(codec, &data, u32::MAX as usize, &Dictionaries::default()).map(Some).ok_or(ImportError::Corrupt)
+    }
+}
+
+fn take<'a>(block: &mut &'a [u8], len: usize) -> Result<&'a [u8], ImportError> {
+    if block.len() < len { return Err(ImportError::Corrupt) }
+    let (taken, rest) = block.split_at(len);
+    *block = rest;
+    Ok(taken)
+}
+
+fn read_record(block: &mut &[u8]) -> Result<(Bytes, Bytes, Option<u64>), ImportError> {
+    let key_len = u32::from_le_bytes(take(block, 4)?.try_into().unwrap()) as usize;
+    let key = Bytes::copy_from_slice(take(block, key_len)?);
+    let value_len = u32::from_le_bytes(take(block, 4)?.try_into().unwrap()) as usize;
+    let value = Bytes::copy_from_slice(take(block, value_len)?);
+    let expires = match u64::from_le_bytes(take(block, 8)?.try_into().unwrap()) {
+        NO_EXPIRY => None,
+        expires => Some(expires)
+    };
+    Ok((key, value, expires))
+}
+
+impl DB {
+    /// Write every live entry of the latest version to `writer` as a logical dump, compressed
+    /// with the database's compression setting. Expired entries are left out, and expiring ones
+    /// keep their expiry. Unlike a backup, a dump can be imported by later versions of the crate
+    /// whatever their on-disk format. Returns the transaction dumped.
+    pub async fn export<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<TransactionIdx, ExportError> {
+        let (version, buffered) = self.buffered_range(Bound::Unbounded, Bound::Unbounded);
+        let dictionaries = self.dictionaries();
+        let (compression, max_depth) = {
+            let options = self.options.lock();
+            (options.compression, options.max_tree_depth)
+        };
+
+        writer.write_all(&MAGIC).await?;
+        writer.write_all(&DUMP_VERSION.to_le_bytes()).await?;
+        writer.write_all(&version.tx.to_le_bytes()).await?;
+
+        let mut records = 0u64;
+        let mut block = Vec::with_capacity(BLOCK_LEN);
+        let mut entries = self.merged_entries(version.tree_root, Bound::Unbounded, Bound::Unbounded, buffered);
+        while let Some((key, value)) = entries.try_next().await? {
+            if self.is_expired(version.expiries, &key).await? { continue }
+            let value = value.read(&self.cache, &dictionaries).await?;
+            let expires = ttl::expiry(&self.cache, version.expiries, &key, max_depth).await?;
+
+            block.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            block.extend_from_slice(&key);
+            block.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            block.extend_from_slice(&value);
+            block.extend_from_slice(&expires.unwrap_or(NO_EXPIRY).to_le_bytes());
+            records += 1;
+
+            if block.len() >= BLOCK_LEN {
+                write_block(writer, &block, compression).await?;
+                block.clear();
+            }
+        }
+        if !block.is_empty() { write_block(writer, &block, compression).await? }
+
+        writer.write_all(&[NONE]).await?;
+        writer.write_all(&0u32.to_le_bytes()).await?;
+        writer.write_all(&records.to_le_bytes()).await?;
+        writer.flush().await?;
+
+        Ok(version.tx)
+    }
+
+    /// Load a dump written by `export` into this database, which must be empty. Records are
+    /// committed in batches as they're read, so a dump that turns out to be truncated or corrupt
+    /// leaves the records before the damage imported. Returns the number of records imported.
+    pub async fn import<R: AsyncRead + Unpin>(&self, reader: &mut R) -> Result<u64, ImportError> {
+        let mut header = [0; 20];
+        reader.read_exact(&mut header).await?;
+        if header[0..8] != MAGIC { return Err(ImportError::BadMagic) }
+        let dump_version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if dump_version != DUMP_VERSION { return Err(ImportError::UnsupportedVersion(dump_version)) }
+
+        let mut txn = self.write().await?;
+        if txn.version.tree_root.is_some() || txn.version.journal.is_some() { return Err(WriteError::NotEmpty.into()) }
+
+        let mut imported = 0u64;
+        let mut pending = 0;
+        while let Some(block) = read_block(reader).await? {
+            let mut block = &block[..];
+            while !block.is_empty() {
+                let (key, value, expires) = read_record(&mut block)?;
+                txn.put(key.clone(), value)?;
+                if let Some(expires) = expires { txn.ttls.insert(key, expires); }
+                imported += 1;
+
+                pending += 1;
+                if pending == IMPORT_BATCH {
+                    txn.commit().await?;
+                    txn = self.write().await?;
+                    pending = 0;
+                }
+            }
+        }
+        txn.commit().await?;
+
+        let mut count = [0; 8];
+        reader.read_exact(&mut count).await?;
+        let expected = u64::from_le_bytes(count);
+        if expected != imported { return Err(ImportError::WrongCount { expected, found: imported }) }
+
+        Ok(imported)
+    }
+}
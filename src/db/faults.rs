@@ -0,0 +1,238 @@
+//! Fault injection for crash-consistency tests, enabled by the `fault-injection` feature.
+//!
+//! A `Faults` handle given to `Options::faults` makes the file store fail or tear chosen page
+//! writes, and lose power or crash at chosen points of a write or sync. Once power is lost,
+//! every write since the last sync is rolled back on disk, so the file holds only what a real
+//! power loss would have guaranteed, and every later operation fails. A test then drops the
+//! database and reopens the file without faults to check it recovered.
+//!
+//! Writes are serialized while faults are injected, so a power loss never races a write in
+//! flight. Rolling writes back reads the file through the OS page cache, so open the database
+//! with `direct_io(false)`.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use futures::lock::{Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard};
+use parking_lot::Mutex;
+
+use super::page::PAGE_SIZE;
+
+/// A point in the file store's I/O where power can be lost
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FaultPoint {
+    /// A page write was submitted but hasn't completed
+    WriteInFlight,
+    /// A page write completed but hasn't been synced
+    WriteCompleted,
+    /// About to sync: during a commit, first the pages it wrote, then its version header
+    BeforeSync,
+    /// Just synced
+    AfterSync
+}
+
+/// A fault to inject. Writes and the hits of each point are counted from zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The nth page write fails with an I/O error without writing anything
+    FailWrite(u64),
+    /// The nth page write writes only its first `len` bytes, then power is lost, keeping them
+    TruncateWrite { nth: u64, len: usize },
+    /// Power is lost the nth time `point` is reached, losing every write since the last sync
+    PowerLoss { point: FaultPoint, nth: u64 },
+    /// The process crashes the nth time `point` is reached. Completed writes survive in the OS
+    /// page cache; a write in flight is lost.
+    Crash { point: FaultPoint, nth: u64 }
+}
+
+/// What an injected fault does to a write
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Injected {
+    Fail,
+    Torn(usize),
+    PoweredOff
+}
+
+impl Injected {
+    /// How much of the page to write
+    pub fn len(self) -> usize {
+        match self {
+            Injected::Torn(len) => len.min(PAGE_SIZE),
+            Injected::Fail | Injected::PoweredOff => 0
+        }
+    }
+}
+
+/// What stopping loses
+enum Lost {
+    Nothing,
+    /// The last write, which was in flight
+    LastWrite,
+    Unsynced
+}
+
+/// What a write overwrote, to roll it back
+struct Undo {
+    pos: u64,
+    old: Vec<u8>,
+    len_before: u64
+}
+
+#[derive(Default)]
+struct State {
+    planned: Vec<Fault>,
+    writes: u64,
+    hits: HashMap<FaultPoint, u64>,
+    powered_off: bool,
+    /// Writes since the last sync, oldest first
+    unsynced: Vec<Undo>,
+    /// The file's length as of the last sync, once known
+    synced_len: Option<u64>
+}
+
+/// Faults to inject into a file store, shared with the test that planned them
+#[derive(Default)]
+pub struct Faults {
+    state: Mutex<State>,
+    serial: AsyncMutex<()>
+}
+
+fn power_lost() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "simulated power loss")
+}
+
+impl Faults {
+    pub fn new() -> Arc<Faults> {
+        Arc::new(Faults::default())
+    }
+
+    pub fn inject(&self, fault: Fault) {
+        self.state.lock().planned.push(fault);
+    }
+
+    /// Whether power has been lost, after which every operation fails
+    pub fn powered_off(&self) -> bool {
+        self.state.lock().powered_off
+    }
+
+    /// Page writes so far, including failed ones. A run without faults shows how many there are to fail.
+    pub fn writes(&self) -> u64 {
+        self.state.lock().writes
+    }
+
+    /// How many times `point` has been reached
+    pub fn hits(&self, point: FaultPoint) -> u64 {
+        self.state.lock().hits.get(&point).copied().unwrap_or(0)
+    }
+
+    /// Hold while writing or syncing
+    pub(crate) async fn serialize(&self) -> AsyncMutexGuard<'_, ()> {
+        self.serial.lock().await
+    }
+
+    /// Fail if power has been lost
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.state.lock().powered_off { Err(power_lost()) } else { Ok(()) }
+    }
+
+    /// Count a write of a page at `pos`, saving what it overwrites, and return the fault to
+    /// inject into it if there is one
+    pub(crate) fn before_write(&self, file: &File, pos: u64) -> io::Result<Option<Injected>> {
+        let mut state = self.state.lock();
+        if state.powered_off { return Ok(Some(Injected::PoweredOff)) }
+
+        let nth = state.writes;
+        state.writes += 1;
+        let injected = state.planned.iter().find_map(|fault| match *fault {
+            Fault::FailWrite(fail) if fail == nth => Some(Injected::Fail),
+            Fault::TruncateWrite { nth: torn, len } if torn == nth => Some(Injected::Torn(len)),
+            _ => None
+        });
+        if let Some(Injected::Fail) = injected { return Ok(injected) }
+
+        let len_before = file.metadata()?.len();
+        if state.synced_len.is_none() { state.synced_len = Some(len_before) }
+        let mut old = vec![0; PAGE_SIZE.min(len_before.saturating_sub(pos) as usize)];
+        file.read_exact_at(&mut old, pos)?;
+        state.unsynced.push(Undo { pos, old, len_before });
+
+        Ok(injected)
+    }
+
+    /// The error for a write the fault was injected into, after writing `written`
+    pub(crate) fn injected(&self, file: &File, pos: u64, written: &[u8], injected: Injected) -> io::Error {
+        match injected {
+            Injected::Fail => io::Error::new(io::ErrorKind::Other, "injected write failure"),
+            Injected::PoweredOff => power_lost(),
+            Injected::Torn(_) => {
+                let mut state = self.state.lock();
+                match power_off(&mut state, file, Lost::Unsynced) {
+                    // the torn prefix was on its way to the platter when the power went
+                    Ok(()) => file.write_all_at(written, pos).err().unwrap_or_else(power_lost),
+                    Err(err) => err
+                }
+            }
+        }
+    }
+
+    /// Reach `point`, failing if power is lost there or was already
+    pub(crate) fn reach(&self, file: &File, point: FaultPoint) -> io::Result<()> {
+        let mut state = self.state.lock();
+        if state.powered_off { return Err(power_lost()) }
+
+        let hits = state.hits.entry(point).or_insert(0);
+        let nth = *hits;
+        *hits += 1;
+
+        let lost = state.planned.iter().find_map(|fault| match *fault {
+            Fault::PowerLoss { point: at, nth: n } if at == point && n == nth => Some(Lost::Unsynced),
+            Fault::Crash { point: at, nth: n } if at == point && n == nth => {
+                Some(if point == FaultPoint::WriteInFlight { Lost::LastWrite } else { Lost::Nothing })
+            },
+            _ => None
+        });
+        match lost {
+            Some(lost) => power_off(&mut state, file, lost).and(Err(power_lost())),
+            None => Ok(())
+        }
+    }
+
+    /// Record a sync, after which the writes before it survive power loss
+    pub(crate) fn synced(&self, file: &File) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.unsynced.clear();
+        state.synced_len = Some(file.metadata()?.len());
+        Ok(())
+    }
+}
+
+/// Stop every later operation, rolling back what stopping loses
+fn power_off(state: &mut State, file: &File, lost: Lost) -> io::Result<()> {
+    state.powered_off = true;
+
+    match lost {
+        Lost::Nothing => {},
+        Lost::LastWrite => if let Some(undo) = state.unsynced.pop() {
+            file.write_all_at(&undo.old, undo.pos)?;
+            let len = file.metadata()?.len();
+            if len == undo.pos + PAGE_SIZE as u64 && undo.len_before < len {
+                file.set_len(undo.len_before.max(undo.pos + undo.old.len() as u64))?;
+            } else {
+                // the page was past the end of the file: it was a hole, which reads as zeros
+                let zeros = vec![0; PAGE_SIZE - undo.old.len()];
+                file.write_all_at(&zeros, undo.pos + undo.old.len() as u64)?;
+            }
+        },
+        Lost::Unsynced => {
+            for undo in state.unsynced.drain(..).rev() {
+                file.write_all_at(&undo.old, undo.pos)?;
+            }
+            if let Some(len) = state.synced_len {
+                file.set_len(len)?;
+            }
+        }
+    }
+    file.sync_all()
+}
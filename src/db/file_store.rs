@@ -4,26 +4,142 @@ use std::{
     path::Path,
     fs::{OpenOptions, File},
     os::unix::{
-        fs::OpenOptionsExt,
+        fs::{OpenOptionsExt, MetadataExt},
         io::AsRawFd
     },
-    sync::{Arc}
+    sync::{Arc, atomic::{AtomicU64, Ordering}},
+    thread,
+    time::{Duration, Instant}
 };
+use futures::channel::oneshot;
+use futures::future::{self, BoxFuture, FutureExt};
+use parking_lot::Mutex;
 use thiserror::Error;
 
 #[cfg(target_os = "linux")]
 use rio::Rio;
 
 #[cfg(target_family = "unix")]
-use libc::{LOCK_NB, LOCK_EX};
+use libc::{LOCK_NB, LOCK_EX, LOCK_SH};
 
 use super::page::{PageContent, PAGE_SIZE, PageIndex};
+use super::{Options, OpenError, PageStore, DescentError};
+#[cfg(feature = "fault-injection")]
+use super::faults::{Faults, FaultPoint, Injected};
+
+pub(crate) const DEFAULT_GROWTH_EXTENT: u64 = 64 * 1024 * 1024;
+
+/// `reserved` once `fallocate` turns out to be unsupported, so growth is left to writes
+const UNRESERVED: u64 = u64::MAX;
 
 pub struct FileStore {
     file: File,
+    /// Bytes of the file with disk space reserved, which writes below need not allocate
+    reserved: AtomicU64,
+    growth_extent: Option<u64>,
+    /// Bytes the file may not grow past
+    max_size: Option<u64>,
+    /// Held while reserving, so concurrent writes past `reserved` reserve once
+    growing: Mutex<()>,
 
     #[cfg(target_os = "linux")]
     ring: Rio,
+    counters: RingCounters,
+    #[cfg(feature = "fault-injection")]
+    faults: Option<Arc<Faults>>
+}
+
+/// Submissions to the io_uring ring, as seen by the engine. rio doesn't expose the kernel's
+/// queue depths or CQE overflows, so these count what was submitted and what completed:
+/// a growing `in_flight` means the kernel is slow to complete, while a stall with few
+/// entries in flight is in the engine's own queues.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreMetrics {
+    pub reads_submitted: u64,
+    pub writes_submitted: u64,
+    pub syncs_submitted: u64,
+    pub completed: u64,
+    /// Submitted but not yet completed
+    pub in_flight: u64,
+    /// Whole pages read and written
+    pub pages_read: u64,
+    pub pages_written: u64,
+    pub bytes_written: u64
+}
+
+impl StoreMetrics {
+    pub fn submitted(&self) -> u64 {
+        self.reads_submitted + self.writes_submitted + self.syncs_submitted
+    }
+
+    /// Submissions per second between an earlier snapshot and this one
+    pub fn submissions_per_sec(&self, earlier: &StoreMetrics, elapsed: Duration) -> f64 {
+        if elapsed == Duration::from_secs(0) { return 0.0 }
+        (self.submitted() - earlier.submitted()) as f64 / elapsed.as_secs_f64()
+    }
+}
+
+#[derive(Default)]
+struct RingCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    syncs: AtomicU64,
+    completed: AtomicU64,
+    pages_read: AtomicU64,
+    pages_written: AtomicU64
+}
+
+/// Counts a submission as completed when dropped, including when it's cancelled
+struct Submission<'a>(&'a RingCounters);
+
+impl RingCounters {
+    fn submit<'a>(&'a self, counter: &AtomicU64) -> Submission<'a> {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Submission(self)
+    }
+
+    fn snapshot(&self) -> StoreMetrics {
+        // completions are read first, so in_flight can't underflow
+        let completed = self.completed.load(Ordering::Relaxed);
+        let reads_submitted = self.reads.load(Ordering::Relaxed);
+        let writes_submitted = self.writes.load(Ordering::Relaxed);
+        let syncs_submitted = self.syncs.load(Ordering::Relaxed);
+        let pages_written = self.pages_written.load(Ordering::Relaxed);
+
+        StoreMetrics {
+            reads_submitted,
+            writes_submitted,
+            syncs_submitted,
+            completed,
+            in_flight: (reads_submitted + writes_submitted + syncs_submitted).saturating_sub(completed),
+            pages_read: self.pages_read.load(Ordering::Relaxed),
+            pages_written,
+            bytes_written: pages_written * PAGE_SIZE as u64
+        }
+    }
+}
+
+impl<'a> Drop for Submission<'a> {
+    fn drop(&mut self) {
+        self.0.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// How hard a commit works to survive power loss
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Don't sync. A commit may be lost (or torn) on power loss, but not on a process crash.
+    NoSync,
+    /// `fdatasync` after writing: flushes data and the metadata needed to read it back
+    SyncData,
+    /// `fsync` after writing: also flushes metadata such as timestamps
+    SyncAll
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::SyncData
+    }
 }
 
 #[derive(Error, Debug, Clone)]
@@ -33,13 +149,57 @@ pub enum RetrieveError {
     #[error("Bad checksum")]
     BadChecksum,
     #[error("Ran out of pages to read")]
-    OutOfPages
+    OutOfPages,
+    #[error("Page {0} is not a well-formed tree page")]
+    Malformed(PageIndex),
+    #[error("Page {0} is quarantined as corrupt")]
+    Quarantined(PageIndex),
+    #[error("{0}")]
+    Descent(#[source] #[from] DescentError)
 }
 
-fn lock_file_for_writing(file: &File) -> io::Result<()> {
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Lock exclusively for writing, or shared for reading so any number of readers can open the file.
+///
+/// If the file is locked, retry until `timeout` passes. The retries run on a thread of their own,
+/// so waiting doesn't block the caller's executor.
+async fn lock_file(file: File, exclusive: bool, timeout: Option<Duration>) -> Result<File, OpenError> {
+    let timeout = match try_lock_file(&file, exclusive) {
+        Ok(()) => return Ok(file),
+        Err(err) if err.kind() != io::ErrorKind::WouldBlock => return Err(Arc::new(err).into()),
+        Err(_) => match timeout {
+            Some(timeout) => timeout,
+            None => return Err(OpenError::Locked { holder_pid: lock_holder(&file) })
+        }
+    };
+
+    let (reply, locked) = oneshot::channel();
+    thread::Builder::new()
+        .name("bssdb-lock-wait".into())
+        .spawn(move || { let _ = reply.send(wait_for_lock(file, exclusive, Instant::now() + timeout)); })
+        .map_err(Arc::new)?;
+
+    locked.await.map_err(|_| Arc::new(io::Error::new(io::ErrorKind::Other, "lock wait thread stopped")))?
+}
+
+/// Retry locking the file until `deadline`, blocking the thread between tries
+fn wait_for_lock(file: File, exclusive: bool, deadline: Instant) -> Result<File, OpenError> {
+    loop {
+        match try_lock_file(&file, exclusive) {
+            Ok(()) => return Ok(file),
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => return Err(Arc::new(err).into()),
+            Err(_) if Instant::now() < deadline => thread::sleep(LOCK_RETRY_INTERVAL),
+            Err(_) => return Err(OpenError::Locked { holder_pid: lock_holder(&file) })
+        }
+    }
+}
+
+fn try_lock_file(file: &File, exclusive: bool) -> io::Result<()> {
     #[cfg(target_family="unix")]
     return {
-        let status = unsafe { libc::flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) };
+        let mode = if exclusive { LOCK_EX } else { LOCK_SH };
+        let status = unsafe { libc::flock(file.as_raw_fd(), mode | LOCK_NB) };
         match status {
             0 => Ok(()),
             _ => Err(io::Error::last_os_error())
@@ -50,29 +210,64 @@ fn lock_file_for_writing(file: &File) -> io::Result<()> {
     compile_error!("locking files is not supported on non-unix")
 }
 
+/// Find the process holding a flock on the file. flock has no API for this, but Linux lists locks in /proc/locks.
+#[cfg(target_os = "linux")]
+fn lock_holder(file: &File) -> Option<u32> {
+    let meta = file.metadata().ok()?;
+    let dev = meta.dev();
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let id = format!("{:02x}:{:02x}:{}", major, minor, meta.ino());
+
+    let locks = std::fs::read_to_string("/proc/locks").ok()?;
+
+    // e.g. "1: FLOCK  ADVISORY  WRITE 1234 08:01:5678 0 EOF"
+    locks.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"FLOCK") || fields.get(5) != Some(&id.as_str()) { return None }
+        fields.get(4)?.parse().ok()
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lock_holder(_file: &File) -> Option<u32> {
+    None
+}
+
 impl FileStore {
-    pub async fn open<'a, P: AsRef<Path>>(path: P) -> io::Result<Arc<FileStore>> {
+    pub async fn open<'a, P: AsRef<Path>>(path: P, options: &Options) -> Result<Arc<FileStore>, OpenError> {
         let mut file = OpenOptions::new();
 
         file.read(true)
-            .write(true)
-            .create(true);
+            .write(!options.read_only)
+            .create(options.create && !options.read_only);
 
         #[cfg(target_os = "linux")]
-        file.custom_flags(libc::O_DIRECT);
+        if options.direct_io { file.custom_flags(libc::O_DIRECT); }
         
-        let file = file.open(path)?;
+        let file = file.open(path).map_err(Arc::new)?;
         
-        lock_file_for_writing(&file)?;
+        let file = lock_file(file, !options.read_only, options.lock_timeout).await?;
 
-        let len = file.metadata()?.len();
-        let page_len = len / (PAGE_SIZE as u64);
-        file.set_len(page_len * (PAGE_SIZE as u64));
+        // drop a partial page left by a crash while growing the file
+        if !options.read_only {
+            let len = file.metadata().map_err(Arc::new)?.len();
+            let page_len = len / (PAGE_SIZE as u64);
+            file.set_len(page_len * (PAGE_SIZE as u64)).map_err(Arc::new)?;
+        }
 
+        let reserved = file.metadata().map_err(Arc::new)?.len();
         let store = Arc::new(FileStore {
             file,
+            reserved: AtomicU64::new(reserved),
+            growth_extent: options.growth_extent.filter(|_| !options.read_only),
+            max_size: options.max_size,
+            growing: Mutex::new(()),
             #[cfg(target_os = "linux")]
-            ring: rio::new()?
+            ring: rio::new().map_err(Arc::new)?,
+            counters: RingCounters::default(),
+            #[cfg(feature = "fault-injection")]
+            faults: options.faults.clone()
         });
 
         Ok(store)
@@ -80,27 +275,107 @@ impl FileStore {
 
     /// Read a page
     pub(super) async fn read_page(&self, idx: PageIndex) -> Result<PageContent, RetrieveError> {
+        #[cfg(feature = "fault-injection")]
+        if let Some(faults) = &self.faults { faults.check().map_err(Arc::new)?; }
+
         let mut page = PageContent::uninit();
        
         let file_pos = idx * (PAGE_SIZE as u64);
         let mut read_bytes = 0;
 
-        {
+        loop {
             let buf = page.as_page_bytes(read_bytes);
-            loop {
-                read_bytes += if cfg!(target_os = "linux") {
-                    self.ring.read_at(&self.file, &buf, file_pos + (read_bytes as u64)).await.map_err(Arc::new)?
-                } else {
-                    #[cfg(not(target_os = "linux"))]
-                    compile_error!("not supported not on linux");
-                    unreachable!();
-                };
-
-                if read_bytes >= PAGE_SIZE { break; }
-            }
+            let read = if cfg!(target_os = "linux") {
+                let _submission = self.counters.submit(&self.counters.reads);
+                self.ring.read_at(&self.file, &buf, file_pos + (read_bytes as u64)).await.map_err(Arc::new)?
+            } else {
+                #[cfg(not(target_os = "linux"))]
+                compile_error!("not supported not on linux");
+                unreachable!();
+            };
+
+            // end of file
+            if read == 0 { return Err(RetrieveError::OutOfPages) }
+
+            read_bytes += read;
+            if read_bytes >= PAGE_SIZE { break; }
         }
+        self.counters.pages_read.fetch_add(1, Ordering::Relaxed);
 
-        return Ok(unsafe { page.assume_init() })
+        // a corrupt type byte must not become an invalid enum
+        unsafe { page.assume_init() }.ok_or(RetrieveError::Malformed(idx))
+    }
+
+    /// Reserve disk space through the end of the extent holding `end`, if it's past what's
+    /// reserved. Failing to is left to the write: a full extent may not fit where a page still does.
+    fn grow(&self, end: u64) {
+        let extent = match self.growth_extent {
+            Some(extent) => extent,
+            None => return
+        };
+        if end <= self.reserved.load(Ordering::Acquire) { return }
+
+        let extent_end = (end + extent - 1) / extent * extent;
+        if let Err(err) = self.reserve(self.max_size.map_or(extent_end, |max_size| extent_end.min(max_size))) {
+            if err.raw_os_error() == Some(libc::EOPNOTSUPP) { self.reserved.store(UNRESERVED, Ordering::Release); }
+        }
+    }
+
+    /// Reserve disk space for the file to grow to `end` bytes, without changing its length
+    fn reserve(&self, end: u64) -> io::Result<()> {
+        let _growing = self.growing.lock();
+        let reserved = self.reserved.load(Ordering::Acquire);
+        if end <= reserved { return Ok(()) }
+
+        #[cfg(target_os = "linux")]
+        {
+            let status = unsafe {
+                libc::fallocate(self.file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, reserved as libc::off_t, (end - reserved) as libc::off_t)
+            };
+            if status != 0 { return Err(io::Error::last_os_error()) }
+        }
+        #[cfg(not(target_os = "linux"))]
+        compile_error!("preallocating is not supported for this os");
+
+        self.reserved.store(end, Ordering::Release);
+        Ok(())
+    }
+
+    /// Counts of ring submissions and completions since opening
+    pub fn metrics(&self) -> StoreMetrics {
+        self.counters.snapshot()
+    }
+
+    /// Flush completed writes to stable storage
+    pub(super) async fn sync(&self, durability: Durability) -> io::Result<()> {
+        #[cfg(feature = "fault-injection")]
+        if let Some(faults) = &self.faults {
+            let _serial = faults.serialize().await;
+            faults.reach(&self.file, FaultPoint::BeforeSync)?;
+            self.sync_file(durability).await?;
+            if durability != Durability::NoSync { faults.synced(&self.file)?; }
+            return faults.reach(&self.file, FaultPoint::AfterSync)
+        }
+
+        self.sync_file(durability).await
+    }
+
+    async fn sync_file(&self, durability: Durability) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        return match durability {
+            Durability::NoSync => Ok(()),
+            Durability::SyncData => {
+                let _submission = self.counters.submit(&self.counters.syncs);
+                self.ring.fdatasync(&self.file).await
+            },
+            Durability::SyncAll => {
+                let _submission = self.counters.submit(&self.counters.syncs);
+                self.ring.fsync(&self.file).await
+            }
+        };
+
+        #[cfg(not(target_os = "linux"))]
+        compile_error!("syncing is not supported for this os")
     }
 
     pub(super) fn write_page<'a>(&'a self, page_idx: u64, page: &'a PageContent) -> PageWrite<'a> {
@@ -108,12 +383,23 @@ impl FileStore {
 
         let content = page.as_slice();
 
+        // an injected fault writes only part of the page, or nothing
+        #[cfg(feature = "fault-injection")]
+        let (content, injected) = match &self.faults {
+            Some(faults) => {
+                let injected = faults.before_write(&self.file, pos).unwrap_or(Some(Injected::Fail));
+                (&content[..injected.map_or(content.len(), Injected::len)], injected)
+            },
+            None => (content, None)
+        };
+
         let ring = &self.ring;
         let file = &self.file;
 
         #[cfg(target_os = "linux")]
         return {
             // immediately start writing
+            let submission = self.counters.submit(&self.counters.writes);
             let completion = ring.write_at(file, &content, pos);
 
             PageWrite {
@@ -122,7 +408,11 @@ impl FileStore {
                 content,
 
                 ring,
-                completion
+                counters: &self.counters,
+                submission,
+                completion,
+                #[cfg(feature = "fault-injection")]
+                faults: self.faults.as_deref().map(|faults| (faults, injected))
             }
         };
         
@@ -131,6 +421,69 @@ impl FileStore {
     }
 }
 
+impl PageStore for FileStore {
+    fn read_page(&self, idx: PageIndex) -> BoxFuture<'_, Result<PageContent, RetrieveError>> {
+        traced!(FileStore::read_page(self, idx), "read_page", idx, bytes = PAGE_SIZE).boxed()
+    }
+
+    fn write_page<'a>(&'a self, idx: PageIndex, page: &'a PageContent) -> BoxFuture<'a, io::Result<()>> {
+        let end = (idx + 1) * PAGE_SIZE as u64;
+        // failing before the write starts, so the file never grows past the cap
+        if self.max_size.map_or(false, |max_size| end > max_size) {
+            return future::ready(Err(io::Error::from_raw_os_error(libc::EDQUOT))).boxed()
+        }
+        self.grow(end);
+
+        #[cfg(feature = "fault-injection")]
+        if let Some(faults) = &self.faults {
+            return async move {
+                let _serial = faults.serialize().await;
+                FileStore::write_page(self, idx, page).finish().await
+            }.boxed()
+        }
+
+        traced!(FileStore::write_page(self, idx, page).finish(), "write_page", idx, bytes = PAGE_SIZE).boxed()
+    }
+
+    fn sync(&self, durability: Durability) -> BoxFuture<'_, io::Result<()>> {
+        traced!(FileStore::sync(self, durability), "sync", ?durability).boxed()
+    }
+
+    fn metrics(&self) -> Option<StoreMetrics> {
+        Some(FileStore::metrics(self))
+    }
+
+    fn preallocate(&self, bytes: u64) -> io::Result<()> {
+        if self.reserved.load(Ordering::Acquire) == UNRESERVED {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        }
+        self.reserve(self.max_size.map_or(bytes, |max_size| bytes.min(max_size)))
+    }
+
+    fn discard(&self, start: PageIndex, pages: u64) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        let status = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                (start * PAGE_SIZE as u64) as libc::off_t,
+                (pages * PAGE_SIZE as u64) as libc::off_t
+            )
+        };
+        #[cfg(not(target_os = "linux"))]
+        compile_error!("discarding pages is not supported for this os");
+
+        match status {
+            0 => Ok(()),
+            // the space stays allocated, which is all discarding would have changed
+            _ => match io::Error::last_os_error() {
+                err if err.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+                err => Err(err)
+            }
+        }
+    }
+}
+
 pub struct PageWrite<'a> {
     file: &'a File,
     pos: u64,
@@ -139,18 +492,36 @@ pub struct PageWrite<'a> {
     #[cfg(target_os = "linux")]
     ring: &'a rio::Rio,
     #[cfg(target_os = "linux")]
-    completion: rio::Completion<'a, usize>
+    counters: &'a RingCounters,
+    #[cfg(target_os = "linux")]
+    submission: Submission<'a>,
+    #[cfg(target_os = "linux")]
+    completion: rio::Completion<'a, usize>,
+    #[cfg(feature = "fault-injection")]
+    faults: Option<(&'a Faults, Option<Injected>)>
 }
 
 impl<'a> PageWrite<'a> {
     pub(crate) async fn finish(self) -> io::Result<()> {
         #[cfg(target_os = "linux")]
         return {
-            let PageWrite {ring, file, pos, content, completion } = self;
+            #[cfg(feature = "fault-injection")]
+            let PageWrite {ring, file, pos, content, counters, submission, completion, faults } = self;
+            #[cfg(not(feature = "fault-injection"))]
+            let PageWrite {ring, file, pos, content, counters, submission, completion } = self;
 
-            let mut total_written = completion.await?;
+            let written = completion.await;
+            std::mem::drop(submission);
+            let mut total_written = written?;
+
+            #[cfg(feature = "fault-injection")]
+            if let Some((faults, injected)) = faults {
+                if let Some(injected) = injected { return Err(faults.injected(file, pos, content, injected)) }
+                faults.reach(file, FaultPoint::WriteInFlight)?;
+            }
 
             while PAGE_SIZE > total_written {
+                let _submission = counters.submit(&counters.writes);
                 let res = ring.write_at(
                     file,
                     &&content[total_written..],
@@ -158,6 +529,10 @@ impl<'a> PageWrite<'a> {
                 ).await;
                 total_written += res?;
             }
+            counters.pages_written.fetch_add(1, Ordering::Relaxed);
+
+            #[cfg(feature = "fault-injection")]
+            if let Some((faults, _)) = faults { faults.reach(file, FaultPoint::WriteCompleted)?; }
 
             Ok(())
         };
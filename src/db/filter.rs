@@ -0,0 +1,41 @@
+//! Bloom filters over the keys of a leaf, kept in its parent branch so a lookup of a missing
+//! key can usually stop without reading the leaf. Keys are hashed with crc32 under two seeds,
+//! which stay the same across builds, and combined into `HASHES` bit positions.
+
+pub const DEFAULT_LEAF_FILTER_LEN: usize = 16;
+
+const HASHES: u32 = 3;
+
+const SEED: u32 = 0x9e37_79b9;
+
+fn positions(key: &[u8], bits: u32) -> impl Iterator<Item = u32> {
+    let h1 = crc32fast::hash(key);
+    let mut hasher = crc32fast::Hasher::new_with_initial(SEED);
+    hasher.update(key);
+    // odd, so the step is never zero
+    let h2 = hasher.finalize() | 1;
+
+    (0..HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bits)
+}
+
+/// A filter of `len` bytes over `keys`
+pub(crate) fn build<'k>(keys: impl Iterator<Item = &'k [u8]>, len: usize) -> Vec<u8> {
+    let mut filter = vec![0; len];
+    let bits = (len * 8) as u32;
+
+    for key in keys {
+        for bit in positions(key, bits) {
+            filter[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    filter
+}
+
+/// False only if `key` is certainly not among the filter's keys
+pub(crate) fn may_contain(filter: &[u8], key: &[u8]) -> bool {
+    if filter.is_empty() { return true }
+    let bits = (filter.len() * 8) as u32;
+
+    positions(key, bits).all(|bit| filter[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+}
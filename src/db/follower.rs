@@ -0,0 +1,85 @@
+//! Followers: read replicas that apply a primary's replication stream and serve reads from
+//! it, rejecting writes of their own. Each of the primary's transactions is applied in one
+//! transaction, so reads see the primary's data as of some transaction, never part of one.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use futures::stream::{Stream, StreamExt};
+
+use super::{DB, OpenError, Options, ReplicationError, TransactionIdx};
+
+pub(crate) struct FollowerState {
+    /// The latest of the primary's transactions applied here
+    applied: Option<TransactionIdx>,
+    /// The latest of the primary's transactions heard of
+    primary: TransactionIdx,
+    last_applied: Option<Instant>
+}
+
+/// How far a follower is behind its primary, as of the records it has received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicationLag {
+    /// The latest of the primary's transactions applied, or `None` before the first
+    pub applied_tx: Option<TransactionIdx>,
+    /// The latest of the primary's transactions the stream has announced
+    pub primary_tx: TransactionIdx,
+    /// Transactions announced and not yet applied
+    pub transactions: u64,
+    /// Time since a transaction was last applied, or `None` if none has been since opening
+    pub since_applied: Option<Duration>
+}
+
+impl DB {
+    /// Open the database at `path` as a follower: writes are rejected as if it were
+    /// read-only, except those applied from a primary's replication stream with `follow` or
+    /// `apply_replication_record`. Resume the primary's stream from `replicated_tx`. The file
+    /// is opened for writing whatever `options` says, since replication writes to it.
+    pub async fn open_follower<P: AsRef<Path>>(path: P, mut options: Options) -> Result<DB, OpenError> {
+        options.read_only(false);
+        let db = DB::open(path, options).await?;
+        let applied = db.replicated_tx().await?;
+        *db.follower.lock() = Some(FollowerState { applied, primary: applied.unwrap_or(0), last_applied: None });
+        Ok(db)
+    }
+
+    pub fn is_follower(&self) -> bool {
+        self.follower.lock().is_some()
+    }
+
+    /// Apply the frames of a primary's replication stream as they arrive, until it ends or a
+    /// frame fails to apply. The frames can come from `DB::replication_stream`, or from the
+    /// network, split with `ReplicationRecord::frame_len`.
+    pub async fn follow<S: Stream<Item = Bytes> + Unpin>(&self, mut frames: S) -> Result<(), ReplicationError> {
+        while let Some(frame) = frames.next().await {
+            self.apply_replication_record(&frame).await?;
+        }
+        Ok(())
+    }
+
+    /// How far behind its primary a follower is, or `None` if this isn't a follower
+    pub fn replication_lag(&self) -> Option<ReplicationLag> {
+        self.follower.lock().as_ref().map(|follower| ReplicationLag {
+            applied_tx: follower.applied,
+            primary_tx: follower.primary,
+            transactions: follower.primary.saturating_sub(follower.applied.unwrap_or(0)),
+            since_applied: follower.last_applied.map(|at| at.elapsed())
+        })
+    }
+
+    /// Note that the primary has reached `tx`
+    pub(super) fn record_position(&self, tx: TransactionIdx) {
+        if let Some(follower) = self.follower.lock().as_mut() {
+            follower.primary = follower.primary.max(tx);
+        }
+    }
+
+    /// Note that the primary's transaction `tx` has been applied
+    pub(super) fn record_applied(&self, tx: TransactionIdx) {
+        if let Some(follower) = self.follower.lock().as_mut() {
+            follower.applied = Some(follower.applied.map_or(tx, |applied| applied.max(tx)));
+            follower.primary = follower.primary.max(tx);
+            follower.last_applied = Some(Instant::now());
+        }
+    }
+}
@@ -1,7 +0,0 @@
-
-
-
-struct FreePages {
-    free_pages
-}
-
@@ -0,0 +1,21 @@
+//! Filesystem helpers shared by everything that creates, renames or removes files.
+//!
+//! Syncing a file makes its contents durable, but its directory entry is only durable once
+//! the directory is synced too. Without that, a file created just before power loss can
+//! vanish on some filesystems even though its data was synced. Growing an existing file
+//! needs no directory sync: `fdatasync` covers the size needed to read the data back.
+
+use std::{fs::File, io, path::Path};
+
+/// Flush the entries of a directory to stable storage
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Make the creation, rename or removal of `path` survive power loss
+pub(crate) fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir),
+        _ => sync_dir(Path::new("."))
+    }
+}
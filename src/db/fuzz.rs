@@ -0,0 +1,57 @@
+//! Entry points for fuzzing the decoders of on-disk structures, enabled by the `fuzzing`
+//! feature. Each takes arbitrary bytes, as a corrupt file could hold, and decodes them,
+//! discarding the result: decoding may fail, but must never panic or read out of bounds.
+//! The targets in `fuzz/` call these.
+
+use super::archive;
+use super::branch::Branch;
+use super::compression::Dictionaries;
+use super::header::FileHeader;
+use super::leaf;
+use super::memtable;
+use super::page::{PageContent, PageType, PAGE_DATA_LEN, PAGE_SIZE};
+use super::version::VersionHeader;
+
+/// Decode a raw page, as read from the file, as every kind of page
+pub fn page(bytes: &[u8]) {
+    let mut padded = [0; PAGE_SIZE];
+    let len = bytes.len().min(PAGE_SIZE);
+    padded[..len].copy_from_slice(&bytes[..len]);
+
+    let page = match PageContent::from_bytes(&padded) {
+        Some(page) => page,
+        None => return
+    };
+    let _ = FileHeader::decode(&page);
+    let _ = VersionHeader::decode(&page);
+    let _ = Branch::decode(&page);
+    let _ = Branch::decode_without_stats(&page);
+    let _ = leaf::decode(&page);
+}
+
+/// Page content of `page_type` holding `data`
+fn content(page_type: PageType, data: &[u8]) -> PageContent {
+    let mut page = PageContent::new(page_type);
+    let len = data.len().min(PAGE_DATA_LEN);
+    page.data[..len].copy_from_slice(&data[..len]);
+    page
+}
+
+/// Decode a leaf page's data
+pub fn leaf(data: &[u8]) {
+    let _ = leaf::decode(&content(PageType::Leaf, data));
+}
+
+/// Decode a branch page's data, with and without subtree stats
+pub fn branch(data: &[u8]) {
+    let page = content(PageType::Branch, data);
+    let _ = Branch::decode(&page);
+    let _ = Branch::decode_without_stats(&page);
+}
+
+/// Decode the data of an overflow chain as each kind of record kept in one
+pub fn chain(data: &[u8]) {
+    let _ = Dictionaries::decode(data);
+    let _ = memtable::decode_record(data);
+    let _ = archive::decode(data);
+}
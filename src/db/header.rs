@@ -0,0 +1,174 @@
+use std::io;
+use std::convert::TryInto;
+use thiserror::Error;
+
+use super::{PageStore, Durability, Options, PageContent, PageIndex, RetrieveError};
+use super::page::{self, PageType, PAGE_SIZE};
+
+/// The superblock, identifying the file and how it is laid out
+pub(crate) const HEADER_PAGE: PageIndex = 0;
+
+const MAGIC: [u8; 8] = *b"BSSDB\0\0\0";
+
+pub(crate) const FORMAT_VERSION: u32 = 5;
+
+/// Written in native byte order, so it reads back differently on a machine of the other endianness
+const ENDIAN_MARKER: u32 = 0x0102_0304;
+
+/// Pages other than the header are sealed with an AEAD
+pub(crate) const FEATURE_ENCRYPTED: u64 = 1 << 0;
+
+/// Feature flags this build knows how to read
+#[cfg(feature = "encryption")]
+pub(crate) const SUPPORTED_FEATURES: u64 = FEATURE_ENCRYPTED;
+#[cfg(not(feature = "encryption"))]
+pub(crate) const SUPPORTED_FEATURES: u64 = 0;
+
+#[derive(Error, Debug, Clone)]
+pub enum FormatError {
+    #[error("Not a bssdb database (bad magic bytes)")]
+    BadMagic,
+    #[error("Database header is corrupt")]
+    BadChecksum,
+    #[error("Database format version {found} is not supported (this build reads version {supported})")]
+    UnsupportedVersion { found: u32, supported: u32 },
+    #[error("Database format version {found} must be upgraded to version {supported} (open it writable with Options::upgrade_format)")]
+    NeedsUpgrade { found: u32, supported: u32 },
+    #[error("Database format version {found} is too old to upgrade (this build upgrades from version {oldest}); export it with an older build and import it")]
+    NoMigration { found: u32, oldest: u32 },
+    #[error("Database uses {found} byte pages, but this build uses {supported} byte pages")]
+    PageSize { found: u32, supported: u32 },
+    #[error("Database was written on a machine with different endianness")]
+    Endianness,
+    #[error("Database uses unsupported features (flags {0:#x})")]
+    UnsupportedFeatures(u64),
+    #[error("Database is encrypted, but no key was given")]
+    Encrypted,
+    #[error("Database is not encrypted, but a key was given")]
+    NotEncrypted,
+    #[error("Encryption key or cipher doesn't match the database, or its root pages are corrupt")]
+    WrongKey,
+    #[error("Database has no valid root page")]
+    NoVersion,
+    #[error("Database file is empty")]
+    Empty
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileHeader {
+    pub format_version: u32,
+    pub page_size: u32,
+    pub features: u64,
+    /// The `Cipher` of an encrypted database, or zero
+    pub cipher: u8,
+    /// The format version before the last upgrade, and a copy of the root page from then
+    pub pre_upgrade: Option<(u32, PageIndex)>
+}
+
+impl FileHeader {
+    /// The header this build writes for a new database
+    pub fn current() -> FileHeader {
+        FileHeader {
+            format_version: FORMAT_VERSION,
+            page_size: PAGE_SIZE as u32,
+            features: 0,
+            cipher: 0,
+            pre_upgrade: None
+        }
+    }
+
+    /// The header for a new database opened with `options`
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub fn for_options(options: &Options) -> FileHeader {
+        #[allow(unused_mut)]
+        let mut header = FileHeader::current();
+
+        #[cfg(feature = "encryption")]
+        if let Some(config) = &options.encryption {
+            header.features |= FEATURE_ENCRYPTED;
+            header.cipher = config.cipher as u8;
+        }
+
+        header
+    }
+
+    pub fn encode(&self) -> PageContent {
+        let mut page = PageContent::new(PageType::Header);
+
+        page.data[0..8].copy_from_slice(&MAGIC);
+        page.data[8..12].copy_from_slice(&self.format_version.to_le_bytes());
+        page.data[12..16].copy_from_slice(&self.page_size.to_le_bytes());
+        page.data[16..20].copy_from_slice(&ENDIAN_MARKER.to_ne_bytes());
+        page.data[20..28].copy_from_slice(&self.features.to_le_bytes());
+        page.data[28] = self.cipher;
+        // the header page is never a root copy, so zero means there's none
+        let (version, root) = self.pre_upgrade.unwrap_or((0, HEADER_PAGE));
+        page.data[29..33].copy_from_slice(&version.to_le_bytes());
+        page.data[33..41].copy_from_slice(&root.to_le_bytes());
+
+        page.update_checksum();
+        page
+    }
+
+    /// Decode the header page, checking that this build can read the database, or upgrade it
+    /// when the format version is older
+    pub fn decode(page: &PageContent) -> Result<FileHeader, FormatError> {
+        if page.data[0..8] != MAGIC { return Err(FormatError::BadMagic) }
+        if !page::checksum_ok(page.as_slice()) { return Err(FormatError::BadChecksum) }
+
+        let mut word = [0; 4];
+        let mut u32_at = |at: usize| {
+            word.copy_from_slice(&page.data[at..at + 4]);
+            word
+        };
+        let format_version = u32::from_le_bytes(u32_at(8));
+        let page_size = u32::from_le_bytes(u32_at(12));
+        let endian_marker = u32::from_ne_bytes(u32_at(16));
+
+        let mut features = [0; 8];
+        features.copy_from_slice(&page.data[20..28]);
+        let features = u64::from_le_bytes(features);
+
+        if endian_marker != ENDIAN_MARKER { return Err(FormatError::Endianness) }
+        if format_version > FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion { found: format_version, supported: FORMAT_VERSION })
+        }
+        if page_size as usize != PAGE_SIZE {
+            return Err(FormatError::PageSize { found: page_size, supported: PAGE_SIZE as u32 })
+        }
+        if features & !SUPPORTED_FEATURES != 0 {
+            return Err(FormatError::UnsupportedFeatures(features & !SUPPORTED_FEATURES))
+        }
+
+        let pre_upgrade = match u64::from_le_bytes(page.data[33..41].try_into().unwrap()) {
+            HEADER_PAGE => None,
+            root => Some((u32::from_le_bytes(u32_at(29)), root))
+        };
+
+        Ok(FileHeader { format_version, page_size, features, cipher: page.data[28], pre_upgrade })
+    }
+
+    /// The error for a database with no root page that can be read. Root pages are sealed in
+    /// an encrypted database, so the likely cause is the wrong key.
+    pub fn missing_version(&self) -> FormatError {
+        match self.features & FEATURE_ENCRYPTED {
+            0 => FormatError::NoVersion,
+            _ => FormatError::WrongKey
+        }
+    }
+
+    /// Read the header, or `None` if the file is empty
+    pub async fn load(store: &dyn PageStore) -> Result<Option<Result<FileHeader, FormatError>>, RetrieveError> {
+        match store.read_page(HEADER_PAGE).await {
+            Ok(page) => Ok(Some(FileHeader::decode(&page))),
+            Err(RetrieveError::OutOfPages) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    pub async fn write(&self, store: &dyn PageStore, durability: Durability) -> io::Result<()> {
+        let page = self.encode();
+        store.write_page(HEADER_PAGE, &page).await?;
+        store.sync(durability).await
+    }
+}
@@ -0,0 +1,199 @@
+//! Secondary indexes, kept in their own tree and updated by the same commits as the keys
+//! they index.
+//!
+//! The tree holds, for each index, a marker `[name_len: u8][name]` recording that the index
+//! was built, and an entry `[name_len: u8][name][secondary_len: u16 big-endian][secondary][primary]`
+//! with no value for each secondary key of each primary key.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Bound;
+use std::sync::Arc;
+use bytes::Bytes;
+use futures::stream::TryStreamExt;
+
+use super::{DB, RetrieveError, WriteError, WriteTransaction};
+use super::compression::Compression;
+use super::leaf::LeafValue;
+use super::tree::{self, Entries, NodeFormat, Write};
+
+/// Index entries applied at a time while building an index
+const BUILD_CHUNK: usize = 64 * 1024;
+
+/// Returns the secondary keys of a primary key and its value
+pub(crate) type Extractor = Arc<dyn Fn(&[u8], &[u8]) -> Vec<Bytes> + Send + Sync>;
+
+fn marker(name: &str) -> Bytes {
+    let mut key = Vec::with_capacity(1 + name.len());
+    key.push(name.len() as u8);
+    key.extend_from_slice(name.as_bytes());
+    key.into()
+}
+
+fn entry_prefix(name: &str, secondary: &[u8]) -> Vec<u8> {
+    let mut key = marker(name).to_vec();
+    key.extend_from_slice(&(secondary.len() as u16).to_be_bytes());
+    key.extend_from_slice(secondary);
+    key
+}
+
+fn entry(name: &str, secondary: &[u8], primary: &[u8]) -> Bytes {
+    let mut key = entry_prefix(name, secondary);
+    key.extend_from_slice(primary);
+    key.into()
+}
+
+fn present() -> Option<LeafValue> {
+    Some(LeafValue::Inline(Bytes::new()))
+}
+
+/// A secondary index, for looking up primary keys by secondary key
+pub struct Index<'db> {
+    db: &'db DB,
+    name: String
+}
+
+impl<'db> Index<'db> {
+    /// The primary keys with `secondary` as a secondary key, in order
+    pub async fn get(&self, secondary: &[u8]) -> Result<Vec<Bytes>, RetrieveError> {
+        let root = self.db.version.lock().indexes;
+        let max_depth = self.db.options.lock().max_tree_depth;
+        let prefix = entry_prefix(&self.name, secondary);
+
+        let mut primary = vec![];
+        let mut entries = Entries::new(root, Bound::Included(Bytes::from(prefix.clone())), max_depth);
+        while let Some(entry) = entries.next(&self.db.cache).await? {
+            if !entry.key.starts_with(&prefix) { break }
+            primary.push(entry.key.slice(prefix.len()..));
+        }
+
+        Ok(primary)
+    }
+}
+
+impl DB {
+    /// Maintain an index named `name` of the secondary keys `extractor` returns for each key
+    /// and value. The index is updated atomically by every commit. The first time an index is
+    /// created, it's built from every key in the database.
+    ///
+    /// Indexes are persisted, but extractors aren't: create each index again, with the same
+    /// extractor, right after opening the database, before any writes. Bulk loads aren't
+    /// indexed, so create indexes after them. To change an extractor, drop the index first.
+    pub async fn create_index<F>(&self, name: &str, extractor: F) -> Result<(), WriteError>
+        where F: Fn(&[u8], &[u8]) -> Vec<Bytes> + Send + Sync + 'static
+    {
+        if name.len() > u8::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "index name is longer than 255 bytes").into())
+        }
+        let extractor: Extractor = Arc::new(extractor);
+
+        let mut tx = self.write().await?;
+        let (max_depth, format) = {
+            let options = self.options.lock();
+            (options.max_tree_depth, NodeFormat { compression: Compression::None, ..NodeFormat::new(&options) })
+        };
+
+        let built = match tx.version.indexes {
+            Some(root) => tree::lookup(&self.cache, root, &marker(name), max_depth).await?.is_some(),
+            None => false
+        };
+        if built {
+            self.indexes.lock().insert(name.to_owned(), extractor);
+            return Ok(())
+        }
+
+        // the commit building the index is its first update, so it's registered first
+        self.indexes.lock().insert(name.to_owned(), extractor.clone());
+        let created: Result<(), WriteError> = async {
+            let mut chunk = BTreeMap::new();
+            let mut pairs = self.range(..);
+            while let Some((key, value)) = pairs.try_next().await? {
+                for secondary in extractor(&key, &value) {
+                    chunk.insert(entry(name, &secondary, &key), present());
+                }
+                if chunk.len() >= BUILD_CHUNK {
+                    let writes: Vec<Write> = std::mem::take(&mut chunk).into_iter().collect();
+                    tx.version.indexes = tree::apply(&self.cache, &tx.txn, tx.version.indexes, &writes, max_depth, format).await?;
+                }
+            }
+
+            chunk.insert(marker(name), present());
+            let writes: Vec<Write> = chunk.into_iter().collect();
+            tx.version.indexes = tree::apply(&self.cache, &tx.txn, tx.version.indexes, &writes, max_depth, format).await?;
+            tx.commit().await?;
+            Ok(())
+        }.await;
+
+        if created.is_err() {
+            self.indexes.lock().remove(name);
+        }
+        created
+    }
+
+    /// Stop maintaining the index named `name`, and delete it
+    pub async fn drop_index(&self, name: &str) -> Result<(), WriteError> {
+        let mut tx = self.write().await?;
+        self.indexes.lock().remove(name);
+
+        let (max_depth, format) = {
+            let options = self.options.lock();
+            (options.max_tree_depth, NodeFormat { compression: Compression::None, ..NodeFormat::new(&options) })
+        };
+
+        // every key of the index starts with its marker, and no other index's keys do, since
+        // markers start with the name's length
+        let prefix = marker(name);
+        let mut deletes: Vec<Write> = vec![];
+        let mut entries = Entries::new(tx.version.indexes, Bound::Included(prefix.clone()), max_depth);
+        while let Some(entry) = entries.next(&self.cache).await? {
+            if !entry.key.starts_with(&prefix) { break }
+            deletes.push((entry.key, None));
+        }
+
+        if !deletes.is_empty() {
+            tx.version.indexes = tree::apply(&self.cache, &tx.txn, tx.version.indexes, &deletes, max_depth, format).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// The index named `name`, which is empty unless it was created
+    pub fn index(&self, name: &str) -> Index<'_> {
+        Index { db: self, name: name.to_owned() }
+    }
+}
+
+impl<'db> WriteTransaction<'db> {
+    /// The index changes this transaction's writes make: the secondary keys of each written
+    /// key's old value are removed, and those of its new value added
+    pub(super) async fn index_writes(&self) -> Result<Vec<Write>, RetrieveError> {
+        let extractors = self.db.indexes.lock().clone();
+        if extractors.is_empty() { return Ok(vec![]) }
+
+        let dictionaries = self.db.dictionaries();
+        let mut changes = BTreeMap::new();
+        for written in self.written() {
+            let (key, value) = written.map_err(Arc::new)?;
+            let key = &key;
+            let old = match self.db.lookup(key).await? {
+                Some(old) => Some(old.read(&self.db.cache, &dictionaries).await?),
+                None => None
+            };
+            let new = match value {
+                Some(new) => Some(self.read_value(key, new).await?),
+                None => None
+            };
+
+            for (name, extractor) in &extractors {
+                for secondary in old.iter().flat_map(|old| extractor(key, old)) {
+                    changes.insert(entry(name, &secondary, key), None);
+                }
+                for secondary in new.iter().flat_map(|new| extractor(key, new)) {
+                    changes.insert(entry(name, &secondary, key), present());
+                }
+            }
+        }
+
+        Ok(changes.into_iter().collect())
+    }
+}
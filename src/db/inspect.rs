@@ -0,0 +1,146 @@
+//! Decoding pages and outlining the tree for people, to debug page layout and splits
+
+use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt};
+
+use super::{DB, PageIndex, RetrieveError, TransactionIdx};
+use super::branch::Branch;
+use super::leaf::{self, LeafValue, BODY_CAPACITY};
+use super::page::{PageContent, PageType, PAGE_DATA_LEN, PAGE_SIZE};
+use super::tree::read_node;
+
+/// A page, decoded as far as its type allows
+#[derive(Debug, Clone)]
+pub struct PageDescription {
+    pub idx: PageIndex,
+    pub page_type: PageType,
+    /// The transaction that wrote it
+    pub lsn: TransactionIdx,
+    /// The share of the page's space its content takes, for tree pages. Leaves count their
+    /// content before compression.
+    pub fill: Option<f64>,
+    pub content: PageDetail,
+    /// The raw page, for a hexdump
+    pub raw: Bytes
+}
+
+#[derive(Debug, Clone)]
+pub enum PageDetail {
+    Branch {
+        first_child: PageIndex,
+        /// Each separator and the child holding the keys from it up to the next
+        separators: Vec<(Bytes, PageIndex)>,
+        /// Entries in each child's subtree
+        entries: Vec<u64>
+    },
+    Leaf {
+        /// Each key, with the length of its value and whether the value is in the value log
+        entries: Vec<(Bytes, u64, bool)>
+    },
+    /// A page of another type, or one that doesn't decode as its type
+    Opaque
+}
+
+/// One page of the tree, in `DB::tree_outline`
+#[derive(Debug, Clone)]
+pub struct OutlineNode {
+    /// Levels below the root
+    pub depth: usize,
+    pub idx: PageIndex,
+    pub is_leaf: bool,
+    /// The separator routing keys to this page in its parent, or `None` for a first child
+    pub separator: Option<Bytes>,
+    /// Entries in a leaf, or children of a branch
+    pub len: usize,
+    pub fill: f64
+}
+
+fn describe(idx: PageIndex, page: &PageContent) -> PageDescription {
+    let (fill, content) = match page.page_type {
+        PageType::Branch => match Branch::decode(page) {
+            Some(branch) => (Some(branch.encoded_len() as f64 / PAGE_DATA_LEN as f64), PageDetail::Branch {
+                first_child: branch.first_child,
+                entries: branch.stats.iter().map(|stats| stats.entries).collect(),
+                separators: branch.separators
+            }),
+            None => (None, PageDetail::Opaque)
+        },
+        PageType::Leaf => match leaf::decode(page) {
+            Some(entries) => (Some(leaf::body_len(&entries) as f64 / BODY_CAPACITY as f64), PageDetail::Leaf {
+                entries: entries.into_iter().map(|entry| match entry.value {
+                    LeafValue::Inline(value) => (entry.key, value.len() as u64, false),
+                    LeafValue::Logged(ptr) => (entry.key, ptr.len, true)
+                }).collect()
+            }),
+            None => (None, PageDetail::Opaque)
+        },
+        _ => (None, PageDetail::Opaque)
+    };
+
+    PageDescription {
+        idx,
+        page_type: page.page_type,
+        lsn: page.lsn(),
+        fill,
+        content,
+        raw: Bytes::copy_from_slice(&page.as_slice()[..PAGE_SIZE])
+    }
+}
+
+impl DB {
+    /// Read and decode page `idx`, whatever it holds
+    pub async fn describe_page(&self, idx: PageIndex) -> Result<PageDescription, RetrieveError> {
+        let page = read_node(&self.cache, idx).await?;
+        Ok(describe(idx, &page))
+    }
+
+    /// The pages of the tree down to `max_depth` levels below the root, in depth-first order
+    pub async fn tree_outline(&self, max_depth: usize) -> Result<Vec<OutlineNode>, RetrieveError> {
+        let mut outline = vec![];
+        if let Some(root) = self.version.lock().tree_root {
+            self.outline(root, 0, None, max_depth, &mut outline).await?;
+        }
+        Ok(outline)
+    }
+
+    fn outline<'a>(&'a self, idx: PageIndex, depth: usize, separator: Option<Bytes>, max_depth: usize, outline: &'a mut Vec<OutlineNode>) -> BoxFuture<'a, Result<(), RetrieveError>> {
+        async move {
+            if depth > self.options.lock().max_tree_depth { return Err(RetrieveError::Malformed(idx)) }
+            let page = read_node(&self.cache, idx).await?;
+
+            match page.page_type {
+                PageType::Branch => {
+                    let branch = Branch::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                    outline.push(OutlineNode {
+                        depth,
+                        idx,
+                        is_leaf: false,
+                        separator,
+                        len: branch.separators.len() + 1,
+                        fill: branch.encoded_len() as f64 / PAGE_DATA_LEN as f64
+                    });
+                    if depth < max_depth {
+                        for i in 0..=branch.separators.len() {
+                            let separator = if i == 0 { None } else { Some(branch.separators[i - 1].0.clone()) };
+                            self.outline(branch.child(i), depth + 1, separator, max_depth, outline).await?;
+                        }
+                    }
+                },
+                PageType::Leaf => {
+                    let entries = leaf::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                    outline.push(OutlineNode {
+                        depth,
+                        idx,
+                        is_leaf: true,
+                        separator,
+                        len: entries.len(),
+                        fill: leaf::body_len(&entries) as f64 / BODY_CAPACITY as f64
+                    });
+                },
+                _ => return Err(RetrieveError::Malformed(idx))
+            }
+
+            Ok(())
+        }.boxed()
+    }
+}
@@ -0,0 +1,490 @@
+//! An encoding of keys whose byte order matches the order of the values they encode, so typed
+//! keys sort and range-scan the way their types compare.
+//!
+//! Primitives, strings, bytes, options and tuples are encoded as in `crate::keys`. Sequence
+//! elements are each preceded by a `1` tag and the sequence ends with `0`, enum variants are
+//! preceded by their index as a big-endian `u32`, and structs are their fields in order. Maps
+//! can't be keys.
+
+use serde_crate::{de, ser};
+use serde_crate::de::{DeserializeSeed, IntoDeserializer, Visitor};
+use serde_crate::ser::{Impossible, Serialize};
+use thiserror::Error;
+
+use crate::keys::{self, KeyPart};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    #[error("{0}")]
+    Custom(String),
+    #[error("{0} can't be encoded in a key")]
+    Unsupported(&'static str),
+    #[error("Key doesn't decode as the key type")]
+    Malformed
+}
+
+impl ser::Error for KeyError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        KeyError::Custom(msg.to_string())
+    }
+}
+
+impl de::Error for KeyError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        KeyError::Custom(msg.to_string())
+    }
+}
+
+/// Encode `key` so that encoded keys sort like the values
+pub fn encode_key<K: Serialize + ?Sized>(key: &K) -> Result<Vec<u8>, KeyError> {
+    let mut encoder = KeyEncoder { out: Vec::new() };
+    key.serialize(&mut encoder)?;
+    Ok(encoder.out)
+}
+
+/// Decode a key written by `encode_key`
+pub fn decode_key<K: de::DeserializeOwned>(bytes: &[u8]) -> Result<K, KeyError> {
+    let mut decoder = KeyDecoder { input: bytes };
+    let key = K::deserialize(&mut decoder)?;
+    if !decoder.input.is_empty() { return Err(KeyError::Malformed) }
+    Ok(key)
+}
+
+const END: u8 = 0;
+const MORE: u8 = 1;
+
+struct KeyEncoder {
+    out: Vec<u8>
+}
+
+impl<'a> ser::Serializer for &'a mut KeyEncoder {
+    type Ok = ();
+    type Error = KeyError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Impossible<(), KeyError>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<(), KeyError> {
+        v.encode_to(&mut self.out);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), KeyError> {
+        v.encode_to(&mut self.out);
+        Ok(())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), KeyError> {
+        v.encode_to(&mut self.out);
+        Ok(())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), KeyError> {
+        v.encode_to(&mut self.out);
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), KeyError> {
+        v.encode_to(&mut self.out);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), KeyError> {
+        v.encode_to(&mut self.out);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), KeyError> {
+        v.encode_to(&mut self.out);
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), KeyError> {
+        v.encode_to(&mut self.out);
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), KeyError> {
+        v.encode_to(&mut self.out);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), KeyError> {
+        v.encode_to(&mut self.out);
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), KeyError> {
+        v.encode_to(&mut self.out);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), KeyError> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), KeyError> {
+        keys::escape(v.as_bytes(), &mut self.out);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), KeyError> {
+        keys::escape(v, &mut self.out);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), KeyError> {
+        self.out.push(END);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), KeyError> {
+        self.out.push(MORE);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), KeyError> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), KeyError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, variant_index: u32, _variant: &'static str) -> Result<(), KeyError> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), KeyError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, variant_index: u32, _variant: &'static str, value: &T) -> Result<(), KeyError> {
+        self.serialize_u32(variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self, KeyError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, KeyError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self, KeyError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, variant_index: u32, _variant: &'static str, _len: usize) -> Result<Self, KeyError> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, KeyError> {
+        Err(KeyError::Unsupported("A map"))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, KeyError> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, variant_index: u32, _variant: &'static str, _len: usize) -> Result<Self, KeyError> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+}
+
+impl<'a> ser::SerializeSeq for &'a mut KeyEncoder {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), KeyError> {
+        self.out.push(MORE);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), KeyError> {
+        self.out.push(END);
+        Ok(())
+    }
+}
+
+macro_rules! fields {
+    ($trait:ident, $method:ident $(, $name:ident)?) => {
+        impl<'a> ser::$trait for &'a mut KeyEncoder {
+            type Ok = ();
+            type Error = KeyError;
+
+            fn $method<T: Serialize + ?Sized>(&mut self, $($name: &'static str,)? value: &T) -> Result<(), KeyError> {
+                $(let _ = $name;)?
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> Result<(), KeyError> {
+                Ok(())
+            }
+        }
+    };
+}
+
+fields!(SerializeTuple, serialize_element);
+fields!(SerializeTupleStruct, serialize_field);
+fields!(SerializeTupleVariant, serialize_field);
+fields!(SerializeStruct, serialize_field, key);
+fields!(SerializeStructVariant, serialize_field, key);
+
+struct KeyDecoder<'de> {
+    input: &'de [u8]
+}
+
+impl<'de> KeyDecoder<'de> {
+    fn part<T: KeyPart>(&mut self) -> Result<T, KeyError> {
+        T::decode_from(&mut self.input).ok_or(KeyError::Malformed)
+    }
+
+    fn tag(&mut self) -> Result<bool, KeyError> {
+        match self.part::<u8>()? {
+            END => Ok(false),
+            MORE => Ok(true),
+            _ => Err(KeyError::Malformed)
+        }
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for &'a mut KeyDecoder<'de> {
+    type Error = KeyError;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, KeyError> {
+        Err(KeyError::Unsupported("A self-describing type"))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_bool(self.part()?)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_i8(self.part()?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_i16(self.part()?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_i32(self.part()?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_i64(self.part()?)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_u8(self.part()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_u16(self.part()?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_u32(self.part()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_u64(self.part()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_f32(self.part()?)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_f64(self.part()?)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        let c = std::char::from_u32(self.part()?).ok_or(KeyError::Malformed)?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_string(self.part()?)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_byte_buf(keys::unescape(&mut self.input).ok_or(KeyError::Malformed)?)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        if self.tag()? { visitor.visit_some(self) } else { visitor.visit_none() }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_seq(Elements { decoder: self, remaining: None })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_seq(Elements { decoder: self, remaining: Some(len) })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, len: usize, visitor: V) -> Result<V::Value, KeyError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, KeyError> {
+        Err(KeyError::Unsupported("A map"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], visitor: V) -> Result<V::Value, KeyError> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, KeyError> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, KeyError> {
+        Err(KeyError::Unsupported("An identifier"))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, KeyError> {
+        Err(KeyError::Unsupported("An ignored value"))
+    }
+}
+
+/// The elements of a sequence, which are tagged, or of a tuple, which has `remaining` fields
+struct Elements<'a, 'de> {
+    decoder: &'a mut KeyDecoder<'de>,
+    remaining: Option<usize>
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for Elements<'a, 'de> {
+    type Error = KeyError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, KeyError> {
+        let more = match &mut self.remaining {
+            Some(0) => false,
+            Some(remaining) => {
+                *remaining -= 1;
+                true
+            },
+            None => self.decoder.tag()?
+        };
+        if !more { return Ok(None) }
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.remaining
+    }
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for &'a mut KeyDecoder<'de> {
+    type Error = KeyError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), KeyError> {
+        let index: u32 = self.part()?;
+        let variant = seed.deserialize(IntoDeserializer::<KeyError>::into_deserializer(index))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a> de::VariantAccess<'de> for &'a mut KeyDecoder<'de> {
+    type Error = KeyError;
+
+    fn unit_variant(self) -> Result<(), KeyError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, KeyError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, KeyError> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, KeyError> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fmt::Debug;
+    use serde_crate::de::DeserializeOwned;
+    use super::*;
+
+    /// Every value round-trips, and encoded values sort in the order given
+    fn check_sorted<K: Serialize + DeserializeOwned + PartialEq + Debug>(values: &[K]) {
+        let encoded: Vec<Vec<u8>> = values.iter().map(|value| encode_key(value).unwrap()).collect();
+        for (value, bytes) in values.iter().zip(&encoded) {
+            assert_eq!(&decode_key::<K>(bytes).unwrap(), value);
+        }
+        for pair in encoded.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} sorts after {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn primitives_match_keys() {
+        let key = (-5i64, "name\0".to_string(), Some(3u16), true, 1.5f64);
+        assert_eq!(encode_key(&key).unwrap(), &keys::encode(&key)[..]);
+    }
+
+    #[test]
+    fn sequences_sort_before_their_extensions() {
+        check_sorted(&[vec![], vec![0u32], vec![0, 0], vec![0, 1], vec![1]]);
+        check_sorted(&[(vec!["a".to_string()], 9u8), (vec!["a".to_string(), String::new()], 0), (vec!["b".to_string()], 0)]);
+    }
+
+    #[test]
+    fn options_and_chars() {
+        check_sorted(&[None, Some(String::new()), Some("a".to_string())]);
+        check_sorted(&['\0', 'a', 'z', '\u{10FFFF}']);
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_encode() {
+        let map: BTreeMap<u8, u8> = BTreeMap::new();
+        assert_eq!(encode_key(&map), Err(KeyError::Unsupported("A map")));
+        assert_eq!(decode_key::<u16>(&[0, 1, 2]), Err(KeyError::Malformed));
+        assert_eq!(decode_key::<Vec<u8>>(&[2]), Err(KeyError::Malformed));
+        assert_eq!(decode_key::<bool>(&[]), Err(KeyError::Malformed));
+    }
+}
@@ -0,0 +1,199 @@
+//! Leaf page encoding. A leaf holds sorted keys, each with its value inline when it's at
+//! most the inline threshold, or otherwise a pointer into the value log.
+//!
+//! Layout of the page data: `[codec: u8][body_len: u16][body]`, where the body is compressed
+//! with `codec` and is `[entries: u16][prefix_len: u16][prefix]`, then for each entry
+//! `[suffix_len: u16][kind: u8][suffix][value]`. Every key is the prefix followed by its suffix,
+//! so keys sharing a long prefix, such as URLs, store it once per leaf. An inline value is
+//! `[len: u16][bytes]` and a logged value is an encoded `ValuePointer`.
+
+use bytes::Bytes;
+
+use super::{PageCache, RetrieveError};
+use super::compression::{self, Compression, Dictionaries};
+use super::page::{Cursor, PageContent, PageType, PAGE_DATA_LEN};
+use super::transaction::Transaction;
+use super::value_log::{self, ValueLogWriter, ValuePointer};
+
+pub const DEFAULT_INLINE_THRESHOLD: usize = 256;
+
+/// Inline values are capped so that a leaf always fits a few entries
+pub const MAX_INLINE_THRESHOLD: usize = PAGE_DATA_LEN / 4;
+
+/// Keys are capped so that a leaf or branch always fits a few
+pub const MAX_KEY_LEN: usize = PAGE_DATA_LEN / 4;
+
+pub const DEFAULT_MAX_VALUE_LEN: u64 = 1 << 30;
+
+const INLINE: u8 = 0;
+const LOGGED: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LeafValue {
+    Inline(Bytes),
+    Logged(ValuePointer)
+}
+
+impl LeafValue {
+    /// Store small values inline, and append the rest to the value log
+    pub fn store(txn: &Transaction, log: &mut ValueLogWriter, key: &[u8], value: Bytes, inline_threshold: usize, compression: Compression) -> LeafValue {
+        if value.len() <= inline_threshold.min(MAX_INLINE_THRESHOLD) {
+            LeafValue::Inline(value)
+        } else {
+            LeafValue::Logged(log.append(txn, key, &value, compression))
+        }
+    }
+
+    pub async fn read(&self, cache: &PageCache, dictionaries: &Dictionaries) -> Result<Bytes, RetrieveError> {
+        match self {
+            LeafValue::Inline(value) => Ok(value.clone()),
+            LeafValue::Logged(ptr) => value_log::read_value(cache, dictionaries, *ptr).await
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        1 + match self {
+            LeafValue::Inline(value) => 2 + value.len(),
+            LeafValue::Logged(_) => ValuePointer::ENCODED_LEN
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LeafEntry {
+    pub key: Bytes,
+    pub value: LeafValue
+}
+
+impl LeafEntry {
+    pub fn encoded_len(&self) -> usize {
+        2 + self.key.len() + self.value.encoded_len()
+    }
+}
+
+/// Bytes before a leaf's body: the codec, and the body's stored length
+const LEAF_HEADER_LEN: usize = 1 + 2;
+
+pub(crate) const BODY_CAPACITY: usize = PAGE_DATA_LEN - LEAF_HEADER_LEN;
+
+/// Compressed leaves hold at most this much, which bounds decompression on corrupt input
+const MAX_BODY_LEN: usize = 4 * PAGE_DATA_LEN;
+
+/// The prefix shared by every key of sorted entries
+fn common_prefix(entries: &[LeafEntry]) -> &[u8] {
+    match (entries.first(), entries.last()) {
+        (Some(first), Some(last)) => {
+            let len = first.key.iter().zip(last.key.iter()).take_while(|(a, b)| a == b).count();
+            &first.key[..len]
+        },
+        _ => &[]
+    }
+}
+
+/// Bytes of a leaf holding `entries`, before compression
+pub(crate) fn body_len(entries: &[LeafEntry]) -> usize {
+    let prefix_len = common_prefix(entries).len();
+    2 + 2 + prefix_len + entries.iter().map(|entry| entry.encoded_len() - prefix_len).sum::<usize>()
+}
+
+/// Whether entries fit in one leaf page without compression
+pub(crate) fn fits(entries: &[LeafEntry]) -> bool {
+    body_len(entries) <= BODY_CAPACITY
+}
+
+fn encode_body(entries: &[LeafEntry]) -> Vec<u8> {
+    let prefix = common_prefix(entries);
+    let mut buf = Vec::with_capacity(body_len(entries));
+    buf.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    buf.extend_from_slice(&(prefix.len() as u16).to_le_bytes());
+    buf.extend_from_slice(prefix);
+
+    for entry in entries {
+        let suffix = &entry.key[prefix.len()..];
+        buf.extend_from_slice(&(suffix.len() as u16).to_le_bytes());
+        match &entry.value {
+            LeafValue::Inline(value) => {
+                buf.push(INLINE);
+                buf.extend_from_slice(suffix);
+                buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+                buf.extend_from_slice(value);
+            },
+            LeafValue::Logged(ptr) => {
+                let mut encoded = [0; ValuePointer::ENCODED_LEN];
+                ptr.encode(&mut encoded);
+                buf.push(LOGGED);
+                buf.extend_from_slice(suffix);
+                buf.extend_from_slice(&encoded);
+            }
+        }
+    }
+
+    buf
+}
+
+/// Encode sorted entries into a leaf page, or `None` if they don't fit. Leaves are only
+/// compressed when they'd overflow the page otherwise.
+pub(crate) fn encode(entries: &[LeafEntry], compression: Compression) -> Option<PageContent> {
+    if body_len(entries) > MAX_BODY_LEN { return None }
+    let body = encode_body(entries);
+
+    let (codec, stored) = if body.len() <= BODY_CAPACITY {
+        (compression::NONE, body)
+    } else {
+        compression.compress(&body, &Dictionaries::default()).filter(|(_, compressed)| compressed.len() <= BODY_CAPACITY)?
+    };
+
+    let mut page = PageContent::new(PageType::Leaf);
+    page.data[0] = codec;
+    page.data[1..3].copy_from_slice(&(stored.len() as u16).to_le_bytes());
+    page.data[LEAF_HEADER_LEN..LEAF_HEADER_LEN + stored.len()].copy_from_slice(&stored);
+
+    Some(page)
+}
+
+/// Decode the entries of a leaf page, or `None` if it isn't a well-formed leaf
+pub(crate) fn decode(page: &PageContent) -> Option<Vec<LeafEntry>> {
+    if !matches!(page.page_type, PageType::Leaf) { return None }
+
+    let codec = page.data[0];
+    let stored_len = u16::from_le_bytes([page.data[1], page.data[2]]) as usize;
+    let stored = page.data.get(LEAF_HEADER_LEN..LEAF_HEADER_LEN + stored_len)?;
+
+    let decompressed;
+    let body = match codec {
+        compression::NONE => stored,
+        codec => {
+            decompressed = compression::decompress(codec, stored, MAX_BODY_LEN, &Dictionaries::default())?;
+            &decompressed[..]
+        }
+    };
+
+    let mut buf = Cursor::new(body);
+
+    let count = buf.u16()?;
+    let prefix_len = buf.u16()?;
+    let prefix = buf.take(prefix_len)?;
+    let mut entries = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let suffix_len = buf.u16()?;
+        let kind = buf.take(1)?[0];
+        let mut key = Vec::with_capacity(prefix_len + suffix_len);
+        key.extend_from_slice(prefix);
+        key.extend_from_slice(buf.take(suffix_len)?);
+        let key = Bytes::from(key);
+
+        let value = match kind {
+            INLINE => {
+                let len = buf.u16()?;
+                LeafValue::Inline(Bytes::copy_from_slice(buf.take(len)?))
+            },
+            LOGGED => LeafValue::Logged(ValuePointer::decode(buf.take(ValuePointer::ENCODED_LEN)?)),
+            _ => return None
+        };
+
+        entries.push(LeafEntry { key, value });
+    }
+
+    Some(entries)
+}
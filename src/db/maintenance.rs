@@ -0,0 +1,53 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::DB;
+
+/// Counts outstanding pauses of background maintenance (the work of `run_in_background`, and
+/// re-encrypting pages after a key rotation). Maintenance tasks check it between units of
+/// work, so a pause takes effect once the current unit finishes.
+pub(crate) struct MaintenanceGate {
+    pauses: AtomicUsize
+}
+
+impl MaintenanceGate {
+    pub fn new() -> MaintenanceGate {
+        MaintenanceGate { pauses: AtomicUsize::new(0) }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pauses.load(Ordering::Acquire) > 0
+    }
+}
+
+/// Holds background maintenance paused until dropped. Pauses nest: maintenance resumes
+/// once every guard is gone.
+#[must_use = "maintenance resumes as soon as the guard is dropped"]
+pub struct MaintenancePause {
+    gate: Arc<MaintenanceGate>
+}
+
+impl Drop for MaintenancePause {
+    fn drop(&mut self) {
+        self.gate.pauses.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl DB {
+    /// Halt background flushes, expiry sweeps and re-encryption, e.g. during a traffic spike
+    /// or while an external tool copies the file. Reads and writes carry on as normal.
+    pub fn pause_maintenance(&self) -> MaintenancePause {
+        self.maintenance.pauses.fetch_add(1, Ordering::AcqRel);
+        MaintenancePause { gate: self.maintenance.clone() }
+    }
+
+    /// Release a pause. The same as dropping it.
+    pub fn resume_maintenance(&self, pause: MaintenancePause) {
+        std::mem::drop(pause);
+    }
+
+    /// Whether any `MaintenancePause` is held
+    pub fn is_maintenance_paused(&self) -> bool {
+        self.maintenance.is_paused()
+    }
+}
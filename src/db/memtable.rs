@@ -0,0 +1,313 @@
+//! The write buffer: committed writes accumulate here, deduplicated by key, and are applied
+//! to the tree in batches instead of copying the path to every changed key on each commit.
+//!
+//! Each buffered commit is durable through the journal, a list of overflow chains linked
+//! newest first from the version header. A record's payload is `[prev: u64][entries: u32]`,
+//! then for each entry `[key_len: u16][kind: u8][key][value]`, where an inline value is
+//! `[len: u16][bytes]`, a logged value is an encoded `ValuePointer`, and a deleted key has
+//! no value.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::time::{Duration, Instant, SystemTime};
+use bytes::Bytes;
+
+use super::{DB, OperationKind, PageCache, PageIndex, RetrieveError, WriteError};
+use super::leaf::LeafValue;
+use super::overflow;
+use super::page::Cursor;
+use super::transaction::Transaction;
+use super::tree::{self, NodeFormat, Write};
+use super::value_log::ValuePointer;
+use super::version::VersionHeader;
+
+const NO_PAGE: PageIndex = u64::MAX;
+
+const INLINE: u8 = 0;
+const LOGGED: u8 = 1;
+const DELETED: u8 = 2;
+
+/// When the write buffer is applied to the tree. Whichever limit is reached first triggers it.
+#[derive(Debug, Clone, Copy)]
+pub struct FlushPolicy {
+    /// Apply once the buffered writes take up this many bytes
+    pub max_bytes: usize,
+    /// Apply once the oldest buffered write is this old. Checked on commit and by
+    /// `DB::flush_write_buffer_if_due`.
+    pub max_age: Option<Duration>
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy { max_bytes: 4 * 1024 * 1024, max_age: Some(Duration::from_secs(1)) }
+    }
+}
+
+/// Committed writes not yet applied to the tree
+pub(crate) struct MemTable {
+    entries: BTreeMap<Bytes, Option<LeafValue>>,
+    /// Approximate encoded size of the entries
+    bytes: usize,
+    /// When the oldest entry was buffered
+    since: Option<SystemTime>
+}
+
+fn entry_len(key: &[u8], value: &Option<LeafValue>) -> usize {
+    key.len() + value.as_ref().map_or(1, LeafValue::encoded_len)
+}
+
+impl MemTable {
+    pub fn new() -> MemTable {
+        MemTable { entries: BTreeMap::new(), bytes: 0, since: None }
+    }
+
+    /// The buffered change to `key`: `Some(None)` if it was deleted, or `None` if the tree
+    /// holds its latest value
+    pub fn get(&self, key: &[u8]) -> Option<Option<LeafValue>> {
+        self.entries.get(key).cloned()
+    }
+
+    pub fn insert(&mut self, writes: &[Write], now: SystemTime) {
+        if self.since.is_none() && !writes.is_empty() {
+            self.since = Some(now);
+        }
+        for (key, value) in writes {
+            self.bytes += entry_len(key, value);
+            if let Some(old) = self.entries.insert(key.clone(), value.clone()) {
+                self.bytes -= entry_len(key, &old);
+            }
+        }
+    }
+
+    /// Approximate encoded size of the buffered writes
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Whether `policy` says to apply the buffer to the tree
+    pub fn is_due(&self, policy: &FlushPolicy, now: SystemTime) -> bool {
+        let too_old = match (self.since, policy.max_age) {
+            (Some(since), Some(max_age)) => now.duration_since(since).map_or(false, |age| age >= max_age),
+            _ => false
+        };
+        self.bytes >= policy.max_bytes || too_old
+    }
+
+    /// Buffered writes to keys within the bounds, in key order
+    pub fn range(&self, from: Bound<Bytes>, to: Bound<Bytes>) -> Vec<Write> {
+        self.entries.range((from, to)).map(|(key, value)| (key.clone(), value.clone())).collect()
+    }
+
+    /// Release the buffered values in the value log that `writes` replace, as they'll never
+    /// reach the tree to be released there
+    pub fn release_replaced(&self, txn: &Transaction, writes: &[Write]) {
+        for (key, value) in writes {
+            if let Some(Some(LeafValue::Logged(ptr))) = self.entries.get(key) {
+                if *value != Some(LeafValue::Logged(*ptr)) { txn.release_value(key, ptr) }
+            }
+        }
+    }
+
+    /// The buffered writes, with `writes` applied on top
+    pub fn merged(&self, writes: Vec<Write>) -> Vec<Write> {
+        let mut merged = self.entries.clone();
+        merged.extend(writes);
+        merged.into_iter().collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+        self.since = None;
+    }
+}
+
+/// Encode a commit's writes as a journal record following `prev`
+pub(crate) fn encode_record(prev: Option<PageIndex>, writes: &[Write]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&prev.unwrap_or(NO_PAGE).to_le_bytes());
+    buf.extend_from_slice(&(writes.len() as u32).to_le_bytes());
+
+    for (key, value) in writes {
+        buf.extend_from_slice(&(key.len() as u16).to_le_bytes());
+        match value {
+            Some(LeafValue::Inline(value)) => {
+                buf.push(INLINE);
+                buf.extend_from_slice(key);
+                buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+                buf.extend_from_slice(value);
+            },
+            Some(LeafValue::Logged(ptr)) => {
+                let mut encoded = [0; ValuePointer::ENCODED_LEN];
+                ptr.encode(&mut encoded);
+                buf.push(LOGGED);
+                buf.extend_from_slice(key);
+                buf.extend_from_slice(&encoded);
+            },
+            None => {
+                buf.push(DELETED);
+                buf.extend_from_slice(key);
+            }
+        }
+    }
+
+    buf
+}
+
+pub(super) fn decode_record(data: &[u8]) -> Option<(Option<PageIndex>, Vec<Write>)> {
+    let mut buf = Cursor::new(data);
+
+    let prev = match buf.u64()? {
+        NO_PAGE => None,
+        idx => Some(idx)
+    };
+    let count = buf.u32()?;
+
+    let mut writes = Vec::with_capacity(count.min(data.len()));
+    for _ in 0..count {
+        let key_len = buf.u16()?;
+        let kind = buf.take(1)?[0];
+        let key = Bytes::copy_from_slice(buf.take(key_len)?);
+
+        let value = match kind {
+            INLINE => {
+                let len = buf.u16()?;
+                Some(LeafValue::Inline(Bytes::copy_from_slice(buf.take(len)?)))
+            },
+            LOGGED => Some(LeafValue::Logged(ValuePointer::decode(buf.take(ValuePointer::ENCODED_LEN)?))),
+            DELETED => None,
+            _ => return None
+        };
+
+        writes.push((key, value));
+    }
+
+    Some((prev, writes))
+}
+
+/// Rebuild the write buffer from the journal starting at `journal`. A journal can't be longer
+/// than the file, which bounds the walk if its links are corrupt.
+pub(crate) async fn replay(cache: &PageCache, journal: Option<PageIndex>, page_count: u64, now: SystemTime) -> Result<MemTable, RetrieveError> {
+    let mut records = vec![];
+    let mut next = journal;
+
+    while let Some(idx) = next {
+        if records.len() as u64 >= page_count { return Err(RetrieveError::Malformed(idx)) }
+
+        let data = overflow::read_chain(cache, idx, 0).await?;
+        let (prev, writes) = decode_record(&data).ok_or(RetrieveError::Malformed(idx))?;
+        records.push(writes);
+        next = prev;
+    }
+
+    let mut table = MemTable::new();
+    for writes in records.iter().rev() {
+        table.insert(writes, now);
+    }
+
+    Ok(table)
+}
+
+/// The latest change to `key` in the journal starting at `journal`, if it has one
+pub(crate) async fn journal_get(cache: &PageCache, journal: Option<PageIndex>, page_count: u64, key: &[u8]) -> Result<Option<Option<LeafValue>>, RetrieveError> {
+    let mut next = journal;
+    let mut records = 0;
+
+    while let Some(idx) = next {
+        if records >= page_count { return Err(RetrieveError::Malformed(idx)) }
+        records += 1;
+
+        let data = overflow::read_chain(cache, idx, 0).await?;
+        let (prev, writes) = decode_record(&data).ok_or(RetrieveError::Malformed(idx))?;
+        if let Some((_, value)) = writes.into_iter().find(|(written, _)| &written[..] == key) {
+            return Ok(Some(value))
+        }
+        next = prev;
+    }
+
+    Ok(None)
+}
+
+impl DB {
+    /// Apply the write buffer to the tree in one transaction on top of `version`, and empty
+    /// the journal. The caller must hold `writer`.
+    pub(super) async fn apply_write_buffer(&self, version: VersionHeader) -> Result<VersionHeader, WriteError> {
+        let started = Instant::now();
+        let writes = self.write_buffer.lock().merged(vec![]);
+        if writes.is_empty() && version.journal.is_none() { return Ok(version) }
+        let previous = version;
+
+        let (max_depth, format, durability, observer) = {
+            let options = self.options.lock();
+            (options.max_tree_depth, NodeFormat::new(&options), options.durability, options.observer.clone())
+        };
+
+        let txn = Transaction::new(version.tx + 1, self.store.clone(), self.write_back.clone(), durability, version.page_count);
+        let tree_root = tree::apply(&self.cache, &txn, version.tree_root, &writes, max_depth, format).await?;
+        let segments = self.value_log.lock().write_table(&txn, version.segments);
+
+        let version = VersionHeader {
+            tx: txn.idx(),
+            tree_root,
+            page_count: txn.page_count(),
+            journal: None,
+            segments,
+            ..version
+        };
+        txn.commit(version).await?;
+        self.value_log.lock().committed(&txn);
+
+        // readers take the buffer and version together, so they never see neither
+        {
+            let mut buffer = self.write_buffer.lock();
+            *self.version.lock() = version;
+            buffer.clear();
+        }
+
+        observer.on_commit(version.tx);
+        self.report_slow_write(started, OperationKind::Checkpoint, &previous, &version);
+        Ok(version)
+    }
+
+    /// Apply every buffered write to the tree now, rather than waiting for the buffer to fill
+    pub async fn flush_write_buffer(&self) -> Result<(), WriteError> {
+        self.check_writable()?;
+        let _writer = self.writer.lock().await;
+
+        let version = *self.version.lock();
+        self.apply_write_buffer(version).await?;
+        Ok(())
+    }
+
+    /// Apply the write buffer if the flush policy says it's due. Call this periodically to
+    /// honor `max_age` while no commits arrive. Returns whether the buffer was applied.
+    pub async fn flush_write_buffer_if_due(&self) -> Result<bool, WriteError> {
+        let (policy, clock) = {
+            let options = self.options.lock();
+            (options.write_buffer, options.clock.clone())
+        };
+        let policy = match policy {
+            Some(policy) => policy,
+            None => return Ok(false)
+        };
+        if !self.write_buffer.lock().is_due(&policy, clock.now()) { return Ok(false) }
+
+        self.check_writable()?;
+        let _writer = self.writer.lock().await;
+
+        // a commit may have applied it while we waited
+        if !self.write_buffer.lock().is_due(&policy, clock.now()) { return Ok(false) }
+
+        let version = *self.version.lock();
+        self.apply_write_buffer(version).await?;
+        Ok(true)
+    }
+
+    /// The buffered writes to keys within the bounds, and the version whose tree they apply
+    /// to, taken together
+    pub(super) fn buffered_range(&self, from: Bound<Bytes>, to: Bound<Bytes>) -> (VersionHeader, Vec<Write>) {
+        let buffer = self.write_buffer.lock();
+        let version = *self.version.lock();
+        (version, buffer.range(from, to))
+    }
+}
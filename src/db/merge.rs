@@ -0,0 +1,34 @@
+//! Merge operators, which fold operands queued by `WriteTransaction::merge` into a key's value,
+//! so counters, sets and lists are updated without the caller reading them first
+
+use std::sync::Arc;
+use bytes::Bytes;
+
+use super::DB;
+
+/// Folds one operand into a key's existing value, if it has one, returning the new value
+pub(crate) type MergeOperator = Arc<dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Bytes + Send + Sync>;
+
+impl DB {
+    /// Set the operator that folds the operands of `WriteTransaction::merge` into values. It's
+    /// called with the key, its existing value if any, and one operand, and returns the new
+    /// value. Operands are folded on commit, in the order they were merged.
+    pub fn register_merge_operator<F>(&self, operator: F)
+        where F: Fn(&[u8], Option<&[u8]>, &[u8]) -> Bytes + Send + Sync + 'static
+    {
+        *self.merge_operator.lock() = Some(Arc::new(operator));
+    }
+
+    pub(super) fn has_merge_operator(&self) -> bool {
+        self.merge_operator.lock().is_some()
+    }
+
+    /// Fold `operands` into `value` in order
+    pub(super) fn fold(&self, key: &[u8], mut value: Option<Bytes>, operands: &[Bytes]) -> Bytes {
+        let operator = self.merge_operator.lock().clone().expect("operands are only queued with a merge operator");
+        for operand in operands {
+            value = Some(operator(key, value.as_deref(), operand));
+        }
+        value.expect("a merged key has at least one operand")
+    }
+}
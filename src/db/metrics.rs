@@ -0,0 +1,247 @@
+//! Counters behind `DB::stats`, kept in atomics so that recording never takes a lock
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::{DB, CacheStats, PageIndex, ReplicationLag, RetrieveError, StoreMetrics, ValueLogStats};
+use super::branch::Branch;
+use super::page::PageType;
+use super::tree::read_node;
+
+/// Buckets in a latency histogram
+const BUCKETS: usize = 32;
+
+/// Counts durations in buckets that double in width: bucket `i` counts durations under `2^i`
+/// microseconds, and the last bucket everything longer too
+#[derive(Default)]
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    sum_micros: AtomicU64
+}
+
+impl Histogram {
+    pub fn record(&self, duration: Duration) {
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (64 - micros.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LatencyHistogram {
+        let mut counts = [0; BUCKETS];
+        for (count, bucket) in counts.iter_mut().zip(self.buckets.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        LatencyHistogram { counts, sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)) }
+    }
+}
+
+/// A snapshot of a latency histogram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKETS],
+    sum: Duration
+}
+
+impl LatencyHistogram {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The total of every duration counted
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            count => Some(Duration::from_micros(self.sum.as_micros() as u64 / count))
+        }
+    }
+
+    /// Each bucket's upper bound and count, in increasing order. The last bucket has no bound.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.counts.iter().enumerate().map(|(i, &count)| {
+            let bound = if i == BUCKETS - 1 { None } else { Some(Duration::from_micros(1 << i)) };
+            (bound, count)
+        })
+    }
+
+    /// An upper bound on the `q` quantile, e.g. 0.99 for p99, or `None` if nothing was counted
+    /// or it's in the last bucket
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 { return None }
+
+        let rank = ((count as f64 * q.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.buckets().find(|(_, bucket)| {
+            seen += bucket;
+            seen >= rank
+        }).and_then(|(bound, _)| bound)
+    }
+}
+
+/// Counters kept by the database itself, rather than its cache or store
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub commits: AtomicU64,
+    pub commit_latency: Histogram,
+    pub active_transactions: AtomicU64,
+    pub delayed_writes: AtomicU64,
+    pub stalled_writes: AtomicU64
+}
+
+/// Counts a write transaction as active from when it starts waiting for the writer lock
+/// until it's dropped
+pub(crate) struct ActiveTransaction<'a>(&'a AtomicU64);
+
+impl<'a> ActiveTransaction<'a> {
+    pub fn begin(active: &'a AtomicU64) -> ActiveTransaction<'a> {
+        active.fetch_add(1, Ordering::Relaxed);
+        ActiveTransaction(active)
+    }
+}
+
+impl<'a> Drop for ActiveTransaction<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A snapshot of the database's counters, from `DB::stats`
+#[derive(Debug, Clone)]
+pub struct Statistics {
+    pub cache: CacheStats,
+    /// Pages read and written, if the page store counts them
+    pub store: Option<StoreMetrics>,
+    pub commits: u64,
+    /// Write transactions begun and not yet committed or dropped, including those waiting for
+    /// the one in progress
+    pub active_transactions: u64,
+    /// Approximate size of the committed writes in the write buffer, which the journal holds
+    /// until they're applied to the tree
+    pub write_buffer_bytes: u64,
+    /// Levels of the tree, from the root to the leaves, or zero while it's empty
+    pub tree_height: usize,
+    /// Pages allocated in the file. Pages are only ever appended, never freed for reuse, so
+    /// there's no count of free pages.
+    pub page_count: u64,
+    /// Live snapshots, scans and backups, each pinning the version it reads
+    pub snapshots: u64,
+    /// Page writes queued for the write-back thread and not yet completed
+    pub dirty_pages: u64,
+    /// Space taken by values kept out of the tree, and how much of it is garbage
+    pub value_log: ValueLogStats,
+    /// Commits delayed by backpressure
+    pub delayed_writes: u64,
+    /// Writes failed with `WriteStall`
+    pub stalled_writes: u64,
+    /// Time from the start of `WriteTransaction::commit` until the new version is durable
+    pub commit_latency: LatencyHistogram,
+    /// How far behind its primary a follower is, or `None` if this isn't a follower
+    pub replication: Option<ReplicationLag>
+}
+
+impl Statistics {
+    /// The share of cache gets served from memory, or `None` before the first get
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        match self.cache.hits + self.cache.misses {
+            0 => None,
+            gets => Some(self.cache.hits as f64 / gets as f64)
+        }
+    }
+
+    /// Encode in the Prometheus text exposition format, to serve from a `/metrics` endpoint.
+    /// Every metric is prefixed `bssdb_`.
+    pub fn encode_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+            let _ = writeln!(out, "# HELP bssdb_{} {}\n# TYPE bssdb_{} {}\nbssdb_{} {}", name, help, name, kind, name, value);
+        };
+
+        metric("cache_hits_total", "counter", "Page cache gets served from memory.", self.cache.hits as f64);
+        metric("cache_misses_total", "counter", "Page cache gets that read from the store.", self.cache.misses as f64);
+        metric("cache_hit_ratio", "gauge", "Share of page cache gets served from memory.", self.cache_hit_rate().unwrap_or(0.0));
+        metric("cache_bytes", "gauge", "Bytes of pages held in the cache.", self.cache.used_bytes as f64);
+        metric("cache_max_bytes", "gauge", "The cache's memory budget in bytes.", self.cache.max_bytes as f64);
+        metric("checksum_failures_total", "counter", "Pages that failed checksum verification.", self.cache.checksums_failed as f64);
+        if let Some(store) = &self.store {
+            metric("pages_read_total", "counter", "Pages read from the store.", store.pages_read as f64);
+            metric("pages_written_total", "counter", "Pages written to the store.", store.pages_written as f64);
+            metric("bytes_written_total", "counter", "Bytes written to the store.", store.bytes_written as f64);
+            metric("io_in_flight", "gauge", "I/O submissions not yet completed.", store.in_flight as f64);
+        }
+        metric("commits_total", "counter", "Committed write transactions.", self.commits as f64);
+        metric("active_transactions", "gauge", "Write transactions open or waiting to begin.", self.active_transactions as f64);
+        metric("write_buffer_bytes", "gauge", "Approximate bytes of committed writes in the write buffer and journal.", self.write_buffer_bytes as f64);
+        metric("tree_height", "gauge", "Levels of the tree.", self.tree_height as f64);
+        metric("pages", "gauge", "Pages allocated in the file.", self.page_count as f64);
+        metric("snapshots", "gauge", "Live snapshots, scans and backups.", self.snapshots as f64);
+        metric("dirty_pages", "gauge", "Page writes queued and not yet completed.", self.dirty_pages as f64);
+        metric("value_log_bytes", "gauge", "Bytes of records appended to the value log.", self.value_log.written_bytes as f64);
+        metric("value_log_live_bytes", "gauge", "Bytes of value log records still pointed at.", self.value_log.live_bytes as f64);
+        metric("delayed_writes_total", "counter", "Commits delayed by backpressure.", self.delayed_writes as f64);
+        metric("stalled_writes_total", "counter", "Writes failed because pending writes are past the stop threshold.", self.stalled_writes as f64);
+        if let Some(lag) = &self.replication {
+            metric("replication_lag_transactions", "gauge", "The primary's transactions announced and not yet applied.", lag.transactions as f64);
+            metric("replication_seconds_since_applied", "gauge", "Seconds since a replicated transaction was last applied.", lag.since_applied.map_or(0.0, |since| since.as_secs_f64()));
+        }
+
+        let _ = writeln!(out, "# HELP bssdb_commit_latency_seconds Time to commit a write transaction.");
+        let _ = writeln!(out, "# TYPE bssdb_commit_latency_seconds histogram");
+        let mut cumulative = 0;
+        for (bound, count) in self.commit_latency.buckets() {
+            cumulative += count;
+            let le = bound.map_or_else(|| "+Inf".to_string(), |bound| bound.as_secs_f64().to_string());
+            let _ = writeln!(out, "bssdb_commit_latency_seconds_bucket{{le=\"{}\"}} {}", le, cumulative);
+        }
+        let _ = writeln!(out, "bssdb_commit_latency_seconds_sum {}", self.commit_latency.sum().as_secs_f64());
+        let _ = writeln!(out, "bssdb_commit_latency_seconds_count {}", cumulative);
+
+        out
+    }
+}
+
+impl DB {
+    /// Snapshot the database's counters. Reads the pages down the tree's left edge to measure
+    /// its height, which are usually cached.
+    pub async fn stats(&self) -> Result<Statistics, RetrieveError> {
+        let version = *self.version.lock();
+        Ok(Statistics {
+            cache: self.cache.stats(),
+            store: self.store.metrics(),
+            commits: self.metrics.commits.load(Ordering::Relaxed),
+            active_transactions: self.metrics.active_transactions.load(Ordering::Relaxed),
+            write_buffer_bytes: self.write_buffer.lock().bytes() as u64,
+            tree_height: self.tree_height(version.tree_root).await?,
+            page_count: version.page_count,
+            snapshots: self.snapshots.count() as u64,
+            dirty_pages: self.write_back.dirty_pages() as u64,
+            value_log: self.value_log.lock().stats(),
+            delayed_writes: self.metrics.delayed_writes.load(Ordering::Relaxed),
+            stalled_writes: self.metrics.stalled_writes.load(Ordering::Relaxed),
+            commit_latency: self.metrics.commit_latency.snapshot(),
+            replication: self.replication_lag()
+        })
+    }
+
+    async fn tree_height(&self, root: Option<PageIndex>) -> Result<usize, RetrieveError> {
+        let max_depth = self.options.lock().max_tree_depth;
+        let mut height = 0;
+        let mut next = root;
+        while let Some(idx) = next {
+            height += 1;
+            if height > max_depth { return Err(RetrieveError::Malformed(idx)) }
+
+            let page = read_node(&self.cache, idx).await?;
+            next = match page.page_type {
+                PageType::Branch => Some(Branch::decode(&page).ok_or(RetrieveError::Malformed(idx))?.first_child),
+                _ => None
+            };
+        }
+        Ok(height)
+    }
+}
@@ -0,0 +1,143 @@
+//! Upgrading databases written in older on-disk formats. Each migration upgrades from one
+//! format version to the next, and opening an older database runs them in turn, if
+//! `Options::upgrade_format` allows it.
+//!
+//! An upgrade commits like a write transaction: new pages are appended, and the pages of the
+//! old version are left as they were. A copy of the old root page is kept, and the new file
+//! header records it, so the old version can still be found. The header is written last, so
+//! a crash midway leaves the old format version, and the upgrade runs again on the next open.
+
+use std::sync::Arc;
+use futures::future::{BoxFuture, FutureExt};
+
+use super::{OpenError, Options, PageIndex, PageStore, RetrieveError, WriteError};
+use super::branch::Branch;
+use super::compression::Compression;
+use super::header::{FileHeader, FormatError, FORMAT_VERSION};
+use super::leaf;
+use super::page::PageType;
+use super::transaction::Transaction;
+use super::tree::{Builder, NodeFormat};
+use super::version::VersionHeader;
+use super::write_back::WriteBack;
+
+/// Rebuilt leaves are filled to this share of a page, as in a bulk load
+const LEAF_FILL: f64 = 0.9;
+
+/// Entries rebuilt between waits for the pages written so far
+const FLUSH_EVERY: u64 = 64 * 1024;
+
+/// What a migration writes with
+struct Upgrade<'a> {
+    store: &'a dyn PageStore,
+    txn: &'a Transaction,
+    options: &'a Options
+}
+
+/// Upgrades the latest version from format version `from` to `from + 1`
+struct Migration {
+    from: u32,
+    run: for<'a> fn(&'a Upgrade<'a>, VersionHeader) -> BoxFuture<'a, Result<VersionHeader, OpenError>>
+}
+
+/// Every migration, oldest first. Versions before the first can't be upgraded.
+const MIGRATIONS: &[Migration] = &[
+    Migration { from: 4, run: add_branch_stats }
+];
+
+/// Bring the database in `store`, whose header is `header`, up to the current format.
+/// Returns the latest version, which is `version` if the database is already current.
+pub(crate) async fn upgrade(store: &Arc<dyn PageStore>, header: FileHeader, version: VersionHeader, options: &Options) -> Result<VersionHeader, OpenError> {
+    let found = header.format_version;
+    if found == FORMAT_VERSION { return Ok(version) }
+
+    let oldest = MIGRATIONS.first().map_or(FORMAT_VERSION, |migration| migration.from);
+    if found < oldest { return Err(FormatError::NoMigration { found, oldest }.into()) }
+    if !options.upgrade_format || options.read_only {
+        return Err(FormatError::NeedsUpgrade { found, supported: FORMAT_VERSION }.into())
+    }
+
+    let write_back = Arc::new(WriteBack::new(store.clone()).map_err(Arc::new)?);
+    let txn = Transaction::new(version.tx + 1, store.clone(), write_back.clone(), options.durability, version.page_count);
+    // a copy of the old root, which the new header points to
+    let backup = txn.alloc_run(1, 1);
+    txn.write_new_page(backup, Box::new(version.encode()));
+
+    let upgrade = Upgrade { store: &**store, txn: &txn, options };
+    let mut upgraded = version;
+    for migration in MIGRATIONS.iter().filter(|migration| migration.from >= found) {
+        upgraded = (migration.run)(&upgrade, upgraded).await?;
+    }
+
+    let upgraded = VersionHeader { tx: txn.idx(), page_count: txn.page_count(), ..upgraded };
+    txn.commit(upgraded).await.map_err(Arc::new)?;
+
+    let header = FileHeader { format_version: FORMAT_VERSION, pre_upgrade: Some((found, backup)), ..header };
+    header.write(&**store, options.durability).await.map_err(Arc::new)?;
+
+    Ok(upgraded)
+}
+
+fn open_error(err: WriteError, root: PageIndex) -> OpenError {
+    match err {
+        WriteError::Io(err) | WriteError::Full(err) => err.into(),
+        WriteError::Retrieve(err) => err.into(),
+        // the entries fit in the old tree, so they fit in the new one unless it's corrupt
+        _ => RetrieveError::Malformed(root).into()
+    }
+}
+
+/// Rebuild the tree rooted at `root` from its leaves, returning the new root. Branches are
+/// read without their subtree sizes, so a tree rebuilt by an interrupted upgrade reads too.
+async fn rebuild(upgrade: &Upgrade<'_>, root: Option<PageIndex>, format: NodeFormat) -> Result<Option<PageIndex>, OpenError> {
+    let root = match root {
+        Some(root) => root,
+        None => return Ok(None)
+    };
+
+    let mut builder = Builder::new(format, LEAF_FILL);
+    let mut rebuilt = 0u64;
+    let mut stack = vec![(root, 1)];
+    while let Some((idx, depth)) = stack.pop() {
+        if depth > upgrade.options.max_tree_depth { return Err(RetrieveError::Malformed(idx).into()) }
+        let page = upgrade.store.read_page(idx).await?;
+
+        match page.page_type {
+            PageType::Branch => {
+                let branch = Branch::decode_without_stats(&page).ok_or(RetrieveError::Malformed(idx))?;
+                let children: Vec<PageIndex> = branch.children().collect();
+                stack.extend(children.into_iter().rev().map(|child| (child, depth + 1)));
+            },
+            PageType::Leaf => {
+                for entry in leaf::decode(&page).ok_or(RetrieveError::Malformed(idx))? {
+                    builder.push(upgrade.txn, entry).map_err(|err| open_error(err, root))?;
+
+                    rebuilt += 1;
+                    if rebuilt % FLUSH_EVERY == 0 { upgrade.txn.flush().await.map_err(Arc::new)? }
+                }
+            },
+            _ => return Err(RetrieveError::Malformed(idx).into())
+        }
+    }
+
+    builder.finish(upgrade.txn).map_err(|err| open_error(err, root))
+}
+
+/// Format 5 added subtree sizes to branches. Every tree is rebuilt from its leaves, which
+/// are unchanged, since a full branch may not fit its page once sizes are added.
+fn add_branch_stats<'a>(upgrade: &'a Upgrade<'a>, version: VersionHeader) -> BoxFuture<'a, Result<VersionHeader, OpenError>> {
+    async move {
+        let format = NodeFormat::new(upgrade.options);
+        let uncompressed = NodeFormat { compression: Compression::None, ..format };
+
+        Ok(VersionHeader {
+            tree_root: rebuild(upgrade, version.tree_root, format).await?,
+            expiries: rebuild(upgrade, version.expiries, uncompressed).await?,
+            indexes: rebuild(upgrade, version.indexes, uncompressed).await?,
+            tokens: rebuild(upgrade, version.tokens, uncompressed).await?,
+            // archived versions point to trees in the old format
+            archive: None,
+            ..version
+        })
+    }.boxed()
+}
@@ -0,0 +1,61 @@
+use std::error::Error;
+use std::ops::Range;
+use std::time::Duration;
+use bytes::Bytes;
+
+use super::{Compaction, PageIndex, TransactionIdx};
+
+/// What a slow operation was doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    /// `DB::get`
+    Read,
+    /// `WriteTransaction::commit`
+    Commit,
+    /// Applying the write buffer to the tree
+    Checkpoint
+}
+
+/// An operation that took longer than `Options::slow_operation_threshold`
+#[derive(Debug, Clone)]
+pub struct SlowOperation {
+    pub kind: OperationKind,
+    pub duration: Duration,
+    /// The key read
+    pub key: Option<Bytes>,
+    /// The transaction committed
+    pub tx: Option<TransactionIdx>,
+    /// For reads, the pages on the path from the root to the key's leaf, as it was just after
+    /// the read
+    pub path: Vec<PageIndex>,
+    /// The pages written, all allocated after the previous version's last page
+    pub written: Range<PageIndex>,
+    /// The length of the value read, or the bytes of pages written
+    pub bytes: u64
+}
+
+/// Hooks for embedders to feed their own metrics or logging.
+///
+/// Every method defaults to doing nothing, so implementations only override what they need.
+/// Hooks are called inline on the engine's threads and should return quickly.
+pub trait Observer: Send + Sync {
+    /// A transaction was committed
+    fn on_commit(&self, _txn: TransactionIdx) {}
+
+    /// A chunk was evicted from the page cache
+    fn on_eviction(&self, _idx: PageIndex, _bytes: usize) {}
+
+    /// A value log segment was collected, after its commit (also passed to `on_commit`)
+    fn on_compaction(&self, _compaction: &Compaction) {}
+
+    /// An operation failed
+    fn on_error(&self, _error: &(dyn Error + 'static)) {}
+
+    /// An operation took longer than `Options::slow_operation_threshold`
+    fn on_slow_operation(&self, _operation: &SlowOperation) {}
+}
+
+/// The default observer, which ignores everything
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {}
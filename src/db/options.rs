@@ -0,0 +1,313 @@
+use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+#[cfg(feature = "encryption")]
+use super::EncryptionConfig;
+#[cfg(feature = "fault-injection")]
+use super::Faults;
+
+use super::{DB, OpenError, CacheConfig, ChecksumSampling, Durability, observer::{Observer, NoopObserver}, clock::{Clock, SystemClock}, spawn::{Spawn, ThreadSpawner}, descent::DEFAULT_MAX_DEPTH, leaf::{DEFAULT_INLINE_THRESHOLD, DEFAULT_MAX_VALUE_LEN, MAX_KEY_LEN}, filter::DEFAULT_LEAF_FILTER_LEN, spill::DEFAULT_MAX_TRANSACTION_MEMORY, file_store::DEFAULT_GROWTH_EXTENT, Backpressure, Compression, FlushPolicy, RecoveryMode};
+
+/// Options for opening a database, in the style of `std::fs::OpenOptions`:
+///
+/// ```ignore
+/// let db = DB::options()
+///     .cache_size(256 * 1024 * 1024)
+///     .durability(Durability::SyncAll)
+///     .open("data.bssdb")
+///     .await?;
+/// ```
+#[derive(Clone)]
+pub struct Options {
+    pub(crate) read_only: bool,
+    pub(crate) create: bool,
+    pub(crate) lock_timeout: Option<Duration>,
+    pub(crate) direct_io: bool,
+    pub(crate) growth_extent: Option<u64>,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) cache: CacheConfig,
+    pub(crate) checksums: ChecksumSampling,
+    pub(crate) durability: Durability,
+    pub(crate) max_tree_depth: usize,
+    pub(crate) value_inline_threshold: usize,
+    pub(crate) max_key_len: usize,
+    pub(crate) max_value_len: u64,
+    pub(crate) max_transaction_memory: Option<usize>,
+    pub(crate) spill_dir: Option<PathBuf>,
+    pub(crate) backpressure: Option<Backpressure>,
+    pub(crate) compression: Compression,
+    pub(crate) leaf_filter_len: usize,
+    pub(crate) write_buffer: Option<FlushPolicy>,
+    pub(crate) archive_commits: usize,
+    pub(crate) recovery: RecoveryMode,
+    pub(crate) upgrade_format: bool,
+    #[cfg(feature = "encryption")]
+    pub(crate) encryption: Option<EncryptionConfig>,
+    #[cfg(feature = "fault-injection")]
+    pub(crate) faults: Option<Arc<Faults>>,
+    pub(crate) observer: Arc<dyn Observer>,
+    pub(crate) slow_threshold: Option<Duration>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) spawner: Arc<dyn Spawn>
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            read_only: false,
+            create: true,
+            lock_timeout: None,
+            direct_io: true,
+            growth_extent: Some(DEFAULT_GROWTH_EXTENT),
+            max_size: None,
+            cache: CacheConfig::default(),
+            checksums: ChecksumSampling::default(),
+            durability: Durability::default(),
+            max_tree_depth: DEFAULT_MAX_DEPTH,
+            value_inline_threshold: DEFAULT_INLINE_THRESHOLD,
+            max_key_len: MAX_KEY_LEN,
+            max_value_len: DEFAULT_MAX_VALUE_LEN,
+            max_transaction_memory: Some(DEFAULT_MAX_TRANSACTION_MEMORY),
+            spill_dir: None,
+            backpressure: Some(Backpressure::default()),
+            compression: Compression::default(),
+            leaf_filter_len: DEFAULT_LEAF_FILTER_LEN,
+            write_buffer: None,
+            archive_commits: 0,
+            recovery: RecoveryMode::Strict,
+            upgrade_format: false,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            #[cfg(feature = "fault-injection")]
+            faults: None,
+            observer: Arc::new(NoopObserver),
+            slow_threshold: None,
+            clock: Arc::new(SystemClock),
+            spawner: Arc::new(ThreadSpawner)
+        }
+    }
+
+    /// Open without write permission and with a shared lock, so any number of read-only handles
+    /// (e.g. inspection or backup tools) can have the database open at once. A writer's exclusive
+    /// lock still excludes them. Writes are rejected.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Create the database if the file is missing or empty. Defaults to true. Ignored when read-only.
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// If another process has the database locked, wait up to `timeout` for it to be released
+    /// instead of failing with `OpenError::Locked` immediately. The wait runs on a thread of its
+    /// own, so it doesn't block the executor opening the database.
+    pub fn lock_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Bypass the OS page cache with `O_DIRECT`, on platforms that support it. Defaults to true,
+    /// since pages are already cached by the database.
+    pub fn direct_io(&mut self, direct_io: bool) -> &mut Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// Reserve disk space for the file `bytes` at a time with `fallocate` as it grows, rather
+    /// than letting each page write allocate its own blocks. `None` leaves growth to the file
+    /// system. Defaults to 64MiB. The file's length still only covers pages written.
+    pub fn growth_extent(&mut self, bytes: Option<u64>) -> &mut Self {
+        self.growth_extent = bytes.filter(|&bytes| bytes > 0);
+        self
+    }
+
+    /// Cap the database file at `bytes`. A commit that would grow it further fails with
+    /// `WriteError::Full`, as it does when the disk fills, and leaves the database as it was.
+    /// Pages are never reused, so a full database takes no further commits, even deletes,
+    /// until the cap is raised. `None`, the default, leaves only the disk as the limit.
+    pub fn max_size(&mut self, bytes: Option<u64>) -> &mut Self {
+        self.max_size = bytes;
+        self
+    }
+
+    pub fn cache(&mut self, cache: CacheConfig) -> &mut Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Set the page cache memory budget, in bytes
+    pub fn cache_size(&mut self, max_bytes: usize) -> &mut Self {
+        self.cache.max_bytes = max_bytes;
+        self
+    }
+
+    /// Hold the page cache in 2MiB huge pages where the system provides them. See
+    /// `CacheConfig::huge_pages`.
+    pub fn huge_pages(&mut self, huge_pages: bool) -> &mut Self {
+        self.cache.huge_pages = huge_pages;
+        self
+    }
+
+    /// How often to verify page checksums on reads
+    pub fn checksum_sampling(&mut self, checksums: ChecksumSampling) -> &mut Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// How commits are synced to disk
+    pub fn durability(&mut self, durability: Durability) -> &mut Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Fail reads that descend more than `max_depth` levels into a tree, which only
+    /// happens when child pointers are corrupt
+    pub fn max_tree_depth(&mut self, max_depth: usize) -> &mut Self {
+        self.max_tree_depth = max_depth;
+        self
+    }
+
+    /// Store values of up to `bytes` directly in leaf pages, and larger ones in the value log.
+    /// Defaults to 256 bytes, and is capped at a quarter of a page.
+    pub fn value_inline_threshold(&mut self, bytes: usize) -> &mut Self {
+        self.value_inline_threshold = bytes;
+        self
+    }
+
+    /// Reject writes of keys longer than `bytes` with `WriteError::KeyTooLarge`. Defaults to,
+    /// and is capped at, a quarter of a page.
+    pub fn max_key_len(&mut self, bytes: usize) -> &mut Self {
+        self.max_key_len = bytes.min(MAX_KEY_LEN);
+        self
+    }
+
+    /// Reject writes of values longer than `bytes` with `WriteError::ValueTooLarge`. Defaults to 1GiB.
+    pub fn max_value_len(&mut self, bytes: u64) -> &mut Self {
+        self.max_value_len = bytes;
+        self
+    }
+
+    /// Once a write transaction's changes hold more than `bytes` of memory, spill them to a
+    /// sorted run on disk, so huge transactions don't exhaust memory. Reads of spilled changes
+    /// go to disk, and committing a spilled transaction skips the write buffer. `None` never
+    /// spills. Defaults to 64MiB.
+    pub fn max_transaction_memory(&mut self, bytes: Option<usize>) -> &mut Self {
+        self.max_transaction_memory = bytes;
+        self
+    }
+
+    /// Where spilled transactions' runs go. Defaults to the system's temporary directory.
+    pub fn spill_dir<P: Into<PathBuf>>(&mut self, dir: P) -> &mut Self {
+        self.spill_dir = Some(dir.into());
+        self
+    }
+
+    /// Delay, then refuse, writes while dirty pages or buffered writes pile up faster than
+    /// they're written out. `None` never pushes back. Defaults to `Backpressure::default()`.
+    pub fn backpressure(&mut self, backpressure: Option<Backpressure>) -> &mut Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Compress leaf pages and value log records, with a codec enabled by the `lz4` or
+    /// `zstd` feature. Branch pages are never compressed, to keep descents cheap.
+    pub fn compression(&mut self, compression: Compression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Keep a bloom filter of `bytes` over each leaf's keys in its parent branch, so a lookup
+    /// of a missing key can usually skip reading the leaf. Larger filters have fewer false
+    /// positives, but leave less room for separators. Defaults to 16 bytes, is capped at 255,
+    /// and zero turns filters off. Applies to leaves written from then on.
+    pub fn leaf_filter_len(&mut self, bytes: usize) -> &mut Self {
+        self.leaf_filter_len = bytes;
+        self
+    }
+
+    /// Buffer committed writes in memory, journaling each commit instead of rewriting the
+    /// tree, and apply them in one batch when `policy` says so. Under many small writes,
+    /// this copies far fewer pages. Reads see buffered writes as soon as they commit.
+    pub fn write_buffer(&mut self, policy: Option<FlushPolicy>) -> &mut Self {
+        self.write_buffer = policy;
+        self
+    }
+
+    /// Encrypt every page but the file header at rest. A database is created encrypted or
+    /// not, and must be opened with the same cipher and its current key after that (see
+    /// `DB::rotate_key`). Backup streams are
+    /// not encrypted, but `backup_to` copies are.
+    #[cfg(feature = "encryption")]
+    pub fn encryption(&mut self, config: Option<EncryptionConfig>) -> &mut Self {
+        self.encryption = config;
+        self
+    }
+
+    /// Inject the faults planned in `faults` into the file store. See `Faults`.
+    #[cfg(feature = "fault-injection")]
+    pub fn faults(&mut self, faults: Arc<Faults>) -> &mut Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Keep the state each of the last `commits` commits replaced, so `DB::explain_change` can
+    /// show what they did to a key. Costs a page write per commit. Defaults to zero, which
+    /// drops the archive.
+    pub fn archive_commits(&mut self, commits: usize) -> &mut Self {
+        self.archive_commits = commits;
+        self
+    }
+
+    /// With `RecoveryMode::BestEffort`, keep reading around corrupt tree pages: lookups of
+    /// the keys they held fail, scans skip them, and `DB::corruption_report` lists them
+    pub fn recovery_mode(&mut self, mode: RecoveryMode) -> &mut Self {
+        self.recovery = mode;
+        self
+    }
+
+    /// Upgrade a database written in an older on-disk format to the current one when it's
+    /// opened. Defaults to false, so an older database fails to open with
+    /// `FormatError::NeedsUpgrade` until upgrading is allowed. Ignored when read-only.
+    pub fn upgrade_format(&mut self, upgrade: bool) -> &mut Self {
+        self.upgrade_format = upgrade;
+        self
+    }
+
+    /// Register an observer to be notified of commits, evictions, value log compactions, errors
+    /// and slow operations
+    pub fn observer<O: Observer + 'static>(&mut self, observer: O) -> &mut Self {
+        self.observer = Arc::new(observer);
+        self
+    }
+
+    /// Report reads, commits and checkpoints that take at least `threshold` to the observer's
+    /// `on_slow_operation`, with the pages involved. Defaults to `None`, reporting nothing.
+    pub fn slow_operation_threshold(&mut self, threshold: Option<Duration>) -> &mut Self {
+        self.slow_threshold = threshold;
+        self
+    }
+
+    /// Replace the system clock, e.g. with a `ManualClock` in tests
+    pub fn clock<C: Clock + 'static>(&mut self, clock: C) -> &mut Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Run background tasks on an async runtime, e.g. with `TokioSpawner`, instead of a thread each
+    pub fn spawner<S: Spawn + 'static>(&mut self, spawner: S) -> &mut Self {
+        self.spawner = Arc::new(spawner);
+        self
+    }
+
+    pub async fn open<P: AsRef<Path>>(&self, path: P) -> Result<DB, OpenError> {
+        DB::open(path, self.clone()).await
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options::new()
+    }
+}
@@ -0,0 +1,116 @@
+//! Overflow chains hold data too large for one page, such as oversized keys, across a chain
+//! of `Overflow` pages. Each page's data is `[next: u64][total_len: u64][payload]`, where
+//! `next` is `NO_PAGE` on the last page. Chains are allocated contiguously, so a reader who
+//! knows roughly how long one is can read all of its pages at once.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use bytes::{Bytes, BytesMut};
+use futures::future::join_all;
+
+use super::{PageCache, PageIndex, PageStore, RetrieveError};
+use super::page::{PageContent, PageType, PAGE_DATA_LEN, PAGE_DATA_OFFSET, PAGE_SIZE};
+use super::transaction::Transaction;
+
+const NO_PAGE: PageIndex = u64::MAX;
+
+const CHAIN_HEADER_LEN: usize = 16;
+
+/// Payload bytes held by each page of a chain
+pub const OVERFLOW_PAYLOAD: usize = PAGE_DATA_LEN - CHAIN_HEADER_LEN;
+
+fn pages_for(len: usize) -> usize {
+    ((len + OVERFLOW_PAYLOAD - 1) / OVERFLOW_PAYLOAD).max(1)
+}
+
+/// Write `data` as a chain, returning its first page
+pub(crate) fn write_chain(txn: &Transaction, data: &[u8]) -> PageIndex {
+    let pages = pages_for(data.len()) as u64;
+    let start = txn.alloc_run(pages, 1);
+
+    for (i, payload) in (0..pages).zip(data.chunks(OVERFLOW_PAYLOAD).chain(std::iter::once(&[][..]))) {
+        let next = if i + 1 < pages { start + i + 1 } else { NO_PAGE };
+
+        let mut page = PageContent::new(PageType::Overflow);
+        page.data[0..8].copy_from_slice(&next.to_le_bytes());
+        page.data[8..16].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        page.data[CHAIN_HEADER_LEN..CHAIN_HEADER_LEN + payload.len()].copy_from_slice(payload);
+
+        txn.write_new_page(start + i, Box::new(page));
+    }
+
+    start
+}
+
+fn chain_header(page: &[u8]) -> (PageIndex, usize) {
+    let data = &page[PAGE_DATA_OFFSET..];
+    let next = u64::from_le_bytes(data[0..8].try_into().unwrap());
+    let total_len = u64::from_le_bytes(data[8..16].try_into().unwrap());
+    (next, total_len as usize)
+}
+
+/// Read a page and, if it starts an overflow chain, the rest of the chain. Returns the raw
+/// pages one after another, so each can still be checked against its checksum.
+///
+/// `size_hint` is the expected payload length, if known: that many pages are read at once
+/// instead of following the chain one page at a time.
+pub(crate) async fn read_chunk<S: PageStore + ?Sized>(store: &S, idx: PageIndex, size_hint: u32) -> Result<Bytes, RetrieveError> {
+    let hinted = if size_hint > 0 { pages_for(size_hint as usize) as u64 } else { 1 };
+    let mut prefetched: HashMap<PageIndex, PageContent> = join_all((idx..idx + hinted).map(|idx| store.read_page(idx))).await
+        .into_iter()
+        .zip(idx..)
+        .filter_map(|(page, idx)| Some((idx, page.ok()?)))
+        .collect();
+
+    let head = match prefetched.remove(&idx) {
+        Some(head) => head,
+        None => store.read_page(idx).await?
+    };
+    if !matches!(head.page_type, PageType::Overflow) {
+        return Ok(Bytes::copy_from_slice(head.as_slice()))
+    }
+
+    let (mut next, total_len) = chain_header(head.as_slice());
+    let pages = pages_for(total_len);
+
+    let mut chunk = BytesMut::with_capacity(pages * PAGE_SIZE);
+    chunk.extend_from_slice(head.as_slice());
+
+    while next != NO_PAGE {
+        // a chain longer than its length needs means a corrupt (perhaps cyclic) pointer
+        if chunk.len() / PAGE_SIZE >= pages { return Err(RetrieveError::Malformed(idx)) }
+
+        let page = match prefetched.remove(&next) {
+            Some(page) => page,
+            None => store.read_page(next).await?
+        };
+        if !matches!(page.page_type, PageType::Overflow) { return Err(RetrieveError::Malformed(next)) }
+
+        chunk.extend_from_slice(page.as_slice());
+        next = chain_header(page.as_slice()).0;
+    }
+
+    Ok(chunk.freeze())
+}
+
+/// Read the data held by an overflow chain, through the page cache
+pub(crate) async fn read_chain(cache: &PageCache, idx: PageIndex, size_hint: u32) -> Result<Bytes, RetrieveError> {
+    let chunk = cache.get(idx, size_hint).await?;
+    if chunk[PAGE_SIZE - 1] != PageType::Overflow as u8 { return Err(RetrieveError::Malformed(idx)) }
+
+    Ok(payload(&chunk))
+}
+
+/// The data held by a chunk read by `read_chunk`
+fn payload(chunk: &[u8]) -> Bytes {
+    let (_, total_len) = chain_header(chunk);
+    let mut payload = BytesMut::with_capacity(total_len);
+
+    for page in chunk.chunks(PAGE_SIZE) {
+        let start = PAGE_DATA_OFFSET + CHAIN_HEADER_LEN;
+        let len = (total_len - payload.len()).min(OVERFLOW_PAYLOAD);
+        payload.extend_from_slice(&page[start..start + len]);
+    }
+
+    payload.freeze()
+}
@@ -0,0 +1,52 @@
+use bytes::Bytes;
+use thiserror::Error;
+
+use super::page::{PageIndex, PAGE_SIZE};
+use super::RetrieveError;
+
+/// A read-only database image held entirely in memory.
+///
+/// The image is the contents of a database file, so a file can be embedded
+/// into a binary with `include_bytes!` and read without touching the filesystem:
+///
+/// ```ignore
+/// static LOOKUP: &[u8] = include_bytes!("lookup.bssdb");
+/// let db = bssdb::PackedDb::from_static(LOOKUP)?;
+/// ```
+pub struct PackedDb {
+    image: Bytes
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum PackedError {
+    #[error("Packed image is empty")]
+    Empty,
+    #[error("Packed image is {0} bytes, which is not a whole number of pages")]
+    PartialPage(usize)
+}
+
+impl PackedDb {
+    /// Use a static image without copying it
+    pub fn from_static(image: &'static [u8]) -> Result<PackedDb, PackedError> {
+        PackedDb::from_bytes(Bytes::from_static(image))
+    }
+
+    pub fn from_bytes(image: Bytes) -> Result<PackedDb, PackedError> {
+        if image.is_empty() { return Err(PackedError::Empty) }
+        if image.len() % PAGE_SIZE != 0 { return Err(PackedError::PartialPage(image.len())) }
+
+        Ok(PackedDb { image })
+    }
+
+    pub fn page_count(&self) -> u64 {
+        (self.image.len() / PAGE_SIZE) as u64
+    }
+
+    /// Read a page. This never copies: the returned bytes share the image.
+    pub fn read_page(&self, idx: PageIndex) -> Result<Bytes, RetrieveError> {
+        if idx >= self.page_count() { return Err(RetrieveError::OutOfPages) }
+
+        let start = idx as usize * PAGE_SIZE;
+        Ok(self.image.slice(start..start + PAGE_SIZE))
+    }
+}
@@ -1,23 +1,81 @@
 pub type PageIndex = u64;
 
+use std::convert::TryInto;
+
+use super::TransactionIdx;
+
 #[repr(u8)]
-#[derive(Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PageType {
     Blank = 0,
     Root = 1,
     FreeList = 2,
     ValueLog = 3,
-    Branch = 4
+    Branch = 4,
+    Header = 5,
+    Leaf = 6,
+    Overflow = 7
+}
+
+impl PageType {
+    /// Whether a byte read from disk is a valid `PageType`. Must list the last variant.
+    fn is_valid(byte: u8) -> bool {
+        byte <= PageType::Overflow as u8
+    }
 }
 
 #[repr(align(4096))]
 #[repr(C)]
 #[derive(Clone)]
 pub struct PageContent {
-    pub data: [u8; 4095 as usize],
+    /// crc32 of the rest of the page, little endian
+    pub checksum: [u8; CHECKSUM_LEN],
+    /// The transaction which wrote this page, little endian
+    pub lsn: [u8; 8],
+    /// Zero, except on disk in an encrypted database, where it holds the rest of the AEAD tag
+    /// (which replaces the checksum) and the nonce salt
+    pub seal: [u8; SEAL_LEN],
+    pub data: [u8; PAGE_DATA_LEN],
     pub page_type: PageType
 }
 
+pub(super) const CHECKSUM_LEN: usize = 4;
+
+pub(super) const SEAL_LEN: usize = 20;
+
+/// Bytes available to a page's content, after the header fields
+pub const PAGE_DATA_LEN: usize = CONFIGURED_PAGE_SIZE - PAGE_DATA_OFFSET - 1;
+
+#[cfg(any(
+    all(feature = "page-8k", feature = "page-16k"),
+    all(feature = "page-8k", feature = "page-64k"),
+    all(feature = "page-16k", feature = "page-64k")
+))]
+compile_error!("at most one of the page-8k, page-16k and page-64k features can be enabled");
+
+// Lengths within a page are stored as u16, so pages can't be larger than 64KiB
+#[cfg(feature = "page-64k")]
+const CONFIGURED_PAGE_SIZE: usize = 64 * 1024;
+#[cfg(feature = "page-16k")]
+const CONFIGURED_PAGE_SIZE: usize = 16 * 1024;
+#[cfg(feature = "page-8k")]
+const CONFIGURED_PAGE_SIZE: usize = 8 * 1024;
+#[cfg(not(any(feature = "page-8k", feature = "page-16k", feature = "page-64k")))]
+const CONFIGURED_PAGE_SIZE: usize = 4 * 1024;
+
+/// Where `data` starts in the raw bytes of a page
+pub const PAGE_DATA_OFFSET: usize = CHECKSUM_LEN + 8 + SEAL_LEN;
+
+/// Check the checksum of a page read as raw bytes
+pub(super) fn checksum_ok(page: &[u8]) -> bool {
+    if page.len() != PAGE_SIZE { return false }
+
+    let mut stored = [0; CHECKSUM_LEN];
+    stored.copy_from_slice(&page[..CHECKSUM_LEN]);
+
+    u32::from_le_bytes(stored) == crc32fast::hash(&page[CHECKSUM_LEN..])
+}
+
 impl PageContent {
     pub(super) fn as_slice<'s>(&'s self) -> &'s [u8] {
         unsafe {
@@ -27,13 +85,48 @@ impl PageContent {
             )
         }
     }
+    pub(super) fn new(page_type: PageType) -> PageContent {
+        PageContent {
+            checksum: [0; CHECKSUM_LEN],
+            lsn: [0; 8],
+            seal: [0; SEAL_LEN],
+            data: [0; PAGE_DATA_LEN],
+            page_type
+        }
+    }
+    pub fn lsn(&self) -> TransactionIdx {
+        TransactionIdx::from_le_bytes(self.lsn)
+    }
+    pub(super) fn set_lsn(&mut self, txn: TransactionIdx) {
+        self.lsn = txn.to_le_bytes();
+    }
+    /// Stamp the checksum. Must be called after the last change, before the page is written.
+    pub(super) fn update_checksum(&mut self) {
+        self.checksum = crc32fast::hash(&self.as_slice()[CHECKSUM_LEN..]).to_le_bytes();
+    }
+    /// Copy a page out of raw bytes, or `None` if they can't be a page
+    pub(super) fn from_bytes(bytes: &[u8]) -> Option<PageContent> {
+        if bytes.len() != PAGE_SIZE { return None }
+
+        let mut page = PageContent::uninit();
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), page.0.as_mut_ptr() as *mut u8, PAGE_SIZE);
+            page.assume_init()
+        }
+    }
     pub(super) fn uninit() -> UninitPage {
         UninitPage(std::mem::MaybeUninit::uninit())
     }
 }
 
+/// Bytes in a page, on disk and in memory. Chosen when bssdb is built, with the `page-8k`,
+/// `page-16k` or `page-64k` feature, and recorded in each database's header; a database can
+/// only be opened by a build with the same page size.
 pub const PAGE_SIZE: usize = std::mem::size_of::<PageContent>();
 
+// fails to compile unless the fields add up to the configured size
+const _: [(); PAGE_SIZE] = [(); CONFIGURED_PAGE_SIZE];
+
 #[repr(C)]
 struct UninitPage(std::mem::MaybeUninit<PageContent>);
 
@@ -63,8 +156,13 @@ impl UninitPage {
 
         MutPageBytes(iovec, &mut std::marker::PhantomData)
     }
-    pub(super) unsafe fn assume_init(self) -> PageContent {
-        self.0.assume_init()
+    /// The page, or `None` if its type byte isn't a `PageType`, which would be undefined behavior
+    ///
+    /// Every byte must have been written.
+    pub(super) unsafe fn assume_init(self) -> Option<PageContent> {
+        let type_byte = *(self.0.as_ptr() as *const u8).add(PAGE_SIZE - 1);
+        if !PageType::is_valid(type_byte) { return None }
+        Some(self.0.assume_init())
     }
 }
 
@@ -81,4 +179,34 @@ impl Page {
     pub fn idx(&self) -> PageIndex {
         self.index
     }
-}
\ No newline at end of file
+}
+
+/// Reads fields out of encoded page data, failing on truncation instead of panicking
+pub(super) struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(buf: &'a [u8]) -> Cursor<'a> {
+        Cursor { buf, pos: 0 }
+    }
+
+    pub fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    pub fn u16(&mut self) -> Option<usize> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().unwrap()) as usize)
+    }
+
+    pub fn u32(&mut self) -> Option<usize> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
@@ -1,75 +1,400 @@
-use std::collections::{HashMap};
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Poll, Waker};
 use bytes::Bytes;
-use futures::future::{Shared, BoxFuture};
+use futures::future::{self, Shared, BoxFuture};
 use futures::FutureExt;
-use lru::LruCache;
 use parking_lot::{Mutex};
 
-use super::{PageIndex, FileStore, RetrieveError};
+use super::{PageIndex, PageStore, RetrieveError, Observer, Options};
+use super::page::{self, PAGE_SIZE};
+use super::eviction::{EvictionPolicy, WeightedCache};
+use super::buffer_pool::{BufferPool, Chunk, PoolBacking};
 
 const CACHE_SHARDS: usize = 64;
 
-type SharedLoad<'l> = Shared<BoxFuture<'l, Result<Bytes, RetrieveError>>>;
+type SharedLoad = Shared<BoxFuture<'static, Result<Chunk, RetrieveError>>>;
 
-struct CacheShard<'l> {
-    cache: Mutex<LruCache<PageIndex, Bytes>>,
-    loads: Mutex<HashMap<PageIndex, SharedLoad<'l>>>
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    /// Upper bound on the bytes of page data held by the cache, split evenly across shards
+    pub max_bytes: usize,
+    pub policy: EvictionPolicy,
+    /// Reads from the store each shard runs at once. Further misses wait their turn.
+    pub max_loads_per_shard: usize,
+    /// Hold cached pages in a pool mapped from 2MiB huge pages, sized to `max_bytes` when the
+    /// database is opened. Falls back to the heap if the pool can't be mapped.
+    pub huge_pages: bool
 }
 
-impl<'l> CacheShard<'l> {
-    fn new(cache_shard_size: usize) -> CacheShard<'l> {
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig { max_bytes: 64 * 1024 * 1024, policy: EvictionPolicy::default(), max_loads_per_shard: 16, huge_pages: false }
+    }
+}
+
+/// The share of reads whose page checksums are verified, from 0.0 (never) to 1.0 (always)
+#[derive(Debug, Clone, Copy)]
+pub struct ChecksumSampling {
+    /// Reads served from the disk
+    pub cold_reads: f64,
+    /// Reads served from the cache, which only catch corruption in memory
+    pub cached_hits: f64
+}
+
+impl Default for ChecksumSampling {
+    fn default() -> Self {
+        ChecksumSampling { cold_reads: 1.0, cached_hits: 0.01 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub used_bytes: usize,
+    pub max_bytes: usize,
+    pub entries: usize,
+    /// Gets served from memory, and gets that had to wait for a read from the store
+    pub hits: u64,
+    pub misses: u64,
+    pub checksums_verified: u64,
+    pub checksums_failed: u64,
+    /// How the huge page pool is backed, if `CacheConfig::huge_pages` is set and it was mapped
+    pub pool_backing: Option<PoolBacking>
+}
+
+/// Selects an exact `rate` share of events, spread evenly
+struct Sampler {
+    rate: f64,
+    events: AtomicU64
+}
+
+impl Sampler {
+    fn new(rate: f64) -> Sampler {
+        Sampler { rate, events: AtomicU64::new(0) }
+    }
+
+    fn sample(&self) -> bool {
+        if self.rate >= 1.0 { return true }
+        if self.rate <= 0.0 { return false }
+
+        let n = self.events.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.rate).floor() > (n * self.rate).floor()
+    }
+}
+
+/// Limits how many loads run at once
+struct LoadLimit {
+    state: Mutex<(usize, VecDeque<Waker>)>
+}
+
+struct LoadPermit<'l>(&'l LoadLimit);
+
+impl LoadLimit {
+    fn new(permits: usize) -> LoadLimit {
+        LoadLimit { state: Mutex::new((permits.max(1), VecDeque::new())) }
+    }
+
+    async fn acquire(&self) -> LoadPermit<'_> {
+        future::poll_fn(|cx| {
+            let (available, waiting) = &mut *self.state.lock();
+            if *available > 0 {
+                *available -= 1;
+                Poll::Ready(())
+            } else {
+                waiting.push_back(cx.waker().clone());
+                Poll::Pending
+            }
+        }).await;
+
+        LoadPermit(self)
+    }
+}
+
+impl<'l> Drop for LoadPermit<'l> {
+    fn drop(&mut self) {
+        let (available, waiting) = &mut *self.0.state.lock();
+        *available += 1;
+        // wake every waiter: one woken alone may have been cancelled, leaving the permit unclaimed
+        for waker in waiting.drain(..) { waker.wake() }
+    }
+}
+
+/// A load in progress, shared by every `get` waiting on it
+struct Load {
+    id: u64,
+    future: SharedLoad,
+    waiters: usize
+}
+
+/// Drops a load once nobody is waiting on it anymore, which cancels it
+struct LoadWaiter<'s> {
+    shard: &'s CacheShard,
+    idx: PageIndex,
+    id: u64
+}
+
+impl<'s> Drop for LoadWaiter<'s> {
+    fn drop(&mut self) {
+        let mut loads = self.shard.loads.lock();
+
+        // the load may have finished, and another started for the same page
+        if let Some(load) = loads.get_mut(&self.idx).filter(|load| load.id == self.id) {
+            load.waiters -= 1;
+            if load.waiters == 0 { loads.remove(&self.idx); }
+        }
+    }
+}
+
+struct CacheShard {
+    cache: Mutex<WeightedCache>,
+    loads: Mutex<HashMap<PageIndex, Load>>,
+    next_load: AtomicU64,
+    load_limit: LoadLimit,
+    observer: Arc<dyn Observer>,
+    pool: Option<Arc<BufferPool>>,
+
+    verify_cold: Sampler,
+    verify_cached: Sampler,
+    verified: AtomicU64,
+    failed: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+impl CacheShard {
+    fn new(max_bytes: usize, options: &Options, pool: Option<Arc<BufferPool>>) -> CacheShard {
         CacheShard {
-            cache: Mutex::new(LruCache::new(cache_shard_size)),
-            loads: Mutex::new(HashMap::new())
+            cache: Mutex::new(WeightedCache::new(max_bytes, options.cache.policy, max_bytes / PAGE_SIZE, options.observer.clone())),
+            loads: Mutex::new(HashMap::new()),
+            next_load: AtomicU64::new(0),
+            load_limit: LoadLimit::new(options.cache.max_loads_per_shard),
+            observer: options.observer.clone(),
+            pool,
+
+            verify_cold: Sampler::new(options.checksums.cold_reads),
+            verify_cached: Sampler::new(options.checksums.cached_hits),
+            verified: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
         }
     }
 
-    pub async fn get(&'l self, store: Arc<FileStore>, idx: PageIndex, overflow_size_hint: u32) -> Result<Bytes, RetrieveError> {
-        if let Some(cached) = self.cache.lock().get(&idx) { return Ok(cached.clone()) };
+    /// Serve `idx` from the cache, or join or start its load. Every get waiting on a load
+    /// gets its result, error or not, and the load leaves `loads` as it finishes either way,
+    /// so a failed load is retried by the next get rather than cached or left behind.
+    pub async fn get(self: Arc<Self>, store: Arc<dyn PageStore>, idx: PageIndex, overflow_size_hint: u32) -> Result<Chunk, RetrieveError> {
+        if let Some(cached) = self.cached(idx) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            trace_event!(idx, bytes = cached.len(), "cache hit");
+            return Ok(cached)
+        };
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        trace_event!(idx, "cache miss");
 
-        let mut loads = self.loads.lock();
+        let (id, future) = {
+            let mut loads = self.loads.lock();
+            match loads.get_mut(&idx) {
+                Some(in_progress) => {
+                    in_progress.waiters += 1;
+                    (in_progress.id, in_progress.future.clone())
+                },
+                None => {
+                    // a load may have finished between the cache miss and taking the loads lock
+                    if let Some(cached) = self.cached(idx) { return Ok(cached) };
 
-        if let Some(in_progress) = loads.get(&idx) {
-            let in_progress: SharedLoad = in_progress.clone().clone();
+                    let id = self.next_load.fetch_add(1, Ordering::Relaxed);
+                    let future = self.clone().load(store, idx, overflow_size_hint, id);
+                    loads.insert(idx, Load { id, future: future.clone(), waiters: 1 });
+                    (id, future)
+                }
+            }
+        };
+        let _waiter = LoadWaiter { shard: &*self, idx, id };
 
-            std::mem::drop(loads);
+        future.await
+    }
+
+    /// Read `idx` from the store, caching it if the read succeeds
+    fn load(self: Arc<Self>, store: Arc<dyn PageStore>, idx: PageIndex, overflow_size_hint: u32, id: u64) -> SharedLoad {
+        async move {
+            let permit = self.load_limit.acquire().await;
+            let res = store.get_chunk(idx, overflow_size_hint).await.and_then(|data| {
+                if self.verify_cold.sample() && !self.verify(&data) { return Err(RetrieveError::BadChecksum) }
+                Ok(self.chunk(data))
+            });
+            std::mem::drop(permit);
 
-            return in_progress.await;
+            match &res {
+                Ok(chunk) => self.cache.lock().put(idx, chunk.clone()),
+                Err(err) => self.observer.on_error(err)
+            }
+            let mut loads = self.loads.lock();
+            if loads.get(&idx).map_or(false, |load| load.id == id) { loads.remove(&idx); }
+
+            res
+        }.boxed().shared()
+    }
+
+    fn cached(&self, idx: PageIndex) -> Option<Chunk> {
+        let cached = self.cache.lock().get(idx)?;
+
+        if self.verify_cached.sample() && !self.verify(&cached) {
+            // corrupted in memory: drop it so it's read from the disk again
+            self.cache.lock().remove(idx);
+            return None;
         }
 
-        let future = async move {
-            let res = store.get_chunk(idx, overflow_size_hint).await;
+        Some(cached)
+    }
 
-            if let Ok(data) = res.clone() { self.cache.lock().put(idx, data); };
-            self.loads.lock().remove(&idx);
+    /// Hold `data` in the pool if it's a single page and a slot is free, else on the heap
+    fn chunk(&self, data: Bytes) -> Chunk {
+        match &self.pool {
+            Some(pool) => pool.store(data).map_or_else(Chunk::Heap, |slot| Chunk::Pooled(Arc::new(slot))),
+            None => Chunk::Heap(data)
+        }
+    }
 
-            res
-        }.boxed().shared();
+    fn verify(&self, chunk: &[u8]) -> bool {
+        let ok = chunk.chunks(PAGE_SIZE).all(page::checksum_ok);
 
-        loads.insert(idx, future.clone());
+        self.verified.fetch_add(1, Ordering::Relaxed);
+        if !ok { self.failed.fetch_add(1, Ordering::Relaxed); }
 
-        std::mem::drop(loads);
+        ok
+    }
 
-        future.await
+    fn stats(&self) -> CacheStats {
+        let cache = self.cache.lock();
+        CacheStats {
+            used_bytes: cache.used_bytes(),
+            max_bytes: cache.max_bytes(),
+            entries: cache.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            checksums_verified: self.verified.load(Ordering::Relaxed),
+            checksums_failed: self.failed.load(Ordering::Relaxed),
+            pool_backing: self.pool.as_ref().map(|pool| pool.backing())
+        }
     }
 }
 
-pub struct PageCache<'l> {
-    store: Arc<FileStore>,
-    shards: [CacheShard<'l>; CACHE_SHARDS]
+pub struct PageCache {
+    store: Arc<dyn PageStore>,
+    shards: Vec<Arc<CacheShard>>
 }
 
-impl<'l> PageCache<'l> {
-    pub fn new(store: FileStore, cache_shard_size: usize) -> &'l PageCache<'l> {
-        &PageCache {
-            store: Arc::new(store),
-            shards: [CacheShard::new(cache_shard_size); CACHE_SHARDS]
+impl PageCache {
+    pub fn new(store: Arc<dyn PageStore>, options: &Options) -> PageCache {
+        let shard_bytes = options.cache.max_bytes / CACHE_SHARDS;
+        let pool = if options.cache.huge_pages { BufferPool::new(options.cache.max_bytes) } else { None };
+
+        PageCache {
+            store,
+            shards: (0..CACHE_SHARDS).map(|_| Arc::new(CacheShard::new(shard_bytes, options, pool.clone()))).collect()
         }
     }
 
-    pub async fn get(&'l self, idx: PageIndex, overflow_size_hint: u32) -> Result<Bytes, RetrieveError> {
+    pub async fn get(&self, idx: PageIndex, overflow_size_hint: u32) -> Result<Chunk, RetrieveError> {
         let cache_shard = unsafe { self.shards.get_unchecked(idx as usize % CACHE_SHARDS) };
-        cache_shard.get(self.store.clone(), idx, overflow_size_hint).await
+        traced!(cache_shard.clone().get(self.store.clone(), idx, overflow_size_hint), "cache_get", idx).await
+    }
+
+    /// Change the memory budget, evicting immediately if it shrank
+    pub fn set_max_bytes(&self, max_bytes: usize) {
+        for shard in self.shards.iter() {
+            shard.cache.lock().set_max_bytes(max_bytes / CACHE_SHARDS);
+        }
+    }
+
+    /// Current memory usage and checksum counters, summed across shards
+    pub fn stats(&self) -> CacheStats {
+        self.shards.iter().map(|shard| shard.stats()).fold(CacheStats::default(), |total, shard| CacheStats {
+            used_bytes: total.used_bytes + shard.used_bytes,
+            max_bytes: total.max_bytes + shard.max_bytes,
+            entries: total.entries + shard.entries,
+            hits: total.hits + shard.hits,
+            misses: total.misses + shard.misses,
+            checksums_verified: total.checksums_verified + shard.checksums_verified,
+            checksums_failed: total.checksums_failed + shard.checksums_failed,
+            pool_backing: total.pool_backing.or(shard.pool_backing)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::atomic::AtomicUsize;
+    use std::task::Poll;
+    use futures::executor::block_on;
+    use super::*;
+    use super::super::{Durability, PageContent, PageType};
+
+    /// Fails its first read, then reads one valid page, taking a poll to do either
+    struct Flaky {
+        reads: AtomicUsize,
+        page: Bytes
+    }
+
+    impl Flaky {
+        fn new() -> Flaky {
+            let mut page = PageContent::new(PageType::Leaf);
+            page.update_checksum();
+            Flaky { reads: AtomicUsize::new(0), page: Bytes::copy_from_slice(page.as_slice()) }
+        }
+    }
+
+    impl PageStore for Flaky {
+        fn read_page(&self, _idx: PageIndex) -> BoxFuture<'_, Result<PageContent, RetrieveError>> {
+            unimplemented!()
+        }
+
+        fn write_page<'a>(&'a self, _idx: PageIndex, _page: &'a PageContent) -> BoxFuture<'a, io::Result<()>> {
+            unimplemented!()
+        }
+
+        fn sync(&self, _durability: Durability) -> BoxFuture<'_, io::Result<()>> {
+            unimplemented!()
+        }
+
+        fn get_chunk(&self, _idx: PageIndex, _overflow_size_hint: u32) -> BoxFuture<'_, Result<Bytes, RetrieveError>> {
+            let first = self.reads.fetch_add(1, Ordering::SeqCst) == 0;
+            let mut yielded = false;
+            // pending once, so a second get finds the load in progress and joins it
+            let ready = future::poll_fn(move |cx| {
+                if yielded { return Poll::Ready(()) }
+                yielded = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            });
+            async move {
+                ready.await;
+                if first { return Err(RetrieveError::Io(Arc::new(io::Error::new(io::ErrorKind::Other, "flaky")))) }
+                Ok(self.page.clone())
+            }.boxed()
+        }
+    }
+
+    #[test]
+    fn failed_load_is_retried() {
+        let store = Arc::new(Flaky::new());
+        let cache = PageCache::new(store.clone(), &Options::new());
+
+        // both gets wait on the one failed read, and both see its error
+        let (a, b) = block_on(future::join(cache.get(7, 0), cache.get(7, 0)));
+        assert!(matches!(a, Err(RetrieveError::Io(_))));
+        assert!(matches!(b, Err(RetrieveError::Io(_))));
+        assert_eq!(store.reads.load(Ordering::SeqCst), 1);
+        assert!(cache.shards.iter().all(|shard| shard.loads.lock().is_empty()));
+
+        // the failure wasn't cached, so the next get reads again
+        assert_eq!(&block_on(cache.get(7, 0)).unwrap()[..], &store.page[..]);
+        assert_eq!(store.reads.load(Ordering::SeqCst), 2);
+
+        assert_eq!(&block_on(cache.get(7, 0)).unwrap()[..], &store.page[..]);
+        assert_eq!(store.reads.load(Ordering::SeqCst), 2);
     }
 }
@@ -0,0 +1,123 @@
+//! Reading around corrupt pages. In best-effort recovery, a tree page that fails to read as
+//! valid is quarantined along with the range of keys its subtree held: lookups of those keys
+//! fail without reading it again, scans skip it, and every other key reads as normal.
+
+use std::collections::BTreeMap;
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use super::{DB, PageIndex, RetrieveError};
+use super::branch::Branch;
+use super::descent::Descent;
+use super::leaf;
+use super::page::PageType;
+use super::tree::read_node;
+
+/// How to handle corrupt pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Fail any read that reaches a corrupt page
+    Strict,
+    /// Quarantine corrupt tree pages and keep reading the rest of the tree, reporting what
+    /// was lost with `DB::corruption_report`
+    BestEffort
+}
+
+impl Default for RecoveryMode {
+    fn default() -> Self {
+        RecoveryMode::Strict
+    }
+}
+
+/// A quarantined page and the keys it held
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamagedRange {
+    pub page: PageIndex,
+    /// The lowest key the page may hold, or `None` if unbounded
+    pub low: Option<Bytes>,
+    /// The key above every key the page may hold, or `None` if unbounded
+    pub high: Option<Bytes>,
+    /// Why the page couldn't be read
+    pub error: String
+}
+
+impl DamagedRange {
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.low.as_ref().map_or(true, |low| &low[..] <= key) && self.high.as_ref().map_or(true, |high| key < &high[..])
+    }
+}
+
+/// The corrupt pages found so far, and the key ranges unreadable because of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptionReport {
+    pub damaged: Vec<DamagedRange>
+}
+
+impl CorruptionReport {
+    pub fn is_clean(&self) -> bool {
+        self.damaged.is_empty()
+    }
+}
+
+pub(crate) struct Quarantine {
+    damaged: Mutex<BTreeMap<PageIndex, DamagedRange>>
+}
+
+impl Quarantine {
+    pub fn new() -> Quarantine {
+        Quarantine { damaged: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn record(&self, page: PageIndex, low: Option<Bytes>, high: Option<Bytes>, err: &RetrieveError) {
+        self.damaged.lock().entry(page).or_insert_with(|| DamagedRange { page, low, high, error: err.to_string() });
+    }
+
+    /// The quarantined page holding `key`, if any
+    pub fn covering(&self, key: &[u8]) -> Option<PageIndex> {
+        self.damaged.lock().values().find(|range| range.contains(key)).map(|range| range.page)
+    }
+}
+
+impl DB {
+    /// The pages quarantined since opening in best-effort recovery, empty in strict recovery
+    pub fn corruption_report(&self) -> CorruptionReport {
+        let damaged = match &self.quarantine {
+            Some(quarantine) => quarantine.damaged.lock().values().cloned().collect(),
+            None => vec![]
+        };
+        CorruptionReport { damaged }
+    }
+
+    /// Descend again toward `key` after a lookup failed, quarantining the first page on the
+    /// path that can't be read, with its key range
+    pub(super) async fn quarantine_path(&self, root: PageIndex, key: &[u8]) {
+        let quarantine = match &self.quarantine {
+            Some(quarantine) => quarantine,
+            None => return
+        };
+        let mut descent = Descent::new(self.options.lock().max_tree_depth);
+        let (mut idx, mut low, mut high) = (root, None, None);
+
+        loop {
+            let branch = match descent.enter(idx).map_err(RetrieveError::from) {
+                Ok(()) => read_node(&self.cache, idx).await.and_then(|page| match page.page_type {
+                    PageType::Branch => Branch::decode(&page).map(Some).ok_or(RetrieveError::Malformed(idx)),
+                    PageType::Leaf => leaf::decode(&page).map(|_| None).ok_or(RetrieveError::Malformed(idx)),
+                    _ => Err(RetrieveError::Malformed(idx))
+                }),
+                Err(err) => Err(err)
+            };
+            let branch = match branch {
+                Ok(Some(branch)) => branch,
+                // the leaf read fine, so the lookup failed for another reason
+                Ok(None) => return,
+                Err(err) => return quarantine.record(idx, low, high, &err)
+            };
+
+            let i = branch.child_index(key);
+            if i > 0 { low = Some(branch.separators[i - 1].0.clone()) }
+            if let Some((separator, _)) = branch.separators.get(i) { high = Some(separator.clone()) }
+            idx = branch.child(i);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Range scans, which merge the write buffer's changes into the tree's entries
+
+use std::iter::Peekable;
+use std::ops::{Bound, RangeBounds};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::vec;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
+
+use super::{DB, PageCache, PageIndex, RetrieveError};
+use super::leaf::LeafValue;
+use super::snapshot::Pinned;
+use super::tree::{Entries, Write};
+
+pub(super) fn owned(bound: Bound<&Bytes>) -> Bound<Bytes> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone()),
+        Bound::Unbounded => Bound::Unbounded
+    }
+}
+
+fn before_end(key: &Bytes, to: &Bound<Bytes>) -> bool {
+    match to {
+        Bound::Included(to) => key <= to,
+        Bound::Excluded(to) => key < to,
+        Bound::Unbounded => true
+    }
+}
+
+/// Whether `key` is between the bounds
+pub(super) fn contains(key: &[u8], from: &Bound<Bytes>, to: &Bound<Bytes>) -> bool {
+    let after_start = match from {
+        Bound::Included(from) => key >= &from[..],
+        Bound::Excluded(from) => key > &from[..],
+        Bound::Unbounded => true
+    };
+    let before_end = match to {
+        Bound::Included(to) => key <= &to[..],
+        Bound::Excluded(to) => key < &to[..],
+        Bound::Unbounded => true
+    };
+    after_start && before_end
+}
+
+/// Whether no key can be between the bounds, which `BTreeMap::range` panics on
+pub(super) fn is_empty(from: &Bound<Bytes>, to: &Bound<Bytes>) -> bool {
+    match (from, to) {
+        (Bound::Included(from), Bound::Included(to)) => from > to,
+        (Bound::Included(from), Bound::Excluded(to))
+            | (Bound::Excluded(from), Bound::Included(to))
+            | (Bound::Excluded(from), Bound::Excluded(to)) => from >= to,
+        _ => false
+    }
+}
+
+/// Merge-sorts buffered writes with the tree's entries, the buffered write winning for a key in both
+struct Merge {
+    buffered: Peekable<vec::IntoIter<Write>>,
+    tree: Entries,
+    /// The tree's next entry, once read
+    tree_next: Option<Write>,
+    tree_done: bool,
+    to: Bound<Bytes>
+}
+
+impl Merge {
+    /// The next change in key order, where `None` values are buffered deletes
+    async fn next(&mut self, cache: &PageCache) -> Result<Option<Write>, RetrieveError> {
+        if self.tree_next.is_none() && !self.tree_done {
+            match self.tree.next(cache).await? {
+                Some(entry) if before_end(&entry.key, &self.to) => self.tree_next = Some((entry.key, Some(entry.value))),
+                _ => self.tree_done = true
+            }
+        }
+
+        let take_buffered = match (self.buffered.peek(), &self.tree_next) {
+            (None, None) => return Ok(None),
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some((buffered, _)), Some((tree, _))) => buffered <= tree
+        };
+
+        if take_buffered {
+            let (key, value) = self.buffered.next().unwrap();
+            if matches!(&self.tree_next, Some((tree, _)) if *tree == key) {
+                self.tree_next = None;
+            }
+            Ok(Some((key, value)))
+        } else {
+            Ok(self.tree_next.take())
+        }
+    }
+}
+
+/// The entries of a range scan, in key order, stamped with the generation of what it reads
+pub struct Scan<'a> {
+    entries: BoxStream<'a, Result<(Bytes, Bytes), RetrieveError>>,
+    generation: u64,
+    /// Keeps the version read from being discarded, for a scan that isn't a snapshot's
+    _pinned: Option<Pinned>
+}
+
+impl<'a> Scan<'a> {
+    pub(super) fn new(entries: BoxStream<'a, Result<(Bytes, Bytes), RetrieveError>>, generation: u64) -> Scan<'a> {
+        Scan { entries, generation, _pinned: None }
+    }
+
+    pub(super) fn empty(generation: u64) -> Scan<'a> {
+        Scan::new(stream::empty().boxed(), generation)
+    }
+
+    /// The database's generation, taken before the scan took the version it reads. See
+    /// `DB::generation`.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl<'a> Stream for Scan<'a> {
+    type Item = Result<(Bytes, Bytes), RetrieveError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.entries.poll_next_unpin(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl DB {
+    /// Stream the entries with keys in `range`, in key order. The scan reads the latest version
+    /// as of when it starts, including writes still in the write buffer.
+    pub fn range<R: RangeBounds<Bytes>>(&self, range: R) -> Scan<'_> {
+        let from = owned(range.start_bound());
+        let to = owned(range.end_bound());
+
+        let generation = self.generation();
+        if is_empty(&from, &to) { return Scan::empty(generation) }
+        let pinned = self.snapshots.pin(self.version.lock().tx);
+        let (version, buffered) = self.buffered_range(from.clone(), to.clone());
+        let dictionaries = self.dictionaries();
+
+        let entries = self.merged_entries(version.tree_root, from, to, buffered).try_filter_map(move |(key, value)| {
+            let dictionaries = dictionaries.clone();
+            async move {
+                if self.is_expired(version.expiries, &key).await? { return Ok(None) }
+                Ok(Some((key, value.read(&self.cache, &dictionaries).await?)))
+            }
+        }).boxed();
+        Scan { entries, generation, _pinned: Some(pinned) }
+    }
+
+    /// Stream the entries of the tree at `tree_root` from `from` to `to`, with sorted `buffered`
+    /// writes merged in
+    pub(super) fn merged_entries(&self, tree_root: Option<PageIndex>, from: Bound<Bytes>, to: Bound<Bytes>, buffered: Vec<Write>) -> BoxStream<'_, Result<(Bytes, LeafValue), RetrieveError>> {
+        let max_depth = self.options.lock().max_tree_depth;
+
+        let merge = Merge {
+            buffered: buffered.into_iter().peekable(),
+            tree: Entries::new(tree_root, from, max_depth).skip_damaged(self.quarantine.clone()),
+            tree_next: None,
+            tree_done: false,
+            to
+        };
+
+        stream::try_unfold(merge, move |mut merge| async move {
+            loop {
+                match merge.next(&self.cache).await? {
+                    None => return Ok::<_, RetrieveError>(None),
+                    Some((_, None)) => continue,
+                    Some((key, Some(value))) => return Ok(Some(((key, value), merge)))
+                }
+            }
+        }).boxed()
+    }
+}
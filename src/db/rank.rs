@@ -0,0 +1,110 @@
+//! Finding keys by position, from the subtree sizes branches keep, reading one page per level
+
+use bytes::Bytes;
+
+use super::{DB, PageIndex, RetrieveError};
+use super::branch::Branch;
+use super::descent::Descent;
+use super::leaf::{self, LeafEntry};
+use super::page::PageType;
+use super::tree::read_node;
+
+impl DB {
+    /// The entry at position `n` of the tree at `root`, counting from zero
+    async fn nth_entry(&self, root: PageIndex, mut n: u64) -> Result<Option<LeafEntry>, RetrieveError> {
+        let mut descent = Descent::new(self.options.lock().max_tree_depth);
+        let mut idx = root;
+
+        loop {
+            descent.enter(idx)?;
+            let page = read_node(&self.cache, idx).await?;
+
+            match page.page_type {
+                PageType::Branch => {
+                    let branch = Branch::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                    let mut child = None;
+                    for (i, stats) in branch.stats.iter().enumerate() {
+                        if n < stats.entries {
+                            child = Some(branch.child(i));
+                            break
+                        }
+                        n -= stats.entries;
+                    }
+                    idx = match child {
+                        Some(child) => child,
+                        None => return Ok(None)
+                    };
+                },
+                PageType::Leaf => {
+                    let mut entries = leaf::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                    return Ok(if n < entries.len() as u64 { Some(entries.swap_remove(n as usize)) } else { None })
+                },
+                _ => return Err(RetrieveError::Malformed(idx))
+            }
+        }
+    }
+
+    /// The entry at position `n` in key order, counting from zero, in one page read per level
+    /// of the tree. Useful for pagination. Positions count the tree as of the last time the
+    /// write buffer was applied, and keys whose TTL expired until they're swept.
+    pub async fn nth(&self, n: u64) -> Result<Option<(Bytes, Bytes)>, RetrieveError> {
+        let root = match self.version.lock().tree_root {
+            Some(root) => root,
+            None => return Ok(None)
+        };
+
+        match self.nth_entry(root, n).await? {
+            Some(entry) => Ok(Some((entry.key, entry.value.read(&self.cache, &self.dictionaries()).await?))),
+            None => Ok(None)
+        }
+    }
+
+    /// The entry with the lowest key, counted like `nth`
+    pub async fn first(&self) -> Result<Option<(Bytes, Bytes)>, RetrieveError> {
+        self.nth(0).await
+    }
+
+    /// The entry with the highest key, counted like `nth`
+    pub async fn last(&self) -> Result<Option<(Bytes, Bytes)>, RetrieveError> {
+        let root = match self.version.lock().tree_root {
+            Some(root) => root,
+            None => return Ok(None)
+        };
+
+        match self.tree_stats(root).await?.entries {
+            0 => Ok(None),
+            len => self.nth(len - 1).await
+        }
+    }
+
+    /// How many keys are below `key`, which is the position `key` has or would have, counted
+    /// like `nth`
+    pub async fn rank(&self, key: &[u8]) -> Result<u64, RetrieveError> {
+        let root = match self.version.lock().tree_root {
+            Some(root) => root,
+            None => return Ok(0)
+        };
+        let mut descent = Descent::new(self.options.lock().max_tree_depth);
+        let mut idx = root;
+        let mut rank = 0;
+
+        loop {
+            descent.enter(idx)?;
+            let page = read_node(&self.cache, idx).await?;
+
+            match page.page_type {
+                PageType::Branch => {
+                    let branch = Branch::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                    let i = branch.child_index(key);
+                    rank += branch.stats[..i].iter().map(|stats| stats.entries).sum::<u64>();
+                    idx = branch.child(i);
+                },
+                PageType::Leaf => {
+                    let entries = leaf::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                    return Ok(rank + entries.partition_point(|entry| &entry.key[..] < key) as u64)
+                },
+                _ => return Err(RetrieveError::Malformed(idx))
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Reads shared by everything that can be read, so code layered on top of the database can be
+//! generic over where it reads from
+
+use std::ops::RangeBounds;
+use bytes::Bytes;
+use futures::future::{try_join_all, BoxFuture, FutureExt};
+use futures::stream::{BoxStream, StreamExt};
+
+use super::{DB, RetrieveError, WriteTransaction};
+
+/// A source of reads: the database's latest committed version, or a write transaction, which
+/// also sees its own uncommitted changes
+pub trait ReadOps: Sync {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<Bytes>, RetrieveError>>;
+
+    /// Whether `key` has a value, without reading the value
+    fn contains_key<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<bool, RetrieveError>>;
+
+    /// Stream the entries with keys in `range`, in key order
+    fn range<R: RangeBounds<Bytes>>(&self, range: R) -> BoxStream<'_, Result<(Bytes, Bytes), RetrieveError>> where Self: Sized;
+
+    /// Read several keys at once, their page reads overlapping
+    fn multi_get<'a>(&'a self, keys: &'a [Bytes]) -> BoxFuture<'a, Result<Vec<Option<Bytes>>, RetrieveError>> {
+        try_join_all(keys.iter().map(move |key| self.get(key))).boxed()
+    }
+}
+
+impl ReadOps for DB {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<Bytes>, RetrieveError>> {
+        DB::get(self, key).boxed()
+    }
+
+    fn contains_key<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<bool, RetrieveError>> {
+        DB::contains_key(self, key).boxed()
+    }
+
+    fn range<R: RangeBounds<Bytes>>(&self, range: R) -> BoxStream<'_, Result<(Bytes, Bytes), RetrieveError>> {
+        DB::range(self, range).boxed()
+    }
+
+    fn multi_get<'a>(&'a self, keys: &'a [Bytes]) -> BoxFuture<'a, Result<Vec<Option<Bytes>>, RetrieveError>> {
+        DB::get_many(self, keys).boxed()
+    }
+}
+
+impl<'db> ReadOps for WriteTransaction<'db> {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<Bytes>, RetrieveError>> {
+        WriteTransaction::get(self, key).boxed()
+    }
+
+    fn contains_key<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<bool, RetrieveError>> {
+        WriteTransaction::contains_key(self, key).boxed()
+    }
+
+    fn range<R: RangeBounds<Bytes>>(&self, range: R) -> BoxStream<'_, Result<(Bytes, Bytes), RetrieveError>> {
+        WriteTransaction::range(self, range).boxed()
+    }
+}
@@ -0,0 +1,505 @@
+//! Replication: a primary streams its committed transactions as framed records, and a
+//! follower applies them in order, so it holds the primary's data as of some transaction.
+//!
+//! A frame is `[len: u32][crc32: u32][record]`, where the checksum covers the record. A record
+//! is one of
+//!
+//! - `[BEGIN][tx: u64]`: the primary's transaction `tx` starts
+//! - `[PUT][key len: u32][key][value len: u32][value]`
+//! - `[DELETE][key len: u32][key]`
+//! - `[COMMIT][tx: u64]`: transaction `tx` ends
+//! - `[POSITION][tx: u64]`: the primary's latest transaction, sent first so a follower knows
+//!   how far behind it is
+//!
+//! Integers are little endian. A follower records each transaction it applies with a token
+//! (see `DB::apply_batch_with_token`), so a transaction delivered twice is applied once.
+
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::ops::Bound;
+use std::pin::Pin;
+use std::sync::Arc;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::stream::{self, BoxStream, Peekable, StreamExt, TryStreamExt};
+use thiserror::Error;
+
+use super::{Batch, BatchOutcome, DB, Observer, PageIndex, RetrieveError, TransactionIdx, WriteError};
+use super::archive;
+use super::leaf::LeafValue;
+use super::memtable;
+use super::page::Cursor;
+use super::tree::{Entries, Write};
+use super::version::VersionHeader;
+
+const BEGIN: u8 = 1;
+const PUT: u8 = 2;
+const DELETE: u8 = 3;
+const COMMIT: u8 = 4;
+const POSITION: u8 = 5;
+
+/// Frames start with the record's length and checksum
+const FRAME_HEADER_LEN: usize = 8;
+
+/// Tokens of replicated transactions are this followed by the primary's transaction, big endian
+/// so they sort in transaction order
+const TOKEN_PREFIX: &[u8] = b"\0bssdb-replicated\0";
+
+/// One record of a replication stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicationRecord {
+    Begin { tx: TransactionIdx },
+    Put { key: Bytes, value: Bytes },
+    Delete { key: Bytes },
+    Commit { tx: TransactionIdx },
+    Position { tx: TransactionIdx }
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum ReplicationError {
+    #[error("{0}")]
+    Retrieve(#[source] #[from] RetrieveError),
+    #[error("{0}")]
+    Write(#[source] #[from] WriteError),
+    #[error("Can't replicate from transaction {from}: the oldest available is {oldest} (keep more with Options::archive_commits)")]
+    Unavailable { from: TransactionIdx, oldest: TransactionIdx },
+    #[error("Replication record is corrupt")]
+    Corrupt,
+    #[error("Replication record out of order: {0}")]
+    OutOfOrder(&'static str)
+}
+
+impl ReplicationRecord {
+    /// Encode the record as a frame
+    pub fn encode(&self) -> Bytes {
+        let mut record = BytesMut::new();
+        match self {
+            ReplicationRecord::Begin { tx } => {
+                record.put_u8(BEGIN);
+                record.put_u64_le(*tx);
+            },
+            ReplicationRecord::Put { key, value } => {
+                record.put_u8(PUT);
+                record.put_u32_le(key.len() as u32);
+                record.put_slice(key);
+                record.put_u32_le(value.len() as u32);
+                record.put_slice(value);
+            },
+            ReplicationRecord::Delete { key } => {
+                record.put_u8(DELETE);
+                record.put_u32_le(key.len() as u32);
+                record.put_slice(key);
+            },
+            ReplicationRecord::Commit { tx } => {
+                record.put_u8(COMMIT);
+                record.put_u64_le(*tx);
+            },
+            ReplicationRecord::Position { tx } => {
+                record.put_u8(POSITION);
+                record.put_u64_le(*tx);
+            }
+        }
+
+        let mut frame = BytesMut::with_capacity(FRAME_HEADER_LEN + record.len());
+        frame.put_u32_le(record.len() as u32);
+        frame.put_u32_le(crc32fast::hash(&record));
+        frame.put_slice(&record);
+        frame.freeze()
+    }
+
+    /// The length of the frame at the start of `buf`, once its header has arrived, to split a byte stream into frames
+    pub fn frame_len(buf: &[u8]) -> Option<usize> {
+        let len = u32::from_le_bytes(buf.get(..4)?.try_into().unwrap()) as usize;
+        Some(FRAME_HEADER_LEN + len)
+    }
+
+    /// Decode a whole frame
+    pub fn decode(frame: &[u8]) -> Result<ReplicationRecord, ReplicationError> {
+        if ReplicationRecord::frame_len(frame) != Some(frame.len()) { return Err(ReplicationError::Corrupt) }
+        let record = &frame[FRAME_HEADER_LEN..];
+        if u32::from_le_bytes(frame[4..8].try_into().unwrap()) != crc32fast::hash(record) {
+            return Err(ReplicationError::Corrupt)
+        }
+
+        let mut buf = Cursor::new(record);
+        let bytes = |buf: &mut Cursor| -> Option<Bytes> {
+            let len = buf.u32()?;
+            Some(Bytes::copy_from_slice(buf.take(len)?))
+        };
+        let decoded = match buf.take(1).map(|kind| kind[0]) {
+            Some(BEGIN) => buf.u64().map(|tx| ReplicationRecord::Begin { tx }),
+            Some(PUT) => bytes(&mut buf).and_then(|key| Some(ReplicationRecord::Put { key, value: bytes(&mut buf)? })),
+            Some(DELETE) => bytes(&mut buf).map(|key| ReplicationRecord::Delete { key }),
+            Some(COMMIT) => buf.u64().map(|tx| ReplicationRecord::Commit { tx }),
+            Some(POSITION) => buf.u64().map(|tx| ReplicationRecord::Position { tx }),
+            _ => None
+        };
+        decoded.ok_or(ReplicationError::Corrupt)
+    }
+}
+
+fn token(tx: TransactionIdx) -> Bytes {
+    let mut token = TOKEN_PREFIX.to_vec();
+    token.extend_from_slice(&tx.to_be_bytes());
+    Bytes::from(token)
+}
+
+/// A version of the tree, with the journal of its write buffer
+#[derive(Clone, Copy)]
+struct Snapshot {
+    tree_root: Option<PageIndex>,
+    journal: Option<PageIndex>
+}
+
+const EMPTY: Snapshot = Snapshot { tree_root: None, journal: None };
+
+type EntryStream<'a> = Peekable<BoxStream<'a, Result<(Bytes, LeafValue), RetrieveError>>>;
+
+/// The keys whose values differ between two snapshots, in key order, with `None` for deleted keys
+struct Diff<'a> {
+    before: EntryStream<'a>,
+    after: EntryStream<'a>
+}
+
+impl<'a> Diff<'a> {
+    async fn peek_key(entries: &mut EntryStream<'a>) -> Result<Option<Bytes>, RetrieveError> {
+        match Pin::new(entries).peek().await {
+            Some(Ok((key, _))) => Ok(Some(key.clone())),
+            Some(Err(err)) => Err(err.clone()),
+            None => Ok(None)
+        }
+    }
+
+    async fn next(&mut self) -> Result<Option<(Bytes, Option<LeafValue>)>, RetrieveError> {
+        loop {
+            let before = Diff::peek_key(&mut self.before).await?;
+            let after = Diff::peek_key(&mut self.after).await?;
+            let order = match (&before, &after) {
+                (None, None) => return Ok(None),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(before), Some(after)) => before.cmp(after)
+            };
+
+            match order {
+                Ordering::Less => {
+                    let (key, _) = self.before.next().await.unwrap()?;
+                    return Ok(Some((key, None)))
+                },
+                Ordering::Greater => {
+                    let (key, value) = self.after.next().await.unwrap()?;
+                    return Ok(Some((key, Some(value))))
+                },
+                Ordering::Equal => {
+                    let (_, old) = self.before.next().await.unwrap()?;
+                    let (key, new) = self.after.next().await.unwrap()?;
+                    if old != new { return Ok(Some((key, Some(new)))) }
+                }
+            }
+        }
+    }
+}
+
+pub(crate) type Replica = UnboundedSender<(TransactionIdx, Vec<(Bytes, Option<Bytes>)>)>;
+
+impl DB {
+    /// Every entry of a snapshot, in key order
+    async fn snapshot_entries(&self, snapshot: Snapshot, page_count: u64) -> Result<EntryStream<'_>, RetrieveError> {
+        let now = self.options.lock().clock.now();
+        let buffered = memtable::replay(&self.cache, snapshot.journal, page_count, now).await?
+            .range(Bound::Unbounded, Bound::Unbounded);
+        Ok(self.merged_entries(snapshot.tree_root, Bound::Unbounded, Bound::Unbounded, buffered).peekable())
+    }
+
+    /// The records of one transaction, which changed `before` into `after`
+    fn transaction_records(&self, tx: TransactionIdx, before: Snapshot, after: Snapshot, page_count: u64) -> BoxStream<'_, Result<Bytes, ReplicationError>> {
+        let changes = async move {
+            let diff = Diff {
+                before: self.snapshot_entries(before, page_count).await?,
+                after: self.snapshot_entries(after, page_count).await?
+            };
+            let dictionaries = self.dictionaries();
+
+            Ok::<_, ReplicationError>(stream::try_unfold(diff, move |mut diff| {
+                let dictionaries = dictionaries.clone();
+                async move {
+                    Ok(match diff.next().await? {
+                        Some((key, Some(value))) => {
+                            let value = value.read(&self.cache, &dictionaries).await?;
+                            Some((ReplicationRecord::Put { key, value }.encode(), diff))
+                        },
+                        Some((key, None)) => Some((ReplicationRecord::Delete { key }.encode(), diff)),
+                        None => None
+                    })
+                }
+            }))
+        };
+
+        stream::once(async move { Ok(ReplicationRecord::Begin { tx }.encode()) })
+            .chain(stream::once(changes).try_flatten())
+            .chain(stream::once(async move { Ok(ReplicationRecord::Commit { tx }.encode()) }))
+            .boxed()
+    }
+
+    /// The records of the transactions after `from` up to `latest`, from the commit archive
+    async fn catch_up(&self, from: TransactionIdx, latest: VersionHeader) -> Result<BoxStream<'_, Result<Bytes, ReplicationError>>, ReplicationError> {
+        if from >= latest.tx { return Ok(stream::empty().boxed()) }
+
+        let commits = archive::load(&self.cache, latest.archive).await?;
+        // commit `tx` replaced the snapshot as of `tx - 1`
+        let as_of = |tx: TransactionIdx| match tx {
+            tx if tx == latest.tx => Some(Snapshot { tree_root: latest.tree_root, journal: latest.journal }),
+            tx => commits.iter().find(|commit| commit.tx == tx + 1).map(|commit| Snapshot { tree_root: commit.tree_root, journal: commit.journal })
+        };
+
+        let transactions: Vec<_> = match as_of(from) {
+            Some(_) => (from + 1..=latest.tx).map(|tx| (tx, as_of(tx - 1).unwrap_or(EMPTY), as_of(tx).unwrap_or(EMPTY))).collect(),
+            // every follower starts from the empty database, so it can always be sent whole
+            None if from == 0 => vec![(latest.tx, EMPTY, as_of(latest.tx).unwrap())],
+            None => {
+                let oldest = commits.last().map_or(latest.tx, |commit| commit.tx - 1);
+                return Err(ReplicationError::Unavailable { from, oldest })
+            }
+        };
+
+        Ok(stream::iter(transactions)
+            .flat_map(move |(tx, before, after)| self.transaction_records(tx, before, after, latest.page_count))
+            .boxed())
+    }
+
+    /// Stream every transaction committed after `from` as framed records, then each commit
+    /// as it happens, until the database is dropped. Transactions already committed are read
+    /// from the commit archive, so `from` must be among the last `Options::archive_commits`
+    /// commits, or 0 to send the whole database as one transaction. Bulk loads and imports
+    /// aren't streamed.
+    pub fn replication_stream(&self, from: TransactionIdx) -> BoxStream<'_, Result<Bytes, ReplicationError>> {
+        // subscribe before reading the version, so no commit falls between the two
+        let (replica, live) = mpsc::unbounded();
+        self.replicas.lock().push(replica);
+        let latest = *self.version.lock();
+
+        let live = live
+            .filter(move |(tx, _)| futures::future::ready(*tx > latest.tx))
+            .flat_map(|(tx, writes)| {
+                let changes = writes.into_iter().map(|(key, value)| match value {
+                    Some(value) => ReplicationRecord::Put { key, value },
+                    None => ReplicationRecord::Delete { key }
+                });
+                stream::iter(std::iter::once(ReplicationRecord::Begin { tx })
+                    .chain(changes)
+                    .chain(std::iter::once(ReplicationRecord::Commit { tx }))
+                    .map(|record| Ok(record.encode()))
+                    .collect::<Vec<_>>())
+            });
+
+        stream::once(async move { Ok(ReplicationRecord::Position { tx: latest.tx }.encode()) })
+            .chain(stream::once(self.catch_up(from, latest)).try_flatten())
+            .chain(live)
+            .boxed()
+    }
+
+    /// Send the changes committed by `tx` to replication streams. Commits call this while
+    /// still holding `writer`, so streams see transactions in order.
+    pub(super) async fn notify_replicas(&self, tx: TransactionIdx, writes: &[Write], observer: &Arc<dyn Observer>) {
+        {
+            let mut replicas = self.replicas.lock();
+            replicas.retain(|replica| !replica.is_closed());
+            if replicas.is_empty() { return }
+        }
+
+        let dictionaries = self.dictionaries();
+        let mut changes = Vec::with_capacity(writes.len());
+        for (key, value) in writes {
+            match value {
+                Some(value) => match value.read(&self.cache, &dictionaries).await {
+                    Ok(value) => changes.push((key.clone(), Some(value))),
+                    Err(err) => {
+                        // a stream missing a change would silently diverge, so end them all
+                        observer.on_error(&err);
+                        self.replicas.lock().clear();
+                        return
+                    }
+                },
+                None => changes.push((key.clone(), None))
+            }
+        }
+
+        for replica in self.replicas.lock().iter() {
+            let _ = replica.unbounded_send((tx, changes.clone()));
+        }
+    }
+
+    /// Apply one frame of a primary's replication stream. Each transaction is committed when
+    /// its `Commit` record arrives, returning the outcome, and one already applied is skipped.
+    /// Works on a follower, which rejects other writes.
+    pub async fn apply_replication_record(&self, frame: &[u8]) -> Result<Option<BatchOutcome>, ReplicationError> {
+        let record = ReplicationRecord::decode(frame)?;
+        let mut pending = self.replicating.lock().await;
+
+        match record {
+            ReplicationRecord::Position { tx } => self.record_position(tx),
+            ReplicationRecord::Begin { tx } => {
+                if pending.is_some() { return Err(ReplicationError::OutOfOrder("begin inside a transaction")) }
+                self.record_position(tx);
+                *pending = Some((tx, Batch::new()));
+            },
+            ReplicationRecord::Put { key, value } => match pending.as_mut() {
+                Some((_, batch)) => { batch.put(key, value); },
+                None => return Err(ReplicationError::OutOfOrder("put outside a transaction"))
+            },
+            ReplicationRecord::Delete { key } => match pending.as_mut() {
+                Some((_, batch)) => { batch.delete(key); },
+                None => return Err(ReplicationError::OutOfOrder("delete outside a transaction"))
+            },
+            ReplicationRecord::Commit { tx } => match pending.take() {
+                Some((begun, batch)) if begun == tx => {
+                    // a follower rejects writes, but not these
+                    let txn = if self.is_follower() { self.begin_write().await } else { self.write().await };
+                    let outcome = self.apply_with_token(txn.map_err(WriteError::from)?, batch, token(tx)).await?;
+                    self.record_applied(tx);
+                    return Ok(Some(outcome))
+                },
+                Some(_) => return Err(ReplicationError::OutOfOrder("commit of a different transaction")),
+                None => return Err(ReplicationError::OutOfOrder("commit outside a transaction"))
+            }
+        }
+        Ok(None)
+    }
+
+    /// The latest of the primary's transactions applied here by `apply_replication_record`, to
+    /// resume its stream from, or `None` if none has been
+    pub async fn replicated_tx(&self) -> Result<Option<TransactionIdx>, RetrieveError> {
+        let (root, max_depth) = match (self.version.lock().tokens, self.options.lock().max_tree_depth) {
+            (Some(root), max_depth) => (root, max_depth),
+            (None, _) => return Ok(None)
+        };
+
+        let mut tokens = Entries::new(Some(root), Bound::Included(Bytes::from_static(TOKEN_PREFIX)), max_depth);
+        let mut latest = None;
+        while let Some(entry) = tokens.next(&self.cache).await? {
+            if !entry.key.starts_with(TOKEN_PREFIX) { break }
+            let tx = entry.key[TOKEN_PREFIX.len()..].try_into().map_err(|_| RetrieveError::Malformed(root))?;
+            latest = Some(TransactionIdx::from_be_bytes(tx));
+        }
+        Ok(latest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use futures::executor::block_on;
+    use super::*;
+    use super::super::Options;
+
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let path = std::env::temp_dir().join(format!("bssdb-replication-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn options() -> Options {
+        let mut options = Options::new();
+        options.direct_io(false).archive_commits(8);
+        options
+    }
+
+    fn commit(db: &DB, puts: &[(&'static [u8], &'static [u8])], deletes: &[&'static [u8]]) -> TransactionIdx {
+        block_on(async {
+            let mut txn = db.write().await.unwrap();
+            for (key, value) in puts {
+                txn.put(Bytes::from_static(key), Bytes::from_static(value)).unwrap();
+            }
+            for key in deletes {
+                txn.delete(Bytes::from_static(key)).unwrap();
+            }
+            txn.commit().await.unwrap()
+        })
+    }
+
+    /// Frames from `stream` up to and including the commit of `tx`
+    fn frames_through(stream: &mut BoxStream<'_, Result<Bytes, ReplicationError>>, tx: TransactionIdx) -> Vec<Bytes> {
+        let mut frames = vec![];
+        loop {
+            let frame = block_on(stream.next()).unwrap().unwrap();
+            let done = ReplicationRecord::decode(&frame).unwrap() == ReplicationRecord::Commit { tx };
+            frames.push(frame);
+            if done { return frames }
+        }
+    }
+
+    fn get(db: &DB, key: &[u8]) -> Option<Bytes> {
+        block_on(db.get(key)).unwrap()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let records = [
+            ReplicationRecord::Position { tx: 9 },
+            ReplicationRecord::Begin { tx: 3 },
+            ReplicationRecord::Put { key: Bytes::from_static(b"key"), value: Bytes::new() },
+            ReplicationRecord::Delete { key: Bytes::from_static(b"gone") },
+            ReplicationRecord::Commit { tx: 3 }
+        ];
+        for record in records.iter() {
+            let frame = record.encode();
+            assert_eq!(ReplicationRecord::frame_len(&frame), Some(frame.len()));
+            assert_eq!(&ReplicationRecord::decode(&frame).unwrap(), record);
+
+            let mut corrupt = frame.to_vec();
+            *corrupt.last_mut().unwrap() ^= 1;
+            assert!(matches!(ReplicationRecord::decode(&corrupt), Err(ReplicationError::Corrupt)));
+            assert!(matches!(ReplicationRecord::decode(&frame[..frame.len() - 1]), Err(ReplicationError::Corrupt)));
+        }
+    }
+
+    #[test]
+    fn follower_replays_primary() {
+        let (primary_file, follower_file) = (TempFile::new("primary"), TempFile::new("follower"));
+        let primary = block_on(DB::open(&primary_file.0, options())).unwrap();
+        commit(&primary, &[(b"a", b"1"), (b"b", b"2")], &[]);
+        let caught_up = commit(&primary, &[(b"c", b"3")], &[b"a"]);
+
+        let follower = block_on(DB::open_follower(&follower_file.0, options())).unwrap();
+        assert!(block_on(follower.write()).is_err());
+
+        // from nothing, the primary's data arrives as one transaction
+        let mut stream = primary.replication_stream(0);
+        let frames = frames_through(&mut stream, caught_up);
+        for frame in &frames {
+            block_on(follower.apply_replication_record(frame)).unwrap();
+        }
+        assert_eq!(get(&follower, b"a"), None);
+        assert_eq!(get(&follower, b"b"), Some(Bytes::from_static(b"2")));
+        assert_eq!(get(&follower, b"c"), Some(Bytes::from_static(b"3")));
+        assert_eq!(block_on(follower.replicated_tx()).unwrap(), Some(caught_up));
+
+        // then each commit as it happens
+        let live = commit(&primary, &[(b"d", b"4")], &[b"b"]);
+        for frame in frames_through(&mut stream, live) {
+            block_on(follower.apply_replication_record(&frame)).unwrap();
+        }
+        assert_eq!(get(&follower, b"b"), None);
+        assert_eq!(get(&follower, b"d"), Some(Bytes::from_static(b"4")));
+        assert_eq!(follower.replication_lag().unwrap().transactions, 0);
+
+        // a transaction delivered again is skipped
+        let mut outcomes = frames.iter().filter_map(|frame| block_on(follower.apply_replication_record(frame)).unwrap());
+        assert_eq!(outcomes.next(), Some(BatchOutcome::AlreadyApplied(follower.latest_transaction() - 1)));
+        assert_eq!(get(&follower, b"b"), None);
+
+        // frames out of order are refused
+        let stray = ReplicationRecord::Put { key: Bytes::from_static(b"x"), value: Bytes::new() }.encode();
+        assert!(matches!(block_on(follower.apply_replication_record(&stray)), Err(ReplicationError::OutOfOrder(_))));
+    }
+}
@@ -0,0 +1,112 @@
+use std::convert::TryInto;
+use std::time::Duration;
+
+use super::{Backpressure, Durability, Options};
+
+/// A runtime-adjustable option, persisted with the database by `DB::set_option`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    /// Page cache memory budget in bytes
+    CacheBytes(usize),
+    Durability(Durability),
+    /// How hard to throttle writers, or `None` to never push back. See `Options::backpressure`.
+    Backpressure(Option<Backpressure>)
+}
+
+/// Settings persisted in the root page. Unset settings fall back to the `Options` the database was opened with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Settings {
+    pub cache_bytes: Option<u64>,
+    pub durability: Option<Durability>,
+    pub backpressure: Option<Option<Backpressure>>
+}
+
+pub(crate) const SETTINGS_LEN: usize = 9;
+
+/// Bytes of the write limits, stored after the root page's other fields rather than beside the other settings
+pub(crate) const LIMITS_LEN: usize = 41;
+
+const UNSET: u8 = 0xff;
+
+/// Limits bytes of zero are unset, as in root pages written before they existed
+const LIMITS_UNSET: u8 = 0;
+const LIMITS_NONE: u8 = 1;
+const LIMITS_SET: u8 = 2;
+
+impl Settings {
+    pub fn set(&mut self, setting: Setting) {
+        match setting {
+            Setting::CacheBytes(bytes) => self.cache_bytes = Some(bytes as u64),
+            Setting::Durability(durability) => self.durability = Some(durability),
+            Setting::Backpressure(backpressure) => self.backpressure = Some(backpressure)
+        }
+    }
+
+    /// Override `options` with every persisted setting
+    pub fn apply(&self, options: &mut Options) {
+        if let Some(bytes) = self.cache_bytes { options.cache.max_bytes = bytes as usize; }
+        if let Some(durability) = self.durability { options.durability = durability; }
+        if let Some(backpressure) = self.backpressure { options.backpressure = backpressure; }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        // zero is never a useful cache size, so it means unset
+        buf[0..8].copy_from_slice(&self.cache_bytes.unwrap_or(0).to_le_bytes());
+        buf[8] = match self.durability {
+            None => UNSET,
+            Some(Durability::NoSync) => 0,
+            Some(Durability::SyncData) => 1,
+            Some(Durability::SyncAll) => 2
+        };
+    }
+
+    pub fn encode_limits(&self, buf: &mut [u8]) {
+        let backpressure = match self.backpressure {
+            None => { buf[0] = LIMITS_UNSET; return },
+            Some(None) => { buf[0] = LIMITS_NONE; return },
+            Some(Some(backpressure)) => backpressure
+        };
+        buf[0] = LIMITS_SET;
+
+        let fields = [
+            backpressure.slowdown_dirty_pages as u64,
+            backpressure.stop_dirty_pages as u64,
+            backpressure.slowdown_buffer_bytes as u64,
+            backpressure.stop_buffer_bytes as u64,
+            backpressure.max_delay.as_micros() as u64
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            buf[1 + i * 8..9 + i * 8].copy_from_slice(&field.to_le_bytes());
+        }
+    }
+
+    pub fn decode(buf: &[u8], limits: &[u8]) -> Settings {
+        let mut cache_bytes = [0; 8];
+        cache_bytes.copy_from_slice(&buf[0..8]);
+        let field = |i: usize| u64::from_le_bytes(limits[1 + i * 8..9 + i * 8].try_into().unwrap());
+
+        Settings {
+            cache_bytes: match u64::from_le_bytes(cache_bytes) {
+                0 => None,
+                bytes => Some(bytes)
+            },
+            durability: match buf[8] {
+                0 => Some(Durability::NoSync),
+                1 => Some(Durability::SyncData),
+                2 => Some(Durability::SyncAll),
+                _ => None
+            },
+            backpressure: match limits[0] {
+                LIMITS_NONE => Some(None),
+                LIMITS_SET => Some(Some(Backpressure {
+                    slowdown_dirty_pages: field(0) as usize,
+                    stop_dirty_pages: field(1) as usize,
+                    slowdown_buffer_bytes: field(2) as usize,
+                    stop_buffer_bytes: field(3) as usize,
+                    max_delay: Duration::from_micros(field(4))
+                })),
+                _ => None
+            }
+        }
+    }
+}
@@ -0,0 +1,405 @@
+//! Deterministic simulation: an in-memory page store that can crash, losing and tearing the
+//! writes that weren't synced, and a single-threaded executor that schedules tasks and time
+//! from a seed. A test runs the same workload with a crash at each point in turn, reopens the
+//! store that survived, and checks the database recovered.
+//!
+//! ```ignore
+//! for crash_after in 0.. {
+//!     let store = SimStore::new(SimConfig { crash_after: Some(crash_after), ..SimConfig::default() });
+//!     let executor = SimExecutor::new(crash_after);
+//!     let mut options = Options::new();
+//!     options.spawner(executor.clone());
+//!     executor.block_on(async {
+//!         if let Ok(db) = DB::open_store(store.clone(), options.clone()).await { workload(&db).await }
+//!     });
+//!     if !store.crashed() { break }
+//!     executor.block_on(async { check(&DB::open_store(store.crash(), options).await?).await })?;
+//! }
+//! ```
+//!
+//! Page writes are submitted from the database's write-back thread, which the executor doesn't
+//! schedule, so the order writes complete in comes from the store's seeded delays alone.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+use futures::future::{BoxFuture, FutureExt};
+use futures::task::{waker, ArcWake};
+use parking_lot::Mutex;
+
+use super::{Durability, PageContent, PageIndex, PageStore, RetrieveError, Spawn};
+use super::page::PAGE_SIZE;
+
+/// Writes tear at sector boundaries
+const SECTOR: usize = 512;
+
+/// A xorshift generator, so every run from a seed makes the same choices
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // xorshift gets stuck at zero
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A uniform sample from [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A uniform sample from [0, n)
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 { 0 } else { self.next() % n }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SimConfig {
+    /// Seeds every choice the store makes
+    pub seed: u64,
+    /// Each operation yields to the executor up to this many times before completing, so
+    /// concurrent operations complete in a seeded order
+    pub max_delay: u32,
+    /// The chance that a write not yet synced survives a crash. Each survives or is lost
+    /// independently, so later writes can survive earlier ones.
+    pub unsynced_survival: f64,
+    /// The chance that an unsynced write which survives a crash is torn, keeping its first
+    /// few sectors and the old contents of the rest
+    pub torn_writes: f64,
+    /// Crash once this many operations have completed: the next one fails, as does every one
+    /// after it
+    pub crash_after: Option<u64>
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig { seed: 0, max_delay: 0, unsynced_survival: 0.5, torn_writes: 0.1, crash_after: None }
+    }
+}
+
+struct State {
+    /// Pages as they'd be after power loss
+    synced: HashMap<PageIndex, Box<[u8]>>,
+    /// Writes since the last sync, oldest first, which reads see but a crash may lose
+    unsynced: Vec<(PageIndex, Box<[u8]>)>,
+    ops: u64,
+    crashed: bool
+}
+
+/// An in-memory `PageStore` that loses, reorders and tears unsynced writes when it crashes
+pub struct SimStore {
+    config: SimConfig,
+    state: Mutex<State>,
+    rng: Mutex<Rng>
+}
+
+impl SimStore {
+    pub fn new(config: SimConfig) -> Arc<SimStore> {
+        SimStore::with_pages(config, HashMap::new())
+    }
+
+    fn with_pages(config: SimConfig, synced: HashMap<PageIndex, Box<[u8]>>) -> Arc<SimStore> {
+        Arc::new(SimStore {
+            config,
+            state: Mutex::new(State { synced, unsynced: vec![], ops: 0, crashed: false }),
+            rng: Mutex::new(Rng::new(config.seed))
+        })
+    }
+
+    /// Operations completed so far. A workload that completes without crashing shows how
+    /// many crash points there are to try.
+    pub fn ops(&self) -> u64 {
+        self.state.lock().ops
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.state.lock().crashed
+    }
+
+    /// Crash now, if the store hasn't already, and return a store holding what survived:
+    /// every synced write, and a seeded choice of the unsynced ones, some torn. This store
+    /// fails every operation from then on. The new store never crashes on its own.
+    pub fn crash(&self) -> Arc<SimStore> {
+        let mut state = self.state.lock();
+        let mut rng = self.rng.lock();
+        state.crashed = true;
+
+        let mut pages = state.synced.clone();
+        for (idx, page) in state.unsynced.iter() {
+            if rng.unit() >= self.config.unsynced_survival { continue }
+
+            let mut survived = page.clone();
+            if rng.unit() < self.config.torn_writes {
+                let sectors = PAGE_SIZE / SECTOR;
+                let kept = (1 + rng.below(sectors as u64 - 1)) as usize * SECTOR;
+                match pages.get(idx) {
+                    Some(old) => survived[kept..].copy_from_slice(&old[kept..]),
+                    None => survived[kept..].iter_mut().for_each(|byte| *byte = 0)
+                }
+            }
+            pages.insert(*idx, survived);
+        }
+
+        let config = SimConfig { seed: rng.next(), crash_after: None, ..self.config };
+        SimStore::with_pages(config, pages)
+    }
+
+    /// Count an operation, crashing if it's the one configured to
+    fn begin(&self) -> io::Result<()> {
+        let mut state = self.state.lock();
+        if !state.crashed && self.config.crash_after.map_or(false, |crash_after| state.ops >= crash_after) {
+            state.crashed = true;
+        }
+        if state.crashed { return Err(io::Error::new(io::ErrorKind::Other, "simulated crash")) }
+        state.ops += 1;
+        Ok(())
+    }
+
+    fn delay(&self) -> Yield {
+        Yield(self.rng.lock().below(self.config.max_delay as u64 + 1) as u32)
+    }
+
+    fn read(&self, idx: PageIndex) -> Result<PageContent, RetrieveError> {
+        let state = self.state.lock();
+        let latest = state.unsynced.iter().rev().find(|(written, _)| *written == idx).map(|(_, page)| page)
+            .or_else(|| state.synced.get(&idx));
+
+        match latest {
+            Some(page) => PageContent::from_bytes(page).ok_or(RetrieveError::BadChecksum),
+            // pages before the end of the file that were never written read as zeros
+            None if state.synced.keys().chain(state.unsynced.iter().map(|(idx, _)| idx)).any(|&written| written > idx) => {
+                Ok(PageContent::from_bytes(&[0; PAGE_SIZE]).unwrap())
+            },
+            None => Err(RetrieveError::OutOfPages)
+        }
+    }
+}
+
+/// Yields to the executor this many times before completing
+struct Yield(u32);
+
+impl Future for Yield {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 == 0 { return Poll::Ready(()) }
+        self.0 -= 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+impl PageStore for SimStore {
+    fn read_page(&self, idx: PageIndex) -> BoxFuture<'_, Result<PageContent, RetrieveError>> {
+        async move {
+            self.begin().map_err(Arc::new)?;
+            self.delay().await;
+            self.read(idx)
+        }.boxed()
+    }
+
+    fn write_page<'a>(&'a self, idx: PageIndex, page: &'a PageContent) -> BoxFuture<'a, io::Result<()>> {
+        // submitted before returning, like a real write
+        let submitted = self.begin().map(|()| {
+            self.state.lock().unsynced.push((idx, page.as_slice().into()));
+        });
+        async move {
+            submitted?;
+            self.delay().await;
+            Ok(())
+        }.boxed()
+    }
+
+    fn sync(&self, durability: Durability) -> BoxFuture<'_, io::Result<()>> {
+        async move {
+            self.begin()?;
+            self.delay().await;
+            if durability != Durability::NoSync {
+                let mut state = self.state.lock();
+                let unsynced = std::mem::take(&mut state.unsynced);
+                state.synced.extend(unsynced);
+            }
+            Ok(())
+        }.boxed()
+    }
+}
+
+struct Scheduler {
+    /// Spawned tasks by id, `None` once finished or while being polled
+    tasks: Vec<Option<BoxFuture<'static, ()>>>,
+    /// Tasks woken since they were last polled
+    ready: Vec<usize>,
+    /// Sleepers, by when they wake and then the order they slept in
+    timers: BinaryHeap<Reverse<(Duration, u64, usize)>>,
+    wakers: HashMap<usize, Waker>,
+    next_timer: u64,
+    /// Time since the executor started, which only moves when every task is asleep
+    now: Duration,
+    rng: Rng,
+    /// The thread running `block_on`, woken when another thread wakes a task
+    runner: Option<Thread>
+}
+
+struct Shared {
+    scheduler: Mutex<Scheduler>
+}
+
+/// The id `block_on`'s own future is scheduled under
+const MAIN: usize = usize::MAX;
+
+struct TaskWaker {
+    id: usize,
+    shared: Arc<Shared>
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(task: &Arc<Self>) {
+        let mut scheduler = task.shared.scheduler.lock();
+        if !scheduler.ready.contains(&task.id) { scheduler.ready.push(task.id) }
+        if let Some(runner) = &scheduler.runner { runner.unpark() }
+    }
+}
+
+/// A single-threaded executor that picks which woken task to poll next, and when sleeping
+/// tasks wake, from a seed. Time is virtual: it jumps to the next sleeper's wake time once
+/// no task can run. Register it with `Options::spawner` so the database's background tasks
+/// are scheduled with everything else.
+#[derive(Clone)]
+pub struct SimExecutor {
+    shared: Arc<Shared>
+}
+
+impl SimExecutor {
+    pub fn new(seed: u64) -> SimExecutor {
+        SimExecutor {
+            shared: Arc::new(Shared {
+                scheduler: Mutex::new(Scheduler {
+                    tasks: vec![],
+                    ready: vec![],
+                    timers: BinaryHeap::new(),
+                    wakers: HashMap::new(),
+                    next_timer: 0,
+                    now: Duration::from_secs(0),
+                    rng: Rng::new(seed),
+                    runner: None
+                })
+            })
+        }
+    }
+
+    /// Time since the executor started
+    pub fn now(&self) -> Duration {
+        self.shared.scheduler.lock().now
+    }
+
+    fn waker(&self, id: usize) -> Waker {
+        waker(Arc::new(TaskWaker { id, shared: self.shared.clone() }))
+    }
+
+    /// Run `future`, and the tasks spawned meanwhile, until `future` completes. Tasks still
+    /// running then are polled again by the next call.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        futures::pin_mut!(future);
+        let main_waker = self.waker(MAIN);
+        {
+            let mut scheduler = self.shared.scheduler.lock();
+            scheduler.runner = Some(thread::current());
+            scheduler.ready.push(MAIN);
+        }
+
+        loop {
+            let next = {
+                let mut scheduler = self.shared.scheduler.lock();
+                if scheduler.ready.is_empty() {
+                    // every task is waiting: wake the next sleeper, or another thread
+                    match scheduler.timers.pop() {
+                        Some(Reverse((at, _, id))) => {
+                            scheduler.now = scheduler.now.max(at);
+                            if let Some(waker) = scheduler.wakers.remove(&id) { waker.wake() }
+                        },
+                        None => {
+                            std::mem::drop(scheduler);
+                            thread::park();
+                        }
+                    }
+                    continue
+                }
+
+                let pick = scheduler.rng.below(scheduler.ready.len() as u64) as usize;
+                let id = scheduler.ready.swap_remove(pick);
+                match id {
+                    MAIN => None,
+                    id => match scheduler.tasks.get_mut(id).and_then(Option::take) {
+                        Some(task) => Some((id, task)),
+                        None => continue
+                    }
+                }
+            };
+
+            match next {
+                None => {
+                    if let Poll::Ready(output) = future.as_mut().poll(&mut Context::from_waker(&main_waker)) {
+                        return output
+                    }
+                },
+                Some((id, mut task)) => {
+                    let waker = self.waker(id);
+                    if task.as_mut().poll(&mut Context::from_waker(&waker)).is_pending() {
+                        self.shared.scheduler.lock().tasks[id] = Some(task);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Wakes once the executor's virtual time reaches `at`
+struct Sleep {
+    at: Duration,
+    shared: Arc<Shared>
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut scheduler = self.shared.scheduler.lock();
+        if scheduler.now >= self.at { return Poll::Ready(()) }
+
+        let timer = scheduler.next_timer;
+        scheduler.next_timer += 1;
+        // timers are keyed apart from tasks, since one task may sleep in several places
+        let key = usize::MAX - 1 - timer as usize;
+        scheduler.wakers.insert(key, cx.waker().clone());
+        scheduler.timers.push(Reverse((self.at, timer, key)));
+        Poll::Pending
+    }
+}
+
+impl Spawn for SimExecutor {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        let mut scheduler = self.shared.scheduler.lock();
+        let id = scheduler.tasks.len();
+        scheduler.tasks.push(Some(task));
+        scheduler.ready.push(id);
+        if let Some(runner) = &scheduler.runner { runner.unpark() }
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let at = self.now() + duration;
+        Sleep { at, shared: self.shared.clone() }.boxed()
+    }
+}
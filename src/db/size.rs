@@ -0,0 +1,227 @@
+//! Counting keys and estimating sizes from the subtree sizes branches keep, without reading
+//! every leaf
+
+use std::ops::{Bound, RangeBounds};
+use bytes::Bytes;
+use futures::future::{self, try_join_all, BoxFuture, FutureExt};
+
+use super::{DB, PageCache, PageIndex, RetrieveError};
+use super::branch::{Branch, Stats};
+use super::descent::Descent;
+use super::leaf;
+use super::page::{PageType, PAGE_SIZE};
+use super::range;
+use super::tree::{self, leaf_stats, read_node};
+
+/// The estimated size of a range of keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RangeSize {
+    pub entries: u64,
+    /// Bytes of keys and values, as stored, which may be compressed
+    pub bytes: u64
+}
+
+fn after_start(key: &[u8], from: &Bound<Bytes>) -> bool {
+    match from {
+        Bound::Included(from) => key >= &from[..],
+        Bound::Excluded(from) => key > &from[..],
+        Bound::Unbounded => true
+    }
+}
+
+fn before_end(key: &[u8], to: &Bound<Bytes>) -> bool {
+    match to {
+        Bound::Included(to) => key <= &to[..],
+        Bound::Excluded(to) => key < &to[..],
+        Bound::Unbounded => true
+    }
+}
+
+/// The size of the part of the subtree at `idx` in `from..to`. Children wholly in or out
+/// of the range are counted from their parent, so only the paths to the ends are read.
+fn range_stats<'a>(cache: &'a PageCache, idx: PageIndex, from: &'a Bound<Bytes>, to: &'a Bound<Bytes>, mut descent: Descent) -> BoxFuture<'a, Result<Stats, RetrieveError>> {
+    async move {
+        descent.enter(idx)?;
+        let page = read_node(cache, idx).await?;
+
+        match page.page_type {
+            PageType::Leaf => {
+                let mut entries = leaf::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                entries.retain(|entry| after_start(&entry.key, from) && before_end(&entry.key, to));
+                Ok(leaf_stats(&entries))
+            },
+            PageType::Branch => {
+                let branch = Branch::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                let mut total = Stats::default();
+                let mut partial = vec![];
+
+                for i in 0..=branch.separators.len() {
+                    // the child holds keys from `low` up to, but not including, `high`
+                    let low = i.checked_sub(1).map(|i| &branch.separators[i].0[..]);
+                    let high = branch.separators.get(i).map(|(high, _)| &high[..]);
+
+                    let below = high.map_or(false, |high| !after_start(high, from) || matches!(from, Bound::Included(from) if &from[..] == high));
+                    let above = low.map_or(false, |low| !before_end(low, to));
+                    if below || above { continue }
+
+                    let starts_inside = match from {
+                        Bound::Unbounded => true,
+                        _ => low.map_or(false, |low| after_start(low, from))
+                    };
+                    let ends_inside = match to {
+                        Bound::Unbounded => true,
+                        Bound::Included(to) | Bound::Excluded(to) => high.map_or(false, |high| high <= &to[..])
+                    };
+
+                    if starts_inside && ends_inside {
+                        total = total.add(branch.stats[i]);
+                    } else {
+                        partial.push(range_stats(cache, branch.child(i), from, to, descent.clone()));
+                    }
+                }
+
+                Ok(try_join_all(partial).await?.into_iter().fold(total, Stats::add))
+            },
+            _ => Err(RetrieveError::Malformed(idx))
+        }
+    }.boxed()
+}
+
+/// The first `depth` bytes of `key`, or all of it if shorter
+fn prefix(key: &[u8], depth: usize) -> &[u8] {
+    &key[..key.len().min(depth)]
+}
+
+/// Add `counts` to the sorted `into`, merging the prefix where they meet
+fn extend_counts(into: &mut Vec<(Bytes, Stats)>, counts: Vec<(Bytes, Stats)>) {
+    let mut counts = counts.into_iter();
+    if let Some((key, stats)) = counts.next() {
+        match into.last_mut() {
+            Some((last, total)) if *last == key => *total = total.add(stats),
+            _ => into.push((key, stats))
+        }
+    }
+    into.extend(counts);
+}
+
+/// The sizes of each `depth`-byte key prefix in the subtree at `idx`. A child whose separators
+/// share a prefix holds only that prefix, so it's counted from its parent without being read.
+fn prefix_stats(cache: &PageCache, idx: PageIndex, depth: usize, mut descent: Descent) -> BoxFuture<'_, Result<Vec<(Bytes, Stats)>, RetrieveError>> {
+    async move {
+        descent.enter(idx)?;
+        let page = read_node(cache, idx).await?;
+
+        match page.page_type {
+            PageType::Leaf => {
+                let entries = leaf::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                let mut counts = vec![];
+                let mut start = 0;
+                for end in 1..=entries.len() {
+                    let group = prefix(&entries[start].key, depth);
+                    if end == entries.len() || prefix(&entries[end].key, depth) != group {
+                        counts.push((Bytes::copy_from_slice(group), leaf_stats(&entries[start..end])));
+                        start = end;
+                    }
+                }
+                Ok(counts)
+            },
+            PageType::Branch => {
+                let branch = Branch::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                let children = (0..=branch.separators.len()).map(|i| {
+                    let low = i.checked_sub(1).map(|i| &branch.separators[i].0);
+                    let high = branch.separators.get(i).map(|(high, _)| high);
+                    match (low, high) {
+                        (Some(low), Some(high)) if low.len() >= depth && high.len() >= depth && low[..depth] == high[..depth] =>
+                            future::ready(Ok(vec![(low.slice(..depth), branch.stats[i])])).boxed(),
+                        _ => prefix_stats(cache, branch.child(i), depth, descent.clone())
+                    }
+                });
+
+                let mut counts = vec![];
+                for child in try_join_all(children).await? {
+                    extend_counts(&mut counts, child);
+                }
+                Ok(counts)
+            },
+            _ => Err(RetrieveError::Malformed(idx))
+        }
+    }.boxed()
+}
+
+impl DB {
+    /// The number of keys. Reads the root page and any keys still in the write buffer.
+    /// Keys whose TTL has expired count until they're swept.
+    pub async fn len(&self) -> Result<u64, RetrieveError> {
+        let (version, buffered) = self.buffered_range(Bound::Unbounded, Bound::Unbounded);
+        let max_depth = self.options.lock().max_tree_depth;
+        let root = match version.tree_root {
+            Some(root) => root,
+            None => return Ok(buffered.iter().filter(|(_, value)| value.is_some()).count() as u64)
+        };
+
+        let mut len = self.tree_stats(root).await?.entries;
+        if !buffered.is_empty() {
+            let keys: Vec<Bytes> = buffered.iter().map(|(key, _)| key.clone()).collect();
+            let in_tree = tree::lookup_many(&self.cache, root, &keys, Descent::new(max_depth)).await?;
+            for ((_, value), in_tree) in buffered.iter().zip(in_tree) {
+                match (value, in_tree) {
+                    (Some(_), None) => len += 1,
+                    (None, Some(_)) => len -= 1,
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(len)
+    }
+
+    pub async fn is_empty(&self) -> Result<bool, RetrieveError> {
+        Ok(self.len().await? == 0)
+    }
+
+    /// The size of the tree at `root`, from its root page
+    pub(super) async fn tree_stats(&self, root: PageIndex) -> Result<Stats, RetrieveError> {
+        let page = read_node(&self.cache, root).await?;
+        match page.page_type {
+            PageType::Leaf => Ok(leaf_stats(&leaf::decode(&page).ok_or(RetrieveError::Malformed(root))?)),
+            PageType::Branch => Ok(Branch::decode(&page).ok_or(RetrieveError::Malformed(root))?.total()),
+            _ => Err(RetrieveError::Malformed(root))
+        }
+    }
+
+    /// Bytes of the database file, including pages no longer in use
+    pub fn size_on_disk(&self) -> u64 {
+        self.version.lock().page_count * PAGE_SIZE as u64
+    }
+
+    /// Estimate the keys and bytes in `range`, for planning scans. Reads only the pages on the
+    /// paths to the ends of the range, and ignores the write buffer.
+    pub async fn approximate_range_size<R: RangeBounds<Bytes>>(&self, range: R) -> Result<RangeSize, RetrieveError> {
+        let from = range::owned(range.start_bound());
+        let to = range::owned(range.end_bound());
+
+        let root = self.version.lock().tree_root;
+        let max_depth = self.options.lock().max_tree_depth;
+        let stats = match root {
+            Some(root) => range_stats(&self.cache, root, &from, &to, Descent::new(max_depth)).await?,
+            None => Stats::default()
+        };
+
+        Ok(RangeSize { entries: stats.entries, bytes: stats.bytes })
+    }
+
+    /// The size of each distinct `depth`-byte key prefix, in key order, for finding unbalanced
+    /// keyspaces and hot prefixes. Keys shorter than `depth` count under themselves. Reads the
+    /// pages whose keys straddle a prefix boundary, so the cost grows with the number of
+    /// prefixes rather than keys, and ignores the write buffer.
+    pub async fn prefix_histogram(&self, depth: usize) -> Result<Vec<(Bytes, RangeSize)>, RetrieveError> {
+        let root = self.version.lock().tree_root;
+        let max_depth = self.options.lock().max_tree_depth;
+        let counts = match root {
+            Some(root) => prefix_stats(&self.cache, root, depth, Descent::new(max_depth)).await?,
+            None => vec![]
+        };
+
+        Ok(counts.into_iter().map(|(prefix, stats)| (prefix, RangeSize { entries: stats.entries, bytes: stats.bytes })).collect())
+    }
+}
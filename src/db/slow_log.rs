@@ -0,0 +1,86 @@
+//! Reporting operations slower than `Options::slow_operation_threshold` to the observer
+
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+
+use super::{DB, PageIndex, SlowOperation, OperationKind};
+use super::branch::Branch;
+use super::descent::Descent;
+use super::page::{PageType, PAGE_SIZE};
+use super::tree::read_node;
+use super::version::VersionHeader;
+
+impl DB {
+    /// How long an operation that began at `started` took, if that's slow enough to report
+    fn slow_since(&self, started: Instant) -> Option<Duration> {
+        let threshold = self.options.lock().slow_threshold?;
+        let duration = started.elapsed();
+        if duration < threshold { return None }
+        Some(duration)
+    }
+
+    fn report_slow(&self, operation: SlowOperation) {
+        let observer = self.options.lock().observer.clone();
+        observer.on_slow_operation(&operation);
+    }
+
+    /// Report a read of `key` that began at `started`, if it was slow. The path to the key is
+    /// descended again to find the pages involved, which the read just cached.
+    pub(super) async fn report_slow_read(&self, started: Instant, key: &[u8], value_len: u64) {
+        let duration = match self.slow_since(started) {
+            Some(duration) => duration,
+            None => return
+        };
+
+        let root = self.version.lock().tree_root;
+        let path = match root {
+            Some(root) => self.path_to(root, key).await,
+            None => vec![]
+        };
+        self.report_slow(SlowOperation {
+            kind: OperationKind::Read,
+            duration,
+            key: Some(Bytes::copy_from_slice(key)),
+            tx: None,
+            path,
+            written: 0..0,
+            bytes: value_len
+        });
+    }
+
+    /// Report a commit or checkpoint from `previous` to `version` that began at `started`, if
+    /// it was slow
+    pub(super) fn report_slow_write(&self, started: Instant, kind: OperationKind, previous: &VersionHeader, version: &VersionHeader) {
+        let duration = match self.slow_since(started) {
+            Some(duration) => duration,
+            None => return
+        };
+
+        let written = previous.page_count..version.page_count;
+        self.report_slow(SlowOperation {
+            kind,
+            duration,
+            key: None,
+            tx: Some(version.tx),
+            path: vec![],
+            bytes: (written.end - written.start) * PAGE_SIZE as u64,
+            written
+        });
+    }
+
+    /// The pages from `root` down to the leaf that would hold `key`, as far as they can be read
+    async fn path_to(&self, root: PageIndex, key: &[u8]) -> Vec<PageIndex> {
+        let mut descent = Descent::new(self.options.lock().max_tree_depth);
+        let mut idx = root;
+        while descent.enter(idx).is_ok() {
+            idx = match read_node(&self.cache, idx).await {
+                Ok(page) if matches!(page.page_type, PageType::Branch) => match Branch::decode(&page) {
+                    Some(branch) => branch.child_for(key),
+                    None => break
+                },
+                _ => break
+            };
+        }
+        descent.trail().to_vec()
+    }
+}
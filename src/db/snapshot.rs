@@ -0,0 +1,284 @@
+//! Read handles pinned to one committed version
+
+use std::{fs, io};
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::Arc;
+use bytes::Bytes;
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use parking_lot::Mutex;
+
+use super::{DB, ExportError, Options, PageIndex, ReadOps, RetrieveError, TransactionIdx};
+use super::compression::Dictionaries;
+use super::fs_util;
+use super::leaf::LeafValue;
+use super::range::{self, Scan};
+use super::tree::{self, Write};
+use super::ttl;
+use super::version::VersionHeader;
+
+/// Expiry times set per transaction when exporting a snapshot
+const EXPIRY_BATCH: usize = 4096;
+
+/// Counts the live snapshots of each version, along with the scans and backups reading it.
+/// Pages a pinned version can reach must not be discarded, so anything that frees pages may
+/// only free those superseded at or before the oldest pinned version.
+pub(crate) struct SnapshotPins {
+    pinned: Mutex<BTreeMap<TransactionIdx, usize>>
+}
+
+impl SnapshotPins {
+    pub fn new() -> SnapshotPins {
+        SnapshotPins { pinned: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// The oldest version a live snapshot is pinned to
+    pub fn oldest(&self) -> Option<TransactionIdx> {
+        self.pinned.lock().keys().next().copied()
+    }
+
+    /// Live snapshots, counting all the clones of one as one
+    pub fn count(&self) -> usize {
+        self.pinned.lock().values().sum()
+    }
+
+    /// Pin version `tx` until the returned pin is dropped. A reader pins the latest version
+    /// before reading it, so whatever version it then reads is no older than its pin.
+    pub fn pin(self: &Arc<Self>, tx: TransactionIdx) -> Pinned {
+        *self.pinned.lock().entry(tx).or_insert(0) += 1;
+        Pinned { pins: self.clone(), tx }
+    }
+}
+
+/// Unpins its version once dropped: for a snapshot, once every clone is
+pub(crate) struct Pinned {
+    pins: Arc<SnapshotPins>,
+    tx: TransactionIdx
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        let mut pinned = self.pins.pinned.lock();
+        if let Some(count) = pinned.get_mut(&self.tx) {
+            *count -= 1;
+            if *count == 0 { pinned.remove(&self.tx); }
+        }
+    }
+}
+
+/// A read-only view of the database as of one commit, which later commits don't change.
+/// Cloning is cheap, and clones share the pin: the version stays readable until the last is
+/// dropped.
+#[derive(Clone)]
+pub struct Snapshot<'db> {
+    db: &'db DB,
+    pub(super) version: VersionHeader,
+    /// The write buffer's contents as of the commit, in key order
+    buffered: Arc<BTreeMap<Bytes, Option<LeafValue>>>,
+    dictionaries: Arc<Dictionaries>,
+    /// The database's generation, taken before the version was pinned
+    generation: u64,
+    _pinned: Arc<Pinned>
+}
+
+impl DB {
+    /// Pin the latest committed version, to read it consistently however long the reads take.
+    /// Takes a copy of the write buffer, if one is configured.
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        let generation = self.generation();
+        let pinned = self.snapshots.pin(self.version.lock().tx);
+        let (version, buffered) = self.buffered_range(Bound::Unbounded, Bound::Unbounded);
+        // a commit landed in between, so move the pin up to the version read
+        let pinned = if pinned.tx == version.tx { pinned } else { self.snapshots.pin(version.tx) };
+
+        Snapshot {
+            db: self,
+            version,
+            buffered: Arc::new(buffered.into_iter().collect()),
+            dictionaries: self.dictionaries(),
+            generation,
+            _pinned: Arc::new(pinned)
+        }
+    }
+
+    /// The oldest transaction a live snapshot is pinned to, if any snapshot is live
+    pub fn oldest_snapshot(&self) -> Option<TransactionIdx> {
+        self.snapshots.oldest()
+    }
+}
+
+impl<'db> Snapshot<'db> {
+    /// The commit this snapshot reads
+    pub fn txn_idx(&self) -> TransactionIdx {
+        self.version.tx
+    }
+
+    /// The database's generation when the snapshot was taken. See `DB::generation`.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Whether no commit has changed data since the snapshot was taken. May report a change
+    /// that landed as the snapshot was taken, but never misses one.
+    pub fn is_current(&self) -> bool {
+        self.db.generation() == self.generation
+    }
+
+    /// The root page of the tree as of the commit, or `None` if the database was empty
+    pub fn root(&self) -> Option<PageIndex> {
+        self.version.tree_root
+    }
+
+    async fn lookup(&self, key: &[u8]) -> Result<Option<LeafValue>, RetrieveError> {
+        let value = match self.buffered.get(key) {
+            Some(buffered) => buffered.clone(),
+            None => match self.version.tree_root {
+                Some(root) => {
+                    let max_depth = self.db.options.lock().max_tree_depth;
+                    tree::lookup(&self.db.cache, root, key, max_depth).await?
+                },
+                None => None
+            }
+        };
+
+        match value {
+            Some(_) if self.db.is_expired(self.version.expiries, key).await? => Ok(None),
+            value => Ok(value)
+        }
+    }
+
+    /// Read the value of `key` as of the commit, unless it has since expired
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Bytes>, RetrieveError> {
+        match self.lookup(key).await? {
+            Some(value) => Ok(Some(value.read(&self.db.cache, &self.dictionaries).await?)),
+            None => Ok(None)
+        }
+    }
+
+    /// Whether `key` had a value as of the commit, without reading the value
+    pub async fn contains_key(&self, key: &[u8]) -> Result<bool, RetrieveError> {
+        Ok(self.lookup(key).await?.is_some())
+    }
+
+    /// Stream the entries with keys in `range` as of the commit, in key order
+    pub fn range<R: RangeBounds<Bytes>>(&self, range: R) -> Scan<'_> {
+        let from = range::owned(range.start_bound());
+        let to = range::owned(range.end_bound());
+        if range::is_empty(&from, &to) { return Scan::empty(self.generation) }
+        let buffered: Vec<Write> = self.buffered.range((from.clone(), to.clone()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        let entries = self.db.merged_entries(self.version.tree_root, from, to, buffered).try_filter_map(move |(key, value)| async move {
+            if self.db.is_expired(self.version.expiries, &key).await? { return Ok(None) }
+            Ok(Some((key, value.read(&self.db.cache, &self.dictionaries).await?)))
+        }).boxed();
+        Scan::new(entries, self.generation)
+    }
+}
+
+impl<'db> Snapshot<'db> {
+    /// Write this snapshot to a new database at `path`, compacted: the tree is rebuilt packed
+    /// and each live value is written once, so the copy holds nothing from older versions and
+    /// no value log garbage. Expiry times and persisted settings carry over. Batch tokens, the
+    /// commit archive and secondary indexes don't; create indexes again on the copy.
+    ///
+    /// Commits carry on while the export runs. The copy is built in a temporary file and
+    /// linked into place once it's durable, so a failed export leaves nothing at `path`.
+    /// Returns the transaction exported.
+    pub async fn export_to<P: AsRef<Path>>(&self, path: P) -> Result<TransactionIdx, ExportError> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "export target already exists").into())
+        }
+
+        let mut tmp_name = path.file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "export path has no file name"))?
+            .to_owned();
+        tmp_name.push(format!(".exporting-{}", std::process::id()));
+        let tmp = path.with_file_name(tmp_name);
+
+        let exported = self.export_file(&tmp).await;
+        let linked = exported.and_then(|()| fs::hard_link(&tmp, path).map_err(ExportError::from));
+        let removed = fs::remove_file(&tmp);
+        linked?;
+        removed?;
+
+        fs_util::sync_parent_dir(path)?;
+        Ok(self.version.tx)
+    }
+
+    async fn export_file(&self, path: &Path) -> Result<(), ExportError> {
+        let options = {
+            let source = self.db.options.lock();
+            let mut options = Options::new();
+            options.durability = source.durability;
+            options.direct_io = source.direct_io;
+            options.growth_extent = source.growth_extent;
+            options.max_size = source.max_size;
+            options.max_tree_depth = source.max_tree_depth;
+            options.value_inline_threshold = source.value_inline_threshold;
+            options.max_key_len = source.max_key_len;
+            options.max_value_len = source.max_value_len;
+            options.compression = source.compression;
+            options.leaf_filter_len = source.leaf_filter_len;
+            options.clock = source.clock.clone();
+            #[cfg(feature = "encryption")]
+            { options.encryption = source.encryption.clone(); }
+            options
+        };
+        let max_depth = options.max_tree_depth;
+        let target = DB::open(path, options).await?;
+
+        // the load takes entries that can't fail, so the first error ends them and is kept here
+        let failed = Mutex::new(None);
+        let expiring = Mutex::new(vec![]);
+        let entries = self.range(..).and_then(|(key, value)| {
+            let expiring = &expiring;
+            async move {
+                if let Some(expires) = ttl::expiry(&self.db.cache, self.version.expiries, &key, max_depth).await? {
+                    expiring.lock().push((key.clone(), expires));
+                }
+                Ok((key, value))
+            }
+        }).scan((), |_, entry| future::ready(match entry {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                *failed.lock() = Some(err);
+                None
+            }
+        })).boxed();
+
+        target.bulk_load(entries).await?;
+        if let Some(err) = failed.into_inner() { return Err(err.into()) }
+
+        let mut expiring = expiring.into_inner().into_iter().peekable();
+        loop {
+            let mut txn = target.write().await?;
+            txn.version.settings = self.version.settings;
+            for (key, expires) in expiring.by_ref().take(EXPIRY_BATCH) {
+                txn.ttls.insert(key, expires);
+            }
+            txn.commit().await?;
+            if expiring.peek().is_none() { break }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'db> ReadOps for Snapshot<'db> {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<Option<Bytes>, RetrieveError>> {
+        Snapshot::get(self, key).boxed()
+    }
+
+    fn contains_key<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<bool, RetrieveError>> {
+        Snapshot::contains_key(self, key).boxed()
+    }
+
+    fn range<R: RangeBounds<Bytes>>(&self, range: R) -> BoxStream<'_, Result<(Bytes, Bytes), RetrieveError>> {
+        Snapshot::range(self, range).boxed()
+    }
+}
@@ -0,0 +1,118 @@
+//! Running background work on the caller's async runtime
+
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+use futures::executor::block_on;
+use futures::future::BoxFuture;
+
+use super::DB;
+
+/// Expired keys deleted per transaction by the background sweeper
+const SWEEP_BATCH: usize = 1024;
+
+/// Runs bssdb's background tasks, and lets them wait between units of work.
+///
+/// Register one with `Options::spawner`. Page I/O completes through io_uring on any runtime,
+/// so only background work needs this. `TokioSpawner` and `AsyncStdSpawner` adapt those
+/// runtimes behind the `tokio` and `async-std` features.
+pub trait Spawn: Send + Sync {
+    fn spawn(&self, task: BoxFuture<'static, ()>);
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Runs each task on its own thread. The default, which needs no runtime.
+pub struct ThreadSpawner;
+
+impl Spawn for ThreadSpawner {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        thread::Builder::new()
+            .name("bssdb-background".into())
+            .spawn(move || block_on(task))
+            .expect("failed to spawn a background thread");
+    }
+
+    /// Blocks the task's thread, which is its own
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(async move { thread::sleep(duration) })
+    }
+}
+
+/// Spawns onto a tokio runtime
+#[cfg(feature = "tokio")]
+pub struct TokioSpawner {
+    handle: tokio_rt::runtime::Handle
+}
+
+#[cfg(feature = "tokio")]
+impl TokioSpawner {
+    pub fn new(handle: tokio_rt::runtime::Handle) -> TokioSpawner {
+        TokioSpawner { handle }
+    }
+
+    /// Spawn onto the runtime this is called from. Panics outside of one.
+    pub fn current() -> TokioSpawner {
+        TokioSpawner { handle: tokio_rt::runtime::Handle::current() }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Spawn for TokioSpawner {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        self.handle.spawn(task);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio_rt::time::sleep(duration))
+    }
+}
+
+/// Spawns onto async-std's global executor
+#[cfg(feature = "async-std")]
+pub struct AsyncStdSpawner;
+
+#[cfg(feature = "async-std")]
+impl Spawn for AsyncStdSpawner {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        async_std::task::spawn(task);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(async_std::task::sleep(duration))
+    }
+}
+
+impl DB {
+    /// Every `interval`, apply the write buffer if its flush policy says it's due and sweep
+    /// expired keys, on the spawner from `Options::spawner`. Skipped while maintenance is
+    /// paused, reported to the observer on error, and stopped once the last `Arc` to the
+    /// database is dropped.
+    pub fn run_in_background(self: &Arc<Self>, interval: Duration) {
+        if self.is_read_only() { return }
+        let spawner = self.options.lock().spawner.clone();
+        let db = Arc::downgrade(self);
+
+        spawner.spawn(Box::pin(background(db, spawner.clone(), interval)));
+    }
+}
+
+async fn background(db: Weak<DB>, spawner: Arc<dyn Spawn>, interval: Duration) {
+    loop {
+        spawner.sleep(interval).await;
+
+        let db = match db.upgrade() {
+            Some(db) => db,
+            None => return
+        };
+        if db.is_maintenance_paused() { continue }
+
+        let observer = db.options.lock().observer.clone();
+        if let Err(err) = db.flush_write_buffer_if_due().await {
+            observer.on_error(&err);
+        }
+        if let Err(err) = db.sweep_expired(SWEEP_BATCH).await {
+            observer.on_error(&err);
+        }
+    }
+}
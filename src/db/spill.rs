@@ -0,0 +1,294 @@
+//! A write transaction's changes, counted against a memory cap. Past the cap they're spilled
+//! to a sorted run in a temporary file, so a transaction can outgrow memory.
+//!
+//! A run is a sequence of blocks, each a journal record (see `memtable::encode_record`) of up
+//! to `BLOCK_WRITES` changes in key order. The first key of each block stays in memory, so
+//! finding a key reads one block. Runs are read with blocking I/O.
+
+use std::{fs, io, process};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::vec;
+use bytes::Bytes;
+
+use super::DB;
+use super::leaf::LeafValue;
+use super::memtable;
+use super::tree::Write;
+
+pub(crate) const DEFAULT_MAX_TRANSACTION_MEMORY: usize = 64 * 1024 * 1024;
+
+/// Changes per block of a run
+const BLOCK_WRITES: usize = 256;
+
+/// Approximate bytes a change costs in memory beyond its key and value
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Distinguishes the runs of a process's transactions in a shared directory
+static RUN_IDS: AtomicU64 = AtomicU64::new(0);
+
+fn entry_len(key: &[u8], value: &Option<LeafValue>) -> usize {
+    ENTRY_OVERHEAD + key.len() + value.as_ref().map_or(0, LeafValue::encoded_len)
+}
+
+fn corrupt_run() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "spilled transaction run is corrupt")
+}
+
+fn in_bounds(key: &Bytes, from: &Bound<Bytes>, to: &Bound<Bytes>) -> bool {
+    let after_start = match from {
+        Bound::Included(from) => key >= from,
+        Bound::Excluded(from) => key > from,
+        Bound::Unbounded => true
+    };
+    let before_end = match to {
+        Bound::Included(to) => key <= to,
+        Bound::Excluded(to) => key < to,
+        Bound::Unbounded => true
+    };
+    after_start && before_end
+}
+
+struct Block {
+    first: Bytes,
+    offset: u64,
+    len: usize
+}
+
+/// Changes spilled together, in key order
+struct Run {
+    file: File,
+    blocks: Vec<Block>,
+    len: usize
+}
+
+impl Run {
+    fn write(dir: &Path, writes: &BTreeMap<Bytes, Option<LeafValue>>) -> io::Result<Run> {
+        let path = dir.join(format!(".bssdb-spill-{}-{}", process::id(), RUN_IDS.fetch_add(1, Ordering::Relaxed)));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        // unlinked at once, so the run is removed however the process exits
+        fs::remove_file(&path)?;
+
+        let mut blocks = vec![];
+        let mut offset = 0;
+        let mut chunk = Vec::with_capacity(BLOCK_WRITES);
+        let mut entries = writes.iter().peekable();
+        while let Some((key, value)) = entries.next() {
+            chunk.push((key.clone(), value.clone()));
+            if chunk.len() < BLOCK_WRITES && entries.peek().is_some() { continue }
+
+            let encoded = memtable::encode_record(None, &chunk);
+            file.write_all_at(&encoded, offset)?;
+            blocks.push(Block { first: chunk[0].0.clone(), offset, len: encoded.len() });
+            offset += encoded.len() as u64;
+            chunk.clear();
+        }
+
+        Ok(Run { file, blocks, len: writes.len() })
+    }
+
+    fn read_block(&self, i: usize) -> io::Result<Vec<Write>> {
+        let block = &self.blocks[i];
+        let mut encoded = vec![0; block.len];
+        self.file.read_exact_at(&mut encoded, block.offset)?;
+        let (_, writes) = memtable::decode_record(&encoded).ok_or_else(corrupt_run)?;
+        Ok(writes)
+    }
+
+    /// The block that would hold `key`, if any could
+    fn block_for(&self, key: &[u8]) -> Option<usize> {
+        match self.blocks.binary_search_by(|block| block.first[..].cmp(key)) {
+            Ok(i) => Some(i),
+            Err(0) => None,
+            Err(i) => Some(i - 1)
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> io::Result<Option<Option<LeafValue>>> {
+        let i = match self.block_for(key) {
+            Some(i) => i,
+            None => return Ok(None)
+        };
+        let writes = self.read_block(i)?;
+        Ok(writes.binary_search_by(|(written, _)| written[..].cmp(key)).ok().map(|found| writes[found].1.clone()))
+    }
+
+    fn range(&self, from: &Bound<Bytes>, to: &Bound<Bytes>, into: &mut BTreeMap<Bytes, Option<LeafValue>>) -> io::Result<()> {
+        let start = match from {
+            Bound::Included(key) | Bound::Excluded(key) => self.block_for(key).unwrap_or(0),
+            Bound::Unbounded => 0
+        };
+        for i in start..self.blocks.len() {
+            if !in_bounds(&self.blocks[i].first, &Bound::Unbounded, to) { break }
+            into.extend(self.read_block(i)?.into_iter().filter(|(key, _)| in_bounds(key, from, to)));
+        }
+        Ok(())
+    }
+}
+
+/// The latest change to each key a write transaction has made
+pub(crate) struct Writes {
+    memory: BTreeMap<Bytes, Option<LeafValue>>,
+    /// Approximate bytes `memory` holds
+    bytes: usize,
+    /// Spilled runs, oldest first. A key's latest change is in memory, or else in the newest
+    /// run holding it.
+    runs: Vec<Run>,
+    max_bytes: Option<usize>,
+    dir: PathBuf
+}
+
+impl Writes {
+    pub fn new(max_bytes: Option<usize>, dir: PathBuf) -> Writes {
+        Writes { memory: BTreeMap::new(), bytes: 0, runs: vec![], max_bytes, dir }
+    }
+
+    /// Record the latest change to `key`, spilling to a run if that passes the memory cap.
+    /// Returns the change it replaces, if that was still in memory.
+    pub fn insert(&mut self, key: Bytes, value: Option<LeafValue>) -> io::Result<Option<Option<LeafValue>>> {
+        self.bytes += entry_len(&key, &value);
+        let replaced = self.memory.insert(key.clone(), value);
+        if let Some(old) = &replaced {
+            self.bytes -= entry_len(&key, old);
+        }
+
+        if self.max_bytes.map_or(false, |max_bytes| self.bytes > max_bytes) {
+            self.runs.push(Run::write(&self.dir, &self.memory)?);
+            self.memory.clear();
+            self.bytes = 0;
+        }
+        Ok(replaced)
+    }
+
+    /// The latest change to `key`: `Some(None)` if it was deleted, or `None` if it wasn't changed
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Option<LeafValue>>> {
+        if let Some(value) = self.memory.get(key) { return Ok(Some(value.clone())) }
+        for run in self.runs.iter().rev() {
+            if let Some(value) = run.get(key)? { return Ok(Some(value)) }
+        }
+        Ok(None)
+    }
+
+    /// The latest changes to keys within the bounds, in key order
+    pub fn range(&self, from: Bound<Bytes>, to: Bound<Bytes>) -> io::Result<BTreeMap<Bytes, Option<LeafValue>>> {
+        let mut changes = BTreeMap::new();
+        for run in &self.runs {
+            run.range(&from, &to, &mut changes)?;
+        }
+        changes.extend(self.memory.range((from, to)).map(|(key, value)| (key.clone(), value.clone())));
+        Ok(changes)
+    }
+
+    /// Changes made, counting a key once for each run and for memory if it's changed in several
+    pub fn len(&self) -> usize {
+        self.memory.len() + self.runs.iter().map(|run| run.len).sum::<usize>()
+    }
+
+    /// Approximate bytes of memory the changes not yet spilled hold
+    pub fn memory_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        !self.runs.is_empty()
+    }
+
+    /// Take the changes, if none were spilled
+    pub fn take_memory(&mut self) -> Vec<Write> {
+        debug_assert!(!self.is_spilled());
+        self.bytes = 0;
+        std::mem::take(&mut self.memory).into_iter().collect()
+    }
+
+    /// Every latest change, in key order, reading the runs a block at a time
+    pub fn sorted(&self) -> Sorted<'_> {
+        let mut cursors: Vec<Cursor<'_>> = self.runs.iter().map(|run| Cursor {
+            run: Some(run),
+            next_block: 0,
+            block: vec![].into_iter(),
+            peeked: None
+        }).collect();
+        cursors.push(Cursor {
+            run: None,
+            next_block: 0,
+            block: self.memory.iter().map(|(key, value)| (key.clone(), value.clone())).collect::<Vec<_>>().into_iter(),
+            peeked: None
+        });
+        Sorted { cursors }
+    }
+}
+
+/// Reads one run, or the changes in memory
+struct Cursor<'a> {
+    run: Option<&'a Run>,
+    next_block: usize,
+    block: vec::IntoIter<Write>,
+    peeked: Option<Write>
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&mut self) -> io::Result<Option<&Write>> {
+        if self.peeked.is_none() {
+            self.peeked = loop {
+                if let Some(write) = self.block.next() { break Some(write) }
+                match self.run {
+                    Some(run) if self.next_block < run.blocks.len() => {
+                        self.block = run.read_block(self.next_block)?.into_iter();
+                        self.next_block += 1;
+                    },
+                    _ => break None
+                }
+            };
+        }
+        Ok(self.peeked.as_ref())
+    }
+}
+
+/// Merges the runs and memory, the newest change to a key winning
+pub(crate) struct Sorted<'a> {
+    /// Oldest first, ending with memory
+    cursors: Vec<Cursor<'a>>
+}
+
+impl<'a> Sorted<'a> {
+    fn next_write(&mut self) -> io::Result<Option<Write>> {
+        let mut least: Option<Bytes> = None;
+        for cursor in &mut self.cursors {
+            if let Some((key, _)) = cursor.peek()? {
+                if least.as_ref().map_or(true, |least| key < least) { least = Some(key.clone()) }
+            }
+        }
+        let least = match least {
+            Some(least) => least,
+            None => return Ok(None)
+        };
+
+        let mut newest = None;
+        for cursor in &mut self.cursors {
+            if matches!(cursor.peek()?, Some((key, _)) if *key == least) {
+                newest = cursor.peeked.take();
+            }
+        }
+        Ok(newest)
+    }
+}
+
+impl<'a> Iterator for Sorted<'a> {
+    type Item = io::Result<Write>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_write().transpose()
+    }
+}
+
+impl DB {
+    /// Whether anything is notified of each commit's writes, which a spilled transaction
+    /// then has to read back in full
+    pub(super) fn has_commit_subscribers(&self) -> bool {
+        !self.commit_hooks.lock().is_empty() || !self.watchers.lock().is_empty() || !self.replicas.lock().is_empty()
+    }
+}
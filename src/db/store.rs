@@ -0,0 +1,42 @@
+use std::io;
+use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt};
+
+use super::{Durability, PageContent, PageIndex, RetrieveError, StoreMetrics};
+use super::overflow;
+
+/// Where pages live. `FileStore` is the real implementation; wrappers can be layered on top,
+/// e.g. to inject latency or faults in tests.
+pub trait PageStore: Send + Sync {
+    fn read_page(&self, idx: PageIndex) -> BoxFuture<'_, Result<PageContent, RetrieveError>>;
+
+    /// Start writing a page. The write is submitted before this returns, and the future resolves once it completes.
+    fn write_page<'a>(&'a self, idx: PageIndex, page: &'a PageContent) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Flush completed writes to stable storage
+    fn sync(&self, durability: Durability) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Submission and completion counts, for stores backed by an I/O queue
+    fn metrics(&self) -> Option<StoreMetrics> {
+        None
+    }
+
+    /// Reserve disk space for the store to grow to `bytes`, so writes up to there don't have
+    /// to allocate. Stores without a file to grow ignore this.
+    fn preallocate(&self, _bytes: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Give back the disk space of `pages` pages from `start`, which must hold nothing any
+    /// version or snapshot can still read. They read as zeroes until written again. Stores
+    /// that can't release space ignore this.
+    fn discard(&self, _start: PageIndex, _pages: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Read a page, followed by the rest of its overflow chain if it starts one, as raw bytes.
+    /// `overflow_size_hint` is the expected length of the chain's data, or zero if unknown.
+    fn get_chunk(&self, idx: PageIndex, overflow_size_hint: u32) -> BoxFuture<'_, Result<Bytes, RetrieveError>> {
+        overflow::read_chunk(self, idx, overflow_size_hint).boxed()
+    }
+}
@@ -0,0 +1,26 @@
+//! Instrumentation with `tracing` spans and events, behind the `tracing` feature. Without it,
+//! these macros compile to nothing, and their arguments aren't evaluated.
+//!
+//! Spans and events are at trace level, under the `bssdb` target. Spans time each page read,
+//! write and sync, so a subscriber that records span durations can tie slow operations to the
+//! pages involved.
+
+/// Run `future` inside a trace-level span named and with the fields given, as for
+/// `tracing::trace_span!`
+macro_rules! traced {
+    ($future:expr, $($span:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        let future = tracing_crate::Instrument::instrument($future, tracing_crate::trace_span!(target: "bssdb", $($span)*));
+        #[cfg(not(feature = "tracing"))]
+        let future = $future;
+        future
+    }};
+}
+
+/// Emit a trace-level event, as with `tracing::trace!`
+macro_rules! trace_event {
+    ($($event:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing_crate::trace!(target: "bssdb", $($event)*);
+    };
+}
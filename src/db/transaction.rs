@@ -1,6 +1,9 @@
-use super::{PageContent, page::Page};
+use super::{PageContent, PageIndex, PageStore, Durability, ValuePointer, page::Page, version::VersionHeader, write_back::{Owner, WriteBack}};
+use std::io;
 use std::ops::{DerefMut, Deref};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use parking_lot::Mutex;
 
 pub type TransactionIdx = u64;
 
@@ -20,21 +23,132 @@ pub enum TxPage<'t> {
 }
 
 pub(crate) struct Transaction {
-    idx: TransactionIdx
+    idx: TransactionIdx,
+    store: Arc<dyn PageStore>,
+    write_back: Arc<WriteBack>,
+    /// Tags this transaction's page writes, so their failures are reported to it alone
+    owner: Owner,
+    durability: Durability,
+    /// Pages allocated in the file, as of this transaction
+    page_count: Mutex<u64>,
+    /// Value log records this transaction overwrote or deleted, as (page, bytes)
+    released: Mutex<Vec<(PageIndex, u64)>>,
+    /// Count pages instead of writing them, to plan a commit
+    dry_run: bool,
+    /// Pages written so far
+    written: AtomicU64,
+    /// Nodes rewritten as more than one, counting each node added
+    splits: AtomicU64
 }
 
 impl Transaction {
+    pub(crate) fn new(idx: TransactionIdx, store: Arc<dyn PageStore>, write_back: Arc<WriteBack>, durability: Durability, page_count: u64) -> Transaction {
+        Transaction {
+            idx,
+            store,
+            owner: write_back.begin(),
+            write_back,
+            durability,
+            page_count: Mutex::new(page_count),
+            released: Mutex::new(vec![]),
+            dry_run: false,
+            written: AtomicU64::new(0),
+            splits: AtomicU64::new(0)
+        }
+    }
+
+    /// A transaction that only counts the pages it would write. It must not be committed.
+    pub(crate) fn dry_run(idx: TransactionIdx, store: Arc<dyn PageStore>, write_back: Arc<WriteBack>, page_count: u64) -> Transaction {
+        let mut txn = Transaction::new(idx, store, write_back, Durability::NoSync, page_count);
+        txn.dry_run = true;
+        txn
+    }
+
+    pub(crate) fn idx(&self) -> TransactionIdx {
+        self.idx
+    }
+
+    pub(crate) fn page_count(&self) -> u64 {
+        *self.page_count.lock()
+    }
+
     fn alloc_page(&self, content: PageContent) -> Page {
-        let index = todo!();
+        let index = self.alloc_run(1, 1);
         Page::new(content, index)
     }
 
+    /// Allocate `pages` contiguous pages, starting at a multiple of `align`
+    pub(crate) fn alloc_run(&self, pages: u64, align: u64) -> PageIndex {
+        let mut page_count = self.page_count.lock();
+        let start = (*page_count + align - 1) / align * align;
+        *page_count = start + pages;
+        start
+    }
+
+    /// Note that the value log record of `key` at `ptr` was overwritten or deleted. It's
+    /// counted as garbage once this transaction commits.
+    pub(crate) fn release_value(&self, key: &[u8], ptr: &ValuePointer) {
+        self.released.lock().push((ptr.page, ptr.record_len(key.len())));
+    }
+
+    /// The values released so far
+    pub(crate) fn released(&self) -> Vec<(PageIndex, u64)> {
+        self.released.lock().clone()
+    }
+
+    pub(crate) fn take_released(&self) -> Vec<(PageIndex, u64)> {
+        std::mem::take(&mut *self.released.lock())
+    }
+
+    /// Stamp a page with this transaction and queue it to be written
+    pub(crate) fn write_new_page(&self, idx: PageIndex, mut content: Box<PageContent>) {
+        self.written.fetch_add(1, Ordering::Relaxed);
+        if self.dry_run { return }
+        content.set_lsn(self.idx);
+        self.write_back.enqueue(self.owner, idx, content);
+    }
+
+    /// Pages written so far
+    pub(crate) fn pages_written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// Note that a node was rewritten as `nodes` nodes
+    pub(crate) fn record_split(&self, nodes: usize) {
+        if nodes > 1 { self.splits.fetch_add(nodes as u64 - 1, Ordering::Relaxed); }
+    }
+
+    pub(crate) fn splits(&self) -> u64 {
+        self.splits.load(Ordering::Relaxed)
+    }
+
+    /// Wait for every page this transaction has written so far to be written out. Fails if
+    /// any write has, which every later flush and the commit report again.
+    pub(crate) async fn flush(&self) -> io::Result<()> {
+        self.write_back.flush(self.owner).await
+    }
+
     pub(crate) fn tx_page<'t>(&'t self, page: Arc<Page>) -> TxPage<'t> {
         TxPage::Shared {
             shared: page,
             txn: self
         }
     }
+
+    /// Wait for every page dirtied by this transaction to reach the disk,
+    /// then make `version` the latest version
+    pub(crate) async fn commit(&self, version: VersionHeader) -> io::Result<()> {
+        self.flush().await?;
+        // the pages must be durable before a root page points to them
+        self.store.sync(self.durability).await?;
+        version.write(&self.store, self.durability).await
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.write_back.end(self.owner);
+    }
 }
 
 impl<'t> TxPage<'t> {
@@ -89,6 +203,8 @@ impl<'a, 't> DerefMut for DirtyPageGuard<'a, 't> {
 
 impl<'a, 't> Drop for DirtyPageGuard<'a, 't> {
     fn drop(&mut self) {
-        todo!("As an optimization, consider queueing a write-to-disk here.")
+        // start writing now, so commit only has to wait for the writes to complete
+        let DirtyPage { page, txn } = &*self.0;
+        txn.write_new_page(page.idx(), Box::new(page.content.clone()));
     }
 }
\ No newline at end of file
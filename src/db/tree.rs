@@ -0,0 +1,832 @@
+//! The tree. Branches route each key to one child, down to the leaf that holds it. Writes
+//! copy every page on the path to a changed key, so committed pages are never modified.
+
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Instant;
+use bytes::Bytes;
+use futures::future::{self, try_join_all, BoxFuture, FutureExt};
+use futures::stream::{self, BoxStream, StreamExt};
+
+use super::{DB, Options, PageCache, PageIndex, RetrieveError, WriteError};
+use super::branch::{Branch, Stats, MAX_FILTER_LEN};
+use super::compression::Compression;
+use super::descent::{Descent, DescentError};
+use super::filter;
+use super::leaf::{self, LeafEntry, LeafValue};
+use super::page::{PageContent, PageType};
+use super::quarantine::Quarantine;
+use super::range;
+use super::transaction::Transaction;
+use super::value_log;
+
+/// Read a page of the tree
+pub(crate) async fn read_node(cache: &PageCache, idx: PageIndex) -> Result<PageContent, RetrieveError> {
+    let raw = cache.get(idx, 0).await?;
+    PageContent::from_bytes(&raw).ok_or(RetrieveError::Malformed(idx))
+}
+
+/// Find the value of `key` in the tree rooted at `root`
+pub(crate) async fn lookup(cache: &PageCache, root: PageIndex, key: &[u8], max_depth: usize) -> Result<Option<LeafValue>, RetrieveError> {
+    let mut descent = Descent::new(max_depth);
+    let mut idx = root;
+    trace_event!(root, key_len = key.len(), "lookup");
+
+    loop {
+        descent.enter(idx)?;
+        let page = read_node(cache, idx).await?;
+
+        match page.page_type {
+            PageType::Branch => {
+                let branch = Branch::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                if !branch.may_contain(key) {
+                    trace_event!(branch = idx, "key excluded by filter");
+                    return Ok(None)
+                }
+                let child = branch.child_for(key);
+                trace_event!(branch = idx, child, "descend");
+                idx = child;
+            },
+            PageType::Leaf => {
+                let mut entries = leaf::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                trace_event!(leaf = idx, entries = entries.len(), "reached leaf");
+                return Ok(match entries.binary_search_by(|entry| entry.key[..].cmp(key)) {
+                    Ok(found) => Some(entries.swap_remove(found).value),
+                    Err(_) => None
+                });
+            },
+            _ => return Err(RetrieveError::Malformed(idx))
+        }
+    }
+}
+
+/// Find the values of sorted, distinct `keys` in the subtree at `idx`. Each page on the paths
+/// to the keys is read once, and sibling subtrees are searched concurrently.
+pub(crate) fn lookup_many<'a>(cache: &'a PageCache, idx: PageIndex, keys: &'a [Bytes], mut descent: Descent) -> BoxFuture<'a, Result<Vec<Option<LeafValue>>, RetrieveError>> {
+    async move {
+        descent.enter(idx)?;
+        let page = read_node(cache, idx).await?;
+
+        match page.page_type {
+            PageType::Branch => {
+                let branch = Branch::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                let mut lookups = Vec::with_capacity(branch.separators.len() + 1);
+
+                let mut rest = keys;
+                for i in 0..=branch.separators.len() {
+                    let end = match branch.separators.get(i) {
+                        Some((next, _)) => rest.partition_point(|key| key < next),
+                        None => rest.len()
+                    };
+                    let (child_keys, after) = rest.split_at(end);
+                    rest = after;
+
+                    if child_keys.is_empty() { continue }
+                    let absent = match branch.filter(i) {
+                        Some(filter) => !child_keys.iter().any(|key| filter::may_contain(&filter, key)),
+                        None => false
+                    };
+                    lookups.push(if absent {
+                        future::ready(Ok(vec![None; child_keys.len()])).boxed()
+                    } else {
+                        lookup_many(cache, branch.child(i), child_keys, descent.clone())
+                    });
+                }
+
+                Ok(try_join_all(lookups).await?.into_iter().flatten().collect())
+            },
+            PageType::Leaf => {
+                let entries = leaf::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                Ok(keys.iter().map(|key| match entries.binary_search_by(|entry| entry.key.cmp(key)) {
+                    Ok(found) => Some(entries[found].value.clone()),
+                    Err(_) => None
+                }).collect())
+            },
+            _ => Err(RetrieveError::Malformed(idx))
+        }
+    }.boxed()
+}
+
+impl DB {
+    /// The latest committed value of `key`, unless it has expired
+    pub(super) async fn lookup(&self, key: &[u8]) -> Result<Option<LeafValue>, RetrieveError> {
+        let (buffered, version) = {
+            let buffer = self.write_buffer.lock();
+            (buffer.get(key), *self.version.lock())
+        };
+        let value = match (buffered, version.tree_root) {
+            (Some(buffered), _) => buffered,
+            (None, Some(root)) => {
+                if let Some(page) = self.quarantine.as_ref().and_then(|quarantine| quarantine.covering(key)) {
+                    return Err(RetrieveError::Quarantined(page))
+                }
+                let max_depth = self.options.lock().max_tree_depth;
+                match lookup(&self.cache, root, key, max_depth).await {
+                    Err(err) if err.is_corruption() => {
+                        self.quarantine_path(root, key).await;
+                        return Err(err)
+                    },
+                    found => found?
+                }
+            },
+            (None, None) => None
+        };
+
+        match value {
+            Some(_) if self.is_expired(version.expiries, key).await? => Ok(None),
+            value => Ok(value)
+        }
+    }
+
+    /// Read the latest committed value of `key`
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Bytes>, RetrieveError> {
+        let started = Instant::now();
+        let value = match self.lookup(key).await? {
+            Some(value) => Some(value.read(&self.cache, &self.dictionaries()).await?),
+            None => None
+        };
+        self.report_slow_read(started, key, value.as_ref().map_or(0, |value| value.len() as u64)).await;
+        Ok(value)
+    }
+
+    /// Read the latest committed values of `keys`, in the same order. Lookups share the pages
+    /// on their paths, and the leaves and values are read concurrently, so this is much
+    /// faster than getting each key in turn.
+    pub async fn get_many(&self, keys: &[Bytes]) -> Result<Vec<Option<Bytes>>, RetrieveError> {
+        let (version, buffered) = {
+            let buffer = self.write_buffer.lock();
+            let buffered: Vec<_> = keys.iter().map(|key| buffer.get(key)).collect();
+            (*self.version.lock(), buffered)
+        };
+
+        let mut unbuffered: Vec<Bytes> = keys.iter().zip(&buffered)
+            .filter(|(_, buffered)| buffered.is_none())
+            .map(|(key, _)| key.clone())
+            .collect();
+        unbuffered.sort();
+        unbuffered.dedup();
+
+        let max_depth = self.options.lock().max_tree_depth;
+        let found = match version.tree_root {
+            Some(root) if !unbuffered.is_empty() => lookup_many(&self.cache, root, &unbuffered, Descent::new(max_depth)).await?,
+            _ => vec![None; unbuffered.len()]
+        };
+
+        let dictionaries = self.dictionaries();
+        let reads = keys.iter().zip(buffered).map(|(key, buffered)| {
+            let value = buffered.unwrap_or_else(|| found[unbuffered.binary_search(key).unwrap()].clone());
+            let dictionaries = &dictionaries;
+            async move {
+                match value {
+                    Some(_) if self.is_expired(version.expiries, key).await? => Ok(None),
+                    Some(value) => Ok(Some(value.read(&self.cache, dictionaries).await?)),
+                    None => Ok::<_, RetrieveError>(None)
+                }
+            }
+        });
+        try_join_all(reads).await
+    }
+
+    /// Whether `key` has a committed value, without reading the value
+    pub async fn contains_key(&self, key: &[u8]) -> Result<bool, RetrieveError> {
+        Ok(self.lookup(key).await?.is_some())
+    }
+
+    /// Read the latest committed value of `key` as a stream of chunks, pulling the pages of
+    /// large values through the page cache as they're consumed instead of all at once
+    pub async fn get_reader(&self, key: &[u8]) -> Result<Option<BoxStream<'_, Result<Bytes, RetrieveError>>>, RetrieveError> {
+        let pinned = self.snapshots.pin(self.version.lock().tx);
+        Ok(self.lookup(key).await?.map(|value| match value {
+            LeafValue::Inline(value) => stream::once(async move { Ok(value) }).boxed(),
+            // the value's pages stay readable until the stream is dropped
+            LeafValue::Logged(ptr) => value_log::stream_value(&self.cache, self.dictionaries(), ptr)
+                .map(move |chunk| { let _pinned = &pinned; chunk })
+                .boxed()
+        }))
+    }
+}
+
+/// A change to one key: its new value, or `None` to delete it
+pub(crate) type Write = (Bytes, Option<LeafValue>);
+
+/// A rewritten node
+struct Node {
+    /// The lowest key it may hold
+    low: Bytes,
+    idx: PageIndex,
+    /// A filter over its keys, for a leaf
+    filter: Option<Bytes>,
+    stats: Stats
+}
+
+/// How new nodes are encoded
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct NodeFormat {
+    pub compression: Compression,
+    /// Bytes of each leaf's filter in its parent, or zero for none
+    pub filter_len: usize
+}
+
+impl NodeFormat {
+    pub fn new(options: &Options) -> NodeFormat {
+        NodeFormat {
+            compression: options.compression,
+            filter_len: options.leaf_filter_len.min(MAX_FILTER_LEN)
+        }
+    }
+}
+
+/// Apply sorted, distinct writes to the tree rooted at `root`, copying every page on the
+/// path to a changed key. Returns the new root, or `None` if the tree is left empty. Without
+/// writes, nothing is copied and the root stays as it is.
+pub(crate) async fn apply(cache: &PageCache, txn: &Transaction, root: Option<PageIndex>, writes: &[Write], max_depth: usize, format: NodeFormat) -> Result<Option<PageIndex>, WriteError> {
+    if writes.is_empty() { return Ok(root) }
+
+    let mut descent = Descent::new(max_depth);
+    let mut level = apply_node(cache, txn, root, Bytes::new(), writes, &mut descent, format).await?.nodes;
+
+    while level.len() > 1 {
+        level = pack_branches(txn, level)?;
+    }
+
+    Ok(level.pop().map(|node| node.idx))
+}
+
+fn apply_node<'a>(
+    cache: &'a PageCache,
+    txn: &'a Transaction,
+    node: Option<PageIndex>,
+    low: Bytes,
+    writes: &'a [Write],
+    descent: &'a mut Descent,
+    format: NodeFormat
+) -> BoxFuture<'a, Result<Replacement, WriteError>> {
+    async move {
+        let idx = match node {
+            Some(idx) => idx,
+            None => return Ok(Replacement::level(pack_leaves(txn, low, merge(txn, vec![], writes), format)?))
+        };
+
+        descent.enter(idx).map_err(RetrieveError::from)?;
+        let page = read_node(cache, idx).await?;
+
+        let replacement = match page.page_type {
+            PageType::Leaf => {
+                let entries = leaf::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                let leaves = pack_leaves(txn, low, merge(txn, entries, writes), format)?;
+                txn.record_split(leaves.len());
+                Replacement::level(leaves)
+            },
+            PageType::Branch => {
+                let branch = Branch::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                let mut children = Vec::with_capacity(branch.separators.len() + 1);
+
+                let lows = std::iter::once(low).chain(branch.separators.iter().map(|(key, _)| key.clone()));
+                let mut rest = writes;
+                for (i, (child_low, child)) in lows.zip(branch.children()).enumerate() {
+                    // the writes routed to this child are those below the next separator
+                    let end = match branch.separators.get(i) {
+                        Some((next, _)) => rest.partition_point(|(key, _)| key < next),
+                        None => rest.len()
+                    };
+                    let (child_writes, after) = rest.split_at(end);
+                    rest = after;
+
+                    if child_writes.is_empty() {
+                        children.push(Replacement::unchanged(Node { low: child_low, idx: child, filter: branch.filter(i), stats: branch.stats[i] }));
+                    } else {
+                        children.push(apply_node(cache, txn, Some(child), child_low, child_writes, descent, format).await?);
+                    }
+                }
+
+                Replacement::branch(txn, children)?
+            },
+            _ => return Err(RetrieveError::Malformed(idx).into())
+        };
+
+        descent.leave();
+        Ok(replacement)
+    }.boxed()
+}
+
+/// How the keys a subtree may hold meet a range
+enum Overlap {
+    Disjoint,
+    Covered,
+    Partial
+}
+
+/// How the keys from `low` up to, but not including, `high` meet the bounds. `None` is unbounded.
+fn overlap(low: &Bytes, high: Option<&Bytes>, from: &Bound<Bytes>, to: &Bound<Bytes>) -> Overlap {
+    let before = match (from, high) {
+        (Bound::Included(from), Some(high)) | (Bound::Excluded(from), Some(high)) => high <= from,
+        _ => false
+    };
+    let after = match to {
+        Bound::Included(to) => low > to,
+        Bound::Excluded(to) => low >= to,
+        Bound::Unbounded => false
+    };
+    if before || after { return Overlap::Disjoint }
+
+    let starts_within = match from {
+        Bound::Included(from) => low >= from,
+        Bound::Excluded(from) => low > from,
+        Bound::Unbounded => true
+    };
+    let ends_within = match (to, high) {
+        (Bound::Unbounded, _) => true,
+        (Bound::Included(to), Some(high)) | (Bound::Excluded(to), Some(high)) => high <= to,
+        _ => false
+    };
+    if starts_within && ends_within { Overlap::Covered } else { Overlap::Partial }
+}
+
+/// Delete every key within the `cleared` bounds from the tree rooted at `root`. Subtrees wholly
+/// within them are unlinked without being read, so only the nodes straddling a bound are rewritten
+/// and have their logged values released. Returns the new root, or `None` if the tree is left empty.
+pub(crate) async fn clear_range(
+    cache: &PageCache,
+    txn: &Transaction,
+    root: Option<PageIndex>,
+    cleared: &(Bound<Bytes>, Bound<Bytes>),
+    max_depth: usize,
+    format: NodeFormat
+) -> Result<Option<PageIndex>, WriteError> {
+    let root = match root {
+        Some(root) => root,
+        None => return Ok(None)
+    };
+
+    let mut descent = Descent::new(max_depth);
+    let mut level = clear_node(cache, txn, root, (Bytes::new(), None), cleared, &mut descent, format).await?.nodes;
+
+    while level.len() > 1 {
+        level = pack_branches(txn, level)?;
+    }
+
+    Ok(level.pop().map(|node| node.idx))
+}
+
+/// Clear a node that may hold keys from `low` up to, but not including, `high`
+fn clear_node<'a>(
+    cache: &'a PageCache,
+    txn: &'a Transaction,
+    idx: PageIndex,
+    (low, high): (Bytes, Option<Bytes>),
+    cleared: &'a (Bound<Bytes>, Bound<Bytes>),
+    descent: &'a mut Descent,
+    format: NodeFormat
+) -> BoxFuture<'a, Result<Replacement, WriteError>> {
+    let (from, to) = cleared;
+    async move {
+        descent.enter(idx).map_err(RetrieveError::from)?;
+        let page = read_node(cache, idx).await?;
+
+        let mut replacement = match page.page_type {
+            PageType::Leaf => {
+                let mut entries = leaf::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                entries.retain(|entry| {
+                    let inside = range::contains(&entry.key, from, to);
+                    if let (true, LeafValue::Logged(ptr)) = (inside, &entry.value) { txn.release_value(&entry.key, ptr) }
+                    !inside
+                });
+                Replacement::level(pack_leaves(txn, low.clone(), entries, format)?)
+            },
+            PageType::Branch => {
+                let branch = Branch::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                let mut children = Vec::with_capacity(branch.separators.len() + 1);
+
+                let lows = std::iter::once(low.clone()).chain(branch.separators.iter().map(|(key, _)| key.clone()));
+                for (i, (child_low, child)) in lows.zip(branch.children()).enumerate() {
+                    let child_high = branch.separators.get(i).map(|(next, _)| next.clone()).or_else(|| high.clone());
+                    match overlap(&child_low, child_high.as_ref(), from, to) {
+                        Overlap::Disjoint => children.push(Replacement::unchanged(Node { low: child_low, idx: child, filter: branch.filter(i), stats: branch.stats[i] })),
+                        Overlap::Covered => {},
+                        Overlap::Partial => children.push(clear_node(cache, txn, child, (child_low, child_high), cleared, descent, format).await?)
+                    }
+                }
+
+                Replacement::branch(txn, children)?
+            },
+            _ => return Err(RetrieveError::Malformed(idx).into())
+        };
+
+        // the node still takes the keys from `low`, so its parent's separators stay as they are
+        if let Some(first) = replacement.nodes.first_mut() { first.low = low; }
+
+        descent.leave();
+        Ok(replacement)
+    }.boxed()
+}
+
+/// Merge sorted writes into sorted leaf entries, releasing the logged values they replace
+fn merge(txn: &Transaction, entries: Vec<LeafEntry>, writes: &[Write]) -> Vec<LeafEntry> {
+    let mut merged = Vec::with_capacity(entries.len() + writes.len());
+    let mut entries = entries.into_iter().peekable();
+
+    for (key, value) in writes {
+        while let Some(entry) = entries.next_if(|entry| &entry.key < key) {
+            merged.push(entry);
+        }
+        if let Some(old) = entries.next_if(|entry| &entry.key == key) {
+            if let LeafValue::Logged(ptr) = &old.value {
+                if value.as_ref() != Some(&old.value) { txn.release_value(key, ptr) }
+            }
+        }
+
+        if let Some(value) = value {
+            merged.push(LeafEntry { key: key.clone(), value: value.clone() });
+        }
+    }
+
+    merged.extend(entries);
+    merged
+}
+
+/// The shortest key above `below` and at most `key`, to separate leaves in their parent. Short
+/// separators fit more children in a branch.
+fn separator(below: &[u8], key: &Bytes) -> Bytes {
+    let shared = below.iter().zip(key.iter()).take_while(|(a, b)| a == b).count();
+    key.slice(..(shared + 1).min(key.len()))
+}
+
+/// The size of a leaf's entries
+pub(crate) fn leaf_stats(entries: &[LeafEntry]) -> Stats {
+    Stats {
+        entries: entries.len() as u64,
+        bytes: entries.iter().map(|entry| entry.key.len() as u64 + match &entry.value {
+            LeafValue::Inline(value) => value.len() as u64,
+            LeafValue::Logged(ptr) => ptr.len
+        }).sum()
+    }
+}
+
+/// Write a leaf page holding `entries`
+fn write_leaf(txn: &Transaction, low: Bytes, entries: &[LeafEntry], page: PageContent, format: NodeFormat) -> Node {
+    Node {
+        low,
+        idx: write_node(txn, page),
+        filter: match format.filter_len {
+            0 => None,
+            len => Some(filter::build(entries.iter().map(|entry| &entry.key[..]), len).into())
+        },
+        stats: leaf_stats(entries)
+    }
+}
+
+/// Write entries into as few leaves as hold them
+fn pack_leaves(txn: &Transaction, low: Bytes, entries: Vec<LeafEntry>, format: NodeFormat) -> Result<Vec<Node>, WriteError> {
+    let compression = format.compression;
+
+    let mut nodes = vec![];
+    let mut page_entries: Vec<LeafEntry> = vec![];
+    let mut page_low = low;
+    // the last encoding of `page_entries` that fit, once they need compressing to fit
+    let mut compressed = None;
+
+    for entry in entries {
+        page_entries.push(entry);
+        if leaf::fits(&page_entries) { continue }
+        if compression != Compression::None {
+            if let Some(page) = leaf::encode(&page_entries, compression) {
+                compressed = Some(page);
+                continue
+            }
+        }
+
+        let overflow = page_entries.pop().unwrap();
+        if page_entries.is_empty() { return Err(WriteError::EntryTooLarge) }
+
+        let page = compressed.take().unwrap_or_else(|| leaf::encode(&page_entries, compression).unwrap());
+        nodes.push(write_leaf(txn, page_low, &page_entries, page, format));
+
+        page_low = separator(&page_entries[page_entries.len() - 1].key, &overflow.key);
+        page_entries = vec![overflow];
+    }
+
+    if !page_entries.is_empty() {
+        let page = compressed.take().unwrap_or_else(|| leaf::encode(&page_entries, compression).unwrap());
+        nodes.push(write_leaf(txn, page_low, &page_entries, page, format));
+    }
+
+    Ok(nodes)
+}
+
+/// A branch with `child` as its only child, and the lowest key it may hold
+fn start_branch(child: Node) -> (Bytes, Branch) {
+    (child.low, Branch {
+        first_child: child.idx,
+        separators: vec![],
+        filters: child.filter.map(|filter| vec![filter]),
+        stats: vec![child.stats]
+    })
+}
+
+/// Add `child` to the end of `branch`, unless the branch would overflow its page
+fn add_child(branch: &mut Branch, child: &Node) -> Result<bool, WriteError> {
+    branch.separators.push((child.low.clone(), child.idx));
+    branch.stats.push(child.stats);
+    let filters = branch.filters.take();
+    // a branch only keeps filters while every child has one
+    branch.filters = filters.clone().zip(child.filter.clone()).map(|(mut filters, filter)| {
+        filters.push(filter);
+        filters
+    });
+    if branch.fits() { return Ok(true) }
+
+    branch.separators.pop();
+    branch.stats.pop();
+    branch.filters = filters;
+    if branch.separators.is_empty() { return Err(WriteError::EntryTooLarge) }
+    Ok(false)
+}
+
+fn write_branch(txn: &Transaction, low: Bytes, branch: Branch) -> Node {
+    let stats = branch.total();
+    Node { low, idx: write_node(txn, branch.encode().unwrap()), filter: None, stats }
+}
+
+/// The nodes standing in for a rewritten node, `short` levels below where it stood
+struct Replacement {
+    nodes: Vec<Node>,
+    short: usize
+}
+
+impl Replacement {
+    fn level(nodes: Vec<Node>) -> Replacement {
+        Replacement { nodes, short: 0 }
+    }
+
+    fn unchanged(node: Node) -> Replacement {
+        Replacement::level(vec![node])
+    }
+
+    /// Replace a branch over `children`. A lone child is passed up in its place rather than given
+    /// a branch of its own, so a shrinking tree loses whole levels and every leaf stays at the same
+    /// depth; it's only wrapped back up to its height if it meets siblings further up.
+    fn branch(txn: &Transaction, children: Vec<Replacement>) -> Result<Replacement, WriteError> {
+        let mut nodes: Vec<(Node, usize)> = children.into_iter()
+            .flat_map(|child| { let short = child.short; child.nodes.into_iter().map(move |node| (node, short)) })
+            .collect();
+        if nodes.len() == 1 {
+            let (node, short) = nodes.pop().unwrap();
+            return Ok(Replacement { nodes: vec![node], short: short + 1 })
+        }
+
+        let mut level = Vec::with_capacity(nodes.len());
+        for (mut node, short) in nodes {
+            for _ in 0..short {
+                let (low, branch) = start_branch(node);
+                node = write_branch(txn, low, branch);
+            }
+            level.push(node);
+        }
+        let branches = pack_branches(txn, level)?;
+        txn.record_split(branches.len());
+        Ok(Replacement::level(branches))
+    }
+}
+
+/// Write branches over `children`, as few as hold them
+fn pack_branches(txn: &Transaction, children: Vec<Node>) -> Result<Vec<Node>, WriteError> {
+    let mut nodes = vec![];
+    let mut current: Option<(Bytes, Branch)> = None;
+
+    for child in children {
+        let (_, branch) = match &mut current {
+            Some(current) => current,
+            None => {
+                current = Some(start_branch(child));
+                continue;
+            }
+        };
+        if add_child(branch, &child)? { continue }
+
+        let (branch_low, branch) = current.replace(start_branch(child)).unwrap();
+        nodes.push(write_branch(txn, branch_low, branch));
+    }
+
+    if let Some((low, branch)) = current {
+        nodes.push(write_branch(txn, low, branch));
+    }
+
+    Ok(nodes)
+}
+
+/// Builds a tree bottom-up from entries in key order, writing each page once, when it's full
+pub(crate) struct Builder {
+    format: NodeFormat,
+    /// Body bytes to fill each leaf to, leaving the rest for later inserts
+    leaf_fill: usize,
+    leaf: Vec<LeafEntry>,
+    leaf_low: Bytes,
+    /// The branch being filled at each level above the leaves, with its lowest key
+    branches: Vec<Option<(Bytes, Branch)>>
+}
+
+impl Builder {
+    /// Fill leaves to `fill`, a share of the page between 0 and 1
+    pub fn new(format: NodeFormat, fill: f64) -> Builder {
+        Builder {
+            format,
+            leaf_fill: (leaf::BODY_CAPACITY as f64 * fill.clamp(0.0, 1.0)) as usize,
+            leaf: vec![],
+            leaf_low: Bytes::new(),
+            branches: vec![]
+        }
+    }
+
+    /// Add an entry, whose key must be greater than every key added before
+    pub fn push(&mut self, txn: &Transaction, entry: LeafEntry) -> Result<(), WriteError> {
+        self.leaf.push(entry);
+        if self.leaf.len() == 1 || leaf::body_len(&self.leaf) <= self.leaf_fill { return Ok(()) }
+
+        let next = self.leaf.pop().unwrap();
+        let low = separator(&self.leaf[self.leaf.len() - 1].key, &next.key);
+        self.write_leaf(txn)?;
+        self.leaf_low = low;
+        self.leaf.push(next);
+        Ok(())
+    }
+
+    fn write_leaf(&mut self, txn: &Transaction) -> Result<(), WriteError> {
+        let entries = std::mem::take(&mut self.leaf);
+        let page = leaf::encode(&entries, self.format.compression).ok_or(WriteError::EntryTooLarge)?;
+        let node = write_leaf(txn, std::mem::take(&mut self.leaf_low), &entries, page, self.format);
+        self.add_node(txn, 0, node)
+    }
+
+    /// Add a node to the branch being filled at `level`, writing the branch once it's full
+    fn add_node(&mut self, txn: &Transaction, level: usize, node: Node) -> Result<(), WriteError> {
+        if self.branches.len() == level { self.branches.push(None) }
+
+        let (_, branch) = match &mut self.branches[level] {
+            Some(current) => current,
+            None => {
+                self.branches[level] = Some(start_branch(node));
+                return Ok(())
+            }
+        };
+        if add_child(branch, &node)? { return Ok(()) }
+
+        let (low, full) = self.branches[level].replace(start_branch(node)).unwrap();
+        let written = write_branch(txn, low, full);
+        self.add_node(txn, level + 1, written)
+    }
+
+    /// Write the pages still being filled. Returns the root, or `None` if no entries were added.
+    pub fn finish(mut self, txn: &Transaction) -> Result<Option<PageIndex>, WriteError> {
+        if !self.leaf.is_empty() { self.write_leaf(txn)? }
+
+        let mut level = 0;
+        while level < self.branches.len() {
+            let top = level == self.branches.len() - 1;
+            if let Some((low, branch)) = self.branches[level].take() {
+                // the top level's branch only has one child if that child holds everything
+                if top && branch.separators.is_empty() { return Ok(Some(branch.first_child)) }
+
+                let written = write_branch(txn, low, branch);
+                if top { return Ok(Some(written.idx)) }
+                self.add_node(txn, level + 1, written)?;
+            }
+            level += 1;
+        }
+
+        Ok(None)
+    }
+}
+
+fn write_node(txn: &Transaction, page: PageContent) -> PageIndex {
+    let idx = txn.alloc_run(1, 1);
+    txn.write_new_page(idx, Box::new(page));
+    idx
+}
+
+/// Visit the leaves of the tree rooted at `root` in key order, until `visit` returns false
+pub(crate) async fn scan_leaves(cache: &PageCache, root: PageIndex, max_depth: usize, mut visit: impl FnMut(Vec<LeafEntry>) -> bool) -> Result<(), RetrieveError> {
+    let mut stack = vec![(root, 1)];
+
+    while let Some((idx, depth)) = stack.pop() {
+        if depth > max_depth {
+            return Err(DescentError::TooDeep { max_depth, trail: vec![idx] }.into())
+        }
+
+        let page = read_node(cache, idx).await?;
+        match page.page_type {
+            PageType::Branch => {
+                let branch = Branch::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                let children: Vec<PageIndex> = branch.children().collect();
+                stack.extend(children.into_iter().rev().map(|child| (child, depth + 1)));
+            },
+            PageType::Leaf => {
+                if !visit(leaf::decode(&page).ok_or(RetrieveError::Malformed(idx))?) { return Ok(()) }
+            },
+            _ => return Err(RetrieveError::Malformed(idx))
+        }
+    }
+
+    Ok(())
+}
+
+/// A page still to be visited by `Entries`
+struct Pending {
+    idx: PageIndex,
+    depth: usize,
+    /// The bounds of the keys its subtree may hold, where `None` is unbounded
+    low: Option<Bytes>,
+    high: Option<Bytes>
+}
+
+/// Walks the entries of a tree in key order, from a lower bound
+pub(crate) struct Entries {
+    from: Bound<Bytes>,
+    max_depth: usize,
+    /// Pages still to visit, the next one last
+    stack: Vec<Pending>,
+    leaf: std::vec::IntoIter<LeafEntry>,
+    /// Where to quarantine corrupt subtrees to skip them, in best-effort recovery
+    quarantine: Option<Arc<Quarantine>>
+}
+
+impl Entries {
+    pub fn new(root: Option<PageIndex>, from: Bound<Bytes>, max_depth: usize) -> Entries {
+        Entries {
+            from,
+            max_depth,
+            stack: root.map(|idx| Pending { idx, depth: 1, low: None, high: None }).into_iter().collect(),
+            leaf: Vec::new().into_iter(),
+            quarantine: None
+        }
+    }
+
+    /// Skip subtrees that can't be read, quarantining them, rather than failing
+    pub fn skip_damaged(mut self, quarantine: Option<Arc<Quarantine>>) -> Entries {
+        self.quarantine = quarantine;
+        self
+    }
+
+    pub async fn next(&mut self, cache: &PageCache) -> Result<Option<LeafEntry>, RetrieveError> {
+        loop {
+            if let Some(entry) = self.leaf.next() { return Ok(Some(entry)) }
+
+            let pending = match self.stack.pop() {
+                Some(next) => next,
+                None => return Ok(None)
+            };
+            let (idx, low, high) = (pending.idx, pending.low.clone(), pending.high.clone());
+
+            if let Err(err) = self.visit(cache, pending).await {
+                match &self.quarantine {
+                    Some(quarantine) if err.is_corruption() => quarantine.record(idx, low, high, &err),
+                    _ => return Err(err)
+                }
+            }
+        }
+    }
+
+    /// Read a page, queueing its children or the entries of a leaf
+    async fn visit(&mut self, cache: &PageCache, pending: Pending) -> Result<(), RetrieveError> {
+        let Pending { idx, depth, low, high } = pending;
+        if depth > self.max_depth {
+            return Err(DescentError::TooDeep { max_depth: self.max_depth, trail: vec![idx] }.into())
+        }
+
+        let page = read_node(cache, idx).await?;
+        match page.page_type {
+            PageType::Branch => {
+                let branch = Branch::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+
+                // children before the one holding the bound only hold lower keys
+                let skip = match &self.from {
+                    Bound::Included(from) | Bound::Excluded(from) => branch.separators.partition_point(|(separator, _)| separator <= from),
+                    Bound::Unbounded => 0
+                };
+                for i in (skip..=branch.separators.len()).rev() {
+                    self.stack.push(Pending {
+                        idx: branch.child(i),
+                        depth: depth + 1,
+                        low: if i == 0 { low.clone() } else { Some(branch.separators[i - 1].0.clone()) },
+                        high: match branch.separators.get(i) {
+                            Some((separator, _)) => Some(separator.clone()),
+                            None => high.clone()
+                        }
+                    });
+                }
+            },
+            PageType::Leaf => {
+                let mut entries = leaf::decode(&page).ok_or(RetrieveError::Malformed(idx))?;
+                let from = &self.from;
+                entries.retain(|entry| match from {
+                    Bound::Included(from) => entry.key >= *from,
+                    Bound::Excluded(from) => entry.key > *from,
+                    Bound::Unbounded => true
+                });
+                self.leaf = entries.into_iter();
+            },
+            _ => return Err(RetrieveError::Malformed(idx))
+        }
+
+        Ok(())
+    }
+}
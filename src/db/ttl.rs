@@ -0,0 +1,197 @@
+//! Keys that expire. Expiry times live in their own tree, indexed both by key, so reads can
+//! check a key's expiry, and by time, so the sweeper finds expired keys without scanning.
+//!
+//! Index keys are `[BY_KEY][key]`, with the expiry as the value, and
+//! `[BY_TIME][expiry: u64 big-endian][key]`, with no value. Expiries are Unix milliseconds.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ops::Bound;
+use std::time::Duration;
+use bytes::Bytes;
+
+use super::{DB, PageCache, PageIndex, RetrieveError, WriteError, WriteTransaction};
+use super::compression::Compression;
+use super::descent::Descent;
+use super::leaf::LeafValue;
+use super::transaction::Transaction;
+use super::range;
+use super::tree::{self, Entries, NodeFormat, Write};
+
+const BY_KEY: u8 = 0;
+const BY_TIME: u8 = 1;
+
+fn by_key(key: &[u8]) -> Bytes {
+    let mut index_key = Vec::with_capacity(1 + key.len());
+    index_key.push(BY_KEY);
+    index_key.extend_from_slice(key);
+    index_key.into()
+}
+
+fn by_time(expires: u64, key: &[u8]) -> Bytes {
+    let mut index_key = Vec::with_capacity(1 + 8 + key.len());
+    index_key.push(BY_TIME);
+    index_key.extend_from_slice(&expires.to_be_bytes());
+    index_key.extend_from_slice(key);
+    index_key.into()
+}
+
+fn decode_expiry(root: PageIndex, value: LeafValue) -> Result<u64, RetrieveError> {
+    match value {
+        LeafValue::Inline(expires) => Ok(u64::from_le_bytes(expires[..].try_into().map_err(|_| RetrieveError::Malformed(root))?)),
+        LeafValue::Logged(_) => Err(RetrieveError::Malformed(root))
+    }
+}
+
+/// When `key` expires, if it does
+pub(crate) async fn expiry(cache: &PageCache, expiries: Option<PageIndex>, key: &[u8], max_depth: usize) -> Result<Option<u64>, RetrieveError> {
+    let root = match expiries {
+        Some(root) => root,
+        None => return Ok(None)
+    };
+
+    match tree::lookup(cache, root, &by_key(key), max_depth).await? {
+        Some(value) => Ok(Some(decode_expiry(root, value)?)),
+        None => Ok(None)
+    }
+}
+
+/// Update the index for sorted `writes`: every written key loses its old expiry, and those in
+/// `ttls` get a new one. Returns the new index.
+pub(crate) async fn update(
+    cache: &PageCache,
+    txn: &Transaction,
+    expiries: Option<PageIndex>,
+    writes: &[Write],
+    ttls: &BTreeMap<Bytes, u64>,
+    max_depth: usize,
+    format: NodeFormat
+) -> Result<Option<PageIndex>, WriteError> {
+    if expiries.is_none() && ttls.is_empty() { return Ok(expiries) }
+
+    let mut changes: BTreeMap<Bytes, Option<LeafValue>> = BTreeMap::new();
+    if let Some(root) = expiries {
+        let keys: Vec<Bytes> = writes.iter().map(|(key, _)| by_key(key)).collect();
+        let old = tree::lookup_many(cache, root, &keys, Descent::new(max_depth)).await?;
+        for ((key, _), old) in writes.iter().zip(old) {
+            if let Some(old) = old {
+                changes.insert(by_key(key), None);
+                changes.insert(by_time(decode_expiry(root, old)?, key), None);
+            }
+        }
+    }
+    for (key, expires) in ttls {
+        changes.insert(by_key(key), Some(LeafValue::Inline(Bytes::copy_from_slice(&expires.to_le_bytes()))));
+        changes.insert(by_time(*expires, key), Some(LeafValue::Inline(Bytes::new())));
+    }
+
+    if changes.is_empty() { return Ok(expiries) }
+
+    let changes: Vec<Write> = changes.into_iter().collect();
+    Ok(tree::apply(cache, txn, expiries, &changes, max_depth, NodeFormat { compression: Compression::None, ..format }).await?)
+}
+
+/// Drop the expiries of the keys within the `cleared` bounds from the index. Their entries by key
+/// are cleared as a range, but each one's entry by time is deleted on its own.
+pub(crate) async fn clear_range(
+    cache: &PageCache,
+    txn: &Transaction,
+    expiries: Option<PageIndex>,
+    (from, to): &(Bound<Bytes>, Bound<Bytes>),
+    max_depth: usize,
+    format: NodeFormat
+) -> Result<Option<PageIndex>, WriteError> {
+    let root = match expiries {
+        Some(root) => root,
+        None => return Ok(None)
+    };
+
+    let by_key_bound = |bound: &Bound<Bytes>, unbounded: Bound<Bytes>| match bound {
+        Bound::Included(key) => Bound::Included(by_key(key)),
+        Bound::Excluded(key) => Bound::Excluded(by_key(key)),
+        Bound::Unbounded => unbounded
+    };
+    let cleared = (
+        by_key_bound(from, Bound::Included(Bytes::from_static(&[BY_KEY]))),
+        by_key_bound(to, Bound::Excluded(Bytes::from_static(&[BY_TIME])))
+    );
+
+    let mut by_time_changes = vec![];
+    let mut entries = Entries::new(Some(root), cleared.0.clone(), max_depth);
+    while let Some(entry) = entries.next(cache).await? {
+        if !range::contains(&entry.key, &cleared.0, &cleared.1) { break }
+        by_time_changes.push((by_time(decode_expiry(root, entry.value)?, &entry.key[1..]), None));
+    }
+
+    let format = NodeFormat { compression: Compression::None, ..format };
+    let expiries = tree::clear_range(cache, txn, Some(root), &cleared, max_depth, format).await?;
+    if by_time_changes.is_empty() { return Ok(expiries) }
+
+    by_time_changes.sort_by(|(a, _): &Write, (b, _)| a.cmp(b));
+    Ok(tree::apply(cache, txn, expiries, &by_time_changes, max_depth, format).await?)
+}
+
+impl DB {
+    /// Whether `key` has expired in the index at `expiries`
+    pub(super) async fn is_expired(&self, expiries: Option<PageIndex>, key: &[u8]) -> Result<bool, RetrieveError> {
+        if expiries.is_none() { return Ok(false) }
+
+        let (max_depth, now) = {
+            let options = self.options.lock();
+            (options.max_tree_depth, options.clock.unix_millis())
+        };
+        Ok(expiry(&self.cache, expiries, key, max_depth).await?.map_or(false, |expires| expires <= now))
+    }
+
+    /// Delete up to `max_keys` expired keys in one transaction, returning how many were
+    /// deleted. Expired keys already read as missing; sweeping reclaims their space. Call
+    /// this periodically, e.g. from a timer task, until it returns zero.
+    pub async fn sweep_expired(&self, max_keys: usize) -> Result<usize, WriteError> {
+        let expiries = self.version.lock().expiries;
+        let root = match expiries {
+            Some(root) => root,
+            None => return Ok(0)
+        };
+        let (max_depth, now) = {
+            let options = self.options.lock();
+            (options.max_tree_depth, options.clock.unix_millis())
+        };
+
+        let mut expired = vec![];
+        let mut entries = Entries::new(Some(root), Bound::Included(Bytes::from_static(&[BY_TIME])), max_depth);
+        while expired.len() < max_keys {
+            let entry = match entries.next(&self.cache).await? {
+                Some(entry) if entry.key.len() >= 9 && entry.key[0] == BY_TIME => entry,
+                _ => break
+            };
+            let expires = u64::from_be_bytes(entry.key[1..9].try_into().unwrap());
+            if expires > now { break }
+            expired.push(entry.key.slice(9..));
+        }
+        if expired.is_empty() { return Ok(0) }
+
+        let mut tx = self.write().await?;
+        let mut deleted = 0;
+        for key in expired {
+            // another commit may have given the key a new value since the scan
+            if self.is_expired(tx.version.expiries, &key).await? {
+                tx.delete(key)?;
+                deleted += 1;
+            }
+        }
+        tx.commit().await?;
+
+        Ok(deleted)
+    }
+}
+
+impl<'db> WriteTransaction<'db> {
+    /// Put a value that expires after `ttl`. Once it expires, reads treat the key as missing,
+    /// and `DB::sweep_expired` deletes it. Putting or deleting the key again clears the expiry.
+    pub fn put_with_ttl(&mut self, key: Bytes, value: Bytes, ttl: Duration) -> Result<(), WriteError> {
+        let expires = self.db.options.lock().clock.unix_millis().saturating_add(ttl.as_millis() as u64);
+        self.put(key.clone(), value)?;
+        self.ttls.insert(key, expires);
+        Ok(())
+    }
+}
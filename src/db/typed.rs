@@ -0,0 +1,125 @@
+//! Typed keys and values over a key prefix, with keys in `key_codec`'s order-preserving
+//! encoding and values in bincode
+
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use bytes::{Bytes, BytesMut};
+use futures::future;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use serde_crate::Serialize;
+use serde_crate::de::DeserializeOwned;
+use thiserror::Error;
+
+use super::{DB, RetrieveError, TransactionIdx, WriteError, WriteTransaction};
+use super::key_codec::{decode_key, encode_key, KeyError};
+
+#[derive(Error, Debug, Clone)]
+pub enum TypedError {
+    #[error("{0}")]
+    Key(#[source] #[from] KeyError),
+    #[error("{0}")]
+    Value(#[source] Arc<bincode::ErrorKind>),
+    #[error("{0}")]
+    Retrieve(#[source] #[from] RetrieveError),
+    #[error("{0}")]
+    Write(#[source] #[from] WriteError)
+}
+
+impl From<bincode::Error> for TypedError {
+    fn from(err: bincode::Error) -> Self {
+        TypedError::Value(Arc::from(err))
+    }
+}
+
+/// A map from `K` to `V` stored under the keys starting with `prefix`. Keys sort and
+/// range-scan the way `K` compares, for integers, strings, bytes, options, sequences, tuples,
+/// structs and enums of them. Give each tree a prefix no other tree's starts with.
+pub struct TypedTree<'db, K, V> {
+    db: &'db DB,
+    prefix: Bytes,
+    types: PhantomData<fn(K, V) -> (K, V)>
+}
+
+impl DB {
+    /// A typed view of the keys starting with `prefix`
+    pub fn typed<K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned>(&self, prefix: Bytes) -> TypedTree<'_, K, V> {
+        TypedTree { db: self, prefix, types: PhantomData }
+    }
+}
+
+impl<'db, K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> TypedTree<'db, K, V> {
+    fn key(&self, key: &K) -> Result<Bytes, TypedError> {
+        let mut bytes = BytesMut::from(&self.prefix[..]);
+        bytes.extend_from_slice(&encode_key(key)?);
+        Ok(bytes.freeze())
+    }
+
+    fn bound(&self, bound: Bound<&K>) -> Result<Bound<Bytes>, TypedError> {
+        Ok(match bound {
+            Bound::Included(key) => Bound::Included(self.key(key)?),
+            Bound::Excluded(key) => Bound::Excluded(self.key(key)?),
+            Bound::Unbounded => Bound::Unbounded
+        })
+    }
+
+    pub async fn get(&self, key: &K) -> Result<Option<V>, TypedError> {
+        match self.db.get(&self.key(key)?).await? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None)
+        }
+    }
+
+    pub async fn contains_key(&self, key: &K) -> Result<bool, TypedError> {
+        Ok(self.db.contains_key(&self.key(key)?).await?)
+    }
+
+    /// Set `key` to `value` in its own transaction
+    pub async fn insert(&self, key: &K, value: &V) -> Result<TransactionIdx, TypedError> {
+        let mut txn = self.db.write().await.map_err(WriteError::from)?;
+        self.put(&mut txn, key, value)?;
+        Ok(txn.commit().await?)
+    }
+
+    /// Delete `key` in its own transaction
+    pub async fn remove(&self, key: &K) -> Result<TransactionIdx, TypedError> {
+        let mut txn = self.db.write().await.map_err(WriteError::from)?;
+        self.delete(&mut txn, key)?;
+        Ok(txn.commit().await?)
+    }
+
+    /// Set `key` to `value` as part of `txn`
+    pub fn put(&self, txn: &mut WriteTransaction<'_>, key: &K, value: &V) -> Result<(), TypedError> {
+        Ok(txn.put(self.key(key)?, Bytes::from(bincode::serialize(value)?))?)
+    }
+
+    /// Delete `key` as part of `txn`
+    pub fn delete(&self, txn: &mut WriteTransaction<'_>, key: &K) -> Result<(), TypedError> {
+        Ok(txn.delete(self.key(key)?)?)
+    }
+
+    /// Stream the entries with keys in `range`, in key order
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> BoxStream<'db, Result<(K, V), TypedError>>
+        where K: Send + 'db, V: Send + 'db
+    {
+        let bounds = self.bound(range.start_bound()).and_then(|from| Ok((from, self.bound(range.end_bound())?)));
+        let (from, to) = match bounds {
+            Ok(bounds) => bounds,
+            Err(err) => return stream::once(future::ready(Err(err))).boxed()
+        };
+        let from = match from {
+            Bound::Unbounded => Bound::Included(self.prefix.clone()),
+            from => from
+        };
+
+        let prefix = self.prefix.clone();
+        let prefix_len = prefix.len();
+        self.db.range((from, to))
+            .map_err(TypedError::from)
+            .try_take_while(move |(key, _)| future::ready(Ok(key.starts_with(&prefix))))
+            .and_then(move |(key, value)| async move {
+                Ok((decode_key(&key[prefix_len..])?, bincode::deserialize(&value)?))
+            })
+            .boxed()
+    }
+}
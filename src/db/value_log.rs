@@ -0,0 +1,990 @@
+//! Large values are kept out of the tree, WiscKey-style: they're appended to a log of
+//! `ValueLog` pages and the tree stores a `ValuePointer` to them. Each record is
+//! `[key_len: u32][value_len: u64][codec: u8][key][value]`, packed through the data regions of
+//! consecutive pages. Records carry their keys, so GC can scan a segment and ask the tree
+//! whether each key still points at its record.
+
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
+use std::io;
+use std::sync::Arc;
+use bytes::{Bytes, BytesMut};
+use futures::stream::{self, BoxStream, StreamExt};
+use parking_lot::Mutex;
+
+use super::{DB, Durability, PageIndex, PageStore, PageCache, RetrieveError, TransactionIdx, WriteError};
+use super::compression::{self, Compression, Dictionaries};
+use super::leaf::LeafValue;
+use super::overflow;
+use super::page::{self, PageContent, PageType, PAGE_DATA_LEN, PAGE_DATA_OFFSET};
+use super::transaction::Transaction;
+use super::tree::{self, NodeFormat, Write};
+use super::version::VersionHeader;
+use super::write_back::WriteBack;
+
+pub type SegmentIdx = u64;
+
+/// Pages per value log segment. Segments are allocated as aligned runs of pages,
+/// so the segment holding a page is found by division. A value too large for one gets a longer
+/// run to itself.
+pub const SEGMENT_PAGES: u64 = 1024;
+
+/// Segments are bucketed by the percentage of their bytes that are garbage
+const GARBAGE_BUCKETS: usize = 101;
+
+const SEGMENT_ENCODED_LEN: usize = 24;
+
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    written_bytes: u64,
+    live_bytes: u64
+}
+
+impl Segment {
+    fn garbage_bucket(&self) -> usize {
+        if self.written_bytes == 0 { return 0 }
+        ((self.written_bytes - self.live_bytes) * 100 / self.written_bytes) as usize
+    }
+}
+
+/// Space used by the value log, from `Statistics::value_log`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ValueLogStats {
+    pub segments: u64,
+    /// Bytes of records appended to the log
+    pub written_bytes: u64,
+    /// Bytes of records the tree or the write buffer still point at
+    pub live_bytes: u64,
+    /// The percentage of bytes that are garbage in the segment with the most
+    pub max_segment_garbage: u8
+}
+
+impl ValueLogStats {
+    /// Bytes on disk per byte of live data: infinite once everything written is garbage, and
+    /// 1 for an empty log
+    pub fn space_amplification(&self) -> f64 {
+        if self.written_bytes == 0 { return 1.0 }
+        if self.live_bytes == 0 { return f64::INFINITY }
+        self.written_bytes as f64 / self.live_bytes as f64
+    }
+}
+
+/// What a pass of `DB::collect_value_log` reclaimed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compaction {
+    /// The transaction that committed the relocated values
+    pub tx: TransactionIdx,
+    /// The segment collected, by its first page divided by the segment size
+    pub segment: u64,
+    /// Bytes of records still pointed at, which were appended to the head of the log
+    pub relocated_bytes: u64,
+    /// Bytes of records that were garbage
+    pub reclaimed_bytes: u64
+}
+
+/// Pages emptied by the GC commit `tx`, whose previous versions still point into them
+#[derive(Debug, Clone, Copy)]
+struct Discard {
+    tx: TransactionIdx,
+    start: PageIndex,
+    pages: u64
+}
+
+/// Where a version keeps its segment table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TableAt {
+    /// Not kept, by files written before it was, so it's rebuilt from the log on open
+    Missing,
+    /// No segment has been written
+    Empty,
+    Chain(PageIndex)
+}
+
+/// Tracks written and live bytes for every value log segment, indexed by their share of
+/// garbage so the worst segment is found without scanning. Each commit that changes it
+/// persists it in an overflow chain, so opening reads it back rather than the log.
+///
+/// Records are counted dead as the commits overwriting or deleting them are applied. Those
+/// dropped unread stay counted as live until GC reads their segment: the values in subtrees
+/// `delete_range` unlinks, and those a transaction overwrites after it spilled.
+pub(crate) struct SegmentTable {
+    segments: BTreeMap<SegmentIdx, Segment>,
+    by_garbage: Vec<HashSet<SegmentIdx>>,
+    written_bytes: u64,
+    live_bytes: u64
+}
+
+impl SegmentTable {
+    pub fn new() -> SegmentTable {
+        SegmentTable {
+            segments: BTreeMap::new(),
+            by_garbage: (0..GARBAGE_BUCKETS).map(|_| HashSet::new()).collect(),
+            written_bytes: 0,
+            live_bytes: 0
+        }
+    }
+
+    pub fn segment_of(page: PageIndex) -> SegmentIdx {
+        page / SEGMENT_PAGES
+    }
+
+    /// A value of `len` bytes was appended at `page`
+    pub fn record_append(&mut self, page: PageIndex, len: u64) {
+        self.update(SegmentTable::segment_of(page), |segment| {
+            segment.written_bytes += len;
+            segment.live_bytes += len;
+        });
+        self.written_bytes += len;
+        self.live_bytes += len;
+    }
+
+    /// A value of `len` bytes at `page` was overwritten or deleted
+    pub fn record_dead(&mut self, page: PageIndex, len: u64) {
+        let idx = SegmentTable::segment_of(page);
+        // a record the rebuild couldn't read, e.g. on a torn page, was never counted
+        if !self.segments.contains_key(&idx) { return }
+
+        let mut dead = 0;
+        self.update(idx, |segment| {
+            dead = len.min(segment.live_bytes);
+            segment.live_bytes -= dead;
+        });
+        self.live_bytes -= dead;
+    }
+
+    /// Forget a segment once GC has relocated its live values
+    pub fn remove_segment(&mut self, idx: SegmentIdx) {
+        if let Some(segment) = self.segments.remove(&idx) {
+            self.by_garbage[segment.garbage_bucket()].remove(&idx);
+            self.written_bytes -= segment.written_bytes;
+            self.live_bytes -= segment.live_bytes;
+        }
+    }
+
+    /// The segment with the largest share of garbage, other than `skip`, if any has garbage at all
+    pub fn gc_candidate(&self, skip: Option<SegmentIdx>) -> Option<SegmentIdx> {
+        self.by_garbage[1..].iter().rev()
+            .find_map(|bucket| bucket.iter().copied().find(|&idx| Some(idx) != skip))
+    }
+
+    fn update(&mut self, idx: SegmentIdx, f: impl FnOnce(&mut Segment)) {
+        let segment = self.segments.entry(idx).or_insert(Segment { written_bytes: 0, live_bytes: 0 });
+
+        let old_bucket = segment.garbage_bucket();
+        f(segment);
+        let new_bucket = segment.garbage_bucket();
+
+        self.by_garbage[old_bucket].remove(&idx);
+        self.by_garbage[new_bucket].insert(idx);
+    }
+
+    pub fn stats(&self) -> ValueLogStats {
+        ValueLogStats {
+            segments: self.segments.len() as u64,
+            written_bytes: self.written_bytes,
+            live_bytes: self.live_bytes,
+            max_segment_garbage: self.by_garbage.iter().rposition(|bucket| !bucket.is_empty()).unwrap_or(0) as u8
+        }
+    }
+
+    /// `[idx: u64][written_bytes: u64][live_bytes: u64]` for each segment, in order
+    fn encode(segments: &BTreeMap<SegmentIdx, Segment>) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(segments.len() * SEGMENT_ENCODED_LEN);
+        for (idx, segment) in segments {
+            buf.extend_from_slice(&idx.to_le_bytes());
+            buf.extend_from_slice(&segment.written_bytes.to_le_bytes());
+            buf.extend_from_slice(&segment.live_bytes.to_le_bytes());
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<SegmentTable> {
+        if buf.len() % SEGMENT_ENCODED_LEN != 0 { return None }
+
+        let mut table = SegmentTable::new();
+        for record in buf.chunks(SEGMENT_ENCODED_LEN) {
+            let field = |at: usize| u64::from_le_bytes(record[at..at + 8].try_into().unwrap());
+            let (written_bytes, live_bytes) = (field(8), field(16));
+            if live_bytes > written_bytes { return None }
+
+            table.update(field(0), |segment| *segment = Segment { written_bytes, live_bytes });
+            table.written_bytes += written_bytes;
+            table.live_bytes += live_bytes;
+        }
+        Some(table)
+    }
+
+    /// Read the table a version keeps, or `None` if it doesn't keep one
+    pub async fn load(cache: &PageCache, at: TableAt) -> Result<Option<SegmentTable>, RetrieveError> {
+        match at {
+            TableAt::Missing => Ok(None),
+            TableAt::Empty => Ok(Some(SegmentTable::new())),
+            TableAt::Chain(idx) => {
+                let encoded = overflow::read_chain(cache, idx, 0).await?;
+                SegmentTable::decode(&encoded).map(Some).ok_or(RetrieveError::Malformed(idx))
+            }
+        }
+    }
+}
+
+const RECORD_HEADER_LEN: usize = 4 + 8 + 1;
+
+/// Where a value lives in the value log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ValuePointer {
+    /// The page holding the first byte of the value
+    pub page: PageIndex,
+    /// Offset of the value into the page's data
+    pub offset: u32,
+    /// Bytes stored, which are compressed if `codec` says so
+    pub len: u64,
+    pub codec: u8
+}
+
+impl ValuePointer {
+    pub const ENCODED_LEN: usize = 8 + 4 + 8 + 1;
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        buf[0..8].copy_from_slice(&self.page.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.offset.to_le_bytes());
+        buf[12..20].copy_from_slice(&self.len.to_le_bytes());
+        buf[20] = self.codec;
+    }
+
+    pub fn decode(buf: &[u8]) -> ValuePointer {
+        ValuePointer {
+            page: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            offset: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            len: u64::from_le_bytes(buf[12..20].try_into().unwrap()),
+            codec: buf[20]
+        }
+    }
+
+    /// Bytes the record holding this value takes up, including its header and key
+    pub fn record_len(&self, key_len: usize) -> u64 {
+        (RECORD_HEADER_LEN + key_len) as u64 + self.len
+    }
+}
+
+/// Pages needed to hold `len` bytes of records
+fn pages_for(len: u64) -> u64 {
+    (len + PAGE_DATA_LEN as u64 - 1) / PAGE_DATA_LEN as u64
+}
+
+/// The head of the value log, where new values are appended.
+///
+/// Records are written into the current segment until it's full, then a new segment is
+/// allocated. A partially filled page is written when the transaction is sealed, and the
+/// next append starts on a fresh page, so pages of committed transactions are never rewritten.
+pub(crate) struct ValueLogWriter {
+    segments: SegmentTable,
+    /// The run of pages being appended to, as (first page, pages)
+    run: Option<(PageIndex, u64)>,
+    /// Next free byte, counted through the data regions of the run
+    pos: u64,
+    page: Box<PageContent>,
+    /// The page holding the header of a value still being appended, whose length is filled in
+    /// when it's finished. It's held back from the write-back queue until then.
+    held: Option<(PageIndex, Option<Box<PageContent>>)>,
+    /// Records appended by the open transaction, counted in `segments` once it commits
+    appended: Vec<(PageIndex, u64)>,
+    /// Segments the open transaction's GC emptied, removed from `segments` once it commits
+    freed: Vec<SegmentIdx>,
+    /// Runs of pages GC emptied, to discard once no version that can be read points into them
+    discards: Vec<Discard>,
+    /// zstd compresses values with the latest dictionary
+    dictionaries: Arc<Dictionaries>
+}
+
+/// A value being appended a chunk at a time
+pub(crate) struct PendingValue {
+    header_offset: usize,
+    key_len: usize,
+    ptr: ValuePointer
+}
+
+impl ValueLogWriter {
+    /// Start a new run on the next append, counting it into `segments`
+    pub fn new(segments: SegmentTable) -> ValueLogWriter {
+        ValueLogWriter {
+            segments,
+            run: None,
+            pos: 0,
+            page: Box::new(PageContent::new(PageType::ValueLog)),
+            held: None,
+            appended: vec![],
+            freed: vec![],
+            discards: vec![],
+            dictionaries: Arc::new(Dictionaries::default())
+        }
+    }
+
+    pub fn set_dictionaries(&mut self, dictionaries: Arc<Dictionaries>) {
+        self.dictionaries = dictionaries;
+    }
+
+    pub fn stats(&self) -> ValueLogStats {
+        self.segments.stats()
+    }
+
+    /// Append a value to the log, compressed if that makes it smaller
+    pub fn append(&mut self, txn: &Transaction, key: &[u8], value: &[u8], compression: Compression) -> ValuePointer {
+        match compression.compress(value, &self.dictionaries) {
+            Some((codec, compressed)) => self.append_stored(txn, key, &compressed, codec),
+            None => self.append_stored(txn, key, value, compression::NONE)
+        }
+    }
+
+    /// Append a value exactly as it's to be stored, compressed with `codec`
+    pub fn append_stored(&mut self, txn: &Transaction, key: &[u8], stored: &[u8], codec: u8) -> ValuePointer {
+        let mut pending = self.begin(txn, key, stored.len() as u64, codec);
+        self.write_value(txn, &mut pending, stored).expect("space was reserved for the whole value");
+        self.finish(txn, pending)
+    }
+
+    /// Start appending a value of about `len_hint` bytes. Space is reserved for `len_hint`
+    /// bytes, so a value that outgrows it may fail in `write_value`.
+    pub fn begin(&mut self, txn: &Transaction, key: &[u8], len_hint: u64, codec: u8) -> PendingValue {
+        let record_len = (RECORD_HEADER_LEN + key.len()) as u64 + len_hint;
+
+        // a record header never straddles pages, so readers find it in one piece
+        if (PAGE_DATA_LEN as u64 - self.pos % PAGE_DATA_LEN as u64) < RECORD_HEADER_LEN as u64 {
+            self.next_page(txn);
+        }
+
+        let fits = match self.run {
+            Some((_, pages)) => self.pos + record_len <= pages * PAGE_DATA_LEN as u64,
+            None => false
+        };
+        if !fits {
+            self.seal(txn);
+            let pages = pages_for(record_len).max(SEGMENT_PAGES);
+            self.run = Some((txn.alloc_run(pages, SEGMENT_PAGES), pages));
+            self.pos = 0;
+        }
+
+        let header_page = self.current_page();
+        let header_offset = (self.pos % PAGE_DATA_LEN as u64) as usize;
+        self.held = Some((header_page, None));
+
+        // the value length is filled in by `finish`
+        let mut header = [0; RECORD_HEADER_LEN];
+        header[0..4].copy_from_slice(&(key.len() as u32).to_le_bytes());
+        header[12] = codec;
+        self.write(txn, &header);
+        self.write(txn, key);
+
+        PendingValue {
+            header_offset,
+            key_len: key.len(),
+            ptr: ValuePointer {
+                page: self.current_page(),
+                offset: (self.pos % PAGE_DATA_LEN as u64) as u32,
+                len: 0,
+                codec
+            }
+        }
+    }
+
+    /// Append the next chunk of a pending value
+    pub fn write_value(&mut self, txn: &Transaction, pending: &mut PendingValue, bytes: &[u8]) -> io::Result<()> {
+        let (_, pages) = self.run.expect("no run allocated");
+        if self.pos + bytes.len() as u64 > pages * PAGE_DATA_LEN as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "value is longer than the space reserved for it"))
+        }
+
+        self.write(txn, bytes);
+        pending.ptr.len += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Fill in the length of a pending value, and release its header page to be written
+    pub fn finish(&mut self, txn: &Transaction, pending: PendingValue) -> ValuePointer {
+        let (header_page, flushed) = self.held.take().expect("no value pending");
+        let len_at = pending.header_offset + 4;
+        let len = pending.ptr.len.to_le_bytes();
+
+        match flushed {
+            Some(mut page) => {
+                page.data[len_at..len_at + 8].copy_from_slice(&len);
+                txn.write_new_page(header_page, page);
+            },
+            None => self.page.data[len_at..len_at + 8].copy_from_slice(&len)
+        }
+
+        // counted where the value starts, as the pointers the tree releases it by are
+        self.appended.push((pending.ptr.page, pending.ptr.record_len(pending.key_len)));
+
+        // a run longer than a segment holds one value, so every segment is one run
+        if let Some((_, pages)) = self.run {
+            if pages > SEGMENT_PAGES {
+                self.seal(txn);
+                self.run = None;
+                self.pos = 0;
+            }
+        }
+        pending.ptr
+    }
+
+    /// Write the partially filled page, if any. Call before committing `txn`.
+    pub fn seal(&mut self, txn: &Transaction) {
+        if self.pos % PAGE_DATA_LEN as u64 != 0 {
+            self.next_page(txn);
+        }
+    }
+
+    /// Remove a segment from the table once `txn`, which relocated its live values, commits
+    pub fn free_segment(&mut self, idx: SegmentIdx) {
+        self.freed.push(idx);
+    }
+
+    /// Discard `pages` pages from `start` once versions before `tx` can no longer be read
+    fn discard_after(&mut self, tx: TransactionIdx, start: PageIndex, pages: u64) {
+        if pages > 0 { self.discards.push(Discard { tx, start, pages }) }
+    }
+
+    /// Take the discards no version that can still be read points into. Versions from
+    /// `readable` on can be: the oldest pinned, archived, or kept in a version slot.
+    fn take_discards(&mut self, readable: TransactionIdx) -> Vec<Discard> {
+        let (due, pending) = self.discards.drain(..).partition(|discard| discard.tx <= readable);
+        self.discards = pending;
+        due
+    }
+
+    /// The segment with the most garbage, other than the one appends go to
+    pub fn gc_candidate(&self) -> Option<SegmentIdx> {
+        self.segments.gc_candidate(self.active_segment())
+    }
+
+    /// A copy of the page being filled, if it's `idx` and holds records not yet written out
+    fn unflushed(&self, idx: PageIndex) -> Option<PageContent> {
+        let filling = self.run.is_some() && self.pos % PAGE_DATA_LEN as u64 != 0;
+        if filling && self.current_page() == idx { Some((*self.page).clone()) } else { None }
+    }
+
+    /// The segment appends go to, which GC leaves alone
+    pub fn active_segment(&self) -> Option<SegmentIdx> {
+        self.run.map(|(start, _)| SegmentTable::segment_of(start))
+    }
+
+    /// Write the segment table as it will stand once `txn` commits, if the transaction changes
+    /// it or the version doesn't keep one yet. Returns where the committed version keeps it.
+    pub fn write_table(&self, txn: &Transaction, at: TableAt) -> TableAt {
+        let released = txn.released();
+        if self.appended.is_empty() && released.is_empty() && self.freed.is_empty() && at != TableAt::Missing { return at }
+
+        // the same changes `committed` makes, in the same order
+        let mut segments = self.segments.segments.clone();
+        for &(page, len) in &self.appended {
+            let segment = segments.entry(SegmentTable::segment_of(page)).or_insert(Segment { written_bytes: 0, live_bytes: 0 });
+            segment.written_bytes += len;
+            segment.live_bytes += len;
+        }
+        for &(page, len) in &released {
+            if let Some(segment) = segments.get_mut(&SegmentTable::segment_of(page)) {
+                segment.live_bytes -= len.min(segment.live_bytes);
+            }
+        }
+        for idx in &self.freed {
+            segments.remove(idx);
+        }
+
+        if segments.is_empty() { return TableAt::Empty }
+        TableAt::Chain(overflow::write_chain(txn, &SegmentTable::encode(&segments)))
+    }
+
+    /// Count the records of a transaction that committed, and those it released
+    pub fn committed(&mut self, txn: &Transaction) {
+        for (page, len) in self.appended.drain(..) {
+            self.segments.record_append(page, len);
+        }
+        for (page, len) in txn.take_released() {
+            self.segments.record_dead(page, len);
+        }
+        for idx in self.freed.drain(..) {
+            self.segments.remove_segment(idx);
+        }
+    }
+
+    /// Bytes of records the open transaction has appended
+    pub fn appended_bytes(&self) -> u64 {
+        self.appended.iter().map(|(_, len)| len).sum()
+    }
+
+    /// Forget the appends of a transaction that was rolled back. Its pages will be allocated
+    /// again, so the next append starts a new run.
+    pub fn rolled_back(&mut self) {
+        self.run = None;
+        self.pos = 0;
+        self.page = Box::new(PageContent::new(PageType::ValueLog));
+        self.held = None;
+        self.appended.clear();
+        self.freed.clear();
+    }
+
+    fn current_page(&self) -> PageIndex {
+        let (start, _) = self.run.expect("no run allocated");
+        start + self.pos / PAGE_DATA_LEN as u64
+    }
+
+    fn write(&mut self, txn: &Transaction, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let offset = (self.pos % PAGE_DATA_LEN as u64) as usize;
+            let len = bytes.len().min(PAGE_DATA_LEN - offset);
+
+            self.page.data[offset..offset + len].copy_from_slice(&bytes[..len]);
+            self.pos += len as u64;
+            bytes = &bytes[len..];
+
+            if self.pos % PAGE_DATA_LEN as u64 == 0 {
+                self.flush_page(txn, self.current_page() - 1);
+            }
+        }
+    }
+
+    /// Pad out the current page and write it. Zeroed space reads back as padding.
+    fn next_page(&mut self, txn: &Transaction) {
+        if self.run.is_none() { return }
+
+        let idx = self.current_page();
+        self.pos = (self.pos / PAGE_DATA_LEN as u64 + 1) * PAGE_DATA_LEN as u64;
+        self.flush_page(txn, idx);
+
+        if let Some((_, pages)) = self.run {
+            if self.pos >= pages * PAGE_DATA_LEN as u64 { self.run = None }
+        }
+    }
+
+    fn flush_page(&mut self, txn: &Transaction, idx: PageIndex) {
+        let page = std::mem::replace(&mut self.page, Box::new(PageContent::new(PageType::ValueLog)));
+
+        match &mut self.held {
+            Some((held_idx, held)) if *held_idx == idx => *held = Some(page),
+            _ => txn.write_new_page(idx, page)
+        }
+    }
+}
+
+/// Read a value through the page cache
+pub(crate) async fn read_value(cache: &PageCache, dictionaries: &Dictionaries, ptr: ValuePointer) -> Result<Bytes, RetrieveError> {
+    let mut value = BytesMut::with_capacity(ptr.len as usize);
+    let mut page = ptr.page;
+    let mut offset = ptr.offset as usize;
+
+    while (value.len() as u64) < ptr.len {
+        let raw = cache.get(page, 0).await?;
+        let data = &raw[PAGE_DATA_OFFSET..PAGE_DATA_OFFSET + PAGE_DATA_LEN];
+
+        let len = (ptr.len as usize - value.len()).min(PAGE_DATA_LEN - offset);
+        value.extend_from_slice(&data[offset..offset + len]);
+
+        page += 1;
+        offset = 0;
+    }
+
+    match ptr.codec {
+        compression::NONE => Ok(value.freeze()),
+        codec => compression::decompress(codec, &value, u32::MAX as usize, dictionaries)
+            .map(Bytes::from)
+            .ok_or(RetrieveError::Malformed(ptr.page))
+    }
+}
+
+/// Read a value the open transaction logged. Its pages are read around the page cache, since
+/// a rollback reuses them: from the page `writer` is filling, from the write-back queue, or
+/// once written, from the store.
+pub(crate) async fn read_own_value(
+    store: &dyn PageStore,
+    write_back: &WriteBack,
+    writer: &Mutex<ValueLogWriter>,
+    dictionaries: &Dictionaries,
+    ptr: ValuePointer
+) -> Result<Bytes, RetrieveError> {
+    let mut value = BytesMut::with_capacity(ptr.len as usize);
+    let mut page = ptr.page;
+    let mut offset = ptr.offset as usize;
+
+    while (value.len() as u64) < ptr.len {
+        let len = (ptr.len as usize - value.len()).min(PAGE_DATA_LEN - offset);
+        let unflushed = writer.lock().unflushed(page);
+        match unflushed {
+            Some(content) => value.extend_from_slice(&content.data[offset..offset + len]),
+            None => match write_back.queued(page) {
+                Some(content) => value.extend_from_slice(&content.data[offset..offset + len]),
+                None => value.extend_from_slice(&store.read_page(page).await?.data[offset..offset + len])
+            }
+        }
+
+        page += 1;
+        offset = 0;
+    }
+
+    match ptr.codec {
+        compression::NONE => Ok(value.freeze()),
+        codec => compression::decompress(codec, &value, u32::MAX as usize, dictionaries)
+            .map(Bytes::from)
+            .ok_or(RetrieveError::Malformed(ptr.page))
+    }
+}
+
+/// Stream a value through the page cache a page at a time, sharing the pages cached on the heap
+/// rather than copying them
+pub(crate) fn stream_value(cache: &PageCache, dictionaries: Arc<Dictionaries>, ptr: ValuePointer) -> BoxStream<'_, Result<Bytes, RetrieveError>> {
+    // compressed values are decompressed whole
+    if ptr.codec != compression::NONE {
+        return stream::once(async move { read_value(cache, &dictionaries, ptr).await }).boxed()
+    }
+
+    let start = (ptr.page, ptr.offset as usize, ptr.len as usize);
+
+    stream::try_unfold(start, move |(page, offset, remaining)| async move {
+        if remaining == 0 { return Ok::<_, RetrieveError>(None) }
+
+        let raw = cache.get(page, 0).await?;
+        let len = remaining.min(PAGE_DATA_LEN - offset);
+        let start = PAGE_DATA_OFFSET + offset;
+
+        Ok(Some((raw.slice(start..start + len), (page + 1, 0, remaining - len))))
+    }).boxed()
+}
+
+/// Rebuild the segment table from the log: every record below `page_count` counts as written,
+/// and those the tree rooted at `root` or the `buffered` writes point at as live. Reads the
+/// pages holding record headers, but not the keys and values between them.
+pub(crate) async fn rebuild(
+    store: &dyn PageStore,
+    cache: &PageCache,
+    root: Option<PageIndex>,
+    buffered: &[Write],
+    page_count: u64,
+    max_depth: usize
+) -> Result<SegmentTable, RetrieveError> {
+    let mut live = HashSet::new();
+    live.extend(buffered.iter().filter_map(|(_, value)| value.as_ref().and_then(logged_at)));
+    if let Some(root) = root {
+        tree::scan_leaves(cache, root, max_depth, |entries| {
+            live.extend(entries.iter().filter_map(|entry| logged_at(&entry.value)));
+            true
+        }).await?;
+    }
+
+    let mut table = SegmentTable::new();
+    let mut reader = LogReader { store, end: page_count, page_idx: 0, page: None, offset: 0 };
+    while reader.page_idx < page_count {
+        // files written before a run longer than a segment was kept to one record may have
+        // more after it, which are counted to the run's first segment, where GC reads from
+        let run = reader.page_idx;
+        while let Some((ptr, len)) = reader.next_record().await? {
+            table.record_append(run, len);
+            if !live.contains(&(ptr.page, ptr.offset)) { table.record_dead(run, len) }
+        }
+        // runs start on segment boundaries, so the log carries on at a later one, if anywhere
+        reader.next_segment();
+    }
+
+    Ok(table)
+}
+
+fn logged_at(value: &LeafValue) -> Option<(PageIndex, u32)> {
+    match value {
+        LeafValue::Logged(ptr) => Some((ptr.page, ptr.offset)),
+        LeafValue::Inline(_) => None
+    }
+}
+
+/// Reads the record headers of the log straight from the store, skipping over keys and values
+struct LogReader<'s> {
+    store: &'s dyn PageStore,
+    end: PageIndex,
+    page_idx: PageIndex,
+    page: Option<PageContent>,
+    offset: usize
+}
+
+impl<'s> LogReader<'s> {
+    /// Load the current page, or `false` if it doesn't hold records
+    async fn load(&mut self) -> Result<bool, RetrieveError> {
+        if self.page.is_some() { return Ok(true) }
+        if self.page_idx >= self.end { return Ok(false) }
+
+        let page = match self.store.read_page(self.page_idx).await {
+            Ok(page) => page,
+            Err(RetrieveError::OutOfPages) | Err(RetrieveError::Malformed(_)) | Err(RetrieveError::BadChecksum) => return Ok(false),
+            Err(err) => return Err(err)
+        };
+
+        // a run may be followed by other pages, or left partly unwritten by a crash
+        if !matches!(page.page_type, PageType::ValueLog) || !page::checksum_ok(page.as_slice()) { return Ok(false) }
+
+        self.page = Some(page);
+        Ok(true)
+    }
+
+    /// Move `len` bytes on through the pages' data regions
+    fn skip(&mut self, len: u64) {
+        let pos = self.offset as u64 + len;
+        if pos >= PAGE_DATA_LEN as u64 { self.page = None }
+        self.page_idx += pos / PAGE_DATA_LEN as u64;
+        self.offset = (pos % PAGE_DATA_LEN as u64) as usize;
+    }
+
+    /// Move to the start of the next segment
+    fn next_segment(&mut self) {
+        self.page = None;
+        self.page_idx = (self.page_idx / SEGMENT_PAGES + 1) * SEGMENT_PAGES;
+        self.offset = 0;
+    }
+
+    /// The next record as a pointer to its value and the record's length, or `None` at a page
+    /// that doesn't hold records
+    async fn next_record(&mut self) -> Result<Option<(ValuePointer, u64)>, RetrieveError> {
+        loop {
+            if PAGE_DATA_LEN - self.offset < RECORD_HEADER_LEN { self.skip((PAGE_DATA_LEN - self.offset) as u64) }
+            if !self.load().await? { return Ok(None) }
+
+            let data = &self.page.as_ref().unwrap().data;
+            let header = &data[self.offset..self.offset + RECORD_HEADER_LEN];
+            let key_len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as u64;
+            let value_len = u64::from_le_bytes(header[4..12].try_into().unwrap());
+            let codec = header[12];
+
+            // an empty header is padding at the end of a page
+            if key_len == 0 && value_len == 0 {
+                self.skip((PAGE_DATA_LEN - self.offset) as u64);
+                continue;
+            }
+
+            self.skip(RECORD_HEADER_LEN as u64 + key_len);
+            let ptr = ValuePointer { page: self.page_idx, offset: self.offset as u32, len: value_len, codec };
+            self.skip(value_len);
+            return Ok(Some((ptr, RECORD_HEADER_LEN as u64 + key_len + value_len)))
+        }
+    }
+}
+
+/// The records of a segment that the tree rooted at `root` still points at, as (key, stored
+/// value, pointer to the value), the bytes of all the segment's records, and the page reading
+/// stopped at. Pages before it hold only the records read.
+async fn live_records(
+    store: &dyn PageStore,
+    cache: &PageCache,
+    root: Option<PageIndex>,
+    segment: SegmentIdx,
+    max_depth: usize
+) -> Result<(Vec<(Bytes, Bytes, ValuePointer)>, u64, PageIndex), RetrieveError> {
+    let mut reader = SegmentReader::new(store, segment);
+    let mut live = vec![];
+    let mut read_bytes = 0;
+
+    while let Some((key, value, ptr)) = reader.next_record().await? {
+        read_bytes += ptr.record_len(key.len());
+
+        let found = match root {
+            Some(root) => tree::lookup(cache, root, &key, max_depth).await?,
+            None => None
+        };
+        if let Some(LeafValue::Logged(found)) = found {
+            if (found.page, found.offset) == (ptr.page, ptr.offset) { live.push((key, value, ptr)) }
+        }
+    }
+
+    Ok((live, read_bytes, reader.page_idx))
+}
+
+/// Scans the records starting in a segment, straight from the store
+struct SegmentReader<'s> {
+    store: &'s dyn PageStore,
+    /// Records start before this page. The last may run past it, into an oversized run.
+    end: PageIndex,
+    page_idx: PageIndex,
+    page: Option<PageContent>,
+    offset: usize
+}
+
+impl<'s> SegmentReader<'s> {
+    fn new(store: &'s dyn PageStore, segment: SegmentIdx) -> SegmentReader<'s> {
+        let start = segment * SEGMENT_PAGES;
+        SegmentReader { store, end: start + SEGMENT_PAGES, page_idx: start, page: None, offset: 0 }
+    }
+
+    /// Load the current page, or `false` if it doesn't hold records
+    async fn load(&mut self) -> Result<bool, RetrieveError> {
+        if self.page.is_some() { return Ok(true) }
+
+        let page = match self.store.read_page(self.page_idx).await {
+            Ok(page) => page,
+            Err(RetrieveError::OutOfPages) => return Ok(false),
+            Err(err) => return Err(err)
+        };
+
+        // a run may be followed by other pages, or left partly unwritten
+        if !matches!(page.page_type, PageType::ValueLog) || !page::checksum_ok(page.as_slice()) { return Ok(false) }
+
+        self.page = Some(page);
+        Ok(true)
+    }
+
+    fn advance_page(&mut self) {
+        self.page = None;
+        self.page_idx += 1;
+        self.offset = 0;
+    }
+
+    async fn read(&mut self, mut len: usize, out: &mut BytesMut) -> Result<bool, RetrieveError> {
+        while len > 0 {
+            if !self.load().await? { return Ok(false) }
+            let data = &self.page.as_ref().unwrap().data;
+
+            let n = len.min(PAGE_DATA_LEN - self.offset);
+            out.extend_from_slice(&data[self.offset..self.offset + n]);
+            self.offset += n;
+            len -= n;
+
+            if self.offset == PAGE_DATA_LEN { self.advance_page() }
+        }
+        Ok(true)
+    }
+
+    /// The next record as (key, stored value, pointer to the value)
+    async fn next_record(&mut self) -> Result<Option<(Bytes, Bytes, ValuePointer)>, RetrieveError> {
+        loop {
+            if PAGE_DATA_LEN - self.offset < RECORD_HEADER_LEN { self.advance_page() }
+            if self.page_idx >= self.end || !self.load().await? { return Ok(None) }
+
+            let data = &self.page.as_ref().unwrap().data;
+            let header = &data[self.offset..self.offset + RECORD_HEADER_LEN];
+            let key_len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+            let value_len = u64::from_le_bytes(header[4..12].try_into().unwrap());
+            let codec = header[12];
+
+            // an empty header is padding at the end of a page
+            if key_len == 0 && value_len == 0 {
+                self.advance_page();
+                continue;
+            }
+            self.offset += RECORD_HEADER_LEN;
+
+            let mut key = BytesMut::with_capacity(key_len);
+            if !self.read(key_len, &mut key).await? { return Ok(None) }
+
+            if !self.load().await? { return Ok(None) }
+            let ptr = ValuePointer { page: self.page_idx, offset: self.offset as u32, len: value_len, codec };
+
+            let mut value = BytesMut::with_capacity(value_len as usize);
+            if !self.read(value_len as usize, &mut value).await? { return Ok(None) }
+
+            return Ok(Some((key.freeze(), value.freeze(), ptr)));
+        }
+    }
+}
+
+impl DB {
+    /// Reclaim the value log segment with the most garbage. Its live values are appended to
+    /// the head of the log and the tree is repointed at them, in one commit. Returns `None`
+    /// if no segment holds garbage.
+    ///
+    /// Readers see the same data before and after, so scans stay valid. Pages are never
+    /// reused, but the segment's disk space is given back by punching a hole over it, on a
+    /// later call once no snapshot, scan, backup, archived version or version slot can still
+    /// read it. Holes still pending when the database is closed are never punched.
+    pub async fn collect_value_log(&self) -> Result<Option<Compaction>, WriteError> {
+        self.check_writable()?;
+        let _writer = self.writer.lock().await;
+
+        // with the buffer applied, the tree alone says which records are live
+        let version = self.apply_write_buffer(*self.version.lock()).await?;
+        self.discard_unreadable(version).await?;
+        let segment = match self.value_log.lock().gc_candidate() {
+            Some(segment) => segment,
+            None => return Ok(None)
+        };
+
+        let (max_depth, format, durability, observer) = {
+            let options = self.options.lock();
+            (options.max_tree_depth, NodeFormat::new(&options), options.durability, options.observer.clone())
+        };
+        let txn = Transaction::new(version.tx + 1, self.store.clone(), self.write_back.clone(), durability, version.page_count);
+
+        let collected = async {
+            let (live, read_bytes, read_to) = live_records(&*self.store, &self.cache, version.tree_root, segment, max_depth).await?;
+
+            let mut relocated_bytes = 0;
+            let writes: Vec<Write> = {
+                let mut value_log = self.value_log.lock();
+                let mut moved = BTreeMap::new();
+                for (key, value, ptr) in live {
+                    relocated_bytes += ptr.record_len(key.len());
+                    let ptr = value_log.append_stored(&txn, &key, &value, ptr.codec);
+                    moved.insert(key, Some(LeafValue::Logged(ptr)));
+                }
+                moved.into_iter().collect()
+            };
+            // the old pointers are released as they're replaced, into the segment being freed
+            let tree_root = tree::apply(&self.cache, &txn, version.tree_root, &writes, max_depth, format).await?;
+
+            let mut value_log = self.value_log.lock();
+            value_log.free_segment(segment);
+            value_log.seal(&txn);
+            let segments = value_log.write_table(&txn, version.segments);
+            Ok::<_, WriteError>((tree_root, segments, relocated_bytes, read_bytes, read_to))
+        }.await;
+        let (tree_root, segments, relocated_bytes, read_bytes, read_to) = match collected {
+            Ok(collected) => collected,
+            Err(err) => {
+                self.value_log.lock().rolled_back();
+                return Err(err)
+            }
+        };
+
+        let version = VersionHeader {
+            tx: txn.idx(),
+            tree_root,
+            page_count: txn.page_count(),
+            segments,
+            ..version
+        };
+        if let Err(err) = txn.commit(version).await {
+            self.value_log.lock().rolled_back();
+            return Err(err.into())
+        }
+        {
+            let mut value_log = self.value_log.lock();
+            value_log.committed(&txn);
+            let start = segment * SEGMENT_PAGES;
+            value_log.discard_after(version.tx, start, read_to.saturating_sub(start));
+        }
+
+        // the data is unchanged, so the generation is too
+        {
+            let _buffer = self.write_buffer.lock();
+            *self.version.lock() = version;
+        }
+        observer.on_commit(version.tx);
+
+        let compaction = Compaction { tx: version.tx, segment, relocated_bytes, reclaimed_bytes: read_bytes - relocated_bytes };
+        observer.on_compaction(&compaction);
+        Ok(Some(compaction))
+    }
+
+    /// Punch holes over the segments GC emptied that nothing can read any more. The caller
+    /// must hold `writer`, and `version` must be the latest.
+    async fn discard_unreadable(&self, version: VersionHeader) -> io::Result<()> {
+        let archive_commits = self.options.lock().archive_commits as u64;
+        // the other version slot holds the version before, which opening falls back to if
+        // the latest is torn
+        let kept = version.tx.saturating_sub(archive_commits.max(1));
+        let readable = self.snapshots.oldest().map_or(kept, |oldest| oldest.min(kept));
+
+        let due = self.value_log.lock().take_discards(readable);
+        if due.is_empty() { return Ok(()) }
+
+        // the commits that emptied them must be durable before the pages read as zeroes
+        let synced = self.store.sync(Durability::SyncData).await;
+        let discarded = synced.and_then(|()| due.iter().try_for_each(|discard| self.store.discard(discard.start, discard.pages)));
+        if discarded.is_err() {
+            let mut value_log = self.value_log.lock();
+            for discard in due { value_log.discard_after(discard.tx, discard.start, discard.pages) }
+        }
+        discarded
+    }
+}
@@ -0,0 +1,253 @@
+//! Checking a version of the database for damage: every reachable page is read from the store
+//! and checked against its checksum, and every tree against the invariants writes keep.
+//!
+//! Pages are only ever appended, never reused, so there's no free list to check against;
+//! instead, no page may be reachable twice or lie past the end of the version.
+
+use std::convert::TryInto;
+use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt};
+
+use super::{DB, PageIndex, TransactionIdx};
+use super::branch::{Branch, Stats};
+use super::descent::{CrossLink, PageWalk};
+use super::leaf::{self, LeafValue};
+use super::overflow;
+use super::page::{self, PageContent, PageType, PAGE_SIZE};
+use super::tree::leaf_stats;
+use super::value_log::TableAt;
+
+/// Something wrong with the database
+#[derive(Debug, Clone)]
+pub enum VerifyProblem {
+    /// The page couldn't be read from the store
+    Unreadable { page: PageIndex, error: String },
+    BadChecksum { page: PageIndex },
+    /// The page isn't the kind of page that should be there, or doesn't decode
+    Malformed { page: PageIndex },
+    /// A leaf's keys, or a branch's separators, aren't in ascending order
+    Unsorted { page: PageIndex },
+    /// A key or separator lies outside the range its parent routes to the page
+    OutOfRange { page: PageIndex, key: Bytes },
+    /// A branch records the wrong size for a child's subtree
+    WrongStats { page: PageIndex, child: usize, recorded: u64, actual: u64 },
+    /// The page is reachable from two places
+    CrossLink(CrossLink),
+    /// A page or value lies past the end of the version
+    PastEnd { page: PageIndex, page_count: u64 }
+}
+
+/// What `DB::verify` found
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    /// The version checked
+    pub tx: TransactionIdx,
+    pub pages_checked: u64,
+    /// Entries in the main tree
+    pub entries: u64,
+    pub problems: Vec<VerifyProblem>
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+struct Verifier<'db> {
+    db: &'db DB,
+    page_count: u64,
+    walk: PageWalk,
+    problems: Vec<VerifyProblem>
+}
+
+impl<'db> Verifier<'db> {
+    /// Record reaching `idx` from `parent`, returning whether to read it
+    fn reach(&mut self, idx: PageIndex, parent: Option<PageIndex>) -> bool {
+        if idx >= self.page_count {
+            self.problems.push(VerifyProblem::PastEnd { page: idx, page_count: self.page_count });
+            return false
+        }
+        match self.walk.visit(idx, parent) {
+            Ok(()) => true,
+            Err(cross_link) => {
+                self.problems.push(VerifyProblem::CrossLink(cross_link));
+                false
+            }
+        }
+    }
+
+    /// Read one page straight from the store, checking its checksum
+    async fn read(&mut self, idx: PageIndex) -> Option<PageContent> {
+        let raw = match self.db.store.get_chunk(idx, 0).await {
+            Ok(raw) => raw,
+            Err(err) => {
+                self.problems.push(VerifyProblem::Unreadable { page: idx, error: err.to_string() });
+                return None
+            }
+        };
+        if !page::checksum_ok(&raw[..PAGE_SIZE.min(raw.len())]) {
+            self.problems.push(VerifyProblem::BadChecksum { page: idx });
+            return None
+        }
+        let page = PageContent::from_bytes(&raw[..PAGE_SIZE]);
+        if page.is_none() { self.problems.push(VerifyProblem::Malformed { page: idx }) }
+        page
+    }
+
+    /// Check the subtree at `idx`, which may only hold keys from `low` up to `high`, returning
+    /// its size, or `None` if it's damaged
+    fn subtree(&mut self, idx: PageIndex, parent: Option<PageIndex>, low: Option<Bytes>, high: Option<Bytes>) -> BoxFuture<'_, Option<Stats>> {
+        async move {
+            if !self.reach(idx, parent) { return None }
+            let page = self.read(idx).await?;
+
+            let in_range = |key: &Bytes| low.as_ref().map_or(true, |low| key >= low) && high.as_ref().map_or(true, |high| key < high);
+
+            match page.page_type {
+                PageType::Branch => {
+                    let branch = match Branch::decode(&page) {
+                        Some(branch) => branch,
+                        None => {
+                            self.problems.push(VerifyProblem::Malformed { page: idx });
+                            return None
+                        }
+                    };
+
+                    if branch.separators.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                        self.problems.push(VerifyProblem::Unsorted { page: idx });
+                    }
+                    if let Some((key, _)) = branch.separators.iter().find(|(key, _)| !in_range(key)) {
+                        self.problems.push(VerifyProblem::OutOfRange { page: idx, key: key.clone() });
+                    }
+
+                    let mut total = Some(Stats::default());
+                    for i in 0..=branch.separators.len() {
+                        let child_low = if i == 0 { low.clone() } else { Some(branch.separators[i - 1].0.clone()) };
+                        let child_high = branch.separators.get(i).map(|(key, _)| key.clone()).or_else(|| high.clone());
+
+                        let actual = self.subtree(branch.child(i), Some(idx), child_low, child_high).await;
+                        match (actual, branch.stats.get(i)) {
+                            (Some(actual), Some(recorded)) if actual.entries != recorded.entries => {
+                                self.problems.push(VerifyProblem::WrongStats { page: idx, child: i, recorded: recorded.entries, actual: actual.entries });
+                            },
+                            _ => {}
+                        }
+                        total = match (total, actual) {
+                            (Some(total), Some(actual)) => Some(total.add(actual)),
+                            _ => None
+                        };
+                    }
+                    total
+                },
+                PageType::Leaf => {
+                    let entries = match leaf::decode(&page) {
+                        Some(entries) => entries,
+                        None => {
+                            self.problems.push(VerifyProblem::Malformed { page: idx });
+                            return None
+                        }
+                    };
+
+                    if entries.windows(2).any(|pair| pair[0].key >= pair[1].key) {
+                        self.problems.push(VerifyProblem::Unsorted { page: idx });
+                    }
+                    if let Some(entry) = entries.iter().find(|entry| !in_range(&entry.key)) {
+                        self.problems.push(VerifyProblem::OutOfRange { page: idx, key: entry.key.clone() });
+                    }
+                    for entry in &entries {
+                        if let LeafValue::Logged(ptr) = &entry.value {
+                            if ptr.page >= self.page_count {
+                                self.problems.push(VerifyProblem::PastEnd { page: ptr.page, page_count: self.page_count });
+                            }
+                        }
+                    }
+                    Some(leaf_stats(&entries))
+                },
+                _ => {
+                    self.problems.push(VerifyProblem::Malformed { page: idx });
+                    None
+                }
+            }
+        }.boxed()
+    }
+
+    /// Check an overflow chain, returning its data if it's intact
+    async fn chain(&mut self, idx: PageIndex) -> Option<Bytes> {
+        if !self.reach(idx, None) { return None }
+        let raw = match self.db.store.get_chunk(idx, 0).await {
+            Ok(raw) => raw,
+            Err(err) => {
+                self.problems.push(VerifyProblem::Unreadable { page: idx, error: err.to_string() });
+                return None
+            }
+        };
+
+        let mut intact = true;
+        for (i, page) in raw.chunks(PAGE_SIZE).enumerate() {
+            let page_idx = idx + i as u64;
+            if i > 0 && !self.reach(page_idx, Some(page_idx - 1)) { intact = false }
+            if !page::checksum_ok(page) {
+                self.problems.push(VerifyProblem::BadChecksum { page: page_idx });
+                intact = false;
+            }
+        }
+        if !intact { return None }
+
+        match overflow::read_chain(&self.db.cache, idx, 0).await {
+            Ok(data) => Some(data),
+            Err(err) => {
+                self.problems.push(VerifyProblem::Unreadable { page: idx, error: err.to_string() });
+                None
+            }
+        }
+    }
+}
+
+impl DB {
+    /// Check the latest version for damage, like fsck: every reachable page is read from the
+    /// store and checked against its checksum, the keys in every leaf and branch must be in
+    /// order and within the range their parent routes to them, the subtree sizes in branches
+    /// must match, and no page may be reachable twice. Runs online against the version
+    /// current when it starts, which later commits never modify. Reads every page, so it's as
+    /// slow as a backup. Problems are reported, not returned as errors, so one check finds them all.
+    pub async fn verify(&self) -> VerifyReport {
+        let version = *self.version.lock();
+        let mut verifier = Verifier { db: self, page_count: version.page_count, walk: PageWalk::new(), problems: vec![] };
+
+        let entries = match version.tree_root {
+            Some(root) => verifier.subtree(root, None, None, None).await.map_or(0, |stats| stats.entries),
+            None => 0
+        };
+        for root in [version.expiries, version.indexes, version.tokens].iter().flatten() {
+            verifier.subtree(*root, None, None, None).await;
+        }
+
+        let segments = match version.segments {
+            TableAt::Chain(idx) => Some(idx),
+            TableAt::Missing | TableAt::Empty => None
+        };
+        for chain in [version.dictionaries, version.archive, segments].iter().flatten() {
+            verifier.chain(*chain).await;
+        }
+
+        // each journal record begins with the first page of the record before it
+        let mut next = version.journal;
+        while let Some(idx) = next {
+            next = match verifier.chain(idx).await {
+                Some(data) if data.len() >= 8 => match u64::from_le_bytes(data[..8].try_into().unwrap()) {
+                    u64::MAX => None,
+                    prev => Some(prev)
+                },
+                _ => None
+            };
+        }
+
+        VerifyReport {
+            tx: version.tx,
+            pages_checked: verifier.walk.len() as u64,
+            entries,
+            problems: verifier.problems
+        }
+    }
+}
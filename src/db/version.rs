@@ -1,7 +1,208 @@
+use std::io;
 
+use super::{PageStore, Durability, PageContent, PageIndex, RetrieveError, TransactionIdx};
+use super::page::{self, PageType};
+use super::settings::{Settings, LIMITS_LEN, SETTINGS_LEN};
+use super::value_log::TableAt;
 
+/// Every commit writes its root page to the slot the previous commit didn't use,
+/// so a torn root write always leaves the previous version intact.
+pub(crate) const VERSION_SLOTS: [PageIndex; 2] = [1, 2];
 
-struct VersionHeader {
-    tx: u64
+/// The first page after the header and version slots
+pub(crate) const FIRST_DATA_PAGE: PageIndex = 1 + VERSION_SLOTS.len() as u64;
+
+const NO_PAGE: u64 = u64::MAX;
+
+const DICTIONARIES_AT: usize = 24 + SETTINGS_LEN;
+const JOURNAL_AT: usize = DICTIONARIES_AT + 8;
+const TOKENS_AT: usize = JOURNAL_AT + 8;
+const ROTATION_AT: usize = TOKENS_AT + 8;
+const ARCHIVE_AT: usize = ROTATION_AT + 32;
+const EXPIRIES_AT: usize = ARCHIVE_AT + 8;
+const INDEXES_AT: usize = EXPIRIES_AT + 8;
+const LIMITS_AT: usize = INDEXES_AT + 8;
+const SEGMENTS_AT: usize = LIMITS_AT + LIMITS_LEN;
+
+/// Progress of re-encrypting pages sealed with the previous key, which sweeps through the
+/// pages that existed when the rotation began
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KeyRotation {
+    /// The next page to re-encrypt
+    pub cursor: PageIndex,
+    /// The page count when the rotation began. Later pages were sealed with the new key.
+    pub end: PageIndex,
+    /// A run of pages holding copies of the batch being rewritten in place
+    pub buffer: PageIndex,
+    /// Pages from `cursor` with copies in `buffer` that may be half-written in place
+    pub pending: u64
+}
+
+/// The content of a root page: one committed version of the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct VersionHeader {
+    pub tx: TransactionIdx,
+    /// Root page of the tree, or `None` while the database is empty
+    pub tree_root: Option<PageIndex>,
+    /// Pages allocated in the file, including the header and version slots
+    pub page_count: u64,
+    /// Options adjusted at runtime, which are committed like any other change
+    pub settings: Settings,
+    /// Overflow chain holding the trained compression dictionaries, if any
+    pub dictionaries: Option<PageIndex>,
+    /// Latest record of the write buffer's journal, if any commits haven't been applied to the tree
+    pub journal: Option<PageIndex>,
+    /// Root of the tree of applied batch tokens, each mapped to the transaction that applied it
+    pub tokens: Option<PageIndex>,
+    /// A key rotation in progress, in an encrypted database
+    pub rotation: Option<KeyRotation>,
+    /// The commit archive, if commits are archived
+    pub archive: Option<PageIndex>,
+    /// The root of the tree of expiry times, if any key expires
+    pub expiries: Option<PageIndex>,
+    /// The root of the tree of secondary indexes, if any
+    pub indexes: Option<PageIndex>,
+    /// The value log's segment table
+    pub segments: TableAt
 }
 
+impl VersionHeader {
+    /// The version of a newly created database
+    pub fn initial() -> VersionHeader {
+        VersionHeader {
+            tx: 0,
+            tree_root: None,
+            page_count: FIRST_DATA_PAGE,
+            settings: Settings::default(),
+            dictionaries: None,
+            journal: None,
+            tokens: None,
+            rotation: None,
+            archive: None,
+            expiries: None,
+            indexes: None,
+            segments: TableAt::Empty
+        }
+    }
+
+    pub fn slot(&self) -> PageIndex {
+        VERSION_SLOTS[(self.tx % VERSION_SLOTS.len() as u64) as usize]
+    }
+
+    pub fn encode(&self) -> PageContent {
+        let mut page = PageContent::new(PageType::Root);
+        page.set_lsn(self.tx);
+
+        page.data[0..8].copy_from_slice(&self.tx.to_le_bytes());
+        page.data[8..16].copy_from_slice(&self.tree_root.unwrap_or(NO_PAGE).to_le_bytes());
+        page.data[16..24].copy_from_slice(&self.page_count.to_le_bytes());
+        self.settings.encode(&mut page.data[24..24 + SETTINGS_LEN]);
+        page.data[DICTIONARIES_AT..DICTIONARIES_AT + 8].copy_from_slice(&self.dictionaries.unwrap_or(NO_PAGE).to_le_bytes());
+        page.data[JOURNAL_AT..JOURNAL_AT + 8].copy_from_slice(&self.journal.unwrap_or(NO_PAGE).to_le_bytes());
+        page.data[TOKENS_AT..TOKENS_AT + 8].copy_from_slice(&self.tokens.unwrap_or(NO_PAGE).to_le_bytes());
+
+        let rotation = self.rotation.map_or([NO_PAGE, 0, 0, 0], |rotation| [rotation.cursor, rotation.end, rotation.buffer, rotation.pending]);
+        for (i, field) in rotation.iter().enumerate() {
+            page.data[ROTATION_AT + i * 8..ROTATION_AT + i * 8 + 8].copy_from_slice(&field.to_le_bytes());
+        }
+        page.data[ARCHIVE_AT..ARCHIVE_AT + 8].copy_from_slice(&self.archive.unwrap_or(NO_PAGE).to_le_bytes());
+        page.data[EXPIRIES_AT..EXPIRIES_AT + 8].copy_from_slice(&self.expiries.unwrap_or(NO_PAGE).to_le_bytes());
+        page.data[INDEXES_AT..INDEXES_AT + 8].copy_from_slice(&self.indexes.unwrap_or(NO_PAGE).to_le_bytes());
+        self.settings.encode_limits(&mut page.data[LIMITS_AT..LIMITS_AT + LIMITS_LEN]);
+        // zero is the header page, which is what files written before the table was kept hold
+        let segments = match self.segments { TableAt::Missing => 0, TableAt::Empty => NO_PAGE, TableAt::Chain(idx) => idx };
+        page.data[SEGMENTS_AT..SEGMENTS_AT + 8].copy_from_slice(&segments.to_le_bytes());
+
+        page.update_checksum();
+        page
+    }
+
+    /// Decode a root page, or `None` if it is torn or was never written
+    pub fn decode(page: &PageContent) -> Option<VersionHeader> {
+        if !page::checksum_ok(page.as_slice()) { return None }
+        if !matches!(page.page_type, PageType::Root) { return None }
+
+        let field = |at: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&page.data[at..at + 8]);
+            u64::from_le_bytes(bytes)
+        };
+
+        Some(VersionHeader {
+            tx: field(0),
+            tree_root: match field(8) {
+                NO_PAGE => None,
+                idx => Some(idx)
+            },
+            page_count: field(16),
+            settings: Settings::decode(&page.data[24..24 + SETTINGS_LEN], &page.data[LIMITS_AT..LIMITS_AT + LIMITS_LEN]),
+            dictionaries: match field(DICTIONARIES_AT) {
+                NO_PAGE => None,
+                idx => Some(idx)
+            },
+            journal: match field(JOURNAL_AT) {
+                NO_PAGE => None,
+                idx => Some(idx)
+            },
+            tokens: match field(TOKENS_AT) {
+                NO_PAGE => None,
+                idx => Some(idx)
+            },
+            rotation: match field(ROTATION_AT) {
+                NO_PAGE => None,
+                cursor => Some(KeyRotation {
+                    cursor,
+                    end: field(ROTATION_AT + 8),
+                    buffer: field(ROTATION_AT + 16),
+                    pending: field(ROTATION_AT + 24)
+                })
+            },
+            archive: match field(ARCHIVE_AT) {
+                NO_PAGE => None,
+                idx => Some(idx)
+            },
+            expiries: match field(EXPIRIES_AT) {
+                NO_PAGE => None,
+                idx => Some(idx)
+            },
+            indexes: match field(INDEXES_AT) {
+                NO_PAGE => None,
+                idx => Some(idx)
+            },
+            segments: match field(SEGMENTS_AT) {
+                0 => TableAt::Missing,
+                NO_PAGE => TableAt::Empty,
+                idx => TableAt::Chain(idx)
+            }
+        })
+    }
+
+    /// Read both slots and pick the newest valid version, if either is valid
+    pub async fn load_latest(store: &dyn PageStore) -> Result<Option<VersionHeader>, RetrieveError> {
+        let mut latest: Option<VersionHeader> = None;
+
+        for &slot in VERSION_SLOTS.iter() {
+            let version = match store.read_page(slot).await {
+                Ok(page) => VersionHeader::decode(&page),
+                // an encrypted store reports a torn page as a bad checksum
+                Err(RetrieveError::OutOfPages) | Err(RetrieveError::BadChecksum) => None,
+                Err(err) => return Err(err)
+            };
+
+            if let Some(version) = version {
+                if latest.map_or(true, |latest| version.tx > latest.tx) {
+                    latest = Some(version);
+                }
+            }
+        }
+
+        Ok(latest)
+    }
+
+    /// Write this version to its slot and sync, making it the latest version
+    pub async fn write(&self, store: &dyn PageStore, durability: Durability) -> io::Result<()> {
+        let page = self.encode();
+        store.write_page(self.slot(), &page).await?;
+        store.sync(durability).await
+    }
+}
@@ -0,0 +1,65 @@
+//! Subscriptions to the changes committed under key prefixes
+
+use std::sync::Arc;
+use bytes::Bytes;
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::stream::{BoxStream, StreamExt};
+
+use super::{DB, Observer, TransactionIdx};
+use super::tree::Write;
+
+/// A committed change to a watched key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// `key` was set to `value`, whether or not it had a value before
+    Put { tx: TransactionIdx, key: Bytes, value: Bytes },
+    Delete { tx: TransactionIdx, key: Bytes }
+}
+
+pub(crate) struct Watcher {
+    prefix: Bytes,
+    events: UnboundedSender<Event>
+}
+
+impl DB {
+    /// Stream every change committed to keys starting with `prefix` from now on, in
+    /// transaction order, and in key order within a transaction. Deletes of keys that had no
+    /// value are streamed too. Bulk loads aren't streamed. Dropping the stream unsubscribes.
+    pub fn watch_prefix(&self, prefix: Bytes) -> BoxStream<'static, Event> {
+        let (events, stream) = mpsc::unbounded();
+        self.watchers.lock().push(Watcher { prefix, events });
+        stream.boxed()
+    }
+
+    /// Send the changes committed by `tx` to the watchers of their keys. Commits call this
+    /// while still holding `writer`, so watchers see transactions in order.
+    pub(super) async fn notify_watchers(&self, tx: TransactionIdx, writes: &[Write], observer: &Arc<dyn Observer>) {
+        let watched: Vec<Write> = {
+            let mut watchers = self.watchers.lock();
+            watchers.retain(|watcher| !watcher.events.is_closed());
+            writes.iter()
+                .filter(|(key, _)| watchers.iter().any(|watcher| key.starts_with(&watcher.prefix)))
+                .cloned()
+                .collect()
+        };
+        if watched.is_empty() { return }
+
+        let dictionaries = self.dictionaries();
+        for (key, value) in watched {
+            let event = match value {
+                Some(value) => match value.read(&self.cache, &dictionaries).await {
+                    Ok(value) => Event::Put { tx, key: key.clone(), value },
+                    Err(err) => {
+                        observer.on_error(&err);
+                        continue
+                    }
+                },
+                None => Event::Delete { tx, key: key.clone() }
+            };
+
+            for watcher in self.watchers.lock().iter().filter(|watcher| key.starts_with(&watcher.prefix)) {
+                let _ = watcher.events.unbounded_send(event.clone());
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use futures::channel::oneshot;
+use futures::future::join_all;
+use parking_lot::Mutex;
+
+use super::{PageStore, PageContent, PageIndex};
+
+/// Identifies the transaction that queued a write, whose flushes report its failures
+pub(crate) type Owner = u64;
+
+enum Job {
+    Write(Owner, PageIndex, Arc<PageContent>),
+    /// Reply once every write queued before this one has completed, with the first of the
+    /// owner's writes to have failed
+    Flush(Owner, oneshot::Sender<io::Result<()>>)
+}
+
+/// Writes dirty pages in the background, so page writes are already in flight
+/// (or finished) by the time a transaction commits.
+///
+/// A failed write stays failed: every later flush by the transaction that queued it reports
+/// it, so reading mid-transaction can't clear the error its commit must see. It's forgotten
+/// once the transaction ends.
+pub(crate) struct WriteBack {
+    jobs: Mutex<mpsc::Sender<Job>>,
+    /// Page writes queued and not yet completed
+    dirty: Arc<AtomicUsize>,
+    /// The first failed write of each transaction with one
+    failed: Arc<Mutex<HashMap<Owner, io::Error>>>,
+    /// The latest content queued for each page not yet written
+    queued: Arc<Mutex<HashMap<PageIndex, Arc<PageContent>>>>,
+    next_owner: AtomicU64
+}
+
+impl WriteBack {
+    pub fn new(store: Arc<dyn PageStore>) -> io::Result<WriteBack> {
+        let (sender, receiver) = mpsc::channel();
+        let dirty = Arc::new(AtomicUsize::new(0));
+
+        let failed = Arc::new(Mutex::new(HashMap::new()));
+        let queued = Arc::new(Mutex::new(HashMap::new()));
+
+        let (written, errors, pending) = (dirty.clone(), failed.clone(), queued.clone());
+        thread::Builder::new()
+            .name("bssdb-write-back".into())
+            .spawn(move || run(store, receiver, written, errors, pending))?;
+
+        Ok(WriteBack { jobs: Mutex::new(sender), dirty, failed, queued, next_owner: AtomicU64::new(0) })
+    }
+
+    /// A new owner for a transaction's writes
+    pub fn begin(&self) -> Owner {
+        self.next_owner.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Forget the failures of a transaction that committed or was rolled back
+    pub fn end(&self, owner: Owner) {
+        self.failed.lock().remove(&owner);
+    }
+
+    /// Queue a snapshot of a page to be written. A later write of the same page supersedes it.
+    pub fn enqueue(&self, owner: Owner, idx: PageIndex, mut content: Box<PageContent>) {
+        content.update_checksum();
+        let content: Arc<PageContent> = Arc::from(content);
+        self.dirty.fetch_add(1, Ordering::Relaxed);
+        self.queued.lock().insert(idx, content.clone());
+
+        // the writer only stops once we're dropped
+        let _ = self.jobs.lock().send(Job::Write(owner, idx, content));
+    }
+
+    /// Wait for every page queued so far to be written. Fails if any write `owner` queued
+    /// has, now or before.
+    pub async fn flush(&self, owner: Owner) -> io::Result<()> {
+        let (reply, done) = oneshot::channel();
+        self.jobs.lock().send(Job::Flush(owner, reply))
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "write-back thread stopped"))?;
+
+        done.await.map_err(|_| io::Error::new(io::ErrorKind::Other, "write-back thread stopped"))?
+    }
+
+    /// The content queued for a page, until it's written. Once it isn't, the store has it.
+    pub fn queued(&self, idx: PageIndex) -> Option<Arc<PageContent>> {
+        self.queued.lock().get(&idx).cloned()
+    }
+
+    /// Page writes queued and not yet completed, counting each write of a page
+    pub fn dirty_pages(&self) -> usize {
+        self.dirty.load(Ordering::Relaxed)
+    }
+}
+
+/// A copy of a write error to report, keeping the OS error code that tells a full disk apart
+fn copy_error(err: &io::Error) -> io::Error {
+    match err.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::new(err.kind(), err.to_string())
+    }
+}
+
+fn run(
+    store: Arc<dyn PageStore>,
+    jobs: mpsc::Receiver<Job>,
+    dirty: Arc<AtomicUsize>,
+    failed: Arc<Mutex<HashMap<Owner, io::Error>>>,
+    queued: Arc<Mutex<HashMap<PageIndex, Arc<PageContent>>>>
+) {
+    while let Ok(job) = jobs.recv() {
+        let mut batch = HashMap::new();
+        let mut queued_writes = 0;
+        let mut flush = None;
+
+        // gather everything already queued so the writes are submitted together
+        let mut next = Some(job);
+        while let Some(job) = next.take() {
+            match job {
+                Job::Write(owner, idx, content) => {
+                    batch.insert(idx, (owner, content));
+                    queued_writes += 1;
+                },
+                Job::Flush(owner, reply) => {
+                    flush = Some((owner, reply));
+                    break;
+                }
+            }
+            next = jobs.try_recv().ok();
+        }
+
+        if !batch.is_empty() {
+            let writes = batch.iter().map(|(idx, (_, content))| store.write_page(*idx, content));
+            let results = futures::executor::block_on(join_all(writes));
+
+            let mut failed = failed.lock();
+            for ((_, (owner, _)), result) in batch.iter().zip(results) {
+                if let Err(err) = result { failed.entry(*owner).or_insert(err); }
+            }
+            drop(failed);
+
+            // unless the page was queued again since
+            let mut pending = queued.lock();
+            for (idx, (_, content)) in &batch {
+                if pending.get(idx).map_or(false, |latest| Arc::ptr_eq(latest, content)) { pending.remove(idx); }
+            }
+            dirty.fetch_sub(queued_writes, Ordering::Relaxed);
+        }
+
+        if let Some((owner, reply)) = flush {
+            let _ = reply.send(match failed.lock().get(&owner) {
+                Some(err) => Err(copy_error(err)),
+                None => Ok(())
+            });
+        }
+    }
+}
@@ -0,0 +1,684 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::{convert::TryInto, io, sync::Arc};
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use bytes::Bytes;
+use futures::io::{AsyncRead, AsyncReadExt};
+use futures::lock::MutexGuard as AsyncMutexGuard;
+use futures::future;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use thiserror::Error;
+
+use super::{DB, OperationKind, PageIndex, RetrieveError, TransactionIdx};
+use super::archive;
+use super::compression::{self, Compression};
+use super::leaf::{LeafValue, MAX_INLINE_THRESHOLD};
+use super::memtable;
+use super::metrics::ActiveTransaction;
+use super::overflow;
+use super::page::PAGE_SIZE;
+use super::range::{self, Scan};
+use super::spill::{Sorted, Writes};
+use super::transaction::Transaction;
+use super::tree::{self, NodeFormat, Write};
+use super::ttl;
+use super::value_log::{self, TableAt, ValuePointer};
+use super::version::VersionHeader;
+
+/// Bytes read at a time by `put_reader`
+const VALUE_CHUNK: usize = 64 * 1024;
+
+/// Changes applied to the tree at a time when committing a spilled transaction
+const SPILLED_CHUNK: usize = 64 * 1024;
+
+#[derive(Error, Debug, Clone)]
+pub enum WriteError {
+    #[error("{0}")]
+    Io(#[source] Arc<io::Error>),
+    #[error("{0}")]
+    Retrieve(#[source] #[from] RetrieveError),
+    #[error("Entry is too large to fit in a page")]
+    EntryTooLarge,
+    #[error("Key of {len} bytes is longer than the maximum of {max}")]
+    KeyTooLarge { len: usize, max: usize },
+    #[error("Value of {len} bytes is longer than the maximum of {max}")]
+    ValueTooLarge { len: u64, max: u64 },
+    #[error("No merge operator is registered")]
+    NoMergeOperator,
+    #[error("Bulk loads need an empty database")]
+    NotEmpty,
+    #[error("Bulk loaded keys must be in strictly increasing order")]
+    Unsorted,
+    /// Dirty pages or buffered writes are past the `Backpressure` stop threshold
+    #[error("Writes are stalled until pending writes drain")]
+    WriteStall,
+    /// The database reached its `max_size`, or the disk is full. The commit is rolled back,
+    /// and the database stays readable.
+    #[error("Database is full: {0}")]
+    Full(#[source] Arc<io::Error>)
+}
+
+impl From<Arc<io::Error>> for WriteError {
+    fn from(err: Arc<io::Error>) -> Self {
+        match err.raw_os_error() {
+            Some(libc::ENOSPC) | Some(libc::EDQUOT) => WriteError::Full(err),
+            _ => WriteError::Io(err)
+        }
+    }
+}
+
+impl From<io::Error> for WriteError {
+    fn from(err: io::Error) -> Self {
+        Arc::new(err).into()
+    }
+}
+
+/// What committing a transaction would write, as estimated by `WriteTransaction::plan`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitPlan {
+    /// Tree, index and journal pages the commit would write
+    pub pages: u64,
+    /// Pages that would split, counting each page added by a split
+    pub splits: u64,
+    /// Bytes of those pages, plus the values this transaction logged
+    pub bytes: u64
+}
+
+/// Buffers changes to the database, and applies them all at once on commit. Only one write
+/// transaction is open at a time. Dropping it without committing rolls it back.
+pub struct WriteTransaction<'db> {
+    db: &'db DB,
+    _writer: AsyncMutexGuard<'db, ()>,
+    _active: ActiveTransaction<'db>,
+    pub(super) txn: Transaction,
+    /// The version this transaction builds on, and commits with changes
+    pub(super) version: VersionHeader,
+    /// The latest change to each key
+    writes: Writes,
+    /// When each key put with a TTL expires
+    pub(super) ttls: BTreeMap<Bytes, u64>,
+    /// Merge operands queued since each key's latest change, folded in on commit
+    operands: BTreeMap<Bytes, Vec<Bytes>>,
+    /// Batch tokens to record as applied by this transaction
+    tokens: BTreeSet<Bytes>,
+    /// The database's generation as of the version this transaction builds on
+    generation: u64,
+    /// Ranges cleared by `delete_range`. Changes made since are in `writes`.
+    cleared: Vec<(Bound<Bytes>, Bound<Bytes>)>,
+    /// Apply to the tree on commit even with a write buffer
+    pub(super) bypass_buffer: bool,
+    committed: bool
+}
+
+impl DB {
+    /// Start a write transaction, waiting for any open one to finish
+    pub async fn write(&self) -> io::Result<WriteTransaction<'_>> {
+        self.check_writable()?;
+        self.begin_write().await
+    }
+
+    /// Begin a write transaction without checking the database accepts writes from callers
+    pub(super) async fn begin_write(&self) -> io::Result<WriteTransaction<'_>> {
+        let active = ActiveTransaction::begin(&self.metrics.active_transactions);
+        let writer = self.writer.lock().await;
+
+        let version = *self.version.lock();
+        let generation = self.generation();
+        let (durability, writes) = {
+            let options = self.options.lock();
+            let spill_dir = options.spill_dir.clone().unwrap_or_else(std::env::temp_dir);
+            (options.durability, Writes::new(options.max_transaction_memory, spill_dir))
+        };
+        let txn = Transaction::new(version.tx + 1, self.store.clone(), self.write_back.clone(), durability, version.page_count);
+
+        Ok(WriteTransaction {
+            db: self,
+            _writer: writer,
+            _active: active,
+            txn,
+            version,
+            writes,
+            operands: BTreeMap::new(),
+            ttls: BTreeMap::new(),
+            tokens: BTreeSet::new(),
+            generation,
+            cleared: vec![],
+            bypass_buffer: false,
+            committed: false
+        })
+    }
+}
+
+impl<'db> WriteTransaction<'db> {
+    /// Fail with `KeyTooLarge` if `key` is longer than the configured maximum
+    pub(super) fn check_key(&self, key: &[u8]) -> Result<(), WriteError> {
+        let max = self.db.options.lock().max_key_len;
+        if key.len() > max { return Err(WriteError::KeyTooLarge { len: key.len(), max }) }
+        Ok(())
+    }
+
+    fn check_value(&self, len: u64) -> Result<(), WriteError> {
+        let max = self.db.options.lock().max_value_len;
+        if len > max { return Err(WriteError::ValueTooLarge { len, max }) }
+        Ok(())
+    }
+
+    pub fn put(&mut self, key: Bytes, value: Bytes) -> Result<(), WriteError> {
+        self.db.check_stall()?;
+        self.check_key(&key)?;
+        self.check_value(value.len() as u64)?;
+
+        let (threshold, compression) = {
+            let options = self.db.options.lock();
+            (options.value_inline_threshold, options.compression)
+        };
+        let value = LeafValue::store(&self.txn, &mut self.db.value_log.lock(), &key, value, threshold, compression);
+        self.operands.remove(&key);
+        self.ttls.remove(&key);
+        self.record(key, Some(value))
+    }
+
+    /// Queue `operand` to be folded into the value of `key` by the registered merge operator,
+    /// without reading the value now. Reads in this transaction see the folded value.
+    pub fn merge(&mut self, key: Bytes, operand: Bytes) -> Result<(), WriteError> {
+        if !self.db.has_merge_operator() { return Err(WriteError::NoMergeOperator) }
+        self.db.check_stall()?;
+        self.check_key(&key)?;
+        self.check_value(operand.len() as u64)?;
+
+        self.operands.entry(key).or_default().push(operand);
+        Ok(())
+    }
+
+    /// Put a value read from `reader`, appending it to the value log as it arrives instead of
+    /// holding it in memory. `len_hint` is the expected length: space is reserved for it, so a
+    /// value much longer than the hint may fail with `InvalidInput`. A hint over the maximum
+    /// value length fails with `ValueTooLarge`. Streamed values are stored uncompressed.
+    pub async fn put_reader<R: AsyncRead + Unpin>(&mut self, key: Bytes, mut reader: R, len_hint: u64) -> Result<(), WriteError> {
+        self.db.check_stall()?;
+        self.check_key(&key)?;
+        self.check_value(len_hint)?;
+
+        let threshold = self.db.options.lock().value_inline_threshold;
+        if len_hint <= threshold as u64 {
+            let mut value = Vec::with_capacity(len_hint as usize);
+            reader.read_to_end(&mut value).await?;
+            return self.put(key, value.into())
+        }
+
+        let mut pending = self.db.value_log.lock().begin(&self.txn, &key, len_hint, compression::NONE);
+        let mut buf = vec![0; VALUE_CHUNK];
+
+        self.operands.remove(&key);
+        self.ttls.remove(&key);
+        let streamed = loop {
+            let read = match reader.read(&mut buf).await {
+                Ok(0) => break Ok(()),
+                Ok(read) => read,
+                Err(err) => break Err(err)
+            };
+            if let Err(err) = self.db.value_log.lock().write_value(&self.txn, &mut pending, &buf[..read]) {
+                break Err(err)
+            }
+        };
+
+        // a failed value is still finished, so the log stays readable, but nothing points to it
+        let ptr = self.db.value_log.lock().finish(&self.txn, pending);
+        if let Err(err) = streamed {
+            self.txn.release_value(&key, &ptr);
+            return Err(err.into())
+        }
+
+        self.record(key, Some(LeafValue::Logged(ptr)))
+    }
+
+    pub fn delete(&mut self, key: Bytes) -> Result<(), WriteError> {
+        self.db.check_stall()?;
+        self.check_key(&key)?;
+        self.operands.remove(&key);
+        self.ttls.remove(&key);
+        self.record(key, None)
+    }
+
+    /// Record the latest change to `key`. A value this transaction logged for it before is
+    /// garbage now, unless it was spilled: that one stays counted as live until reopening.
+    fn record(&mut self, key: Bytes, value: Option<LeafValue>) -> Result<(), WriteError> {
+        if let Some(Some(LeafValue::Logged(ptr))) = self.writes.insert(key.clone(), value)? {
+            self.txn.release_value(&key, &ptr);
+        }
+        Ok(())
+    }
+
+    /// Delete every key in `range`. On commit, subtrees of the tree wholly inside the range are
+    /// unlinked without being read, so clearing costs about as much as rewriting the leaves at
+    /// its ends, however many keys it holds. The values those keys kept in the value log aren't
+    /// counted as garbage. The commit bypasses the write buffer.
+    ///
+    /// Secondary indexes, commit hooks, watchers and replicas need each deleted key, so while
+    /// any are registered the range is read and its keys deleted one by one instead.
+    pub async fn delete_range<R: RangeBounds<Bytes>>(&mut self, range: R) -> Result<(), WriteError> {
+        self.db.check_stall()?;
+        let from = range::owned(range.start_bound());
+        let to = range::owned(range.end_bound());
+        if range::is_empty(&from, &to) { return Ok(()) }
+
+        if !self.db.indexes.lock().is_empty() || self.db.has_commit_subscribers() {
+            let keys: Vec<Bytes> = self.range((from, to)).map_ok(|(key, _)| key).try_collect().await?;
+            for key in keys {
+                self.delete(key)?;
+            }
+            return Ok(())
+        }
+
+        // this transaction's own changes in the range came before the clear, so it undoes them
+        let written = self.writes.range(from.clone(), to.clone()).map_err(Arc::new)?;
+        for (key, _) in written {
+            self.record(key, None)?;
+        }
+        let merged: Vec<Bytes> = self.operands.range((from.clone(), to.clone())).map(|(key, _)| key.clone()).collect();
+        for key in merged {
+            self.operands.remove(&key);
+        }
+        let expiring: Vec<Bytes> = self.ttls.range((from.clone(), to.clone())).map(|(key, _)| key.clone()).collect();
+        for key in expiring {
+            self.ttls.remove(&key);
+        }
+
+        self.cleared.push((from, to));
+        self.bypass_buffer = true;
+        Ok(())
+    }
+
+    /// The database's generation this transaction builds on. See `DB::generation`.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Whether `key` is in a range this transaction cleared
+    fn is_cleared(&self, key: &[u8]) -> bool {
+        is_cleared(&self.cleared, key)
+    }
+
+    /// Read the value of `key`, including this transaction's changes
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Bytes>, RetrieveError> {
+        let value = self.get_unmerged(key).await?;
+        Ok(match self.operands.get(key) {
+            Some(operands) => Some(self.db.fold(key, value, operands)),
+            None => value
+        })
+    }
+
+    /// Read the value of `key` before any queued merges
+    async fn get_unmerged(&self, key: &[u8]) -> Result<Option<Bytes>, RetrieveError> {
+        let value = match self.writes.get(key).map_err(Arc::new)? {
+            Some(_) if self.own_expired(key) => None,
+            Some(written) => written,
+            None if self.is_cleared(key) => None,
+            None => self.db.lookup(key).await?
+        };
+
+        match value {
+            Some(value) => Ok(Some(self.read_value(key, value).await?)),
+            None => Ok(None)
+        }
+    }
+
+    /// Whether `key` has a value, including this transaction's changes
+    pub async fn contains_key(&self, key: &[u8]) -> Result<bool, RetrieveError> {
+        if self.operands.contains_key(key) { return Ok(true) }
+        match self.writes.get(key).map_err(Arc::new)? {
+            Some(written) => Ok(written.is_some() && !self.own_expired(key)),
+            None if self.is_cleared(key) => Ok(false),
+            None => self.db.contains_key(key).await
+        }
+    }
+
+    /// Stream the entries with keys in `range`, in key order, including this transaction's changes
+    pub fn range<R: RangeBounds<Bytes>>(&self, range: R) -> Scan<'_> {
+        let from = range::owned(range.start_bound());
+        let to = range::owned(range.end_bound());
+        if range::is_empty(&from, &to) { return Scan::empty(self.generation) }
+
+        let merged = self.operands.range((from.clone(), to.clone())).map(|(key, _)| key.clone()).collect::<Vec<_>>();
+
+        // keys with queued merges are folded first, so keys only merged into are scanned too
+        let entries = stream::once(async move {
+            let mut folded = Vec::with_capacity(merged.len());
+            for key in merged {
+                let value = self.get(&key).await?;
+                folded.push((key, value.map(LeafValue::Inline)));
+            }
+            Ok::<_, RetrieveError>(folded)
+        }).map(move |folded| match folded {
+            Ok(folded) => {
+                let written = match self.writes.range(from.clone(), to.clone()) {
+                    Ok(written) => written,
+                    Err(err) => return stream::once(future::ready(Err(Arc::new(err).into()))).boxed()
+                };
+                let (_, buffered) = self.db.buffered_range(from.clone(), to.clone());
+                let mut changes: BTreeMap<Bytes, Option<LeafValue>> = buffered.into_iter().collect();
+                changes.extend(written);
+                changes.extend(folded);
+
+                self.db.merged_entries(self.version.tree_root, from.clone(), to.clone(), changes.into_iter().collect())
+                    .try_filter_map(move |(key, value)| async move {
+                        let gone = match self.writes.get(&key).map_err(Arc::new)? {
+                            Some(_) => self.own_expired(&key),
+                            None if self.operands.contains_key(&key) => false,
+                            None => self.is_cleared(&key) || self.db.is_expired(self.version.expiries, &key).await?
+                        };
+                        if gone { return Ok(None) }
+
+                        let value = self.read_value(&key, value).await?;
+                        Ok(Some((key, value)))
+                    })
+                    .boxed()
+            },
+            Err(err) => stream::once(future::ready(Err(err))).boxed()
+        }).flatten().boxed();
+        Scan::new(entries, self.generation)
+    }
+
+    /// The latest change to each key, in key order
+    pub(super) fn written(&self) -> Sorted<'_> {
+        self.writes.sorted()
+    }
+
+    /// Approximate bytes of memory this transaction's changes hold. Past
+    /// `Options::max_transaction_memory` they're spilled to disk, which frees it.
+    pub fn memory_used(&self) -> usize {
+        self.writes.memory_bytes()
+    }
+
+    /// Whether a value this transaction put has already expired
+    fn own_expired(&self, key: &[u8]) -> bool {
+        match self.ttls.get(key) {
+            Some(expires) => *expires <= self.db.options.lock().clock.unix_millis(),
+            None => false
+        }
+    }
+
+    /// Read a value, which may be one this transaction logged. Those are read around the page
+    /// cache: a rollback reuses their pages, so they mustn't be cached.
+    pub(super) async fn read_value(&self, key: &[u8], value: LeafValue) -> Result<Bytes, RetrieveError> {
+        let dictionaries = self.db.dictionaries();
+        let own = matches!(value, LeafValue::Logged(_)) && self.writes.get(key).map_err(Arc::new)? == Some(Some(value.clone()));
+        match value {
+            LeafValue::Logged(ptr) if own => value_log::read_own_value(&*self.db.store, &self.db.write_back, &self.db.value_log, &dictionaries, ptr).await,
+            value => value.read(&self.db.cache, &dictionaries).await
+        }
+    }
+
+    /// The transaction that applied a batch token, if any has
+    pub(super) async fn token_applied(&self, token: &[u8]) -> Result<Option<TransactionIdx>, RetrieveError> {
+        if self.tokens.contains(token) { return Ok(Some(self.txn.idx())) }
+
+        let root = match self.version.tokens {
+            Some(root) => root,
+            None => return Ok(None)
+        };
+        let max_depth = self.db.options.lock().max_tree_depth;
+
+        Ok(match tree::lookup(&self.db.cache, root, token, max_depth).await? {
+            Some(LeafValue::Inline(applied)) => Some(TransactionIdx::from_le_bytes(applied[..].try_into().map_err(|_| RetrieveError::Malformed(root))?)),
+            Some(LeafValue::Logged(_)) => return Err(RetrieveError::Malformed(root)),
+            None => None
+        })
+    }
+
+    pub(super) fn record_token(&mut self, token: Bytes) {
+        self.tokens.insert(token);
+    }
+
+    /// Apply the changes, and wait for them to be durable. Returns the committed transaction.
+    ///
+    /// With a write buffer, the changes are journaled and buffered instead, and the commit
+    /// that finds the buffer due by its flush policy also applies it to the tree. If applying fails, the commit still
+    /// stands: the error goes to the observer, and the next commit tries again.
+    pub async fn commit(mut self) -> Result<TransactionIdx, WriteError> {
+        self.db.throttle().await?;
+        let started = Instant::now();
+        let previous = self.version;
+        trace_event!(tx = self.txn.idx(), writes = self.writes.len(), "commit");
+        for (key, operands) in std::mem::take(&mut self.operands) {
+            let value = self.get_unmerged(&key).await?;
+            let folded = self.db.fold(&key, value, &operands);
+            // folding into a value put with a TTL in this transaction keeps the TTL
+            let expires = self.ttls.get(&key).copied();
+            self.put(key.clone(), folded)?;
+            if let Some(expires) = expires { self.ttls.insert(key, expires); }
+        }
+
+        let index_writes = self.index_writes().await?;
+        // a spilled transaction is applied a chunk at a time, and only read back whole for
+        // anything notified of its writes
+        let spilled = self.writes.is_spilled();
+        let mut writes: Vec<Write> = if spilled { vec![] } else { self.writes.take_memory() };
+        let (max_depth, format, write_buffer, archive_commits, observer, clock) = {
+            let options = self.db.options.lock();
+            (options.max_tree_depth, NodeFormat::new(&options), options.write_buffer, options.archive_commits, options.observer.clone(), options.clock.clone())
+        };
+
+        let write_buffer = write_buffer.filter(|_| !self.bypass_buffer && !spilled);
+        let cleared = std::mem::take(&mut self.cleared);
+        for range in &cleared {
+            self.version.expiries = ttl::clear_range(&self.db.cache, &self.txn, self.version.expiries, range, max_depth, format).await?;
+            self.version.tree_root = tree::clear_range(&self.db.cache, &self.txn, self.version.tree_root, range, max_depth, format).await?;
+        }
+        self.version.expiries = if spilled {
+            self.apply_spilled_expiries(max_depth, format).await?
+        } else {
+            ttl::update(&self.db.cache, &self.txn, self.version.expiries, &writes, &self.ttls, max_depth, format).await?
+        };
+        if !index_writes.is_empty() {
+            self.version.indexes = tree::apply(&self.db.cache, &self.txn, self.version.indexes, &index_writes, max_depth, NodeFormat { compression: Compression::None, ..format }).await?;
+        }
+        self.version.archive = archive::record(&self.db.cache, &self.txn, &self.version, archive_commits).await?;
+
+        if !self.tokens.is_empty() {
+            let applied = LeafValue::Inline(Bytes::copy_from_slice(&self.txn.idx().to_le_bytes()));
+            let tokens: Vec<Write> = std::mem::take(&mut self.tokens).into_iter().map(|token| (token, Some(applied.clone()))).collect();
+            self.version.tokens = tree::apply(&self.db.cache, &self.txn, self.version.tokens, &tokens, max_depth, NodeFormat { compression: Compression::None, ..format }).await?;
+        }
+
+        let version = match write_buffer {
+            Some(_) => {
+                let record = memtable::encode_record(self.version.journal, &writes);
+                let journal = overflow::write_chain(&self.txn, &record);
+                self.db.write_buffer.lock().release_replaced(&self.txn, &writes);
+                let segments = self.seal_value_log();
+
+                VersionHeader {
+                    tx: self.txn.idx(),
+                    page_count: self.txn.page_count(),
+                    journal: Some(journal),
+                    segments,
+                    ..self.version
+                }
+            },
+            None => {
+                let tree_root = if spilled {
+                    self.apply_spilled_tree(&cleared, max_depth, format).await?
+                } else {
+                    // anything left in the buffer, e.g. from a journal replayed at open, goes first
+                    let mut merged = {
+                        let buffer = self.db.write_buffer.lock();
+                        buffer.release_replaced(&self.txn, &writes);
+                        buffer.merged(writes.clone())
+                    };
+                    if !cleared.is_empty() {
+                        let txn = &self.txn;
+                        merged.retain(|(key, value)| {
+                            let keep = !is_cleared(&cleared, key) || writes.binary_search_by(|(written, _)| written.cmp(key)).is_ok();
+                            // a buffered value cleared before it reached the tree
+                            if let (false, Some(LeafValue::Logged(ptr))) = (keep, value) { txn.release_value(key, ptr) }
+                            keep
+                        });
+                    }
+                    let writes = merged;
+                    tree::apply(&self.db.cache, &self.txn, self.version.tree_root, &writes, max_depth, format).await?
+                };
+                let segments = self.seal_value_log();
+
+                VersionHeader {
+                    tx: self.txn.idx(),
+                    tree_root,
+                    page_count: self.txn.page_count(),
+                    journal: None,
+                    segments,
+                    ..self.version
+                }
+            }
+        };
+        let changed = spilled || !writes.is_empty() || !cleared.is_empty();
+        if spilled && self.db.has_commit_subscribers() {
+            writes = self.writes.sorted().collect::<io::Result<_>>()?;
+        }
+        traced!(self.txn.commit(version), "write_version", tx = version.tx, page_count = version.page_count).await?;
+        self.db.metrics.commits.fetch_add(1, Ordering::Relaxed);
+        self.db.metrics.commit_latency.record(started.elapsed());
+        trace_event!(tx = version.tx, elapsed_us = started.elapsed().as_micros() as u64, "committed");
+        self.db.report_slow_write(started, OperationKind::Commit, &previous, &version);
+
+        self.committed = true;
+        self.db.value_log.lock().committed(&self.txn);
+        {
+            let mut buffer = self.db.write_buffer.lock();
+            match version.journal {
+                Some(_) => buffer.insert(&writes, clock.now()),
+                None => buffer.clear()
+            }
+            *self.db.version.lock() = version;
+            // an empty commit changes no data, so scans and snapshots stay current
+            if changed { self.db.generation.store(version.tx, Ordering::Release); }
+        }
+        observer.on_commit(version.tx);
+        self.db.run_commit_hooks(version.tx, &writes);
+        self.db.notify_watchers(version.tx, &writes, &observer).await;
+        self.db.notify_replicas(version.tx, &writes, &observer).await;
+
+        let due = write_buffer.map_or(false, |policy| self.db.write_buffer.lock().is_due(&policy, clock.now()));
+        if due {
+            if let Err(err) = self.db.apply_write_buffer(version).await {
+                observer.on_error(&err);
+            }
+        }
+
+        Ok(version.tx)
+    }
+}
+
+impl<'db> WriteTransaction<'db> {
+    /// Estimate what committing would write, without committing or writing anything, for
+    /// capacity checks and admission control. Walks the same paths through the tree as the
+    /// commit, so it costs about as many page reads. Queued merge operands are folded as the
+    /// commit would; a spilled transaction's changes are read back whole.
+    pub async fn plan(&self) -> Result<CommitPlan, WriteError> {
+        let dry = Transaction::dry_run(self.txn.idx(), self.db.store.clone(), self.db.write_back.clone(), self.txn.page_count());
+        let (max_depth, format, write_buffer, archive_commits, threshold) = {
+            let options = self.db.options.lock();
+            (options.max_tree_depth, NodeFormat::new(&options), options.write_buffer, options.archive_commits, options.value_inline_threshold)
+        };
+
+        let mut writes: Vec<Write> = self.written().collect::<io::Result<_>>()?;
+        let mut logged = self.db.value_log.lock().appended_bytes();
+        for (key, operands) in &self.operands {
+            let folded = self.db.fold(key, self.get_unmerged(key).await?, operands);
+            // a folded value too large to inline takes a pointer's room in its leaf
+            let value = if folded.len() <= threshold.min(MAX_INLINE_THRESHOLD) { LeafValue::Inline(folded) } else {
+                logged += folded.len() as u64;
+                LeafValue::Logged(ValuePointer { page: 0, offset: 0, len: folded.len() as u64, codec: compression::NONE })
+            };
+            match writes.binary_search_by(|(written, _)| written.cmp(key)) {
+                Ok(i) => writes[i].1 = Some(value),
+                Err(i) => writes.insert(i, (key.clone(), Some(value)))
+            }
+        }
+
+        let index_writes = self.index_writes().await?;
+        let write_buffer = write_buffer.filter(|_| !self.bypass_buffer && !self.writes.is_spilled());
+        let mut version = self.version;
+        for range in &self.cleared {
+            version.expiries = ttl::clear_range(&self.db.cache, &dry, version.expiries, range, max_depth, format).await?;
+            version.tree_root = tree::clear_range(&self.db.cache, &dry, version.tree_root, range, max_depth, format).await?;
+        }
+        version.expiries = ttl::update(&self.db.cache, &dry, version.expiries, &writes, &self.ttls, max_depth, format).await?;
+        if !index_writes.is_empty() {
+            tree::apply(&self.db.cache, &dry, version.indexes, &index_writes, max_depth, NodeFormat { compression: Compression::None, ..format }).await?;
+        }
+        archive::record(&self.db.cache, &dry, &version, archive_commits).await?;
+        if !self.tokens.is_empty() {
+            let applied = LeafValue::Inline(Bytes::copy_from_slice(&self.txn.idx().to_le_bytes()));
+            let tokens: Vec<Write> = self.tokens.iter().map(|token| (token.clone(), Some(applied.clone()))).collect();
+            tree::apply(&self.db.cache, &dry, version.tokens, &tokens, max_depth, NodeFormat { compression: Compression::None, ..format }).await?;
+        }
+
+        match write_buffer {
+            Some(_) => { overflow::write_chain(&dry, &memtable::encode_record(version.journal, &writes)); },
+            None => {
+                let mut merged = self.db.write_buffer.lock().merged(writes.clone());
+                merged.retain(|(key, _)| !is_cleared(&self.cleared, key) || writes.binary_search_by(|(written, _)| written.cmp(key)).is_ok());
+                tree::apply(&self.db.cache, &dry, version.tree_root, &merged, max_depth, format).await?;
+            }
+        }
+
+        let pages = dry.pages_written();
+        Ok(CommitPlan { pages, splits: dry.splits(), bytes: pages * PAGE_SIZE as u64 + logged })
+    }
+
+    /// Write out the value log's last page and the segment table as of this commit
+    fn seal_value_log(&self) -> TableAt {
+        let mut value_log = self.db.value_log.lock();
+        value_log.seal(&self.txn);
+        value_log.write_table(&self.txn, self.version.segments)
+    }
+
+    /// Update the expiry index for a spilled transaction's changes, a chunk at a time
+    async fn apply_spilled_expiries(&self, max_depth: usize, format: NodeFormat) -> Result<Option<PageIndex>, WriteError> {
+        let mut expiries = self.version.expiries;
+        let mut sorted = self.writes.sorted().peekable();
+        while sorted.peek().is_some() {
+            let chunk: Vec<Write> = sorted.by_ref().take(SPILLED_CHUNK).collect::<io::Result<_>>()?;
+            let (first, last) = (chunk[0].0.clone(), chunk[chunk.len() - 1].0.clone());
+            // only the chunk's own expiries, so a later chunk doesn't clear them as old
+            let ttls: BTreeMap<Bytes, u64> = self.ttls.range(first..=last).map(|(key, expires)| (key.clone(), *expires)).collect();
+            expiries = ttl::update(&self.db.cache, &self.txn, expiries, &chunk, &ttls, max_depth, format).await?;
+        }
+        Ok(expiries)
+    }
+
+    /// Apply a spilled transaction's changes to the tree a chunk at a time, with the write
+    /// buffer's changes outside the `cleared` ranges applied first
+    async fn apply_spilled_tree(&self, cleared: &[(Bound<Bytes>, Bound<Bytes>)], max_depth: usize, format: NodeFormat) -> Result<Option<PageIndex>, WriteError> {
+        let mut buffered = self.db.write_buffer.lock().merged(vec![]);
+        buffered.retain(|(key, value)| {
+            let keep = !is_cleared(cleared, key);
+            if let (false, Some(LeafValue::Logged(ptr))) = (keep, value) { self.txn.release_value(key, ptr) }
+            keep
+        });
+        let mut tree_root = self.version.tree_root;
+        if !buffered.is_empty() {
+            tree_root = tree::apply(&self.db.cache, &self.txn, tree_root, &buffered, max_depth, format).await?;
+        }
+
+        let mut sorted = self.writes.sorted().peekable();
+        while sorted.peek().is_some() {
+            let chunk: Vec<Write> = sorted.by_ref().take(SPILLED_CHUNK).collect::<io::Result<_>>()?;
+            tree_root = tree::apply(&self.db.cache, &self.txn, tree_root, &chunk, max_depth, format).await?;
+        }
+        Ok(tree_root)
+    }
+}
+
+fn is_cleared(cleared: &[(Bound<Bytes>, Bound<Bytes>)], key: &[u8]) -> bool {
+    cleared.iter().any(|(from, to)| range::contains(key, from, to))
+}
+
+impl<'db> Drop for WriteTransaction<'db> {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.db.value_log.lock().rolled_back();
+        }
+    }
+}
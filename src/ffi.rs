@@ -0,0 +1,302 @@
+//! A C interface over the blocking API, declared in `include/bssdb.h`.
+//!
+//! Functions return `BSSDB_OK`, `BSSDB_NOT_FOUND` or `BSSDB_DONE` where documented, or
+//! `BSSDB_ERROR`, after which `bssdb_last_error_code` and `bssdb_last_error_message` describe
+//! the error on the calling thread. Bytes returned to the caller are owned by it and freed with
+//! `bssdb_bytes_free`. Transactions and iterators borrow their database, so they must be
+//! finished before it's closed. Every pointer passed in must be valid, and a handle must not
+//! be used from two threads at once.
+
+#![allow(non_camel_case_types, clippy::missing_safety_doc)]
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::fmt::Display;
+use std::ops::Bound;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use bytes::Bytes;
+
+use crate::blocking::{DB, Iter, WriteTransaction};
+use crate::db::{Error, Options};
+
+pub const BSSDB_OK: c_int = 0;
+pub const BSSDB_NOT_FOUND: c_int = 1;
+pub const BSSDB_DONE: c_int = 2;
+pub const BSSDB_ERROR: c_int = -1;
+
+/// An open database
+pub struct bssdb_db {
+    db: DB
+}
+
+/// A write transaction, which borrows its database
+pub struct bssdb_txn {
+    txn: WriteTransaction<'static>
+}
+
+/// A range scan, which borrows its database
+pub struct bssdb_iter {
+    entries: Iter<'static>
+}
+
+/// Bytes owned by the caller, freed with `bssdb_bytes_free`
+#[repr(C)]
+pub struct bssdb_bytes {
+    pub data: *mut u8,
+    pub len: usize
+}
+
+impl bssdb_bytes {
+    fn empty() -> bssdb_bytes {
+        bssdb_bytes { data: ptr::null_mut(), len: 0 }
+    }
+
+    fn from(bytes: &[u8]) -> bssdb_bytes {
+        let boxed: Box<[u8]> = bytes.into();
+        let len = boxed.len();
+        bssdb_bytes { data: Box::into_raw(boxed) as *mut u8, len }
+    }
+}
+
+/// Called with each entry of a scan. Returning non-zero stops the scan.
+pub type bssdb_scan_fn = extern "C" fn(ctx: *mut c_void, key: *const u8, key_len: usize, value: *const u8, value_len: usize) -> c_int;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<(CString, CString)>> = RefCell::new(None);
+}
+
+fn fail<E: Display>(code: &'static str, err: E) -> c_int {
+    let code = CString::new(code).unwrap_or_default();
+    let message = CString::new(err.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some((code, message)));
+    BSSDB_ERROR
+}
+
+/// Run `f`, turning a panic into an error rather than unwinding into C
+fn guard<F: FnOnce() -> c_int>(f: F) -> c_int {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| fail("panic", "bssdb panicked"))
+}
+
+unsafe fn slice<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 { &[] } else { std::slice::from_raw_parts(data, len) }
+}
+
+unsafe fn bytes(data: *const u8, len: usize) -> Bytes {
+    Bytes::copy_from_slice(slice(data, len))
+}
+
+/// A bound from a key, or unbounded if `data` is null
+unsafe fn bound(data: *const u8, len: usize, inclusive: bool) -> Bound<Bytes> {
+    match (data.is_null(), inclusive) {
+        (true, _) => Bound::Unbounded,
+        (false, true) => Bound::Included(bytes(data, len)),
+        (false, false) => Bound::Excluded(bytes(data, len))
+    }
+}
+
+/// The stable code of the calling thread's last error, valid until its next error
+#[no_mangle]
+pub extern "C" fn bssdb_last_error_code() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |(code, _)| code.as_ptr()))
+}
+
+/// The message of the calling thread's last error, valid until its next error
+#[no_mangle]
+pub extern "C" fn bssdb_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |(_, message)| message.as_ptr()))
+}
+
+/// Open or create the database at `path` with default options
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_open(path: *const c_char, out: *mut *mut bssdb_db) -> c_int {
+    guard(|| {
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(path) => path,
+            Err(err) => return fail("invalid_path", err)
+        };
+        match DB::open(path, Options::new()) {
+            Ok(db) => {
+                *out = Box::into_raw(Box::new(bssdb_db { db }));
+                BSSDB_OK
+            },
+            Err(err) => fail(err.code(), err)
+        }
+    })
+}
+
+/// Close a database. Its transactions and iterators must already be finished.
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_close(db: *mut bssdb_db) {
+    if !db.is_null() { drop(Box::from_raw(db)) }
+}
+
+/// Read `key` into `value`, returning `BSSDB_NOT_FOUND` if it has none
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_get(db: *const bssdb_db, key: *const u8, key_len: usize, value: *mut bssdb_bytes) -> c_int {
+    guard(|| match (*db).db.get(slice(key, key_len)) {
+        Ok(Some(found)) => {
+            *value = bssdb_bytes::from(&found);
+            BSSDB_OK
+        },
+        Ok(None) => {
+            *value = bssdb_bytes::empty();
+            BSSDB_NOT_FOUND
+        },
+        Err(err) => fail(err.code(), err)
+    })
+}
+
+unsafe fn write_one(db: *const bssdb_db, key: Bytes, value: Option<Bytes>) -> c_int {
+    let mut txn = match (*db).db.write() {
+        Ok(txn) => txn,
+        Err(err) => {
+            let err = Error::from(err);
+            return fail(err.code(), err)
+        }
+    };
+    let written = match value {
+        Some(value) => txn.put(key, value),
+        None => txn.delete(key)
+    };
+    match written.and_then(|()| txn.commit()) {
+        Ok(_) => BSSDB_OK,
+        Err(err) => fail(err.code(), err)
+    }
+}
+
+/// Set `key` to `value` in its own transaction
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_put(db: *const bssdb_db, key: *const u8, key_len: usize, value: *const u8, value_len: usize) -> c_int {
+    guard(|| write_one(db, bytes(key, key_len), Some(bytes(value, value_len))))
+}
+
+/// Delete `key` in its own transaction
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_delete(db: *const bssdb_db, key: *const u8, key_len: usize) -> c_int {
+    guard(|| write_one(db, bytes(key, key_len), None))
+}
+
+/// Begin a write transaction, waiting for any other to finish
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_txn_begin(db: *const bssdb_db, out: *mut *mut bssdb_txn) -> c_int {
+    guard(|| match (*db).db.write() {
+        Ok(txn) => {
+            // the caller promises to finish the transaction before closing the database
+            let txn: WriteTransaction<'static> = std::mem::transmute(txn);
+            *out = Box::into_raw(Box::new(bssdb_txn { txn }));
+            BSSDB_OK
+        },
+        Err(err) => {
+            let err = Error::from(err);
+            fail(err.code(), err)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_txn_put(txn: *mut bssdb_txn, key: *const u8, key_len: usize, value: *const u8, value_len: usize) -> c_int {
+    guard(|| match (*txn).txn.put(bytes(key, key_len), bytes(value, value_len)) {
+        Ok(()) => BSSDB_OK,
+        Err(err) => fail(err.code(), err)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_txn_delete(txn: *mut bssdb_txn, key: *const u8, key_len: usize) -> c_int {
+    guard(|| match (*txn).txn.delete(bytes(key, key_len)) {
+        Ok(()) => BSSDB_OK,
+        Err(err) => fail(err.code(), err)
+    })
+}
+
+/// Read `key` as the transaction sees it, like `bssdb_get`
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_txn_get(txn: *const bssdb_txn, key: *const u8, key_len: usize, value: *mut bssdb_bytes) -> c_int {
+    guard(|| match (*txn).txn.get(slice(key, key_len)) {
+        Ok(Some(found)) => {
+            *value = bssdb_bytes::from(&found);
+            BSSDB_OK
+        },
+        Ok(None) => {
+            *value = bssdb_bytes::empty();
+            BSSDB_NOT_FOUND
+        },
+        Err(err) => fail(err.code(), err)
+    })
+}
+
+/// Commit and free the transaction, whether or not the commit succeeds
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_txn_commit(txn: *mut bssdb_txn) -> c_int {
+    let txn = Box::from_raw(txn);
+    guard(move || match txn.txn.commit() {
+        Ok(_) => BSSDB_OK,
+        Err(err) => fail(err.code(), err)
+    })
+}
+
+/// Roll back and free the transaction
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_txn_abort(txn: *mut bssdb_txn) {
+    if !txn.is_null() { drop(Box::from_raw(txn)) }
+}
+
+/// Start a scan from `start` to `end`, either of which may be null for no bound. `start` is
+/// inclusive and `end` exclusive.
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_iter_open(db: *const bssdb_db, start: *const u8, start_len: usize, end: *const u8, end_len: usize, out: *mut *mut bssdb_iter) -> c_int {
+    guard(|| {
+        let range = (bound(start, start_len, true), bound(end, end_len, false));
+        // the caller promises to free the iterator before closing the database
+        let entries: Iter<'static> = std::mem::transmute((*db).db.range(range));
+        *out = Box::into_raw(Box::new(bssdb_iter { entries }));
+        BSSDB_OK
+    })
+}
+
+/// Read the next entry, returning `BSSDB_DONE` after the last
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_iter_next(iter: *mut bssdb_iter, key: *mut bssdb_bytes, value: *mut bssdb_bytes) -> c_int {
+    guard(|| match (*iter).entries.next() {
+        Some(Ok((k, v))) => {
+            *key = bssdb_bytes::from(&k);
+            *value = bssdb_bytes::from(&v);
+            BSSDB_OK
+        },
+        Some(Err(err)) => fail(err.code(), err),
+        None => BSSDB_DONE
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_iter_free(iter: *mut bssdb_iter) {
+    if !iter.is_null() { drop(Box::from_raw(iter)) }
+}
+
+/// Call `callback` with each entry from `start` to `end`, bounded as for `bssdb_iter_open`,
+/// until it returns non-zero. The pointers passed to it are valid only during the call.
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_scan(db: *const bssdb_db, start: *const u8, start_len: usize, end: *const u8, end_len: usize, callback: bssdb_scan_fn, ctx: *mut c_void) -> c_int {
+    guard(|| {
+        let range = (bound(start, start_len, true), bound(end, end_len, false));
+        for entry in (*db).db.range(range) {
+            match entry {
+                Ok((key, value)) => {
+                    if callback(ctx, key.as_ptr(), key.len(), value.as_ptr(), value.len()) != 0 { break }
+                },
+                Err(err) => return fail(err.code(), err)
+            }
+        }
+        BSSDB_OK
+    })
+}
+
+/// Free bytes returned by bssdb
+#[no_mangle]
+pub unsafe extern "C" fn bssdb_bytes_free(bytes: bssdb_bytes) {
+    if !bytes.data.is_null() {
+        drop(Box::from_raw(std::slice::from_raw_parts_mut(bytes.data, bytes.len)));
+    }
+}
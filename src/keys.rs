@@ -0,0 +1,284 @@
+//! Order-preserving key encoding: encoded keys compare byte by byte the way the values they
+//! encode compare, so range scans over encoded keys visit values in order.
+//!
+//! Unsigned integers are big-endian, signed integers big-endian with the sign bit flipped,
+//! and floats big-endian with the sign bit flipped, or every bit if negative. Strings and
+//! bytes escape `0x00` as `0x00 0xFF` and end with `0x00 0x00`, so a string sorts before its
+//! extensions even when more parts follow. `None` is `0`, and `Some` is `1` then the value.
+//! Tuples are their parts concatenated. With the `serde` feature, `encode_key` writes the
+//! same encoding for these types.
+
+use bytes::Bytes;
+
+/// A value with an order-preserving encoding, which can be one part of a composite key
+pub trait KeyPart: Sized {
+    /// Append the encoding to `out`
+    fn encode_to(&self, out: &mut Vec<u8>);
+
+    /// Decode a value from the start of `input`, advancing it past the value
+    fn decode_from(input: &mut &[u8]) -> Option<Self>;
+}
+
+/// Encode `key`, which may be a tuple of parts
+pub fn encode<K: KeyPart>(key: &K) -> Bytes {
+    let mut out = Vec::new();
+    key.encode_to(&mut out);
+    Bytes::from(out)
+}
+
+/// Decode a whole key written by `encode`
+pub fn decode<K: KeyPart>(mut bytes: &[u8]) -> Option<K> {
+    let key = K::decode_from(&mut bytes)?;
+    if !bytes.is_empty() { return None }
+    Some(key)
+}
+
+/// The lowest key greater than every key starting with `prefix`, to end a range scan over
+/// the prefix, or `None` if there's no such key (the prefix is all `0xFF`s)
+pub fn prefix_end(prefix: &[u8]) -> Option<Bytes> {
+    let last = prefix.iter().rposition(|&byte| byte != 0xFF)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(Bytes::from(end))
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len { return None }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Some(taken)
+}
+
+/// Append `bytes` escaped and terminated
+pub(crate) fn escape(bytes: &[u8], out: &mut Vec<u8>) {
+    for &byte in bytes {
+        out.push(byte);
+        if byte == 0 { out.push(0xFF) }
+    }
+    out.extend_from_slice(&[0, 0]);
+}
+
+/// Read escaped and terminated bytes from the start of `input`
+pub(crate) fn unescape(input: &mut &[u8]) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    loop {
+        match take(input, 1)?[0] {
+            0 => match take(input, 1)?[0] {
+                0 => return Some(out),
+                0xFF => out.push(0),
+                _ => return None
+            },
+            byte => out.push(byte)
+        }
+    }
+}
+
+fn f32_bits(v: f32) -> u32 {
+    let bits = v.to_bits();
+    if bits >> 31 == 1 { !bits } else { bits ^ (1 << 31) }
+}
+
+fn f32_from_bits(bits: u32) -> f32 {
+    f32::from_bits(if bits >> 31 == 1 { bits ^ (1 << 31) } else { !bits })
+}
+
+fn f64_bits(v: f64) -> u64 {
+    let bits = v.to_bits();
+    if bits >> 63 == 1 { !bits } else { bits ^ (1 << 63) }
+}
+
+fn f64_from_bits(bits: u64) -> f64 {
+    f64::from_bits(if bits >> 63 == 1 { bits ^ (1 << 63) } else { !bits })
+}
+
+macro_rules! unsigned {
+    ($($ty:ty),*) => {$(
+        impl KeyPart for $ty {
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Option<Self> {
+                let mut bytes = [0; std::mem::size_of::<$ty>()];
+                bytes.copy_from_slice(take(input, bytes.len())?);
+                Some(<$ty>::from_be_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+unsigned!(u8, u16, u32, u64, u128);
+
+macro_rules! signed {
+    ($($ty:ty => $unsigned:ty),*) => {$(
+        impl KeyPart for $ty {
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                (*self as $unsigned ^ (1 << (std::mem::size_of::<$unsigned>() * 8 - 1))).encode_to(out)
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Option<Self> {
+                Some((<$unsigned>::decode_from(input)? ^ (1 << (std::mem::size_of::<$unsigned>() * 8 - 1))) as $ty)
+            }
+        }
+    )*};
+}
+
+signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl KeyPart for f32 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        f32_bits(*self).encode_to(out)
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        Some(f32_from_bits(u32::decode_from(input)?))
+    }
+}
+
+impl KeyPart for f64 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        f64_bits(*self).encode_to(out)
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        Some(f64_from_bits(u64::decode_from(input)?))
+    }
+}
+
+impl KeyPart for bool {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8)
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        match take(input, 1)?[0] {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None
+        }
+    }
+}
+
+impl KeyPart for String {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        escape(self.as_bytes(), out)
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        String::from_utf8(unescape(input)?).ok()
+    }
+}
+
+impl KeyPart for Bytes {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        escape(self, out)
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        Some(Bytes::from(unescape(input)?))
+    }
+}
+
+impl<T: KeyPart> KeyPart for Option<T> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Some(value) => {
+                out.push(1);
+                value.encode_to(out);
+            },
+            None => out.push(0)
+        }
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        match take(input, 1)?[0] {
+            0 => Some(None),
+            1 => Some(Some(T::decode_from(input)?)),
+            _ => None
+        }
+    }
+}
+
+macro_rules! tuple {
+    ($($part:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$($part: KeyPart),*> KeyPart for ($($part,)*) {
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                let ($($part,)*) = self;
+                $($part.encode_to(out);)*
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Option<Self> {
+                Some(($($part::decode_from(input)?,)*))
+            }
+        }
+    };
+}
+
+tuple!(A);
+tuple!(A, B);
+tuple!(A, B, C);
+tuple!(A, B, C, D);
+tuple!(A, B, C, D, E);
+tuple!(A, B, C, D, E, F);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every value round-trips, and encoded values sort in the order given
+    fn check_sorted<K: KeyPart + PartialEq + std::fmt::Debug>(values: &[K]) {
+        let encoded: Vec<Bytes> = values.iter().map(encode).collect();
+        for (value, bytes) in values.iter().zip(&encoded) {
+            assert_eq!(decode::<K>(bytes).as_ref(), Some(value));
+        }
+        for pair in encoded.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} sorts after {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn integers() {
+        check_sorted(&[0u8, 1, 127, 128, 255]);
+        check_sorted(&[0u64, 1, 256, u32::MAX as u64, u64::MAX]);
+        check_sorted(&[i32::MIN, -256, -1, 0, 1, 256, i32::MAX]);
+        check_sorted(&[i128::MIN, -1, 0, i128::MAX]);
+    }
+
+    #[test]
+    fn floats() {
+        check_sorted(&[f64::NEG_INFINITY, f64::MIN, -1.5, -0.0, 0.0, f64::MIN_POSITIVE, 1.5, f64::MAX, f64::INFINITY]);
+        check_sorted(&[f32::NEG_INFINITY, -1.0, 0.0, 1.0, f32::INFINITY]);
+    }
+
+    #[test]
+    fn strings_sort_before_their_extensions() {
+        check_sorted(&[String::new(), "\0".into(), "\0\0".into(), "a".into(), "a\0".into(), "a\u{1}".into(), "ab".into(), "b".into()]);
+        check_sorted(&[Bytes::new(), Bytes::from_static(&[0, 0xFF]), Bytes::from_static(&[0xFF])]);
+    }
+
+    #[test]
+    fn tuples_and_options() {
+        check_sorted(&[("a".to_string(), 9u32), ("a".to_string(), 10), ("ab".to_string(), 0), ("b".to_string(), 0)]);
+        check_sorted(&[None, Some(-1i64), Some(0), Some(1)]);
+        check_sorted(&[(false, None::<u8>), (false, Some(0)), (true, None)]);
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert_eq!(decode::<u32>(&[0, 0, 1]), None);
+        assert_eq!(decode::<u16>(&[0, 0, 1]), None);
+        assert_eq!(decode::<bool>(&[2]), None);
+        // an escape other than 0x00 0xFF or the terminator
+        assert_eq!(decode::<Bytes>(&[b'a', 0, 1]), None);
+        assert_eq!(decode::<String>(&[0xC0, 0, 0]), None);
+    }
+
+    #[test]
+    fn prefix_ends() {
+        assert_eq!(prefix_end(b"ab"), Some(Bytes::from_static(b"ac")));
+        assert_eq!(prefix_end(&[1, 0xFF, 0xFF]), Some(Bytes::from_static(&[2])));
+        assert_eq!(prefix_end(&[0xFF]), None);
+        assert_eq!(prefix_end(b""), None);
+    }
+}
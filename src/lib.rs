@@ -1,5 +1,26 @@
 mod db;
-mod write_transaction;
-mod tree_node;
 
-pub use db::DB;
+pub mod blocking;
+pub mod keys;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "fuzzing")]
+pub use db::fuzz;
+
+pub use db::{DB, TransactionIdx, Error, ReadOps, Snapshot, Scan, WriteTransaction, WriteError, CommitPlan, CasError, Batch, BatchOutcome, KeyChange, Event, CommitSummary, Index, RangeSize, OpenError, FormatError, ErrorKind, BackupError, RestoreError, ExportError, ImportError, ReplicationRecord, ReplicationError, ReplicationLag, Options, Setting, Durability, Observer, OperationKind, SlowOperation, MaintenancePause, PackedDb, PackedError, CacheConfig, CacheStats, PoolBacking, Statistics, ValueLogStats, Compaction, LatencyHistogram, ChecksumSampling, EvictionPolicy, RecoveryMode, DamagedRange, CorruptionReport, VerifyReport, VerifyProblem};
+#[cfg(feature = "serde")]
+pub use db::{TypedTree, TypedError, KeyError, encode_key, decode_key};
+#[cfg(feature = "encryption")]
+pub use db::{EncryptionConfig, Cipher};
+pub use db::{Clock, SystemClock, ManualClock, Compression, FlushPolicy, Backpressure, Spawn, ThreadSpawner};
+#[cfg(feature = "tokio")]
+pub use db::TokioSpawner;
+#[cfg(feature = "async-std")]
+pub use db::AsyncStdSpawner;
+pub use db::{PageStore, FileStore, StoreMetrics, PageContent, PageIndex, PageType, PageDescription, PageDetail, OutlineNode, RetrieveError, DescentError, CrossLink};
+#[cfg(feature = "test-util")]
+pub use db::{DelayStore, DelayConfig, Latency};
+#[cfg(feature = "sim")]
+pub use db::{SimStore, SimConfig, SimExecutor};
+#[cfg(feature = "fault-injection")]
+pub use db::{Faults, Fault, FaultPoint};
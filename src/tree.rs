@@ -1,7 +0,0 @@
-
-use crate::db::PageContent;
-
-
-struct Tree {
-    root_content: PageContent
-}
@@ -0,0 +1,186 @@
+//! Crash-consistency: run a workload with a fault injected at each write, sync and crash point
+//! in turn, then reopen the file and check the database recovered to a commit at least as
+//! recent as the last one acknowledged, with no damage.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use bytes::Bytes;
+use bssdb::{Fault, FaultPoint, Faults, Options};
+use bssdb::blocking::DB;
+
+const COMMITS: u64 = 6;
+const KEYS_PER_COMMIT: u64 = 40;
+
+fn key(commit: u64, i: u64) -> Bytes {
+    Bytes::from(format!("key-{:03}-{:04}", commit, i))
+}
+
+fn options() -> Options {
+    let mut options = Options::new();
+    options.direct_io(false);
+    options
+}
+
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(name: &str) -> TempFile {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("bssdb-crash-{}-{}-{}", std::process::id(), name, n));
+        let _ = std::fs::remove_file(&path);
+        TempFile(path)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Commit `COMMITS` transactions, each adding keys and bumping a counter, until one fails.
+/// Returns how many were acknowledged.
+fn workload(file: &TempFile, faults: Option<std::sync::Arc<Faults>>) -> u64 {
+    let mut options = options();
+    if let Some(faults) = faults { options.faults(faults); }
+
+    let db = match DB::open(&file.0, options) {
+        Ok(db) => db,
+        Err(_) => return 0
+    };
+
+    for commit in 1..=COMMITS {
+        let committed = (|| {
+            let mut txn = db.write().map_err(|err| err.to_string())?;
+            for i in 0..KEYS_PER_COMMIT {
+                txn.put(key(commit, i), Bytes::from(vec![commit as u8; 100 + i as usize])).map_err(|err| err.to_string())?;
+            }
+            // overwrite a key from the commit before, so old pages are replaced, not just added to
+            if commit > 1 { txn.delete(key(commit - 1, 0)).map_err(|err| err.to_string())?; }
+            txn.put(Bytes::from_static(b"counter"), Bytes::from(commit.to_be_bytes().to_vec())).map_err(|err| err.to_string())?;
+            txn.commit().map_err(|err| err.to_string())
+        })();
+        if committed.is_err() { return commit - 1 }
+    }
+    COMMITS
+}
+
+/// Reopen without faults and check the database holds exactly some commit no older than `acknowledged`
+fn check(file: &TempFile, acknowledged: u64, case: &str) {
+    let db = DB::open(&file.0, options()).unwrap_or_else(|err| panic!("{}: reopening failed: {}", case, err));
+
+    let recovered = match db.get(b"counter").unwrap() {
+        Some(counter) => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&counter);
+            u64::from_be_bytes(bytes)
+        },
+        None => 0
+    };
+    assert!(recovered >= acknowledged, "{}: commit {} was acknowledged but only {} survived", case, acknowledged, recovered);
+
+    for commit in 1..=COMMITS {
+        for i in 0..KEYS_PER_COMMIT {
+            let deleted = i == 0 && commit < recovered;
+            let expected = commit <= recovered && !deleted;
+            let value = db.get(&key(commit, i)).unwrap_or_else(|err| panic!("{}: reading failed: {}", case, err));
+            assert_eq!(value.is_some(), expected, "{}: key {} of commit {} after recovering commit {}", case, i, commit, recovered);
+            if let Some(value) = value {
+                assert_eq!(&value[..], &vec![commit as u8; 100 + i as usize][..], "{}: wrong value", case);
+            }
+        }
+    }
+    assert_eq!(db.len().unwrap(), recovered * KEYS_PER_COMMIT - recovered.saturating_sub(1) + (recovered > 0) as u64, "{}: wrong length", case);
+
+    let report = futures::executor::block_on(db.into_async().verify());
+    assert!(report.is_ok(), "{}: {:?}", case, report.problems);
+}
+
+/// Run the workload once with `fault`, then check recovery
+fn run(name: &str, fault: Fault) {
+    let file = TempFile::new(name);
+    let faults = Faults::new();
+    faults.inject(fault);
+
+    let acknowledged = workload(&file, Some(faults.clone()));
+    check(&file, acknowledged, &format!("{:?}", fault));
+}
+
+/// Count writes and hits of each point in a run without faults
+fn baseline() -> std::sync::Arc<Faults> {
+    let file = TempFile::new("baseline");
+    let faults = Faults::new();
+    assert_eq!(workload(&file, Some(faults.clone())), COMMITS);
+    check(&file, COMMITS, "baseline");
+    faults
+}
+
+#[test]
+fn failed_writes() {
+    let baseline = baseline();
+    for nth in 0..baseline.writes() {
+        run("fail", Fault::FailWrite(nth));
+    }
+}
+
+#[test]
+fn torn_writes() {
+    let baseline = baseline();
+    for nth in 0..baseline.writes() {
+        for &len in &[0, 512, 2048] {
+            run("torn", Fault::TruncateWrite { nth, len });
+        }
+    }
+}
+
+#[test]
+fn power_loss() {
+    let baseline = baseline();
+    for &point in &[FaultPoint::WriteInFlight, FaultPoint::WriteCompleted, FaultPoint::BeforeSync, FaultPoint::AfterSync] {
+        for nth in 0..baseline.hits(point) {
+            run("power", Fault::PowerLoss { point, nth });
+        }
+    }
+}
+
+#[test]
+fn crashes() {
+    let baseline = baseline();
+    for &point in &[FaultPoint::WriteInFlight, FaultPoint::WriteCompleted, FaultPoint::BeforeSync, FaultPoint::AfterSync] {
+        for nth in 0..baseline.hits(point) {
+            run("crash", Fault::Crash { point, nth });
+        }
+    }
+}
+
+/// A page write that fails mid-transaction fails the commit, however many reads flush first
+#[test]
+fn failed_write_fails_commit() {
+    let file = TempFile::new("sticky");
+    let faults = Faults::new();
+    let mut options = options();
+    options.faults(faults.clone());
+    let db = DB::open(&file.0, options).unwrap();
+
+    // the value is logged, so its pages are written as it's put
+    faults.inject(Fault::FailWrite(faults.writes()));
+    let mut txn = db.write().unwrap();
+    txn.put(key(1, 0), Bytes::from(vec![1; 100_000])).unwrap();
+    for _ in 0..2 {
+        let _ = txn.get(&key(1, 0));
+    }
+    assert!(txn.commit().is_err(), "commit published pages that were never written");
+
+    // the next transaction doesn't inherit the failure
+    let mut txn = db.write().unwrap();
+    txn.put(key(2, 0), Bytes::from(vec![2; 100_000])).unwrap();
+    txn.commit().unwrap();
+    drop(db);
+
+    let db = DB::open(&file.0, options()).unwrap();
+    assert_eq!(db.get(&key(1, 0)).unwrap(), None);
+    assert_eq!(db.get(&key(2, 0)).unwrap(), Some(Bytes::from(vec![2; 100_000])));
+    let report = futures::executor::block_on(db.into_async().verify());
+    assert!(report.is_ok(), "{:?}", report.problems);
+}
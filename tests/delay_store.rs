@@ -0,0 +1,54 @@
+//! Delay store: delays measured on a manual clock end only as the clock is advanced, in deadline order.
+
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use futures::executor::block_on;
+use futures::future::{BoxFuture, FutureExt};
+use bssdb::{DelayConfig, DelayStore, Durability, Latency, ManualClock, PageContent, PageIndex, PageStore, RetrieveError};
+
+/// Holds no pages, and finishes everything at once
+struct Empty;
+
+impl PageStore for Empty {
+    fn read_page(&self, _idx: PageIndex) -> BoxFuture<'_, Result<PageContent, RetrieveError>> {
+        async { Err(RetrieveError::OutOfPages) }.boxed()
+    }
+
+    fn write_page<'a>(&'a self, _idx: PageIndex, _page: &'a PageContent) -> BoxFuture<'a, io::Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+
+    fn sync(&self, _durability: Durability) -> BoxFuture<'_, io::Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+}
+
+#[test]
+fn manual_clock_orders_completions() {
+    let clock = Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH));
+    let config = DelayConfig {
+        read: Latency::Fixed(Duration::from_secs(20)),
+        sync: Latency::Fixed(Duration::from_secs(10)),
+        ..DelayConfig::default()
+    };
+    let store = DelayStore::with_clock(Empty, config, clock.clone());
+
+    // delays start when the operation is called, not when its future is first polled
+    let mut read = store.read_page(0);
+    let mut sync = store.sync(Durability::SyncData);
+
+    // without the clock moving, nothing ends however long we wait
+    thread::sleep(Duration::from_millis(50));
+    assert!((&mut read).now_or_never().is_none());
+    assert!((&mut sync).now_or_never().is_none());
+
+    clock.advance(Duration::from_secs(10));
+    block_on(&mut sync).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    assert!((&mut read).now_or_never().is_none());
+
+    clock.advance(Duration::from_secs(10));
+    assert!(matches!(block_on(read), Err(RetrieveError::OutOfPages)));
+}
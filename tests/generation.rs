@@ -0,0 +1,47 @@
+//! Generations: only commits that change data move the generation, so scans started before an
+//! empty commit are still current after it.
+
+use std::path::PathBuf;
+use bytes::Bytes;
+use bssdb::Options;
+use bssdb::blocking::DB;
+
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn empty_commit_keeps_scans_current() {
+    let file = TempFile(std::env::temp_dir().join(format!("bssdb-generation-{}", std::process::id())));
+    let _ = std::fs::remove_file(&file.0);
+
+    let mut options = Options::new();
+    options.direct_io(false);
+    let db = DB::open(&file.0, options).unwrap();
+
+    let mut txn = db.write().unwrap();
+    for key in [&b"a"[..], b"b", b"c"].iter() {
+        txn.put(Bytes::copy_from_slice(key), Bytes::from_static(b"value")).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let mut scan = db.range(..);
+    assert_eq!(scan.next().unwrap().unwrap().0, Bytes::from_static(b"a"));
+    assert_eq!(scan.generation(), db.generation());
+
+    let before = db.latest_transaction();
+    db.write().unwrap().commit().unwrap();
+    assert!(db.latest_transaction() > before);
+    assert_eq!(scan.generation(), db.generation(), "an empty commit moved the generation");
+    assert_eq!(scan.map(|entry| entry.unwrap().0).collect::<Vec<_>>(), vec![Bytes::from_static(b"b"), Bytes::from_static(b"c")]);
+
+    let mut txn = db.write().unwrap();
+    txn.put(Bytes::from_static(b"d"), Bytes::from_static(b"value")).unwrap();
+    let generation = db.generation();
+    txn.commit().unwrap();
+    assert!(db.generation() > generation);
+}
@@ -0,0 +1,282 @@
+//! Model-based tests: random sequences of writes, range deletes, reads, scans, commits, reopens
+//! and crashes run against the database and a `BTreeMap`, which must agree on everything observable.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+use bytes::Bytes;
+use futures::executor::block_on;
+use proptest::prelude::*;
+use bssdb::{Clock, Fault, FaultPoint, Faults, FlushPolicy, ManualClock, Options};
+use bssdb::blocking::DB;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Put(Bytes, Bytes),
+    /// Put a value that expires after this many seconds
+    PutTtl(Bytes, Bytes, u64),
+    /// Put a run of keys under a prefix, enough to fill several leaves, so range deletes cross
+    /// branches and cover whole subtrees
+    Fill(Bytes),
+    Delete(Bytes),
+    DeleteRange(Bound<Bytes>, Bound<Bytes>),
+    Get(Bytes),
+    Scan(Bound<Bytes>, Bound<Bytes>),
+    Commit,
+    /// Apply the write buffer to the tree, if there is one
+    Flush,
+    /// Move the clock on this many seconds, expiring values
+    Advance(u64),
+    /// Collect the value log segment with the most garbage
+    Collect,
+    /// Drop the database, losing uncommitted writes, and open it again
+    Reopen,
+    /// Lose power during the nth sync of committing the pending writes, then reopen
+    Crash(u64)
+}
+
+/// Keys from a small alphabet, so writes often hit keys already written
+fn key() -> impl Strategy<Value = Bytes> {
+    prop::collection::vec(0u8..4, 1..5).prop_map(Bytes::from)
+}
+
+fn value() -> impl Strategy<Value = Bytes> {
+    prop_oneof![
+        8 => prop::collection::vec(any::<u8>(), 0..64),
+        // past the inline threshold, so some values are logged
+        1 => prop::collection::vec(any::<u8>(), 1000..6000)
+    ].prop_map(Bytes::from)
+}
+
+fn bound() -> impl Strategy<Value = Bound<Bytes>> {
+    prop_oneof![
+        Just(Bound::Unbounded),
+        key().prop_map(Bound::Included),
+        key().prop_map(Bound::Excluded)
+    ]
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        10 => (key(), value()).prop_map(|(key, value)| Op::Put(key, value)),
+        2 => (key(), value(), 1u64..4).prop_map(|(key, value, ttl)| Op::PutTtl(key, value, ttl)),
+        1 => key().prop_map(Op::Fill),
+        4 => key().prop_map(Op::Delete),
+        2 => (bound(), bound()).prop_map(|(start, end)| Op::DeleteRange(start, end)),
+        4 => key().prop_map(Op::Get),
+        2 => (bound(), bound()).prop_map(|(start, end)| Op::Scan(start, end)),
+        4 => Just(Op::Commit),
+        1 => Just(Op::Flush),
+        1 => (1u64..3).prop_map(Op::Advance),
+        1 => Just(Op::Collect),
+        1 => Just(Op::Reopen),
+        1 => (0u64..2).prop_map(Op::Crash)
+    ]
+}
+
+/// Whether no key is between the bounds. `BTreeMap::range` panics on them; the database treats
+/// them as empty.
+fn inverted(start: &Bound<Bytes>, end: &Bound<Bytes>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false
+    }
+}
+
+#[derive(Debug)]
+enum Change {
+    /// A put, expiring after the given seconds if any
+    Put(Bytes, Bytes, Option<u64>),
+    Delete(Bytes),
+    DeleteRange(Bound<Bytes>, Bound<Bytes>)
+}
+
+struct Harness {
+    path: PathBuf,
+    db: Option<DB>,
+    faults: Arc<Faults>,
+    clock: Arc<ManualClock>,
+    /// Whether commits go through the write buffer
+    buffered: bool,
+    /// What the database holds as of the last commit, with when each value expires
+    committed: BTreeMap<Bytes, (Bytes, Option<u64>)>,
+    /// Changes since, in order
+    pending: Vec<Change>
+}
+
+impl Harness {
+    fn new(buffered: bool) -> Harness {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("bssdb-model-{}-{}", std::process::id(), n));
+        let _ = std::fs::remove_file(&path);
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000)));
+        let mut harness = Harness { path, db: None, faults: Faults::new(), clock, buffered, committed: BTreeMap::new(), pending: vec![] };
+        harness.open();
+        harness
+    }
+
+    fn open(&mut self) {
+        self.db = None;
+        self.pending.clear();
+        self.faults = Faults::new();
+
+        let mut options = Options::new();
+        options.direct_io(false).faults(self.faults.clone()).clock(self.clock.clone());
+        if self.buffered {
+            // big and old enough that only `Op::Flush` applies it
+            options.write_buffer(Some(FlushPolicy { max_bytes: usize::MAX, max_age: None }));
+        }
+        self.db = Some(DB::open(&self.path, options).expect("opening failed"));
+    }
+
+    fn db(&self) -> &DB {
+        self.db.as_ref().unwrap()
+    }
+
+    /// The committed value of `key`, unless it's expired
+    fn visible(&self, key: &Bytes) -> Option<Bytes> {
+        let now = self.clock.unix_millis();
+        match self.committed.get(key) {
+            Some((_, Some(expires))) if *expires <= now => None,
+            Some((value, _)) => Some(value.clone()),
+            None => None
+        }
+    }
+
+    fn commit(&mut self) -> Result<(), String> {
+        let mut txn = self.db().write().map_err(|err| err.to_string())?;
+        for change in &self.pending {
+            match change {
+                Change::Put(key, value, None) => txn.put(key.clone(), value.clone()),
+                Change::Put(key, value, Some(ttl)) => txn.as_async().put_with_ttl(key.clone(), value.clone(), Duration::from_secs(*ttl)),
+                Change::Delete(key) => txn.delete(key.clone()),
+                Change::DeleteRange(start, end) => txn.delete_range((start.clone(), end.clone()))
+            }.map_err(|err| err.to_string())?;
+        }
+        txn.commit().map_err(|err| err.to_string())?;
+
+        let now = self.clock.unix_millis();
+        for change in self.pending.drain(..) {
+            match change {
+                Change::Put(key, value, ttl) => {
+                    self.committed.insert(key, (value, ttl.map(|ttl| now + ttl * 1000)));
+                },
+                Change::Delete(key) => {
+                    self.committed.remove(&key);
+                },
+                Change::DeleteRange(start, end) => {
+                    let keys: Vec<Bytes> = self.committed.range((start, end)).map(|(key, _)| key.clone()).collect();
+                    for key in keys {
+                        self.committed.remove(&key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, op: Op) -> Result<(), TestCaseError> {
+        match op {
+            Op::Put(key, value) => self.pending.push(Change::Put(key, value, None)),
+            Op::PutTtl(key, value, ttl) => self.pending.push(Change::Put(key, value, Some(ttl))),
+            Op::Fill(prefix) => {
+                for a in 0u8..32 {
+                    for b in 0u8..32 {
+                        let mut key = prefix.to_vec();
+                        key.extend_from_slice(&[a, b]);
+                        self.pending.push(Change::Put(Bytes::from(key), Bytes::from(vec![a ^ b; 40]), None));
+                    }
+                }
+            },
+            Op::Delete(key) => self.pending.push(Change::Delete(key)),
+            // nothing to clear, so no change to commit
+            Op::DeleteRange(start, end) if inverted(&start, &end) => {},
+            Op::DeleteRange(start, end) => self.pending.push(Change::DeleteRange(start, end)),
+            Op::Get(key) => {
+                let found = self.db().get(&key).map_err(|err| TestCaseError::fail(err.to_string()))?;
+                prop_assert_eq!(found, self.visible(&key), "get {:?}", key);
+            },
+            Op::Scan(start, end) => {
+                let expected: Vec<(Bytes, Bytes)> = if inverted(&start, &end) { vec![] } else {
+                    self.committed.range((start.clone(), end.clone()))
+                        .filter_map(|(key, _)| self.visible(key).map(|value| (key.clone(), value)))
+                        .collect()
+                };
+                let found: Result<Vec<(Bytes, Bytes)>, _> = self.db().range((start.clone(), end.clone())).collect();
+                let found = found.map_err(|err| TestCaseError::fail(err.to_string()))?;
+                prop_assert_eq!(found, expected, "scan {:?}..{:?}", start, end);
+            },
+            Op::Commit => self.commit().map_err(TestCaseError::fail)?,
+            Op::Flush => self.db().flush_write_buffer().map_err(|err| TestCaseError::fail(err.to_string()))?,
+            Op::Advance(secs) => self.clock.advance(Duration::from_secs(secs)),
+            Op::Collect => {
+                block_on(self.db().collect_value_log()).map_err(|err| TestCaseError::fail(err.to_string()))?;
+            },
+            Op::Reopen => self.open(),
+            // with nothing to commit there's no sync to lose power in
+            Op::Crash(_) if self.pending.is_empty() => self.open(),
+            Op::Crash(nth) => {
+                let syncs = self.faults.hits(FaultPoint::BeforeSync);
+                self.faults.inject(Fault::PowerLoss { point: FaultPoint::BeforeSync, nth: syncs + nth });
+                prop_assert!(self.commit().is_err(), "commit survived a power loss before it synced");
+                prop_assert!(self.faults.powered_off());
+                self.open();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.db = None;
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn matches_btree_map(buffered in any::<bool>(), ops in prop::collection::vec(op(), 1..80)) {
+        let mut harness = Harness::new(buffered);
+        for op in ops {
+            harness.apply(op)?;
+        }
+
+        // everything committed survives a clean reopen
+        harness.apply(Op::Commit)?;
+        harness.apply(Op::Reopen)?;
+        harness.apply(Op::Scan(Bound::Unbounded, Bound::Unbounded))?;
+
+        // collecting every segment with garbage moves values without changing them
+        while block_on(harness.db().collect_value_log()).map_err(|err| TestCaseError::fail(err.to_string()))?.is_some() {}
+        harness.apply(Op::Reopen)?;
+        harness.apply(Op::Scan(Bound::Unbounded, Bound::Unbounded))?;
+        let report = block_on(harness.db().verify());
+        prop_assert!(report.is_ok(), "{:?}", report.problems);
+
+        // expired keys count until they're swept
+        block_on(harness.db().sweep_expired(usize::MAX)).map_err(|err| TestCaseError::fail(err.to_string()))?;
+        let live = harness.committed.keys().filter(|key| harness.visible(key).is_some()).count();
+        prop_assert_eq!(harness.db().len().map_err(|err| TestCaseError::fail(err.to_string()))?, live as u64);
+
+        // the histogram counts the tree, so apply the write buffer to it first
+        harness.apply(Op::Flush)?;
+        let mut by_prefix: BTreeMap<Bytes, u64> = BTreeMap::new();
+        for key in harness.committed.keys().filter(|key| harness.visible(key).is_some()) {
+            *by_prefix.entry(key.slice(..key.len().min(2))).or_default() += 1;
+        }
+        let histogram = harness.db().prefix_histogram(2).map_err(|err| TestCaseError::fail(err.to_string()))?;
+        let counted: Vec<(Bytes, u64)> = histogram.into_iter().map(|(prefix, size)| (prefix, size.entries)).collect();
+        prop_assert_eq!(counted, by_prefix.into_iter().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,73 @@
+//! Observer hooks: a recording observer sees commits and value log compactions as they happen.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use bytes::Bytes;
+use futures::executor::block_on;
+use parking_lot::Mutex;
+use bssdb::{Compaction, Compression, Observer, Options, TransactionIdx};
+use bssdb::blocking::DB;
+
+/// Larger than a value log segment, so it gets a run to itself
+const HUGE: usize = 5 << 20;
+
+#[derive(Default)]
+struct Recording {
+    commits: Mutex<Vec<TransactionIdx>>,
+    compactions: Mutex<Vec<Compaction>>
+}
+
+#[derive(Clone, Default)]
+struct Recorder(Arc<Recording>);
+
+impl Observer for Recorder {
+    fn on_commit(&self, txn: TransactionIdx) {
+        self.0.commits.lock().push(txn);
+    }
+
+    fn on_compaction(&self, compaction: &Compaction) {
+        self.0.compactions.lock().push(*compaction);
+    }
+}
+
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn compaction() {
+    let file = TempFile(std::env::temp_dir().join(format!("bssdb-observer-{}", std::process::id())));
+    let _ = std::fs::remove_file(&file.0);
+
+    let recorder = Recorder::default();
+    let mut options = Options::new();
+    options.direct_io(false).compression(Compression::None).observer(recorder.clone());
+    let db = DB::open(&file.0, options).unwrap();
+
+    // nothing is garbage yet
+    assert_eq!(block_on(db.collect_value_log()).unwrap(), None);
+    assert!(recorder.0.compactions.lock().is_empty());
+
+    let mut txn = db.write().unwrap();
+    txn.put(Bytes::from_static(b"huge"), Bytes::from(vec![1; HUGE])).unwrap();
+    txn.commit().unwrap();
+    let mut txn = db.write().unwrap();
+    txn.put(Bytes::from_static(b"huge"), Bytes::from(vec![2; HUGE])).unwrap();
+    txn.commit().unwrap();
+
+    let collected = block_on(db.collect_value_log()).unwrap().expect("the first value is garbage");
+    assert_eq!(collected.relocated_bytes, 0);
+    assert!(collected.reclaimed_bytes > HUGE as u64);
+
+    // reported after its commit, exactly once
+    assert_eq!(*recorder.0.compactions.lock(), vec![collected]);
+    assert_eq!(recorder.0.commits.lock().last(), Some(&collected.tx));
+
+    assert_eq!(db.get(b"huge").unwrap(), Some(Bytes::from(vec![2; HUGE])));
+    assert_eq!(block_on(db.collect_value_log()).unwrap(), None);
+    assert_eq!(recorder.0.compactions.lock().len(), 1);
+}
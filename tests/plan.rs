@@ -0,0 +1,57 @@
+//! Planning a commit: the estimate writes nothing, and covers what the commit then writes.
+
+use std::path::PathBuf;
+use bytes::Bytes;
+use bssdb::{CommitPlan, Compression, Options};
+use bssdb::blocking::DB;
+
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn key(i: u32) -> Bytes {
+    Bytes::from(format!("key-{:06}", i))
+}
+
+#[test]
+fn plan_matches_commit() {
+    let file = TempFile(std::env::temp_dir().join(format!("bssdb-plan-{}", std::process::id())));
+    let _ = std::fs::remove_file(&file.0);
+
+    let mut options = Options::new();
+    options.direct_io(false).compression(Compression::None);
+    let db = DB::open(&file.0, options).unwrap();
+
+    let mut txn = db.write().unwrap();
+    txn.put(key(0), Bytes::from_static(b"first")).unwrap();
+    assert_eq!(txn.plan().unwrap().splits, 0);
+    txn.commit().unwrap();
+
+    // enough to split the one leaf many times over
+    let mut txn = db.write().unwrap();
+    for i in 1..2000 {
+        txn.put(key(i), Bytes::from(vec![i as u8; 100])).unwrap();
+    }
+    let before = db.size_on_disk();
+    let plan = txn.plan().unwrap();
+    assert!(plan.splits > 10, "{:?}", plan);
+    assert!(plan.pages > plan.splits, "{:?}", plan);
+
+    // nothing was written, so planning again gives the same answer
+    assert_eq!(db.size_on_disk(), before);
+    assert_eq!(txn.plan().unwrap(), plan);
+
+    txn.commit().unwrap();
+    assert!(db.size_on_disk() - before >= plan.bytes, "grew {} bytes, planned {:?}", db.size_on_disk() - before, plan);
+    assert_eq!(db.get(&key(1999)).unwrap(), Some(Bytes::from(vec![1999u32 as u8; 100])));
+
+    // an empty transaction leaves the tree alone
+    let mut txn = db.write().unwrap();
+    assert_eq!(txn.plan().unwrap(), CommitPlan::default());
+    txn.delete(key(0)).unwrap();
+    assert!(txn.plan().unwrap().pages > 0);
+}